@@ -0,0 +1,12 @@
+//! Admission to relays that only serve token holders. A relay whose operator
+//! handed us a token lists `/limiinal/admission/1.0.0` over identify, and we
+//! present the token there before requesting a reservation. The token itself
+//! is opaque to us, the relay checks its signature, expiry and quotas.
+
+use libp2p::request_response::ProtocolSupport;
+
+pub use limiinal_common::admission::{AdmissionRequest, AdmissionResponse, Behaviour, PROTOCOL};
+
+pub fn new_behaviour() -> Behaviour {
+    limiinal_common::admission::new_behaviour(ProtocolSupport::Outbound)
+}
@@ -0,0 +1,220 @@
+//! Settings read from a TOML file, by default `config.toml` in the per-user
+//! config directory (e.g. `~/.config/limiinal` on Linux), with `--config`
+//! pointing elsewhere. Every setting is optional and flags given on the
+//! command line take precedence over the file.
+//!
+//! ```toml
+//! identity = "/home/me/.local/share/limiinal/identity.key"
+//!
+//! [network]
+//! listen = ["/ip4/0.0.0.0/udp/0/quic-v1", "/ip4/0.0.0.0/tcp/0"]
+//! relays = ["/ip4/195.114.14.137/tcp/4001/p2p/12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN"]
+//! relay_token = "eyJpZCI6…"
+//!
+//! [gossipsub]
+//! heartbeat_interval_ms = 1000
+//!
+//! [logging]
+//! filter = "info"
+//!
+//! [ui]
+//! theme = "dark"
+//! backend = true
+//! ```
+
+use std::{path::PathBuf, time::Duration};
+
+use libp2p::{gossipsub, multiaddr::Protocol, rendezvous::Namespace, Multiaddr, PeerId};
+use serde::Deserialize;
+
+use super::{identity, network::Opts, APP_NAME};
+
+pub use limiinal_common::config::{ConfigError, LoggingConfig};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Path of the identity keystore, defaults to `identity.key` in the user data directory.
+    pub identity: Option<PathBuf>,
+    pub network: NetworkConfig,
+    pub gossipsub: GossipsubConfig,
+    pub logging: LoggingConfig,
+    pub ui: UiConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Peer to connect to on startup.
+    pub remote_peer_id: Option<PeerId>,
+    /// Local addresses to listen on.
+    pub listen: Vec<Multiaddr>,
+    /// Relays to reserve a slot on, each ending in `/p2p/<relay peer id>`.
+    pub relays: Vec<Multiaddr>,
+    /// Number of relays to hold a reservation on at the same time.
+    pub reservations: usize,
+    /// Admission token minted by a relay operator, presented to relays that ask for one.
+    pub relay_token: Option<String>,
+    /// Protocol version announced over identify.
+    pub identify_protocol: String,
+    pub idle_timeout_secs: u64,
+    /// Rendezvous namespaces to register under on startup, e.g. a team name.
+    pub namespaces: Vec<String>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            remote_peer_id: None,
+            listen: vec![
+                "/ip4/0.0.0.0/udp/0/quic-v1"
+                    .parse()
+                    .expect("valid multiaddr"),
+                "/ip4/0.0.0.0/tcp/0".parse().expect("valid multiaddr"),
+            ],
+            relays: Vec::new(),
+            reservations: 2,
+            relay_token: None,
+            identify_protocol: "/limiinal/0.1.0".to_string(),
+            idle_timeout_secs: 60,
+            namespaces: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GossipsubConfig {
+    pub heartbeat_interval_ms: u64,
+    /// Target number of peers in the mesh of a topic, and the bounds it is kept in.
+    pub mesh_n: usize,
+    pub mesh_n_low: usize,
+    pub mesh_n_high: usize,
+    /// Largest message accepted or published, in bytes.
+    pub max_transmit_size: usize,
+}
+
+impl Default for GossipsubConfig {
+    fn default() -> Self {
+        GossipsubConfig {
+            heartbeat_interval_ms: 1000,
+            mesh_n: 6,
+            mesh_n_low: 5,
+            mesh_n_high: 12,
+            max_transmit_size: 65536,
+        }
+    }
+}
+
+impl GossipsubConfig {
+    pub fn build(&self) -> Result<gossipsub::Config, ConfigError> {
+        gossipsub::ConfigBuilder::default()
+            .heartbeat_interval(Duration::from_millis(self.heartbeat_interval_ms))
+            .mesh_n(self.mesh_n)
+            .mesh_n_low(self.mesh_n_low)
+            .mesh_n_high(self.mesh_n_high)
+            .max_transmit_size(self.max_transmit_size)
+            .validation_mode(gossipsub::ValidationMode::Strict)
+            .build()
+            .map_err(|e| ConfigError::Invalid(format!("gossipsub: {e}")))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThemePreference {
+    #[default]
+    Dark,
+    Light,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UiConfig {
+    pub theme: ThemePreference,
+    /// Start the backend once the identity is unlocked, like `--backend-enable`.
+    pub backend: bool,
+}
+
+impl Config {
+    /// Reads the config file named by `--config`, or the default one if it
+    /// exists, and applies the command line flags on top.
+    pub fn load(opts: &Opts) -> Result<Self, ConfigError> {
+        let mut config: Config = limiinal_common::config::load(opts.config.as_deref(), APP_NAME)?;
+
+        if let Some(identity) = &opts.identity {
+            config.identity = Some(identity.clone());
+        }
+        if let Some(peer) = opts.remote_peer_id {
+            config.network.remote_peer_id = Some(peer);
+        }
+        // Relays given on the command line replace the configured ones.
+        if !opts.relay_address.is_empty() {
+            config.network.relays = opts.relay_address.clone();
+        }
+        config.ui.backend |= opts.backend_enable;
+
+        Ok(config)
+    }
+
+    /// Path of the identity keystore.
+    pub fn identity_path(&self) -> PathBuf {
+        self.identity
+            .clone()
+            .unwrap_or_else(|| identity::default_path(APP_NAME))
+    }
+
+    /// Checks what parsing alone cannot: that the settings are usable together.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.network.listen.is_empty() {
+            return Err(ConfigError::Invalid("network.listen is empty".to_string()));
+        }
+        if self.network.reservations == 0 {
+            return Err(ConfigError::Invalid(
+                "network.reservations must be at least 1".to_string(),
+            ));
+        }
+        if self.network.relays.is_empty() {
+            return Err(ConfigError::Invalid(
+                "no relay, set network.relays or pass --relay-address".to_string(),
+            ));
+        }
+        for relay in &self.network.relays {
+            if !matches!(relay.iter().last(), Some(Protocol::P2p(_))) {
+                return Err(ConfigError::Invalid(format!(
+                    "relay {relay} does not end in /p2p/<relay peer id>"
+                )));
+            }
+        }
+        if self
+            .network
+            .relay_token
+            .as_ref()
+            .is_some_and(|token| token.trim().is_empty())
+        {
+            return Err(ConfigError::Invalid(
+                "network.relay_token is empty".to_string(),
+            ));
+        }
+        if self.network.identify_protocol.is_empty() {
+            return Err(ConfigError::Invalid(
+                "network.identify_protocol is empty".to_string(),
+            ));
+        }
+        for namespace in &self.network.namespaces {
+            Namespace::new(namespace.clone())
+                .map_err(|e| ConfigError::Invalid(format!("namespace {namespace:?}: {e}")))?;
+        }
+        self.gossipsub.build()?;
+        self.logging.validate()?;
+
+        Ok(())
+    }
+}
+
+/// Entry point of `limiinal_client config check`.
+pub fn check(opts: &Opts) -> Result<(), ConfigError> {
+    limiinal_common::config::check(opts.config.as_deref(), APP_NAME, || {
+        Config::load(opts)?.validate()
+    })
+}
@@ -0,0 +1,447 @@
+//! Connection manager: keeps the peers we want to stay connected to
+//! connected, redialling them with exponential backoff and jitter, and sums
+//! up the open connections as a [`Connectivity`] state for the frontends.
+//!
+//! Peers are dialled through the circuits of our relays, and DCUtR then tries
+//! to upgrade the relayed connection to a direct one. How that went is kept
+//! per peer as a [`HolePunchStatus`].
+
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use libp2p::{
+    autonat,
+    swarm::{
+        dial_opts::{DialOpts, PeerCondition},
+        ConnectionId, NetworkBehaviour, Swarm,
+    },
+    Multiaddr, PeerId,
+};
+use rand::Rng;
+use serde::Serialize;
+
+/// Delay before the first retry.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest delay between two retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Exponential backoff with jitter: every failure doubles the delay up to
+/// [`MAX_BACKOFF`], and each delay is drawn from its upper half so that
+/// clients losing the same relay do not all come back at once.
+#[derive(Debug, Clone, Default)]
+pub struct Backoff {
+    failures: u32,
+}
+
+impl Backoff {
+    /// Records a failure and returns the time to wait before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = INITIAL_BACKOFF
+            .saturating_mul(2u32.saturating_pow(self.failures))
+            .min(MAX_BACKOFF);
+        self.failures = self.failures.saturating_add(1);
+
+        rand::thread_rng().gen_range(delay / 2..=delay)
+    }
+
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+/// How well the client is connected, from worst to best.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Connectivity {
+    /// No connection at all.
+    #[default]
+    Offline,
+    /// Connected, e.g. to a relay, but not reachable by other peers yet.
+    Online,
+    /// Reachable through a relay reservation or at a public address, or
+    /// talking to peers over relayed connections only.
+    Relayed,
+    /// Holding a direct connection to a peer other than a relay.
+    Direct,
+}
+
+impl fmt::Display for Connectivity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Connectivity::Offline => "Offline",
+            Connectivity::Online => "Online",
+            Connectivity::Relayed => "Relayed",
+            Connectivity::Direct => "Direct",
+        })
+    }
+}
+
+/// Whether other peers can dial us without a relay, as probed by AutoNAT.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Reachability {
+    /// Not probed often enough to tell yet.
+    #[default]
+    Unknown,
+    /// Behind NAT or a firewall, reachable through relays only.
+    Private,
+    /// Dialable at a public address.
+    Public,
+}
+
+impl From<&autonat::NatStatus> for Reachability {
+    fn from(status: &autonat::NatStatus) -> Self {
+        match status {
+            autonat::NatStatus::Unknown => Reachability::Unknown,
+            autonat::NatStatus::Private => Reachability::Private,
+            autonat::NatStatus::Public(_) => Reachability::Public,
+        }
+    }
+}
+
+impl fmt::Display for Reachability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Reachability::Unknown => "NAT unknown",
+            Reachability::Private => "Behind NAT",
+            Reachability::Public => "Public",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HolePunchState {
+    /// Connected through a relay circuit, DCUtR is trying to upgrade it.
+    Relayed,
+    /// Connected directly, hole punched or reachable without it.
+    Direct,
+    /// The last hole punch failed for this reason, the connection stays relayed.
+    Failed(String),
+}
+
+impl fmt::Display for HolePunchState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HolePunchState::Relayed => f.write_str("relayed"),
+            HolePunchState::Direct => f.write_str("direct"),
+            HolePunchState::Failed(_) => f.write_str("failed"),
+        }
+    }
+}
+
+/// How the connection to a peer other than a relay stands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HolePunchStatus {
+    pub state: HolePunchState,
+    /// Hole punches with the peer that completed so far, either way.
+    pub attempts: u32,
+}
+
+#[derive(Debug, Default)]
+struct DesiredPeer {
+    /// Addresses the peer was dialled at, tried besides the relay circuits.
+    addresses: Vec<Multiaddr>,
+    /// Addresses the peer last published in the DHT.
+    located: Vec<Multiaddr>,
+    backoff: Backoff,
+    retry_at: Option<Instant>,
+    /// A dial is in flight, its outcome schedules the next one.
+    dialing: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct OpenConnection {
+    peer: PeerId,
+    relayed: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct ConnectionManager {
+    desired: HashMap<PeerId, DesiredPeer>,
+    connections: HashMap<ConnectionId, OpenConnection>,
+    hole_punches: HashMap<PeerId, HolePunchStatus>,
+    connectivity: Connectivity,
+}
+
+impl ConnectionManager {
+    /// Keeps `peer` connected from now on, dialling it at `address` too if given.
+    pub fn want(&mut self, peer: PeerId, address: Option<Multiaddr>) {
+        let desired = self.desired.entry(peer).or_default();
+        if let Some(address) = address {
+            if !desired.addresses.contains(&address) {
+                desired.addresses.push(address);
+            }
+        }
+        desired.backoff.reset();
+        desired.retry_at = None;
+    }
+
+    /// Stops keeping `peer` connected. An open connection is left to close
+    /// once idle.
+    pub fn release(&mut self, peer: &PeerId) {
+        self.desired.remove(peer);
+    }
+
+    /// Records a new connection. Unless `peer` is a relay, returns its hole
+    /// punch status if the connection changed it.
+    pub fn connected(
+        &mut self,
+        connection: ConnectionId,
+        peer: PeerId,
+        relayed: bool,
+        is_relay: bool,
+    ) -> Option<HolePunchStatus> {
+        self.connections
+            .insert(connection, OpenConnection { peer, relayed });
+        if let Some(desired) = self.desired.get_mut(&peer) {
+            desired.backoff.reset();
+            desired.retry_at = None;
+            desired.dialing = false;
+        }
+        if is_relay {
+            return None;
+        }
+
+        let state = if self.is_directly_connected(&peer) {
+            HolePunchState::Direct
+        } else {
+            HolePunchState::Relayed
+        };
+        match self.hole_punches.get_mut(&peer) {
+            Some(status) if status.state == state => None,
+            Some(status) => {
+                status.state = state;
+                Some(status.clone())
+            }
+            None => {
+                let status = HolePunchStatus { state, attempts: 0 };
+                self.hole_punches.insert(peer, status.clone());
+                Some(status)
+            }
+        }
+    }
+
+    /// DCUtR finished a hole punch with `peer`.
+    pub fn hole_punched(&mut self, peer: PeerId, result: Result<(), String>) -> HolePunchStatus {
+        let direct = self.is_directly_connected(&peer);
+        let status = self
+            .hole_punches
+            .entry(peer)
+            .or_insert_with(|| HolePunchStatus {
+                state: HolePunchState::Relayed,
+                attempts: 0,
+            });
+        status.attempts += 1;
+        status.state = match result {
+            Ok(()) => HolePunchState::Direct,
+            Err(_) if direct => HolePunchState::Direct,
+            Err(reason) => HolePunchState::Failed(reason),
+        };
+
+        status.clone()
+    }
+
+    /// Forgets a closed connection. If its peer stays connected, returns the
+    /// peer's hole punch status if it changed, i.e. the last direct
+    /// connection closed and only relayed ones are left.
+    pub fn closed(&mut self, connection: ConnectionId) -> Option<HolePunchStatus> {
+        let closed = self.connections.remove(&connection)?;
+        if !self.is_connected(&closed.peer) {
+            // The frontends forget the status when the peer disconnects.
+            self.hole_punches.remove(&closed.peer);
+            self.failed(&closed.peer);
+            return None;
+        }
+        if self.is_directly_connected(&closed.peer) {
+            return None;
+        }
+
+        let status = self
+            .hole_punches
+            .get_mut(&closed.peer)
+            .filter(|status| status.state == HolePunchState::Direct)?;
+        status.state = HolePunchState::Relayed;
+        Some(status.clone())
+    }
+
+    /// A dial of `peer` failed, or its last connection closed.
+    pub fn failed(&mut self, peer: &PeerId) {
+        if self.is_connected(peer) {
+            return;
+        }
+        if let Some(desired) = self.desired.get_mut(peer) {
+            let delay = desired.backoff.next_delay();
+            tracing::info!(%peer, ?delay, "Redialling peer after backoff");
+            desired.retry_at = Some(Instant::now() + delay);
+            desired.dialing = false;
+        }
+    }
+
+    /// A DHT lookup found the current `addresses` of `peer`. If it is not
+    /// connected, it is redialled there without waiting for its backoff.
+    pub fn located(&mut self, peer: &PeerId, addresses: Vec<Multiaddr>) {
+        let connected = self.is_connected(peer);
+        if let Some(desired) = self.desired.get_mut(peer) {
+            desired.located = addresses;
+            if !connected && !desired.dialing {
+                desired.retry_at = None;
+            }
+        }
+    }
+
+    /// Whether `peer` is kept connected.
+    pub fn is_wanted(&self, peer: &PeerId) -> bool {
+        self.desired.contains_key(peer)
+    }
+
+    fn is_connected(&self, peer: &PeerId) -> bool {
+        self.connections.values().any(|c| c.peer == *peer)
+    }
+
+    pub fn is_directly_connected(&self, peer: &PeerId) -> bool {
+        self.connections
+            .values()
+            .any(|c| c.peer == *peer && !c.relayed)
+    }
+
+    /// Dials the desired peers that are due, at their known addresses and
+    /// through the `circuits` of our relays.
+    pub fn maintain<B: NetworkBehaviour>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        circuits: impl Fn(PeerId) -> Vec<Multiaddr>,
+    ) {
+        let now = Instant::now();
+        let due: Vec<PeerId> = self
+            .desired
+            .iter()
+            .filter(|(peer, desired)| {
+                !self.is_connected(peer)
+                    && !desired.dialing
+                    && desired.retry_at.is_none_or(|at| at <= now)
+            })
+            .map(|(peer, _)| *peer)
+            .collect();
+
+        for peer in due {
+            let desired = self.desired.get_mut(&peer).expect("due peers are desired");
+            let mut addresses = desired.addresses.clone();
+            addresses.extend(desired.located.iter().cloned());
+            addresses.extend(circuits(peer));
+            if addresses.is_empty() {
+                // No relay connected yet, wait for one instead of failing the dial.
+                continue;
+            }
+            let opts = DialOpts::peer_id(peer)
+                .addresses(addresses)
+                .condition(PeerCondition::DisconnectedAndNotDialing)
+                .build();
+
+            // Until the dial reports back, do not try again.
+            desired.dialing = true;
+            if let Err(e) = swarm.dial(opts) {
+                tracing::debug!(%peer, "Did not redial peer: {e}");
+                self.failed(&peer);
+            }
+        }
+    }
+
+    /// Recomputes the connectivity, returning it if it changed. `reachable`
+    /// tells whether a relay holds a reservation for us or we are public.
+    pub fn update_connectivity(
+        &mut self,
+        reachable: bool,
+        is_relay: impl Fn(&PeerId) -> bool,
+    ) -> Option<Connectivity> {
+        let direct = self
+            .connections
+            .values()
+            .any(|c| !c.relayed && !is_relay(&c.peer));
+        let relayed = self.connections.values().any(|c| c.relayed);
+
+        let connectivity = if direct {
+            Connectivity::Direct
+        } else if reachable || relayed {
+            Connectivity::Relayed
+        } else if !self.connections.is_empty() {
+            Connectivity::Online
+        } else {
+            Connectivity::Offline
+        };
+
+        if connectivity == self.connectivity {
+            return None;
+        }
+        self.connectivity = connectivity;
+        Some(connectivity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut backoff = Backoff::default();
+        for failures in 0..20 {
+            let ceiling = INITIAL_BACKOFF
+                .saturating_mul(2u32.saturating_pow(failures))
+                .min(MAX_BACKOFF);
+            let delay = backoff.next_delay();
+            assert!(
+                (ceiling / 2..=ceiling).contains(&delay),
+                "{delay:?} after {failures} failures"
+            );
+        }
+        assert!(backoff.next_delay() <= MAX_BACKOFF);
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= INITIAL_BACKOFF);
+    }
+
+    #[test]
+    fn backoff_is_jittered() {
+        let delays: Vec<_> = (0..10)
+            .map(|_| Backoff { failures: 8 }.next_delay())
+            .collect();
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+    }
+
+    #[test]
+    fn hole_punch_status_follows_closed_connections() {
+        let mut connections = ConnectionManager::default();
+        let peer = PeerId::random();
+        let (relayed, direct) = (
+            ConnectionId::new_unchecked(1),
+            ConnectionId::new_unchecked(2),
+        );
+
+        let status = connections.connected(relayed, peer, true, false).unwrap();
+        assert_eq!(status.state, HolePunchState::Relayed);
+        connections.hole_punched(peer, Ok(()));
+        let status = connections.connected(direct, peer, false, false);
+        assert_eq!(status, None, "already direct after the hole punch");
+
+        let status = connections.closed(direct).unwrap();
+        assert_eq!(status.state, HolePunchState::Relayed);
+        assert_eq!(status.attempts, 1);
+
+        assert_eq!(connections.closed(relayed), None);
+        assert!(connections.hole_punches.is_empty());
+    }
+
+    #[test]
+    fn released_peers_are_no_longer_wanted() {
+        let mut connections = ConnectionManager::default();
+        let peer = PeerId::random();
+
+        connections.want(peer, None);
+        assert!(connections.is_wanted(&peer));
+        connections.release(&peer);
+        assert!(!connections.is_wanted(&peer));
+    }
+}
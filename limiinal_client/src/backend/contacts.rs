@@ -0,0 +1,157 @@
+//! Contacts: peers the user named, kept in the [`History`] database and
+//! sealed like the rest of it. Every sealed column is bound to the blind
+//! index of its contact, so it cannot be moved to another one.
+
+use libp2p::PeerId;
+use rusqlite::params;
+
+use super::history::{History, HistoryError};
+
+/// Largest avatar image accepted, in bytes.
+pub const MAX_AVATAR_LEN: usize = 256 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct Contact {
+    pub peer: PeerId,
+    pub nickname: String,
+    /// Encoded image, e.g. PNG or JPEG.
+    pub avatar: Option<Vec<u8>>,
+    /// Unix timestamp in milliseconds at which the peer was last connected.
+    pub last_seen: Option<i64>,
+}
+
+impl History {
+    /// Adds `peer` as a contact, or renames it if it already is one.
+    pub fn add_contact(&self, peer: &PeerId, nickname: &str) -> Result<(), HistoryError> {
+        let peer = peer.to_base58();
+        let hash = self.cipher.blind(&peer);
+        self.conn.execute(
+            "INSERT INTO contacts (peer_hash, peer_id, nickname, added_at) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (peer_hash) DO UPDATE SET nickname = ?3",
+            params![
+                hash,
+                self.cipher.seal_with("peer", &hash, peer.as_bytes()),
+                self.cipher
+                    .seal_with("nickname", &hash, nickname.as_bytes()),
+                chrono::Utc::now().timestamp_millis(),
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Renames a contact, returning whether `peer` is one.
+    pub fn rename_contact(&self, peer: &PeerId, nickname: &str) -> Result<bool, HistoryError> {
+        let hash = self.cipher.blind(&peer.to_base58());
+        let changed = self.conn.execute(
+            "UPDATE contacts SET nickname = ?2 WHERE peer_hash = ?1",
+            params![
+                hash,
+                self.cipher
+                    .seal_with("nickname", &hash, nickname.as_bytes()),
+            ],
+        )?;
+
+        Ok(changed > 0)
+    }
+
+    /// Replaces or clears the avatar of a contact, returning whether `peer` is one.
+    pub fn set_avatar(&self, peer: &PeerId, avatar: Option<&[u8]>) -> Result<bool, HistoryError> {
+        let hash = self.cipher.blind(&peer.to_base58());
+        let changed = self.conn.execute(
+            "UPDATE contacts SET avatar = ?2 WHERE peer_hash = ?1",
+            params![
+                hash,
+                avatar.map(|avatar| self.cipher.seal_with("avatar", &hash, avatar)),
+            ],
+        )?;
+
+        Ok(changed > 0)
+    }
+
+    /// Removes a contact, returning whether `peer` was one. The messages
+    /// exchanged with it stay in the history.
+    pub fn remove_contact(&self, peer: &PeerId) -> Result<bool, HistoryError> {
+        let removed = self.conn.execute(
+            "DELETE FROM contacts WHERE peer_hash = ?1",
+            params![self.cipher.blind(&peer.to_base58())],
+        )?;
+
+        Ok(removed > 0)
+    }
+
+    /// All contacts, in the order they were added.
+    pub fn contacts(&self) -> Result<Vec<Contact>, HistoryError> {
+        let mut statement = self.conn.prepare_cached(
+            "SELECT c.peer_id, c.nickname, c.avatar, p.last_seen, c.peer_hash
+             FROM contacts c
+             LEFT JOIN peers p ON p.peer_hash = c.peer_hash
+             ORDER BY c.added_at, c.rowid",
+        )?;
+
+        let mut rows = statement.query([])?;
+        let mut contacts = Vec::new();
+        while let Some(row) = rows.next()? {
+            let peer: Vec<u8> = row.get(0)?;
+            let nickname: Vec<u8> = row.get(1)?;
+            let avatar: Option<Vec<u8>> = row.get(2)?;
+            let hash: Vec<u8> = row.get(4)?;
+
+            contacts.push(Contact {
+                peer: self
+                    .cipher
+                    .open_string_with("peer", &hash, &peer)?
+                    .parse()
+                    .map_err(|_| HistoryError::Decrypt)?,
+                nickname: self.cipher.open_string_with("nickname", &hash, &nickname)?,
+                avatar: avatar
+                    .map(|avatar| self.cipher.open_with("avatar", &hash, &avatar))
+                    .transpose()?,
+                last_seen: row.get(3)?,
+            });
+        }
+
+        Ok(contacts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contacts_round_trip() {
+        let history = History::in_memory().unwrap();
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        history.add_contact(&alice, "Alice").unwrap();
+        history.add_contact(&bob, "Bob").unwrap();
+        assert!(history.rename_contact(&bob, "Robert").unwrap());
+        assert!(history.set_avatar(&alice, Some(b"png")).unwrap());
+        assert!(!history.rename_contact(&PeerId::random(), "Nobody").unwrap());
+
+        let contacts = history.contacts().unwrap();
+        assert_eq!(contacts[0].peer, alice);
+        assert_eq!(contacts[0].avatar.as_deref(), Some(&b"png"[..]));
+        assert_eq!(contacts[1].nickname, "Robert");
+
+        assert!(history.remove_contact(&alice).unwrap());
+        assert_eq!(history.contacts().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn swapped_nickname_fails_to_open() {
+        let history = History::in_memory().unwrap();
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        history.add_contact(&alice, "Alice").unwrap();
+        history.add_contact(&bob, "Bob").unwrap();
+
+        history
+            .conn
+            .execute_batch(
+                "UPDATE contacts SET nickname = (SELECT nickname FROM contacts WHERE rowid = 2)
+                 WHERE rowid = 1",
+            )
+            .unwrap();
+        assert!(matches!(history.contacts(), Err(HistoryError::Decrypt)));
+    }
+}
@@ -0,0 +1,1072 @@
+//! Headless mode: runs [`AppCore`] without the GUI and serves a line-delimited
+//! JSON-RPC 2.0 API on a Unix domain socket.
+//!
+//! Every request is one JSON object per line, e.g.
+//! `{"jsonrpc":"2.0","id":1,"method":"send","params":{"body":"hi"}}`, and gets
+//! exactly one response line with the same `id`. After `subscribe_events` the
+//! connection additionally receives `{"jsonrpc":"2.0","method":"event",...}`
+//! notifications for everything the swarm reports.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Local};
+use libp2p::{identity::Keypair, rendezvous::Namespace, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::broadcast,
+};
+
+use super::{
+    config::Config,
+    connections::{Connectivity, HolePunchState, Reachability},
+    discovery::Discovered,
+    dm::{DeliveryState, MessageId},
+    e2e,
+    history::{self, Conversation, ConversationKind, History, NewMessage},
+    identity,
+    invite::Invite,
+    keystore::Keystore,
+    network::{self, AppCore, AppCoreHandle, BackendConfig, Opts, Subcommand},
+    APP_NAME,
+};
+
+/// Environment variable holding the keystore passphrase when no file is given.
+const PASSPHRASE_ENV: &str = "LIMIINAL_PASSPHRASE";
+
+/// Events buffered per subscribed connection before it starts lagging.
+const EVENT_BUFFER: usize = 256;
+
+/// Messages returned by `history` unless the request asks for another amount.
+const HISTORY_PAGE: usize = 50;
+/// Most messages returned by a single `history` request.
+const MAX_HISTORY_PAGE: usize = 500;
+
+/// JSON-RPC error codes, see <https://www.jsonrpc.org/specification#error_object>.
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct SendParams {
+    #[serde(default = "default_topic")]
+    topic: String,
+    body: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TopicParams {
+    topic: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SendDirectParams {
+    peer: String,
+    body: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PeerParams {
+    peer: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct NamespaceParams {
+    namespace: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DialParams {
+    address: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct InviteParams {
+    /// Nickname suggested to whoever accepts the invite.
+    name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AcceptInviteParams {
+    link: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryParams {
+    /// Defaults to the default topic if `peer` is not given either.
+    topic: Option<String>,
+    peer: Option<String>,
+    /// Only return messages older than this row, to page backwards.
+    before: Option<i64>,
+    limit: Option<usize>,
+}
+
+fn default_topic() -> String {
+    network::DEFAULT_TOPIC.to_string()
+}
+
+/// Event notification as streamed to subscribed connections.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum EventNotification {
+    MessageReceived {
+        topic: String,
+        source: Option<String>,
+        body: String,
+        time: String,
+    },
+    PeerConnected {
+        peer_id: String,
+    },
+    PeerDisconnected {
+        peer_id: String,
+    },
+    ReservationAccepted {
+        relay_peer_id: String,
+        relay_address: String,
+    },
+    ReservationLost {
+        relay_peer_id: String,
+    },
+    ConnectivityChanged {
+        connectivity: Connectivity,
+    },
+    ReachabilityChanged {
+        reachability: Reachability,
+        /// Our public address, while we are public.
+        address: Option<String>,
+    },
+    HolePunch {
+        peer: String,
+        /// `relayed`, `direct` or `failed`.
+        state: String,
+        attempts: u32,
+        error: Option<String>,
+    },
+    DirectMessageReceived {
+        peer: String,
+        id: MessageId,
+        body: String,
+        sent_at: i64,
+    },
+    DirectMessageState {
+        peer: String,
+        id: MessageId,
+        state: &'static str,
+    },
+    Registered {
+        namespace: String,
+        rendezvous_peer_id: String,
+    },
+    RegistrationFailed {
+        namespace: String,
+        rendezvous_peer_id: String,
+        error: String,
+    },
+    PeerDiscovered(DiscoveredInfo),
+    DiscoveryExpired {
+        peer: String,
+    },
+    NearbyDiscovered {
+        peer: String,
+        addresses: Vec<String>,
+        /// Contacts are dialled at once.
+        contact: bool,
+    },
+    NearbyExpired {
+        peer: String,
+    },
+    PeerLocated {
+        peer: String,
+        /// Empty if the peer published no valid record.
+        addresses: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct DiscoveredInfo {
+    namespace: String,
+    peer: String,
+    addresses: Vec<String>,
+}
+
+impl From<&Discovered> for DiscoveredInfo {
+    fn from(discovered: &Discovered) -> Self {
+        DiscoveredInfo {
+            namespace: discovered.namespace.clone(),
+            peer: discovered.peer.to_string(),
+            addresses: discovered
+                .addresses
+                .iter()
+                .map(Multiaddr::to_string)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ConversationInfo {
+    /// Topic name or peer ID.
+    id: String,
+    kind: ConversationKind,
+    message_count: usize,
+    last_sender: Option<String>,
+    last_message: Option<String>,
+    last_time: Option<String>,
+}
+
+/// What the daemon has learned from the swarm since it started.
+#[derive(Default)]
+struct DaemonState {
+    peers: BTreeSet<PeerId>,
+    /// Relays we hold a reservation on, the first one is put in invites.
+    relays: Vec<(PeerId, Multiaddr)>,
+    connectivity: Connectivity,
+    reachability: Reachability,
+    /// Address AutoNAT confirmed we are reachable at, while we are public.
+    public_address: Option<Multiaddr>,
+    /// Peers found under our rendezvous namespaces and not expired since.
+    discovered: Vec<Discovered>,
+    /// Peers on the local network and their addresses there.
+    nearby: BTreeMap<PeerId, Vec<Multiaddr>>,
+}
+
+struct Daemon {
+    keypair: Keypair,
+    local_peer_id: PeerId,
+    /// Configured relays, the first one is put in invites while we are public.
+    relays: Vec<Multiaddr>,
+    handle: AppCoreHandle,
+    state: Mutex<DaemonState>,
+    history: Mutex<History>,
+    events: broadcast::Sender<EventNotification>,
+}
+
+/// Default location of the API socket.
+pub fn default_socket_path() -> PathBuf {
+    identity::data_dir(APP_NAME).join("daemon.sock")
+}
+
+/// Entry point of `limiinal_client daemon`.
+pub async fn run(opts: Opts) -> Result<(), Box<dyn Error>> {
+    let config = Config::load(&opts)?;
+    config.validate()?;
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(config.logging.env_filter())
+        .try_init();
+
+    let Some(Subcommand::Daemon {
+        socket,
+        passphrase_file,
+    }) = &opts.command
+    else {
+        return Err("daemon subcommand expected".into());
+    };
+
+    let identity_path = config.identity_path();
+    let keypair = load_identity(&opts, &identity_path, passphrase_file.as_deref())?;
+    let socket = socket.clone().unwrap_or_else(default_socket_path);
+    let session_store = opts
+        .insecure_test_seed
+        .is_none()
+        .then(|| e2e::store_path(&identity_path));
+    // Test identities are shared by design, so their history is not kept either.
+    let history = match opts.insecure_test_seed {
+        Some(_) => History::in_memory()?,
+        None => History::open(&history::store_path(&identity_path), &keypair)?,
+    };
+    history.conversation(&Conversation::Topic(network::DEFAULT_TOPIC.to_string()))?;
+
+    let local_peer_id = keypair.public().to_peer_id();
+    let relays = config.network.relays.clone();
+    let backend_config = BackendConfig {
+        session_store,
+        ..BackendConfig::new(&config)
+    };
+    let (app_core, handle, mut core_events) = AppCore::new(keypair.clone(), backend_config);
+    let backend = tokio::spawn(app_core.run());
+
+    let (events, _) = broadcast::channel(EVENT_BUFFER);
+    let daemon = Arc::new(Daemon {
+        keypair,
+        local_peer_id,
+        relays,
+        handle,
+        state: Mutex::new(DaemonState::default()),
+        history: Mutex::new(history),
+        events,
+    });
+
+    let listener = bind(&socket)?;
+    tracing::info!(socket=%socket.display(), peer=%local_peer_id, "Daemon API listening");
+
+    let accept = {
+        let daemon = daemon.clone();
+        async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(serve(daemon.clone(), stream));
+                    }
+                    Err(e) => tracing::error!("Failed to accept API connection: {e}"),
+                }
+            }
+        }
+    };
+
+    let pump = async {
+        while let Some(event) = core_events.recv().await {
+            daemon.handle_event(event);
+        }
+    };
+
+    tokio::select! {
+        _ = accept => {}
+        _ = pump => tracing::warn!("Backend stopped"),
+    }
+
+    let _ = fs::remove_file(&socket);
+    backend.await??;
+    Ok(())
+}
+
+fn load_identity(
+    opts: &Opts,
+    identity_path: &Path,
+    passphrase_file: Option<&Path>,
+) -> Result<Keypair, Box<dyn Error>> {
+    if let Some(seed) = opts.insecure_test_seed {
+        tracing::warn!("Using an insecure identity derived from --insecure-test-seed");
+        return Ok(identity::insecure_from_seed(seed));
+    }
+
+    let passphrase = match passphrase_file {
+        Some(path) => fs::read_to_string(path)?
+            .trim_end_matches(['\r', '\n'])
+            .to_string(),
+        None => env::var(PASSPHRASE_ENV)
+            .map_err(|_| format!("set {PASSPHRASE_ENV} or pass --passphrase-file"))?,
+    };
+
+    let keystore = Keystore::new(identity_path.to_path_buf());
+    let keypair = if keystore.exists() {
+        keystore.unlock(&passphrase)?
+    } else {
+        keystore.create(&passphrase)?
+    };
+
+    Ok(keypair)
+}
+
+/// Binds the API socket, replacing a stale one, in a directory only the
+/// current user may enter.
+fn bind(socket: &Path) -> Result<UnixListener, Box<dyn Error>> {
+    let listener = limiinal_common::socket::bind_private(socket).map_err(|e| e.to_string())?;
+    listener.set_nonblocking(true)?;
+
+    Ok(UnixListener::from_std(listener)?)
+}
+
+async fn serve(daemon: Arc<Daemon>, stream: UnixStream) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut events: Option<broadcast::Receiver<EventNotification>> = None;
+
+    loop {
+        let reply = tokio::select! {
+            line = lines.next_line() => match line {
+                Ok(Some(line)) if line.trim().is_empty() => continue,
+                Ok(Some(line)) => daemon.handle_line(&line, &mut events),
+                Ok(None) => break,
+                Err(e) => {
+                    tracing::warn!("Failed to read API request: {e}");
+                    break;
+                }
+            },
+            event = recv_event(&mut events) => match event {
+                Ok(event) => json!({ "jsonrpc": "2.0", "method": "event", "params": event }),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!(missed, "API client is lagging behind, dropped events");
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+        };
+
+        let mut line = reply.to_string();
+        line.push('\n');
+        if writer.write_all(line.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Waits for the next event, or forever if the connection did not subscribe.
+async fn recv_event(
+    events: &mut Option<broadcast::Receiver<EventNotification>>,
+) -> Result<EventNotification, broadcast::error::RecvError> {
+    match events {
+        Some(events) => events.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+impl Daemon {
+    fn handle_line(
+        &self,
+        line: &str,
+        events: &mut Option<broadcast::Receiver<EventNotification>>,
+    ) -> Value {
+        let request: Request = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => return error(Value::Null, PARSE_ERROR, e.to_string()),
+        };
+
+        let result = match request.method.as_str() {
+            "send" => self.send(request.params),
+            "send_direct" => self.send_direct(request.params),
+            "subscribe" => self.subscribe(request.params),
+            "dial" => self.dial(request.params),
+            "connect" => self.connect(request.params),
+            "release" => self.release(request.params),
+            "locate" => self.locate(request.params),
+            "list_peers" => Ok(self.list_peers()),
+            "connectivity" => Ok(json!(self.state.lock().unwrap().connectivity)),
+            "reachability" => {
+                let state = self.state.lock().unwrap();
+                Ok(json!({
+                    "reachability": state.reachability,
+                    "address": state.public_address.as_ref().map(Multiaddr::to_string),
+                }))
+            }
+            "register" => self.register(request.params),
+            "unregister" => self.unregister(request.params),
+            "discover" => self.discover(request.params),
+            "list_discovered" => Ok(self.list_discovered()),
+            "list_nearby" => Ok(self.list_nearby()),
+            "list_conversations" => self.list_conversations(),
+            "history" => self.history(request.params),
+            "safety_number" => self.safety_number(request.params),
+            "invite" => self.invite(request.params),
+            "accept_invite" => self.accept_invite(request.params),
+            "subscribe_events" => {
+                *events = Some(self.events.subscribe());
+                Ok(json!(true))
+            }
+            method => Err((METHOD_NOT_FOUND, format!("unknown method `{method}`"))),
+        };
+
+        match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": request.id, "result": result }),
+            Err((code, message)) => error(request.id, code, message),
+        }
+    }
+
+    fn send(&self, params: Value) -> Result<Value, (i64, String)> {
+        let SendParams { topic, body } = parse_params(params)?;
+        self.handle
+            .publish(topic.clone(), body.clone().into_bytes());
+        self.record(
+            &Conversation::Topic(topic),
+            NewMessage {
+                id: None,
+                sender: Some(self.local_peer_id.to_string()),
+                outgoing: true,
+                body: &body,
+                state: None,
+            },
+        );
+
+        Ok(json!(true))
+    }
+
+    fn send_direct(&self, params: Value) -> Result<Value, (i64, String)> {
+        let SendDirectParams { peer, body } = parse_params(params)?;
+        let peer: PeerId = peer
+            .parse()
+            .map_err(|e| (INVALID_PARAMS, format!("invalid peer id: {e}")))?;
+        let id = self.handle.send_direct(peer, body.clone());
+        self.record(
+            &Conversation::Direct(peer),
+            NewMessage {
+                id: Some(id),
+                sender: Some(self.local_peer_id.to_string()),
+                outgoing: true,
+                body: &body,
+                state: Some(DeliveryState::Sending),
+            },
+        );
+
+        Ok(json!({ "id": id }))
+    }
+
+    fn subscribe(&self, params: Value) -> Result<Value, (i64, String)> {
+        let TopicParams { topic } = parse_params(params)?;
+        self.handle.subscribe(topic.clone());
+        self.history
+            .lock()
+            .unwrap()
+            .conversation(&Conversation::Topic(topic))
+            .map_err(internal_error)?;
+
+        Ok(json!(true))
+    }
+
+    fn dial(&self, params: Value) -> Result<Value, (i64, String)> {
+        let DialParams { address } = parse_params(params)?;
+        let address: Multiaddr = address
+            .parse()
+            .map_err(|e| (INVALID_PARAMS, format!("invalid multiaddr: {e}")))?;
+        self.handle.dial(address);
+
+        Ok(json!(true))
+    }
+
+    fn list_peers(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        json!(state
+            .peers
+            .iter()
+            .map(PeerId::to_string)
+            .collect::<Vec<_>>())
+    }
+
+    fn list_conversations(&self) -> Result<Value, (i64, String)> {
+        let conversations = self
+            .history
+            .lock()
+            .unwrap()
+            .conversations()
+            .map_err(internal_error)?;
+
+        Ok(json!(conversations
+            .into_iter()
+            .map(|conversation| ConversationInfo {
+                id: conversation.key,
+                kind: conversation.kind,
+                message_count: conversation.message_count,
+                last_sender: conversation.last_sender,
+                last_message: conversation.last_message,
+                last_time: conversation.last_time.and_then(rfc3339),
+            })
+            .collect::<Vec<_>>()))
+    }
+
+    fn history(&self, params: Value) -> Result<Value, (i64, String)> {
+        let HistoryParams {
+            topic,
+            peer,
+            before,
+            limit,
+        } = parse_params(params)?;
+        let conversation = match (topic, peer) {
+            (Some(_), Some(_)) => {
+                return Err((INVALID_PARAMS, "pass either topic or peer".to_string()))
+            }
+            (_, Some(peer)) => Conversation::Direct(
+                peer.parse()
+                    .map_err(|e| (INVALID_PARAMS, format!("invalid peer id: {e}")))?,
+            ),
+            (topic, None) => Conversation::Topic(topic.unwrap_or_else(default_topic)),
+        };
+        let limit = limit.unwrap_or(HISTORY_PAGE).min(MAX_HISTORY_PAGE);
+
+        let history = self.history.lock().unwrap();
+        let messages = match before {
+            Some(row) => history.before(&conversation, row, limit),
+            None => history.latest(&conversation, limit),
+        }
+        .map_err(internal_error)?;
+
+        Ok(json!(messages))
+    }
+
+    fn connect(&self, params: Value) -> Result<Value, (i64, String)> {
+        let PeerParams { peer } = parse_params(params)?;
+        let peer: PeerId = peer
+            .parse()
+            .map_err(|e| (INVALID_PARAMS, format!("invalid peer id: {e}")))?;
+        self.handle.connect(peer);
+
+        Ok(json!(true))
+    }
+
+    fn release(&self, params: Value) -> Result<Value, (i64, String)> {
+        let PeerParams { peer } = parse_params(params)?;
+        let peer: PeerId = peer
+            .parse()
+            .map_err(|e| (INVALID_PARAMS, format!("invalid peer id: {e}")))?;
+        self.handle.release(peer);
+
+        Ok(json!(true))
+    }
+
+    fn register(&self, params: Value) -> Result<Value, (i64, String)> {
+        let namespace = namespace_params(params)?;
+        self.handle.register(namespace);
+
+        Ok(json!(true))
+    }
+
+    fn unregister(&self, params: Value) -> Result<Value, (i64, String)> {
+        let namespace = namespace_params(params)?;
+        self.state
+            .lock()
+            .unwrap()
+            .discovered
+            .retain(|discovered| discovered.namespace != namespace.to_string());
+        self.handle.unregister(namespace);
+
+        Ok(json!(true))
+    }
+
+    fn discover(&self, params: Value) -> Result<Value, (i64, String)> {
+        let namespace = namespace_params(params)?;
+        self.handle.discover(namespace);
+
+        Ok(json!(true))
+    }
+
+    fn list_discovered(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        json!(state
+            .discovered
+            .iter()
+            .map(DiscoveredInfo::from)
+            .collect::<Vec<_>>())
+    }
+
+    fn list_nearby(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        json!(state
+            .nearby
+            .iter()
+            .map(|(peer, addresses)| json!({
+                "peer": peer.to_string(),
+                "addresses": addresses.iter().map(Multiaddr::to_string).collect::<Vec<_>>(),
+            }))
+            .collect::<Vec<_>>())
+    }
+
+    /// Whether `peer` is in the contact list.
+    fn is_contact(&self, peer: &PeerId) -> bool {
+        match self.history.lock().unwrap().contacts() {
+            Ok(contacts) => contacts.iter().any(|contact| contact.peer == *peer),
+            Err(e) => {
+                tracing::error!("Failed to load contacts: {e}");
+                false
+            }
+        }
+    }
+
+    fn locate(&self, params: Value) -> Result<Value, (i64, String)> {
+        let PeerParams { peer } = parse_params(params)?;
+        let peer: PeerId = peer
+            .parse()
+            .map_err(|e| (INVALID_PARAMS, format!("invalid peer id: {e}")))?;
+        self.handle.locate(peer);
+
+        Ok(json!(true))
+    }
+
+    fn safety_number(&self, params: Value) -> Result<Value, (i64, String)> {
+        let PeerParams { peer } = parse_params(params)?;
+        let peer: PeerId = peer
+            .parse()
+            .map_err(|e| (INVALID_PARAMS, format!("invalid peer id: {e}")))?;
+        let number = e2e::safety_number(&self.local_peer_id, &peer).ok_or((
+            INVALID_PARAMS,
+            "peer id does not embed an ed25519 key".to_string(),
+        ))?;
+
+        Ok(json!(number))
+    }
+
+    fn invite(&self, params: Value) -> Result<Value, (i64, String)> {
+        let InviteParams { name } = parse_params::<Option<_>>(params)?.unwrap_or_default();
+        let state = self.state.lock().unwrap();
+        // Public peers hold no reservation, whoever accepts finds them in the DHT
+        // once the circuit through the relay fails.
+        let relay = match state.relays.first() {
+            Some((_, address)) => Some(address),
+            None if state.reachability == Reachability::Public => self.relays.first(),
+            None => None,
+        }
+        .cloned()
+        .ok_or((
+            INTERNAL_ERROR,
+            "no relay reservation yet, peers could not reach us".to_string(),
+        ))?;
+        let link = Invite::link(&self.keypair, &relay, name.as_deref())
+            .map_err(|e| (INVALID_PARAMS, e.to_string()))?;
+
+        Ok(json!(link))
+    }
+
+    fn accept_invite(&self, params: Value) -> Result<Value, (i64, String)> {
+        let AcceptInviteParams { link } = parse_params(params)?;
+        let invite = Invite::parse(&link).map_err(|e| (INVALID_PARAMS, e.to_string()))?;
+        if invite.peer == self.local_peer_id {
+            return Err((INVALID_PARAMS, "this is our own invite".to_string()));
+        }
+
+        let name = invite
+            .name
+            .clone()
+            .unwrap_or_else(|| invite.peer.to_base58());
+        self.history
+            .lock()
+            .unwrap()
+            .add_contact(&invite.peer, &name)
+            .map_err(internal_error)?;
+        self.handle.dial(invite.circuit_address());
+
+        Ok(json!({ "peer": invite.peer.to_string(), "name": name }))
+    }
+
+    /// Appends a message to the history. The daemon keeps running without it.
+    fn record(&self, conversation: &Conversation, message: NewMessage<'_>) {
+        if let Err(e) = self.history.lock().unwrap().record(conversation, message) {
+            tracing::error!("Failed to record message: {e}");
+        }
+    }
+
+    fn handle_event(&self, event: network::Event) {
+        let notification = {
+            let mut state = self.state.lock().unwrap();
+            match event {
+                network::Event::MessageReceived {
+                    topic,
+                    source,
+                    data,
+                } => {
+                    let source = source.map(|peer| peer.to_string());
+                    let body = String::from_utf8_lossy(&data).into_owned();
+                    self.record(
+                        &Conversation::Topic(topic.clone()),
+                        NewMessage {
+                            id: None,
+                            sender: source.clone(),
+                            outgoing: false,
+                            body: &body,
+                            state: None,
+                        },
+                    );
+                    EventNotification::MessageReceived {
+                        topic,
+                        source,
+                        body,
+                        time: Local::now().to_rfc3339(),
+                    }
+                }
+                network::Event::PeerConnected(peer_id) => {
+                    state.peers.insert(peer_id);
+                    if let Err(e) = self.history.lock().unwrap().saw_peer(&peer_id) {
+                        tracing::error!("Failed to record peer: {e}");
+                    }
+                    EventNotification::PeerConnected {
+                        peer_id: peer_id.to_string(),
+                    }
+                }
+                network::Event::PeerDisconnected(peer_id) => {
+                    state.peers.remove(&peer_id);
+                    EventNotification::PeerDisconnected {
+                        peer_id: peer_id.to_string(),
+                    }
+                }
+                network::Event::ReservationAccepted {
+                    relay_peer_id,
+                    relay_address,
+                } => {
+                    state.relays.push((relay_peer_id, relay_address.clone()));
+                    EventNotification::ReservationAccepted {
+                        relay_peer_id: relay_peer_id.to_string(),
+                        relay_address: relay_address.to_string(),
+                    }
+                }
+                network::Event::ReservationLost { relay_peer_id } => {
+                    state.relays.retain(|(peer, _)| *peer != relay_peer_id);
+                    EventNotification::ReservationLost {
+                        relay_peer_id: relay_peer_id.to_string(),
+                    }
+                }
+                network::Event::ConnectivityChanged(connectivity) => {
+                    state.connectivity = connectivity;
+                    EventNotification::ConnectivityChanged { connectivity }
+                }
+                network::Event::ReachabilityChanged {
+                    reachability,
+                    address,
+                } => {
+                    state.reachability = reachability;
+                    state.public_address = address.clone();
+                    EventNotification::ReachabilityChanged {
+                        reachability,
+                        address: address.as_ref().map(Multiaddr::to_string),
+                    }
+                }
+                network::Event::HolePunch { peer, status } => EventNotification::HolePunch {
+                    peer: peer.to_string(),
+                    state: status.state.to_string(),
+                    attempts: status.attempts,
+                    error: match status.state {
+                        HolePunchState::Failed(reason) => Some(reason),
+                        _ => None,
+                    },
+                },
+                network::Event::DirectMessageReceived {
+                    peer,
+                    id,
+                    body,
+                    sent_at,
+                } => {
+                    self.record(
+                        &Conversation::Direct(peer),
+                        NewMessage {
+                            id: Some(id),
+                            sender: Some(peer.to_string()),
+                            outgoing: false,
+                            body: &body,
+                            state: None,
+                        },
+                    );
+                    let peer = peer.to_string();
+                    EventNotification::DirectMessageReceived {
+                        peer,
+                        id,
+                        body,
+                        sent_at,
+                    }
+                }
+                network::Event::Registered {
+                    namespace,
+                    rendezvous_peer_id,
+                } => EventNotification::Registered {
+                    namespace,
+                    rendezvous_peer_id: rendezvous_peer_id.to_string(),
+                },
+                network::Event::RegistrationFailed {
+                    namespace,
+                    rendezvous_peer_id,
+                    error,
+                } => EventNotification::RegistrationFailed {
+                    namespace,
+                    rendezvous_peer_id: rendezvous_peer_id.to_string(),
+                    error,
+                },
+                network::Event::PeerDiscovered(discovered) => {
+                    let notification = EventNotification::PeerDiscovered((&discovered).into());
+                    state.discovered.retain(|known| {
+                        (&known.namespace, known.peer) != (&discovered.namespace, discovered.peer)
+                    });
+                    state.discovered.push(discovered);
+                    notification
+                }
+                network::Event::DiscoveryExpired(peer) => {
+                    state.discovered.retain(|known| known.peer != peer);
+                    EventNotification::DiscoveryExpired {
+                        peer: peer.to_string(),
+                    }
+                }
+                network::Event::NearbyDiscovered { peer, addresses } => {
+                    let contact = self.is_contact(&peer);
+                    if contact {
+                        self.handle.connect(peer);
+                    }
+                    let notification = EventNotification::NearbyDiscovered {
+                        peer: peer.to_string(),
+                        addresses: addresses.iter().map(Multiaddr::to_string).collect(),
+                        contact,
+                    };
+                    state.nearby.insert(peer, addresses);
+                    notification
+                }
+                network::Event::NearbyExpired(peer) => {
+                    state.nearby.remove(&peer);
+                    EventNotification::NearbyExpired {
+                        peer: peer.to_string(),
+                    }
+                }
+                network::Event::PeerLocated { peer, addresses } => EventNotification::PeerLocated {
+                    peer: peer.to_string(),
+                    addresses: addresses.iter().map(Multiaddr::to_string).collect(),
+                },
+                network::Event::DirectMessageStateChanged { peer, id, state } => {
+                    if let Err(e) = self.history.lock().unwrap().set_state(id, state) {
+                        tracing::error!("Failed to record delivery state: {e}");
+                    }
+                    EventNotification::DirectMessageState {
+                        peer: peer.to_string(),
+                        id,
+                        state: state.as_str(),
+                    }
+                }
+            }
+        };
+
+        // No receivers just means nobody subscribed to events.
+        let _ = self.events.send(notification);
+    }
+}
+
+fn parse_params<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, (i64, String)> {
+    serde_json::from_value(params).map_err(|e| (INVALID_PARAMS, e.to_string()))
+}
+
+fn namespace_params(params: Value) -> Result<Namespace, (i64, String)> {
+    let NamespaceParams { namespace } = parse_params(params)?;
+    Namespace::new(namespace).map_err(|e| (INVALID_PARAMS, e.to_string()))
+}
+
+fn internal_error(e: history::HistoryError) -> (i64, String) {
+    (INTERNAL_ERROR, e.to_string())
+}
+
+fn rfc3339(millis: i64) -> Option<String> {
+    DateTime::from_timestamp_millis(millis).map(|time| time.with_timezone(&Local).to_rfc3339())
+}
+
+fn error(id: Value, code: i64, message: String) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A daemon whose backend never runs, with the core kept so commands are queued.
+    fn daemon() -> (Daemon, AppCore) {
+        let keypair = Keypair::generate_ed25519();
+        let (core, handle, _) = AppCore::new(keypair.clone(), BackendConfig::default());
+        let daemon = Daemon {
+            local_peer_id: keypair.public().to_peer_id(),
+            keypair,
+            relays: Vec::new(),
+            handle,
+            state: Mutex::new(DaemonState::default()),
+            history: Mutex::new(History::in_memory().unwrap()),
+            events: broadcast::channel(EVENT_BUFFER).0,
+        };
+
+        (daemon, core)
+    }
+
+    fn call(daemon: &Daemon, request: Value) -> Value {
+        daemon.handle_line(&request.to_string(), &mut None)
+    }
+
+    #[test]
+    fn reports_protocol_errors() {
+        let (daemon, _core) = daemon();
+
+        let reply = daemon.handle_line("{not json", &mut None);
+        assert_eq!(reply["error"]["code"], PARSE_ERROR);
+        assert_eq!(reply["id"], Value::Null);
+
+        let reply = call(
+            &daemon,
+            json!({ "jsonrpc": "2.0", "id": 7, "method": "frobnicate" }),
+        );
+        assert_eq!(reply["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(reply["id"], 7);
+
+        let reply = call(
+            &daemon,
+            json!({ "jsonrpc": "2.0", "id": 8, "method": "send", "params": { "topic": "chat" } }),
+        );
+        assert_eq!(reply["error"]["code"], INVALID_PARAMS);
+
+        let reply = call(
+            &daemon,
+            json!({ "jsonrpc": "2.0", "id": 9, "method": "send_direct",
+                    "params": { "peer": "not a peer", "body": "hi" } }),
+        );
+        assert_eq!(reply["error"]["code"], INVALID_PARAMS);
+    }
+
+    #[test]
+    fn sent_messages_show_up_in_history() {
+        let (daemon, _core) = daemon();
+
+        let reply = call(
+            &daemon,
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "send",
+                    "params": { "topic": "chat", "body": "hello" } }),
+        );
+        assert_eq!(reply["result"], true);
+
+        let reply = call(
+            &daemon,
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "history", "params": { "topic": "chat" } }),
+        );
+        assert_eq!(reply["id"], 2);
+        let messages = reply["result"].as_array().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["body"], "hello");
+        assert_eq!(messages[0]["outgoing"], true);
+        assert_eq!(messages[0]["sender"], daemon.local_peer_id.to_string());
+    }
+
+    #[test]
+    fn direct_messages_page_backwards() {
+        let (daemon, _core) = daemon();
+        let peer = PeerId::random().to_string();
+
+        for body in ["one", "two", "three"] {
+            let reply = call(
+                &daemon,
+                json!({ "jsonrpc": "2.0", "id": 1, "method": "send_direct",
+                        "params": { "peer": peer, "body": body } }),
+            );
+            assert!(reply["result"]["id"].is_u64());
+        }
+
+        let latest = call(
+            &daemon,
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "history",
+                    "params": { "peer": peer, "limit": 2 } }),
+        );
+        let latest = latest["result"].as_array().unwrap();
+        assert_eq!(latest.len(), 2);
+        assert_eq!(latest[0]["body"], "two");
+        assert_eq!(latest[1]["state"], "sending");
+
+        let older = call(
+            &daemon,
+            json!({ "jsonrpc": "2.0", "id": 3, "method": "history",
+                    "params": { "peer": peer, "before": latest[0]["row"] } }),
+        );
+        assert_eq!(older["result"][0]["body"], "one");
+        assert_eq!(older["result"].as_array().unwrap().len(), 1);
+
+        let both = call(
+            &daemon,
+            json!({ "jsonrpc": "2.0", "id": 4, "method": "history",
+                    "params": { "peer": peer, "topic": "chat" } }),
+        );
+        assert_eq!(both["error"]["code"], INVALID_PARAMS);
+    }
+}
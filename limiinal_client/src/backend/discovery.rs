@@ -0,0 +1,193 @@
+//! Peer discovery through the rendezvous servers of our relays. Joining a
+//! namespace, e.g. a team name, registers our circuit addresses under it on
+//! every connected relay and looks up who else registered there, so peers
+//! find each other without exchanging peer IDs out of band. Registrations
+//! expire on the servers, so they are renewed while we stay in the namespace,
+//! and the lookups are repeated to notice peers joining after us.
+
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+use libp2p::{
+    multiaddr::Protocol,
+    rendezvous::{self, Namespace},
+    Multiaddr, PeerId,
+};
+
+/// How often registrations are renewed, well within the servers' default
+/// time to live of two hours.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How often the joined namespaces are looked up again.
+const DISCOVER_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A peer registered under a namespace we looked up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Discovered {
+    pub namespace: String,
+    pub peer: PeerId,
+    /// Addresses the peer registered, usually its relay circuits, each
+    /// ending in `/p2p/<peer>`.
+    pub addresses: Vec<Multiaddr>,
+}
+
+#[derive(Debug)]
+pub struct Discovery {
+    namespaces: HashSet<Namespace>,
+    /// Peers reported so far, per namespace.
+    known: HashSet<(Namespace, PeerId)>,
+    refresh_at: Instant,
+    discover_at: Instant,
+}
+
+impl Discovery {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Discovery {
+            namespaces: HashSet::new(),
+            known: HashSet::new(),
+            refresh_at: now + REFRESH_INTERVAL,
+            discover_at: now + DISCOVER_INTERVAL,
+        }
+    }
+
+    /// Registers under `namespace` at each of the rendezvous `points` and
+    /// looks up the peers registered there. Without any point yet, this
+    /// happens once we are connected to a relay.
+    pub fn join(
+        &mut self,
+        behaviour: &mut rendezvous::client::Behaviour,
+        points: &[PeerId],
+        namespace: Namespace,
+    ) {
+        for point in points {
+            register(behaviour, *point, &namespace);
+            behaviour.discover(Some(namespace.clone()), None, None, *point);
+        }
+        self.namespaces.insert(namespace);
+    }
+
+    pub fn leave(
+        &mut self,
+        behaviour: &mut rendezvous::client::Behaviour,
+        points: &[PeerId],
+        namespace: &Namespace,
+    ) {
+        if self.namespaces.remove(namespace) {
+            for point in points {
+                behaviour.unregister(namespace.clone(), *point);
+            }
+        }
+        self.known.retain(|(known, _)| known != namespace);
+    }
+
+    /// Looks up the peers under `namespace` now, reporting all of them again.
+    pub fn discover(
+        &mut self,
+        behaviour: &mut rendezvous::client::Behaviour,
+        points: &[PeerId],
+        namespace: &Namespace,
+    ) {
+        self.known.retain(|(known, _)| known != namespace);
+        for point in points {
+            behaviour.discover(Some(namespace.clone()), None, None, *point);
+        }
+    }
+
+    /// Registers every joined namespace at a rendezvous point we just got
+    /// to know, and looks up the peers there.
+    pub fn joined_at(&self, behaviour: &mut rendezvous::client::Behaviour, point: PeerId) {
+        for namespace in &self.namespaces {
+            register(behaviour, point, namespace);
+            behaviour.discover(Some(namespace.clone()), None, None, point);
+        }
+    }
+
+    /// Registers every joined namespace at `points` again, e.g. because our
+    /// circuit addresses changed.
+    pub fn refresh(&mut self, behaviour: &mut rendezvous::client::Behaviour, points: &[PeerId]) {
+        for point in points {
+            for namespace in &self.namespaces {
+                register(behaviour, *point, namespace);
+            }
+        }
+        self.refresh_at = Instant::now() + REFRESH_INTERVAL;
+    }
+
+    /// Renews the registrations and repeats the lookups when they are due.
+    pub fn maintain(&mut self, behaviour: &mut rendezvous::client::Behaviour, points: &[PeerId]) {
+        let now = Instant::now();
+        if self.refresh_at <= now {
+            self.refresh(behaviour, points);
+        }
+        if self.discover_at <= now {
+            for point in points {
+                for namespace in &self.namespaces {
+                    behaviour.discover(Some(namespace.clone()), None, None, *point);
+                }
+            }
+            self.discover_at = now + DISCOVER_INTERVAL;
+        }
+    }
+
+    /// Peers other than `local_peer_id` found by a lookup that were not
+    /// reported before.
+    pub fn discovered(
+        &mut self,
+        local_peer_id: &PeerId,
+        registrations: Vec<rendezvous::Registration>,
+    ) -> Vec<Discovered> {
+        registrations
+            .into_iter()
+            .filter(|registration| {
+                let peer = registration.record.peer_id();
+                peer != *local_peer_id
+                    && self.namespaces.contains(&registration.namespace)
+                    && self.known.insert((registration.namespace.clone(), peer))
+            })
+            .map(|registration| {
+                let peer = registration.record.peer_id();
+                Discovered {
+                    namespace: registration.namespace.to_string(),
+                    peer,
+                    addresses: dialable(peer, registration.record.addresses()),
+                }
+            })
+            .collect()
+    }
+
+    /// The registrations of `peer` we found expired, returning whether it
+    /// was reported before.
+    pub fn expired(&mut self, peer: &PeerId) -> bool {
+        let before = self.known.len();
+        self.known.retain(|(_, known)| known != peer);
+        self.known.len() != before
+    }
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Discovery::new()
+    }
+}
+
+/// `addresses` of `peer`, each ending in `/p2p/<peer>` so they can be dialled as they are.
+fn dialable(peer: PeerId, addresses: &[Multiaddr]) -> Vec<Multiaddr> {
+    addresses
+        .iter()
+        .map(|address| match address.iter().last() {
+            Some(Protocol::P2p(_)) => address.clone(),
+            _ => address.clone().with(Protocol::P2p(peer)),
+        })
+        .collect()
+}
+
+fn register(behaviour: &mut rendezvous::client::Behaviour, point: PeerId, namespace: &Namespace) {
+    if let Err(e) = behaviour.register(namespace.clone(), point, None) {
+        // Without a reservation we have no address worth registering yet,
+        // registering is tried again once a circuit address comes up.
+        tracing::debug!(%namespace, rendezvous=%point, "Not registering yet: {e}");
+    }
+}
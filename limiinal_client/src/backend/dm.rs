@@ -0,0 +1,77 @@
+//! Direct one-to-one messages over the `/limiinal/dm/2.0.0` request-response
+//! protocol. The request carries the message sealed for the recipient, the
+//! response acknowledges that the remote client received and opened it.
+
+use std::str::FromStr;
+
+use libp2p::{
+    request_response::{self, ProtocolSupport},
+    StreamProtocol,
+};
+use serde::{Deserialize, Serialize};
+
+use super::e2e::Envelope;
+
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/limiinal/dm/2.0.0");
+
+/// Identifies a direct message between its sender and recipient.
+pub type MessageId = u64;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectMessage {
+    pub id: MessageId,
+    pub envelope: Envelope,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ack {
+    pub id: MessageId,
+}
+
+pub type Behaviour = request_response::json::Behaviour<DirectMessage, Ack>;
+
+pub fn new_behaviour() -> Behaviour {
+    request_response::json::Behaviour::new(
+        [(PROTOCOL, ProtocolSupport::Full)],
+        request_response::Config::default(),
+    )
+}
+
+/// Delivery state of a direct message sent by us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryState {
+    /// Handed to the backend.
+    Sending,
+    /// Passed to the swarm, waiting for the acknowledgement.
+    Sent,
+    /// The recipient acknowledged the message.
+    Delivered,
+    /// The message could not be delivered.
+    Failed,
+}
+
+impl DeliveryState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryState::Sending => "sending",
+            DeliveryState::Sent => "sent",
+            DeliveryState::Delivered => "delivered",
+            DeliveryState::Failed => "failed",
+        }
+    }
+}
+
+impl FromStr for DeliveryState {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sending" => Ok(DeliveryState::Sending),
+            "sent" => Ok(DeliveryState::Sent),
+            "delivered" => Ok(DeliveryState::Delivered),
+            "failed" => Ok(DeliveryState::Failed),
+            _ => Err(()),
+        }
+    }
+}
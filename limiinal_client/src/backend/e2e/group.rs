@@ -0,0 +1,149 @@
+//! Sender keys for topics. Every member encrypts its topic messages with a
+//! symmetric chain of its own and hands the current chain key to the other
+//! members over their pairwise Double Ratchet sessions. Keys are advanced and
+//! forgotten after every message, so a leaked chain key does not reveal
+//! earlier messages.
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use serde::{Deserialize, Serialize};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use super::{
+    ratchet::{kdf_ck, open, seal, Key},
+    E2eError,
+};
+
+/// Most message keys skipped over in a single step.
+const MAX_SKIP: u32 = 1000;
+/// Most skipped message keys kept per chain.
+const MAX_SKIPPED_KEYS: usize = 2000;
+
+/// State of a sender chain as handed to another member.
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct SenderKey {
+    pub key_id: u32,
+    pub chain_key: Key,
+    /// Position of the next message sealed with this chain.
+    pub n: u32,
+}
+
+/// Payload of a gossipsub message on an encrypted topic.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupEnvelope {
+    pub key_id: u32,
+    pub n: u32,
+    pub nonce: [u8; 24],
+    pub ciphertext: Vec<u8>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+struct SkippedKey {
+    n: u32,
+    key: Key,
+}
+
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct SenderChain {
+    key_id: u32,
+    chain_key: Key,
+    n: u32,
+    skipped: Vec<SkippedKey>,
+}
+
+impl SenderChain {
+    pub fn generate() -> Self {
+        let mut chain_key = [0u8; 32];
+        OsRng.fill_bytes(&mut chain_key);
+
+        SenderChain {
+            key_id: OsRng.next_u32(),
+            chain_key,
+            n: 0,
+            skipped: Vec::new(),
+        }
+    }
+
+    pub fn from_key(key: &SenderKey) -> Self {
+        SenderChain {
+            key_id: key.key_id,
+            chain_key: key.chain_key,
+            n: key.n,
+            skipped: Vec::new(),
+        }
+    }
+
+    pub fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    pub fn key(&self) -> SenderKey {
+        SenderKey {
+            key_id: self.key_id,
+            chain_key: self.chain_key,
+            n: self.n,
+        }
+    }
+
+    pub fn encrypt(&mut self, ad: &[u8], plaintext: &[u8]) -> GroupEnvelope {
+        let n = self.n;
+        let message_key = kdf_ck(&mut self.chain_key);
+        self.n += 1;
+
+        let (nonce, ciphertext) = seal(&message_key, &aad(ad, self.key_id, n), plaintext);
+        GroupEnvelope {
+            key_id: self.key_id,
+            n,
+            nonce,
+            ciphertext,
+        }
+    }
+
+    /// Decrypts a message of this chain, leaving the chain untouched if it does
+    /// not authenticate.
+    pub fn decrypt(&mut self, ad: &[u8], envelope: &GroupEnvelope) -> Result<Vec<u8>, E2eError> {
+        let aad = aad(ad, envelope.key_id, envelope.n);
+        if let Some(i) = self.skipped.iter().position(|k| k.n == envelope.n) {
+            let plaintext = open(
+                &self.skipped[i].key,
+                &aad,
+                &envelope.nonce,
+                &envelope.ciphertext,
+            )?;
+            self.skipped.remove(i);
+            return Ok(plaintext.to_vec());
+        }
+        if envelope.n < self.n {
+            // Already decrypted, or from before we were handed the chain.
+            return Err(E2eError::Decrypt);
+        }
+        if envelope.n - self.n > MAX_SKIP {
+            return Err(E2eError::TooManySkipped);
+        }
+
+        let mut next = self.clone();
+        while next.n < envelope.n {
+            let key = *kdf_ck(&mut next.chain_key);
+            next.skipped.push(SkippedKey { n: next.n, key });
+            next.n += 1;
+        }
+        let message_key = kdf_ck(&mut next.chain_key);
+        next.n += 1;
+        let plaintext = open(&message_key, &aad, &envelope.nonce, &envelope.ciphertext)?;
+
+        if next.skipped.len() > MAX_SKIPPED_KEYS {
+            let excess = next.skipped.len() - MAX_SKIPPED_KEYS;
+            next.skipped.drain(..excess);
+        }
+        *self = next;
+
+        Ok(plaintext.to_vec())
+    }
+}
+
+fn aad(ad: &[u8], key_id: u32, n: u32) -> Vec<u8> {
+    let mut aad = ad.to_vec();
+    aad.extend_from_slice(&key_id.to_be_bytes());
+    aad.extend_from_slice(&n.to_be_bytes());
+
+    aad
+}
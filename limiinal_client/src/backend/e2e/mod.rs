@@ -0,0 +1,858 @@
+//! End-to-end encryption of direct messages and topic messages.
+//!
+//! Noise only protects a single hop, so payloads relayed by `limiinal_relay` or
+//! forwarded by gossipsub peers would otherwise be readable by them. Direct
+//! payloads are sealed with a Double Ratchet session per peer. Sessions start
+//! with an X3DH handshake against the peer's libp2p ed25519 identity key and a
+//! prekey bundle fetched over `/limiinal/prekey/1.0.0`. Topic messages are
+//! sealed with per-sender chains ("sender keys") handed out over those sessions.
+//!
+//! Sessions, prekeys and sender keys are kept in a file next to the identity
+//! keystore, encrypted with a key derived from the identity. Changes are
+//! written out by [`E2e::flush`], which the swarm loop calls once a second,
+//! rather than after every message.
+
+mod group;
+mod ratchet;
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    error::Error,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload as AeadPayload},
+    XChaCha20Poly1305, XNonce,
+};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use hkdf::Hkdf;
+use libp2p::{
+    identity::{self, ed25519},
+    request_response::{self, ProtocolSupport},
+    PeerId, StreamProtocol,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use self::{
+    group::SenderChain,
+    ratchet::{dh, x3dh_secret, Session},
+};
+use super::identity::write_private;
+
+pub use self::{
+    group::{GroupEnvelope, SenderKey},
+    ratchet::Header,
+};
+
+pub const PREKEY_PROTOCOL: StreamProtocol = StreamProtocol::new("/limiinal/prekey/1.0.0");
+
+// Store layout:
+//   magic (4) | version (1) | nonce (24) | ciphertext
+const STORE_MAGIC: &[u8; 4] = b"LME2";
+const STORE_VERSION: u8 = 1;
+const STORE_HEADER_LEN: usize = STORE_MAGIC.len() + 1 + 24;
+const STORE_INFO: &[u8] = b"limiinal-e2e-store";
+
+const SIGNED_PREKEY_CONTEXT: &[u8] = b"limiinal-signed-prekey";
+/// Age after which the signed prekey is replaced, in milliseconds.
+const SIGNED_PREKEY_LIFETIME: i64 = 7 * 24 * 60 * 60 * 1000;
+/// One-time prekeys generated at once when fewer than [`MIN_ONE_TIME_PREKEYS`]
+/// are left to hand out.
+const ONE_TIME_PREKEY_BATCH: u32 = 20;
+const MIN_ONE_TIME_PREKEYS: usize = 5;
+/// One-time prekeys handed out but never used are dropped past this count.
+const MAX_ONE_TIME_PREKEYS: usize = 100;
+/// Sessions kept per peer, to follow a peer that started a new session while
+/// messages under the old one are still in flight.
+const MAX_SESSIONS_PER_PEER: usize = 4;
+/// Topic messages held back until their sender key arrives.
+const MAX_PENDING_GROUP_MESSAGES: usize = 64;
+const FINGERPRINT_ITERATIONS: usize = 5200;
+
+#[derive(Debug)]
+pub enum E2eError {
+    Io(io::Error),
+    /// The session store could not be decrypted or parsed.
+    Store,
+    /// The identity or a peer ID does not carry an ed25519 key.
+    UnsupportedKey,
+    /// A public key is malformed or of low order.
+    InvalidKey,
+    /// The signed prekey of a bundle is not signed by the peer's identity.
+    BadSignature,
+    /// There is no session with the peer yet.
+    NoSession,
+    /// The message was built on a prekey we no longer have.
+    UnknownPrekey,
+    /// The message does not authenticate under any session.
+    Decrypt,
+    /// The message is too far ahead of its chain.
+    TooManySkipped,
+    /// The sender key of the message's author has not arrived yet.
+    MissingSenderKey,
+}
+
+impl fmt::Display for E2eError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            E2eError::Io(e) => write!(f, "session store I/O error: {e}"),
+            E2eError::Store => write!(f, "unreadable session store"),
+            E2eError::UnsupportedKey => write!(f, "not an ed25519 identity"),
+            E2eError::InvalidKey => write!(f, "invalid public key"),
+            E2eError::BadSignature => write!(f, "bad signed prekey signature"),
+            E2eError::NoSession => write!(f, "no session with peer"),
+            E2eError::UnknownPrekey => write!(f, "unknown prekey"),
+            E2eError::Decrypt => write!(f, "message does not decrypt"),
+            E2eError::TooManySkipped => write!(f, "too many skipped messages"),
+            E2eError::MissingSenderKey => write!(f, "sender key not received yet"),
+        }
+    }
+}
+
+impl Error for E2eError {}
+
+impl From<io::Error> for E2eError {
+    fn from(e: io::Error) -> Self {
+        E2eError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrekeyRequest;
+
+/// Keys a peer needs to start a session with us without a round trip of its own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrekeyBundle {
+    pub signed_prekey_id: u32,
+    pub signed_prekey: [u8; 32],
+    /// Signature of the signed prekey by the identity key.
+    pub signature: Vec<u8>,
+    pub one_time_prekey: Option<(u32, [u8; 32])>,
+}
+
+pub type PrekeyBehaviour = request_response::json::Behaviour<PrekeyRequest, PrekeyBundle>;
+
+pub fn new_prekey_behaviour() -> PrekeyBehaviour {
+    request_response::json::Behaviour::new(
+        [(PREKEY_PROTOCOL, ProtocolSupport::Full)],
+        request_response::Config::default(),
+    )
+}
+
+/// X3DH parameters the initiator repeats on its messages until the peer answers,
+/// so the peer can set up the session from whichever arrives first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrekeyHeader {
+    pub ephemeral: [u8; 32],
+    pub signed_prekey_id: u32,
+    pub one_time_prekey_id: Option<u32>,
+}
+
+/// A payload sealed for a single peer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub prekey: Option<PrekeyHeader>,
+    pub header: Header,
+    pub nonce: [u8; 24],
+    pub ciphertext: Vec<u8>,
+}
+
+/// Content of an [`Envelope`].
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Payload {
+    Text {
+        body: String,
+        /// Unix timestamp in milliseconds, taken by the sender.
+        sent_at: i64,
+    },
+    /// Our sender key for a topic.
+    SenderKey { topic: String, key: SenderKey },
+}
+
+#[derive(Serialize, Deserialize)]
+struct SignedPrekey {
+    id: u32,
+    secret: [u8; 32],
+    created_at: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PeerSession {
+    ratchet: Session,
+    /// Ephemeral key of the handshake that set the session up.
+    base_key: [u8; 32],
+    pending_prekey: Option<PrekeyHeader>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OwnSenderKey {
+    chain: SenderChain,
+    /// Members holding the chain.
+    shared_with: HashSet<PeerId>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    signed_prekey: Option<SignedPrekey>,
+    /// Kept for one rotation to finish handshakes begun before it.
+    previous_signed_prekey: Option<SignedPrekey>,
+    one_time_prekeys: BTreeMap<u32, [u8; 32]>,
+    /// One-time prekeys below this id were handed out already.
+    next_handout: u32,
+    next_prekey_id: u32,
+    /// Sessions per peer, the one we send with first.
+    sessions: HashMap<PeerId, Vec<PeerSession>>,
+    own_sender_keys: HashMap<String, OwnSenderKey>,
+    /// Sender chains of other members, per member and topic.
+    sender_keys: HashMap<PeerId, HashMap<String, SenderChain>>,
+}
+
+/// End-to-end sessions of the local identity.
+pub struct E2e {
+    identity: ed25519::Keypair,
+    local_peer_id: PeerId,
+    store: Option<PathBuf>,
+    state: State,
+    /// Topic messages whose sender key has not arrived yet.
+    pending_group: VecDeque<(PeerId, String, GroupEnvelope)>,
+    /// Whether `state` changed since it was last written to the store.
+    dirty: bool,
+}
+
+impl E2e {
+    /// Loads the sessions kept at `store`. Without a store they only live as
+    /// long as the process.
+    pub fn load(keypair: &identity::Keypair, store: Option<PathBuf>) -> Result<Self, E2eError> {
+        let local_peer_id = keypair.public().to_peer_id();
+        let identity = keypair
+            .clone()
+            .try_into_ed25519()
+            .map_err(|_| E2eError::UnsupportedKey)?;
+        let state = match &store {
+            Some(path) if path.exists() => open_store(&identity, &fs::read(path)?)?,
+            _ => State::default(),
+        };
+
+        let mut e2e = E2e {
+            identity,
+            local_peer_id,
+            store,
+            state,
+            pending_group: VecDeque::new(),
+            dirty: true,
+        };
+        e2e.refresh_prekeys();
+        e2e.flush();
+
+        Ok(e2e)
+    }
+
+    /// Bundle handed to a peer that wants to start a session with us. Every
+    /// bundle carries another one-time prekey while there are some left.
+    pub fn bundle(&mut self) -> PrekeyBundle {
+        self.refresh_prekeys();
+
+        let signed_prekey = self
+            .state
+            .signed_prekey
+            .as_ref()
+            .expect("refresh_prekeys creates a signed prekey");
+        let public = public_key(&signed_prekey.secret).to_bytes();
+        let one_time_prekey = self
+            .state
+            .one_time_prekeys
+            .range(self.state.next_handout..)
+            .next()
+            .map(|(id, secret)| (*id, public_key(secret).to_bytes()));
+        if let Some((id, _)) = one_time_prekey {
+            self.state.next_handout = id + 1;
+        }
+
+        let bundle = PrekeyBundle {
+            signed_prekey_id: signed_prekey.id,
+            signed_prekey: public,
+            signature: self.identity.sign(&signed_prekey_message(&public)),
+            one_time_prekey,
+        };
+        self.changed();
+
+        bundle
+    }
+
+    pub fn has_session(&self, peer: &PeerId) -> bool {
+        self.state
+            .sessions
+            .get(peer)
+            .is_some_and(|sessions| !sessions.is_empty())
+    }
+
+    /// Runs X3DH against `peer`'s bundle. The new session is used for sending
+    /// from now on.
+    pub fn initiate(&mut self, peer: PeerId, bundle: &PrekeyBundle) -> Result<(), E2eError> {
+        let remote_identity = identity_key(&peer).ok_or(E2eError::UnsupportedKey)?;
+        if !remote_identity.verify(
+            &signed_prekey_message(&bundle.signed_prekey),
+            &bundle.signature,
+        ) {
+            return Err(E2eError::BadSignature);
+        }
+
+        let signed_prekey = PublicKey::from(bundle.signed_prekey);
+        let ephemeral = StaticSecret::random_from_rng(OsRng);
+        let mut dh_outputs = vec![
+            dh(&self.x25519_identity(), &signed_prekey)?,
+            dh(&ephemeral, &montgomery(&remote_identity)?)?,
+            dh(&ephemeral, &signed_prekey)?,
+        ];
+        if let Some((_, one_time_prekey)) = bundle.one_time_prekey {
+            dh_outputs.push(dh(&ephemeral, &PublicKey::from(one_time_prekey))?);
+        }
+
+        let secret = x3dh_secret(&dh_outputs);
+        let ad = associated_data(&self.identity.public(), &remote_identity);
+        let base_key = PublicKey::from(&ephemeral).to_bytes();
+        let session = PeerSession {
+            ratchet: Session::initiator(&secret, &signed_prekey, ad)?,
+            base_key,
+            pending_prekey: Some(PrekeyHeader {
+                ephemeral: base_key,
+                signed_prekey_id: bundle.signed_prekey_id,
+                one_time_prekey_id: bundle.one_time_prekey.map(|(id, _)| id),
+            }),
+        };
+        self.push_session(peer, session);
+        self.changed();
+
+        Ok(())
+    }
+
+    pub fn encrypt(&mut self, peer: &PeerId, payload: &Payload) -> Result<Envelope, E2eError> {
+        let session = self
+            .state
+            .sessions
+            .get_mut(peer)
+            .and_then(|sessions| sessions.first_mut())
+            .ok_or(E2eError::NoSession)?;
+
+        let plaintext = Zeroizing::new(serde_json::to_vec(payload).expect("payloads serialize"));
+        let (header, nonce, ciphertext) = session.ratchet.encrypt(&plaintext)?;
+        let envelope = Envelope {
+            prekey: session.pending_prekey.clone(),
+            header,
+            nonce,
+            ciphertext,
+        };
+        self.changed();
+
+        Ok(envelope)
+    }
+
+    pub fn decrypt(&mut self, peer: PeerId, envelope: &Envelope) -> Result<Payload, E2eError> {
+        let plaintext = match &envelope.prekey {
+            Some(prekey) if !self.knows_base_key(&peer, &prekey.ephemeral) => {
+                let mut session = self.respond(&peer, prekey)?;
+                let plaintext = session.ratchet.decrypt(
+                    &envelope.header,
+                    &envelope.nonce,
+                    &envelope.ciphertext,
+                )?;
+                if let Some(id) = prekey.one_time_prekey_id {
+                    self.state.one_time_prekeys.remove(&id);
+                }
+                self.push_session(peer, session);
+
+                plaintext
+            }
+            _ => self.decrypt_existing(&peer, envelope)?,
+        };
+        self.changed();
+
+        serde_json::from_slice(&plaintext).map_err(|_| E2eError::Decrypt)
+    }
+
+    /// Encrypts a message for `topic` with our sender chain. Returns the
+    /// envelope and the sender key payloads for the `members` that do not
+    /// hold the chain yet, which have to be delivered for them to read it.
+    pub fn encrypt_group(
+        &mut self,
+        topic: &str,
+        plaintext: &[u8],
+        members: impl IntoIterator<Item = PeerId>,
+    ) -> (GroupEnvelope, Vec<(PeerId, Payload)>) {
+        let ad = group_associated_data(&self.local_peer_id, topic);
+        let own = self
+            .state
+            .own_sender_keys
+            .entry(topic.to_string())
+            .or_insert_with(|| OwnSenderKey {
+                chain: SenderChain::generate(),
+                shared_with: HashSet::new(),
+            });
+
+        let key = own.chain.key();
+        let distributions = members
+            .into_iter()
+            .filter(|peer| own.shared_with.insert(*peer))
+            .map(|peer| {
+                let payload = Payload::SenderKey {
+                    topic: topic.to_string(),
+                    key: key.clone(),
+                };
+                (peer, payload)
+            })
+            .collect();
+        let envelope = own.chain.encrypt(&ad, plaintext);
+        self.changed();
+
+        (envelope, distributions)
+    }
+
+    /// Decrypts a topic message from `source`. A message whose sender key has
+    /// not arrived yet is held back and returned by [`E2e::add_sender_key`].
+    pub fn decrypt_group(
+        &mut self,
+        source: PeerId,
+        topic: &str,
+        envelope: GroupEnvelope,
+    ) -> Result<Vec<u8>, E2eError> {
+        let ad = group_associated_data(&source, topic);
+        let chain = self
+            .state
+            .sender_keys
+            .get_mut(&source)
+            .and_then(|chains| chains.get_mut(topic))
+            .filter(|chain| chain.key_id() == envelope.key_id);
+        let Some(chain) = chain else {
+            if self.pending_group.len() == MAX_PENDING_GROUP_MESSAGES {
+                self.pending_group.pop_front();
+            }
+            self.pending_group
+                .push_back((source, topic.to_string(), envelope));
+            return Err(E2eError::MissingSenderKey);
+        };
+
+        let plaintext = chain.decrypt(&ad, &envelope)?;
+        self.changed();
+
+        Ok(plaintext)
+    }
+
+    /// Stores the sender key `source` handed us for `topic` and returns the
+    /// held back messages it opens.
+    pub fn add_sender_key(
+        &mut self,
+        source: PeerId,
+        topic: &str,
+        key: &SenderKey,
+    ) -> Vec<(String, Vec<u8>)> {
+        let chains = self.state.sender_keys.entry(source).or_default();
+        if chains.get(topic).map(SenderChain::key_id) != Some(key.key_id) {
+            chains.insert(topic.to_string(), SenderChain::from_key(key));
+        }
+
+        let (ready, waiting) = std::mem::take(&mut self.pending_group)
+            .into_iter()
+            .partition::<VecDeque<_>, _>(|(peer, t, _)| *peer == source && t == topic);
+        self.pending_group = waiting;
+
+        let messages = ready
+            .into_iter()
+            .filter_map(|(_, topic, envelope)| {
+                let plaintext = self.decrypt_group(source, &topic, envelope).ok()?;
+                Some((topic, plaintext))
+            })
+            .collect();
+        self.changed();
+
+        messages
+    }
+
+    /// Forgets that `peer` holds our sender key for `topic`, e.g. because
+    /// handing it over failed, so it is sent again with the next message.
+    pub fn sender_key_lost(&mut self, topic: &str, peer: &PeerId) {
+        if let Some(own) = self.state.own_sender_keys.get_mut(topic) {
+            own.shared_with.remove(peer);
+            self.changed();
+        }
+    }
+
+    /// Replaces our sender key for `topic` if `peer` held it, so a member
+    /// that left cannot read what follows.
+    pub fn member_left(&mut self, topic: &str, peer: &PeerId) {
+        if self
+            .state
+            .own_sender_keys
+            .get(topic)
+            .is_some_and(|own| own.shared_with.contains(peer))
+        {
+            self.state.own_sender_keys.remove(topic);
+            self.changed();
+        }
+    }
+
+    fn knows_base_key(&self, peer: &PeerId, base_key: &[u8; 32]) -> bool {
+        self.state
+            .sessions
+            .get(peer)
+            .is_some_and(|sessions| sessions.iter().any(|s| &s.base_key == base_key))
+    }
+
+    /// Answers the X3DH handshake described by `prekey`.
+    fn respond(&self, peer: &PeerId, prekey: &PrekeyHeader) -> Result<PeerSession, E2eError> {
+        let remote_identity = identity_key(peer).ok_or(E2eError::UnsupportedKey)?;
+        let signed_prekey = [
+            &self.state.signed_prekey,
+            &self.state.previous_signed_prekey,
+        ]
+        .into_iter()
+        .flatten()
+        .find(|signed_prekey| signed_prekey.id == prekey.signed_prekey_id)
+        .map(|signed_prekey| StaticSecret::from(signed_prekey.secret))
+        .ok_or(E2eError::UnknownPrekey)?;
+
+        let ephemeral = PublicKey::from(prekey.ephemeral);
+        let mut dh_outputs = vec![
+            dh(&signed_prekey, &montgomery(&remote_identity)?)?,
+            dh(&self.x25519_identity(), &ephemeral)?,
+            dh(&signed_prekey, &ephemeral)?,
+        ];
+        if let Some(id) = prekey.one_time_prekey_id {
+            let one_time_prekey = self
+                .state
+                .one_time_prekeys
+                .get(&id)
+                .ok_or(E2eError::UnknownPrekey)?;
+            dh_outputs.push(dh(&StaticSecret::from(*one_time_prekey), &ephemeral)?);
+        }
+
+        let secret = x3dh_secret(&dh_outputs);
+        let ad = associated_data(&remote_identity, &self.identity.public());
+        Ok(PeerSession {
+            ratchet: Session::responder(&secret, &signed_prekey, ad),
+            base_key: prekey.ephemeral,
+            pending_prekey: None,
+        })
+    }
+
+    /// Tries every session with `peer`, newest first. The one that opens the
+    /// message becomes the one we send with, which is how two peers that
+    /// started sessions at the same time settle on one.
+    fn decrypt_existing(
+        &mut self,
+        peer: &PeerId,
+        envelope: &Envelope,
+    ) -> Result<Zeroizing<Vec<u8>>, E2eError> {
+        let sessions = self
+            .state
+            .sessions
+            .get_mut(peer)
+            .ok_or(E2eError::NoSession)?;
+
+        for i in 0..sessions.len() {
+            let Ok(plaintext) = sessions[i].ratchet.decrypt(
+                &envelope.header,
+                &envelope.nonce,
+                &envelope.ciphertext,
+            ) else {
+                continue;
+            };
+
+            let mut session = sessions.remove(i);
+            // The peer answered, so it has set up the session.
+            session.pending_prekey = None;
+            sessions.insert(0, session);
+            return Ok(plaintext);
+        }
+
+        Err(E2eError::Decrypt)
+    }
+
+    fn push_session(&mut self, peer: PeerId, session: PeerSession) {
+        let sessions = self.state.sessions.entry(peer).or_default();
+        sessions.insert(0, session);
+        sessions.truncate(MAX_SESSIONS_PER_PEER);
+    }
+
+    fn refresh_prekeys(&mut self) {
+        let now = chrono::Utc::now().timestamp_millis();
+        let state = &mut self.state;
+
+        if state
+            .signed_prekey
+            .as_ref()
+            .is_none_or(|signed_prekey| now - signed_prekey.created_at > SIGNED_PREKEY_LIFETIME)
+        {
+            let signed_prekey = SignedPrekey {
+                id: state.next_prekey_id,
+                secret: StaticSecret::random_from_rng(OsRng).to_bytes(),
+                created_at: now,
+            };
+            state.next_prekey_id += 1;
+            state.previous_signed_prekey = state.signed_prekey.replace(signed_prekey);
+        }
+
+        if state.one_time_prekeys.range(state.next_handout..).count() < MIN_ONE_TIME_PREKEYS {
+            for _ in 0..ONE_TIME_PREKEY_BATCH {
+                let secret = StaticSecret::random_from_rng(OsRng).to_bytes();
+                state.one_time_prekeys.insert(state.next_prekey_id, secret);
+                state.next_prekey_id += 1;
+            }
+        }
+        while state.one_time_prekeys.len() > MAX_ONE_TIME_PREKEYS {
+            state.one_time_prekeys.pop_first();
+        }
+    }
+
+    /// The identity key converted to its X25519 form.
+    fn x25519_identity(&self) -> StaticSecret {
+        let hash = Sha512::digest(self.identity.secret().as_ref());
+        let mut secret = Zeroizing::new([0u8; 32]);
+        secret.copy_from_slice(&hash[..32]);
+
+        StaticSecret::from(*secret)
+    }
+
+    fn changed(&mut self) {
+        self.dirty = true;
+    }
+
+    /// Writes the sessions to the store if they changed since the last write.
+    pub fn flush(&mut self) {
+        if !std::mem::take(&mut self.dirty) {
+            return;
+        }
+        let Some(path) = &self.store else {
+            return;
+        };
+
+        let plaintext = Zeroizing::new(serde_json::to_vec(&self.state).expect("state serializes"));
+        if let Err(e) = write_private(path, &seal_store(&self.identity, &plaintext)) {
+            tracing::warn!(path=%path.display(), "Failed to save end-to-end sessions: {e}");
+        }
+    }
+}
+
+impl Drop for E2e {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Location of the session store belonging to the identity keystore at `identity`.
+pub fn store_path(identity: &Path) -> PathBuf {
+    identity.with_extension("sessions")
+}
+
+/// The ed25519 identity key embedded in `peer`.
+pub fn identity_key(peer: &PeerId) -> Option<ed25519::PublicKey> {
+    let multihash = peer.as_ref();
+    if multihash.code() != 0 {
+        return None;
+    }
+
+    identity::PublicKey::try_decode_protobuf(multihash.digest())
+        .ok()?
+        .try_into_ed25519()
+        .ok()
+}
+
+/// Safety number of the conversation between `local` and `remote`: 60 digits
+/// in groups of five, the same on both sides. Comparing it over another channel
+/// confirms that no one sits in between.
+pub fn safety_number(local: &PeerId, remote: &PeerId) -> Option<String> {
+    let mut fingerprints = [fingerprint(local)?, fingerprint(remote)?];
+    fingerprints.sort();
+    let digits = fingerprints.concat();
+
+    let groups: Vec<&str> = (0..digits.len())
+        .step_by(5)
+        .map(|i| &digits[i..i + 5])
+        .collect();
+    Some(groups.join(" "))
+}
+
+fn fingerprint(peer: &PeerId) -> Option<String> {
+    let key = identity_key(peer)?.to_bytes();
+    let mut hash = Sha512::new()
+        .chain_update([0u8, 0])
+        .chain_update(key)
+        .chain_update(peer.to_bytes())
+        .finalize();
+    for _ in 1..FINGERPRINT_ITERATIONS {
+        hash = Sha512::new()
+            .chain_update(hash)
+            .chain_update(key)
+            .finalize();
+    }
+
+    Some(
+        hash[..30]
+            .chunks(5)
+            .map(|chunk| {
+                let value = chunk.iter().fold(0u64, |acc, b| acc << 8 | *b as u64);
+                format!("{:05}", value % 100_000)
+            })
+            .collect(),
+    )
+}
+
+fn public_key(secret: &[u8; 32]) -> PublicKey {
+    PublicKey::from(&StaticSecret::from(*secret))
+}
+
+/// Converts an ed25519 public key to the X25519 key of the same secret.
+fn montgomery(key: &ed25519::PublicKey) -> Result<PublicKey, E2eError> {
+    let point = CompressedEdwardsY(key.to_bytes())
+        .decompress()
+        .ok_or(E2eError::InvalidKey)?;
+
+    Ok(PublicKey::from(point.to_montgomery().to_bytes()))
+}
+
+fn signed_prekey_message(public: &[u8; 32]) -> Vec<u8> {
+    [SIGNED_PREKEY_CONTEXT, public].concat()
+}
+
+fn associated_data(initiator: &ed25519::PublicKey, responder: &ed25519::PublicKey) -> Vec<u8> {
+    [initiator.to_bytes(), responder.to_bytes()].concat()
+}
+
+fn group_associated_data(source: &PeerId, topic: &str) -> Vec<u8> {
+    [source.to_bytes(), topic.as_bytes().to_vec()].concat()
+}
+
+fn store_key(identity: &ed25519::Keypair) -> Zeroizing<[u8; 32]> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(None, identity.secret().as_ref())
+        .expand(STORE_INFO, key.as_mut())
+        .expect("32 bytes is a valid HKDF output length");
+
+    key
+}
+
+fn seal_store(identity: &ed25519::Keypair, plaintext: &[u8]) -> Vec<u8> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let mut blob = Vec::with_capacity(STORE_HEADER_LEN + plaintext.len() + 16);
+    blob.extend_from_slice(STORE_MAGIC);
+    blob.push(STORE_VERSION);
+    blob.extend_from_slice(&nonce);
+
+    let ciphertext = XChaCha20Poly1305::new(store_key(identity).as_slice().into())
+        .encrypt(
+            &nonce,
+            AeadPayload {
+                msg: plaintext,
+                aad: &blob,
+            },
+        )
+        .expect("encryption with a valid key does not fail");
+    blob.extend_from_slice(&ciphertext);
+
+    blob
+}
+
+fn open_store(identity: &ed25519::Keypair, blob: &[u8]) -> Result<State, E2eError> {
+    if blob.len() <= STORE_HEADER_LEN
+        || !blob.starts_with(STORE_MAGIC)
+        || blob[STORE_MAGIC.len()] != STORE_VERSION
+    {
+        return Err(E2eError::Store);
+    }
+
+    let (header, ciphertext) = blob.split_at(STORE_HEADER_LEN);
+    let plaintext = Zeroizing::new(
+        XChaCha20Poly1305::new(store_key(identity).as_slice().into())
+            .decrypt(
+                XNonce::from_slice(&header[STORE_MAGIC.len() + 1..]),
+                AeadPayload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| E2eError::Store)?,
+    );
+
+    serde_json::from_slice(&plaintext).map_err(|_| E2eError::Store)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(body: &str) -> Payload {
+        Payload::Text {
+            body: body.to_owned(),
+            sent_at: 0,
+        }
+    }
+
+    fn body(payload: Payload) -> String {
+        match payload {
+            Payload::Text { body, .. } => body,
+            Payload::SenderKey { .. } => panic!("expected a text payload"),
+        }
+    }
+
+    #[test]
+    fn handshake_and_reply() {
+        let alice_key = identity::Keypair::generate_ed25519();
+        let bob_key = identity::Keypair::generate_ed25519();
+        let (alice_id, bob_id) = (
+            alice_key.public().to_peer_id(),
+            bob_key.public().to_peer_id(),
+        );
+        let mut alice = E2e::load(&alice_key, None).unwrap();
+        let mut bob = E2e::load(&bob_key, None).unwrap();
+
+        alice.initiate(bob_id, &bob.bundle()).unwrap();
+        let envelope = alice.encrypt(&bob_id, &text("hello")).unwrap();
+        assert_eq!(body(bob.decrypt(alice_id, &envelope).unwrap()), "hello");
+
+        let envelope = bob.encrypt(&alice_id, &text("hi")).unwrap();
+        assert!(envelope.prekey.is_none());
+        assert_eq!(body(alice.decrypt(bob_id, &envelope).unwrap()), "hi");
+    }
+
+    #[test]
+    fn rejects_bundle_signed_by_someone_else() {
+        let mut alice = E2e::load(&identity::Keypair::generate_ed25519(), None).unwrap();
+        let mut mallory = E2e::load(&identity::Keypair::generate_ed25519(), None).unwrap();
+        let bob_id = identity::Keypair::generate_ed25519().public().to_peer_id();
+
+        assert!(matches!(
+            alice.initiate(bob_id, &mallory.bundle()),
+            Err(E2eError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn sessions_are_written_on_flush() {
+        let alice_key = identity::Keypair::generate_ed25519();
+        let bob_key = identity::Keypair::generate_ed25519();
+        let bob_id = bob_key.public().to_peer_id();
+        let store = std::env::temp_dir().join(format!(
+            "limiinal-e2e-{}.sessions",
+            alice_key.public().to_peer_id()
+        ));
+        let mut bob = E2e::load(&bob_key, None).unwrap();
+
+        let mut alice = E2e::load(&alice_key, Some(store.clone())).unwrap();
+        alice.initiate(bob_id, &bob.bundle()).unwrap();
+        assert!(!E2e::load(&alice_key, Some(store.clone()))
+            .unwrap()
+            .has_session(&bob_id));
+
+        alice.flush();
+        assert!(E2e::load(&alice_key, Some(store.clone()))
+            .unwrap()
+            .has_session(&bob_id));
+        drop(alice);
+        fs::remove_file(&store).unwrap();
+    }
+}
@@ -0,0 +1,418 @@
+//! Double Ratchet sessions as described in the Signal specification, keyed by
+//! the shared secret of an X3DH handshake.
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use super::E2eError;
+
+/// Most message keys skipped over in a single step, so a forged header cannot
+/// make us derive keys forever.
+const MAX_SKIP: u32 = 1000;
+/// Most skipped message keys kept per session.
+const MAX_SKIPPED_KEYS: usize = 2000;
+
+const X3DH_INFO: &[u8] = b"limiinal-x3dh";
+const RATCHET_INFO: &[u8] = b"limiinal-ratchet";
+
+pub type Key = [u8; 32];
+
+/// Diffie-Hellman that refuses low-order public keys.
+pub fn dh(secret: &StaticSecret, public: &PublicKey) -> Result<Zeroizing<Key>, E2eError> {
+    let shared = secret.diffie_hellman(public);
+    if !shared.was_contributory() {
+        return Err(E2eError::InvalidKey);
+    }
+
+    Ok(Zeroizing::new(shared.to_bytes()))
+}
+
+/// Derives the shared secret of an X3DH handshake from its Diffie-Hellman outputs.
+pub fn x3dh_secret(dh_outputs: &[Zeroizing<Key>]) -> Zeroizing<Key> {
+    let mut ikm = Zeroizing::new(vec![0xff; 32]);
+    for output in dh_outputs {
+        ikm.extend_from_slice(output.as_ref());
+    }
+
+    let mut secret = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(&[0u8; 32]), &ikm)
+        .expand(X3DH_INFO, secret.as_mut())
+        .expect("32 bytes is a valid HKDF output length");
+
+    secret
+}
+
+/// Advances a symmetric chain and returns the message key of its previous position.
+pub fn kdf_ck(chain: &mut Key) -> Zeroizing<Key> {
+    let message_key = hmac(chain, 0x01);
+    *chain = *hmac(chain, 0x02);
+
+    message_key
+}
+
+pub fn seal(key: &Key, aad: &[u8], plaintext: &[u8]) -> ([u8; 24], Vec<u8>) {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = XChaCha20Poly1305::new(key.into())
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .expect("encryption with a valid key does not fail");
+
+    (nonce.into(), ciphertext)
+}
+
+pub fn open(
+    key: &Key,
+    aad: &[u8],
+    nonce: &[u8; 24],
+    ciphertext: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, E2eError> {
+    XChaCha20Poly1305::new(key.into())
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map(Zeroizing::new)
+        .map_err(|_| E2eError::Decrypt)
+}
+
+fn hmac(key: &Key, input: u8) -> Zeroizing<Key> {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&[input]);
+
+    Zeroizing::new(mac.finalize().into_bytes().into())
+}
+
+fn kdf_rk(root_key: &Key, dh_output: &Zeroizing<Key>) -> (Key, Key) {
+    let mut output = Zeroizing::new([0u8; 64]);
+    Hkdf::<Sha256>::new(Some(root_key), dh_output.as_ref())
+        .expand(RATCHET_INFO, output.as_mut())
+        .expect("64 bytes is a valid HKDF output length");
+
+    let mut root_key = [0u8; 32];
+    let mut chain_key = [0u8; 32];
+    root_key.copy_from_slice(&output[..32]);
+    chain_key.copy_from_slice(&output[32..]);
+
+    (root_key, chain_key)
+}
+
+/// Sent in the clear with every message so the recipient can follow the ratchet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Header {
+    /// Current ratchet public key of the sender.
+    pub dh: [u8; 32],
+    /// Length of the sender's previous sending chain.
+    pub pn: u32,
+    /// Position of the message in the current sending chain.
+    pub n: u32,
+}
+
+impl Header {
+    fn encode(&self) -> [u8; 40] {
+        let mut bytes = [0u8; 40];
+        bytes[..32].copy_from_slice(&self.dh);
+        bytes[32..36].copy_from_slice(&self.pn.to_be_bytes());
+        bytes[36..].copy_from_slice(&self.n.to_be_bytes());
+
+        bytes
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+struct SkippedKey {
+    dh: [u8; 32],
+    n: u32,
+    key: Key,
+}
+
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct Session {
+    dh_self: Key,
+    dh_remote: Option<[u8; 32]>,
+    root_key: Key,
+    send_chain: Option<Key>,
+    recv_chain: Option<Key>,
+    ns: u32,
+    nr: u32,
+    pn: u32,
+    skipped: Vec<SkippedKey>,
+    /// Identity keys of both sides, authenticated with every message.
+    ad: Vec<u8>,
+}
+
+impl Session {
+    /// Session of the side that ran X3DH against the other's signed prekey. It
+    /// can send right away.
+    pub fn initiator(
+        secret: &Key,
+        remote_ratchet: &PublicKey,
+        ad: Vec<u8>,
+    ) -> Result<Self, E2eError> {
+        let dh_self = StaticSecret::random_from_rng(OsRng);
+        let (root_key, send_chain) = kdf_rk(secret, &dh(&dh_self, remote_ratchet)?);
+
+        Ok(Session {
+            dh_self: dh_self.to_bytes(),
+            dh_remote: Some(remote_ratchet.to_bytes()),
+            root_key,
+            send_chain: Some(send_chain),
+            recv_chain: None,
+            ns: 0,
+            nr: 0,
+            pn: 0,
+            skipped: Vec::new(),
+            ad,
+        })
+    }
+
+    /// Session of the side whose signed prekey was used. It can send once the
+    /// first message arrived.
+    pub fn responder(secret: &Key, signed_prekey: &StaticSecret, ad: Vec<u8>) -> Self {
+        Session {
+            dh_self: signed_prekey.to_bytes(),
+            dh_remote: None,
+            root_key: *secret,
+            send_chain: None,
+            recv_chain: None,
+            ns: 0,
+            nr: 0,
+            pn: 0,
+            skipped: Vec::new(),
+            ad,
+        }
+    }
+
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<(Header, [u8; 24], Vec<u8>), E2eError> {
+        let chain = self.send_chain.as_mut().ok_or(E2eError::NoSession)?;
+        let message_key = kdf_ck(chain);
+        let header = Header {
+            dh: PublicKey::from(&StaticSecret::from(self.dh_self)).to_bytes(),
+            pn: self.pn,
+            n: self.ns,
+        };
+        self.ns += 1;
+
+        let (nonce, ciphertext) = seal(&message_key, &self.aad(&header), plaintext);
+        Ok((header, nonce, ciphertext))
+    }
+
+    /// Decrypts a message. The session is left untouched if it does not
+    /// authenticate, so a forged message cannot desynchronise it.
+    pub fn decrypt(
+        &mut self,
+        header: &Header,
+        nonce: &[u8; 24],
+        ciphertext: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>, E2eError> {
+        let mut next = self.clone();
+        let plaintext = next.ratchet_decrypt(header, nonce, ciphertext)?;
+        *self = next;
+
+        Ok(plaintext)
+    }
+
+    fn ratchet_decrypt(
+        &mut self,
+        header: &Header,
+        nonce: &[u8; 24],
+        ciphertext: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>, E2eError> {
+        if let Some(i) = self
+            .skipped
+            .iter()
+            .position(|skipped| skipped.dh == header.dh && skipped.n == header.n)
+        {
+            let skipped = self.skipped.remove(i);
+            return open(&skipped.key, &self.aad(header), nonce, ciphertext);
+        }
+
+        if self.dh_remote != Some(header.dh) {
+            self.skip_message_keys(header.pn)?;
+            self.dh_ratchet(header)?;
+        }
+        self.skip_message_keys(header.n)?;
+
+        let chain = self.recv_chain.as_mut().ok_or(E2eError::Decrypt)?;
+        let message_key = kdf_ck(chain);
+        self.nr += 1;
+
+        open(&message_key, &self.aad(header), nonce, ciphertext)
+    }
+
+    fn skip_message_keys(&mut self, until: u32) -> Result<(), E2eError> {
+        let (Some(chain), Some(dh)) = (self.recv_chain.as_mut(), self.dh_remote) else {
+            return Ok(());
+        };
+        if until.saturating_sub(self.nr) > MAX_SKIP {
+            return Err(E2eError::TooManySkipped);
+        }
+
+        while self.nr < until {
+            self.skipped.push(SkippedKey {
+                dh,
+                n: self.nr,
+                key: *kdf_ck(chain),
+            });
+            self.nr += 1;
+        }
+        if self.skipped.len() > MAX_SKIPPED_KEYS {
+            let excess = self.skipped.len() - MAX_SKIPPED_KEYS;
+            self.skipped.drain(..excess);
+        }
+
+        Ok(())
+    }
+
+    fn dh_ratchet(&mut self, header: &Header) -> Result<(), E2eError> {
+        let remote = PublicKey::from(header.dh);
+        let (root_key, recv_chain) = kdf_rk(
+            &self.root_key,
+            &dh(&StaticSecret::from(self.dh_self), &remote)?,
+        );
+        let dh_self = StaticSecret::random_from_rng(OsRng);
+        let (root_key, send_chain) = kdf_rk(&root_key, &dh(&dh_self, &remote)?);
+
+        self.pn = self.ns;
+        self.ns = 0;
+        self.nr = 0;
+        self.dh_self = dh_self.to_bytes();
+        self.dh_remote = Some(header.dh);
+        self.root_key = root_key;
+        self.send_chain = Some(send_chain);
+        self.recv_chain = Some(recv_chain);
+
+        Ok(())
+    }
+
+    fn aad(&self, header: &Header) -> Vec<u8> {
+        let mut aad = self.ad.clone();
+        aad.extend_from_slice(&header.encode());
+
+        aad
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> (Session, Session) {
+        let secret = [7u8; 32];
+        let signed_prekey = StaticSecret::random_from_rng(OsRng);
+        let alice =
+            Session::initiator(&secret, &PublicKey::from(&signed_prekey), b"ad".to_vec()).unwrap();
+        let bob = Session::responder(&secret, &signed_prekey, b"ad".to_vec());
+
+        (alice, bob)
+    }
+
+    fn send(from: &mut Session, text: &str) -> (Header, [u8; 24], Vec<u8>) {
+        from.encrypt(text.as_bytes()).unwrap()
+    }
+
+    fn receive(
+        to: &mut Session,
+        (header, nonce, ciphertext): &(Header, [u8; 24], Vec<u8>),
+    ) -> String {
+        String::from_utf8(to.decrypt(header, nonce, ciphertext).unwrap().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn round_trip_in_both_directions() {
+        let (mut alice, mut bob) = pair();
+
+        for round in 0..3 {
+            let message = send(&mut alice, &format!("ping {round}"));
+            assert_eq!(receive(&mut bob, &message), format!("ping {round}"));
+            let message = send(&mut bob, &format!("pong {round}"));
+            assert_eq!(receive(&mut alice, &message), format!("pong {round}"));
+        }
+    }
+
+    #[test]
+    fn responder_cannot_send_first() {
+        let (_, mut bob) = pair();
+        assert!(matches!(bob.encrypt(b"hi"), Err(E2eError::NoSession)));
+    }
+
+    #[test]
+    fn out_of_order_messages() {
+        let (mut alice, mut bob) = pair();
+        let messages: Vec<_> = (0..3).map(|i| send(&mut alice, &i.to_string())).collect();
+
+        assert_eq!(receive(&mut bob, &messages[2]), "2");
+        assert_eq!(receive(&mut bob, &messages[0]), "0");
+        assert_eq!(receive(&mut bob, &messages[1]), "1");
+    }
+
+    #[test]
+    fn skipped_messages_across_a_ratchet_step() {
+        let (mut alice, mut bob) = pair();
+        let first = send(&mut alice, "first");
+        let late = send(&mut alice, "late");
+        assert_eq!(receive(&mut bob, &first), "first");
+
+        let reply = send(&mut bob, "reply");
+        assert_eq!(receive(&mut alice, &reply), "reply");
+        let next = send(&mut alice, "next");
+        assert_ne!(next.0.dh, late.0.dh);
+
+        assert_eq!(receive(&mut bob, &next), "next");
+        assert_eq!(receive(&mut bob, &late), "late");
+    }
+
+    #[test]
+    fn replayed_message_is_rejected() {
+        let (mut alice, mut bob) = pair();
+        let message = send(&mut alice, "once");
+        assert_eq!(receive(&mut bob, &message), "once");
+
+        let (header, nonce, ciphertext) = &message;
+        assert!(bob.decrypt(header, nonce, ciphertext).is_err());
+    }
+
+    #[test]
+    fn tampered_message_leaves_the_session_usable() {
+        let (mut alice, mut bob) = pair();
+        let (header, nonce, mut ciphertext) = send(&mut alice, "hello");
+        ciphertext[0] ^= 1;
+        assert!(matches!(
+            bob.decrypt(&header, &nonce, &ciphertext),
+            Err(E2eError::Decrypt)
+        ));
+
+        ciphertext[0] ^= 1;
+        assert_eq!(receive(&mut bob, &(header, nonce, ciphertext)), "hello");
+    }
+
+    #[test]
+    fn too_many_skipped_messages() {
+        let (mut alice, mut bob) = pair();
+        let first = send(&mut alice, "first");
+        assert_eq!(receive(&mut bob, &first), "first");
+
+        let (mut header, nonce, ciphertext) = send(&mut alice, "far ahead");
+        header.n = MAX_SKIP + 2;
+        assert!(matches!(
+            bob.decrypt(&header, &nonce, &ciphertext),
+            Err(E2eError::TooManySkipped)
+        ));
+    }
+}
@@ -0,0 +1,723 @@
+//! Local message history kept in SQLite: conversations, their messages with
+//! the delivery state of those we sent, and the peers we have seen.
+//!
+//! Message bodies, senders, topic names and peer IDs are sealed one by one
+//! with XChaCha20-Poly1305, under a key derived from the identity that only
+//! the keystore passphrase unlocks. Conversations and peers are looked up by
+//! a keyed hash instead of their plaintext. What stays readable is the shape
+//! of the history: how many messages, when, and their delivery state.
+//! Deleting the identity keystore leaves the history unreadable for good.
+
+use std::{
+    error::Error,
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use hmac::{Hmac, Mac};
+use libp2p::{identity::Keypair, PeerId};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::Serialize;
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+use super::{
+    dm::{DeliveryState, MessageId},
+    identity::create_private_dir,
+};
+
+/// Bumped whenever [`SCHEMA`] changes, see `PRAGMA user_version`.
+const SCHEMA_VERSION: i64 = 1;
+
+// Sealed columns hold nonce (24) | ciphertext, `*_hash` columns an HMAC of
+// the plaintext for lookups. Columns of rows with a `*_hash` are bound to it,
+// messages to their conversation.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS meta (
+        name  TEXT PRIMARY KEY,
+        value BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS peers (
+        peer_hash  BLOB PRIMARY KEY,
+        peer_id    BLOB NOT NULL,
+        first_seen INTEGER NOT NULL,
+        last_seen  INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS conversations (
+        id       INTEGER PRIMARY KEY,
+        kind     TEXT NOT NULL,
+        key_hash BLOB NOT NULL,
+        key      BLOB NOT NULL,
+        -- row of the last message the user has seen
+        read_up_to INTEGER NOT NULL DEFAULT 0,
+        UNIQUE (kind, key_hash)
+    );
+    CREATE TABLE IF NOT EXISTS messages (
+        id           INTEGER PRIMARY KEY,
+        conversation INTEGER NOT NULL REFERENCES conversations (id),
+        message_id   INTEGER,
+        sender       BLOB,
+        outgoing     INTEGER NOT NULL,
+        body         BLOB NOT NULL,
+        time         INTEGER NOT NULL,
+        state        TEXT
+    );
+    CREATE INDEX IF NOT EXISTS messages_by_conversation ON messages (conversation, id);
+    CREATE INDEX IF NOT EXISTS messages_by_message_id ON messages (message_id);
+    CREATE TABLE IF NOT EXISTS contacts (
+        peer_hash BLOB PRIMARY KEY,
+        peer_id   BLOB NOT NULL,
+        nickname  BLOB NOT NULL,
+        avatar    BLOB,
+        added_at  INTEGER NOT NULL
+    );
+";
+
+const KEY_DOMAIN: &[u8] = b"limiinal-history";
+const INDEX_KEY_DOMAIN: &[u8] = b"limiinal-history-index";
+/// Sealed into `meta` to tell a wrong key from a damaged record.
+const KEY_CHECK: &[u8] = b"limiinal-history-key-check";
+const NONCE_LEN: usize = 24;
+
+#[derive(Debug)]
+pub enum HistoryError {
+    Io(io::Error),
+    Sqlite(rusqlite::Error),
+    /// The identity does not support deriving a storage key.
+    UnsupportedKey,
+    /// The database was written under another identity.
+    WrongKey,
+    /// A record does not authenticate, it was damaged or tampered with.
+    Decrypt,
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryError::Io(e) => write!(f, "history I/O error: {e}"),
+            HistoryError::Sqlite(e) => write!(f, "history database error: {e}"),
+            HistoryError::UnsupportedKey => write!(f, "identity cannot derive a storage key"),
+            HistoryError::WrongKey => write!(f, "history belongs to another identity"),
+            HistoryError::Decrypt => write!(f, "damaged history record"),
+        }
+    }
+}
+
+impl Error for HistoryError {}
+
+impl From<io::Error> for HistoryError {
+    fn from(e: io::Error) -> Self {
+        HistoryError::Io(e)
+    }
+}
+
+impl From<rusqlite::Error> for HistoryError {
+    fn from(e: rusqlite::Error) -> Self {
+        HistoryError::Sqlite(e)
+    }
+}
+
+const MESSAGE_COLUMNS: &str = "id, conversation, message_id, sender, outgoing, body, time, state";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConversationKind {
+    /// Gossipsub topic, keyed by the topic name.
+    Topic,
+    /// Direct messages, keyed by the peer ID.
+    Direct,
+}
+
+impl ConversationKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ConversationKind::Topic => "topic",
+            ConversationKind::Direct => "direct",
+        }
+    }
+}
+
+/// A gossipsub topic, or the direct messages exchanged with one peer.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Conversation {
+    Topic(String),
+    Direct(PeerId),
+}
+
+impl Conversation {
+    pub fn kind(&self) -> ConversationKind {
+        match self {
+            Conversation::Topic(_) => ConversationKind::Topic,
+            Conversation::Direct(_) => ConversationKind::Direct,
+        }
+    }
+
+    /// Topic name or peer ID.
+    pub fn key(&self) -> String {
+        match self {
+            Conversation::Topic(topic) => topic.clone(),
+            Conversation::Direct(peer) => peer.to_base58(),
+        }
+    }
+}
+
+/// A message to append to a conversation.
+pub struct NewMessage<'a> {
+    /// Direct message id, to update the delivery state later.
+    pub id: Option<MessageId>,
+    pub sender: Option<String>,
+    pub outgoing: bool,
+    pub body: &'a str,
+    pub state: Option<DeliveryState>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredMessage {
+    /// Position in the history, older messages have smaller rows.
+    pub row: i64,
+    pub id: Option<MessageId>,
+    pub sender: Option<String>,
+    pub outgoing: bool,
+    pub body: String,
+    /// Unix timestamp in milliseconds at which the message was stored.
+    pub time: i64,
+    pub state: Option<DeliveryState>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConversationSummary {
+    pub kind: ConversationKind,
+    pub key: String,
+    pub message_count: usize,
+    pub last_sender: Option<String>,
+    pub last_message: Option<String>,
+    pub last_time: Option<i64>,
+    /// Messages received since the user last opened the conversation.
+    pub unread: usize,
+}
+
+/// Per-record encryption of the history.
+pub(super) struct Cipher {
+    key: Zeroizing<[u8; 32]>,
+    index_key: Zeroizing<[u8; 32]>,
+}
+
+impl Cipher {
+    fn derive(keypair: &Keypair) -> Result<Self, HistoryError> {
+        let derive = |domain| {
+            keypair
+                .derive_secret(domain)
+                .map(Zeroizing::new)
+                .ok_or(HistoryError::UnsupportedKey)
+        };
+
+        Ok(Cipher {
+            key: derive(KEY_DOMAIN)?,
+            index_key: derive(INDEX_KEY_DOMAIN)?,
+        })
+    }
+
+    fn random() -> Self {
+        Cipher {
+            key: Zeroizing::new(XChaCha20Poly1305::generate_key(&mut OsRng).into()),
+            index_key: Zeroizing::new(XChaCha20Poly1305::generate_key(&mut OsRng).into()),
+        }
+    }
+
+    /// Seals `plaintext` for `column` of a record belonging to `context`, so
+    /// records cannot be swapped between columns or conversations.
+    pub(super) fn seal(&self, column: &str, context: i64, plaintext: &[u8]) -> Vec<u8> {
+        self.seal_with(column, &context.to_be_bytes(), plaintext)
+    }
+
+    /// Like [`Cipher::seal`], binding the record to arbitrary bytes such as
+    /// the blind index of its row.
+    pub(super) fn seal_with(&self, column: &str, context: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = XChaCha20Poly1305::new(self.key.as_slice().into())
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: &aad(column, context),
+                },
+            )
+            .expect("encryption with a valid key does not fail");
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    pub(super) fn open(
+        &self,
+        column: &str,
+        context: i64,
+        sealed: &[u8],
+    ) -> Result<Vec<u8>, HistoryError> {
+        self.open_with(column, &context.to_be_bytes(), sealed)
+    }
+
+    pub(super) fn open_with(
+        &self,
+        column: &str,
+        context: &[u8],
+        sealed: &[u8],
+    ) -> Result<Vec<u8>, HistoryError> {
+        if sealed.len() < NONCE_LEN {
+            return Err(HistoryError::Decrypt);
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        XChaCha20Poly1305::new(self.key.as_slice().into())
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad(column, context),
+                },
+            )
+            .map_err(|_| HistoryError::Decrypt)
+    }
+
+    pub(super) fn open_string(
+        &self,
+        column: &str,
+        context: i64,
+        sealed: &[u8],
+    ) -> Result<String, HistoryError> {
+        self.open_string_with(column, &context.to_be_bytes(), sealed)
+    }
+
+    pub(super) fn open_string_with(
+        &self,
+        column: &str,
+        context: &[u8],
+        sealed: &[u8],
+    ) -> Result<String, HistoryError> {
+        String::from_utf8(self.open_with(column, context, sealed)?)
+            .map_err(|_| HistoryError::Decrypt)
+    }
+
+    /// Keyed hash of `value`, to find records without storing it in plaintext.
+    pub(super) fn blind(&self, value: &str) -> Vec<u8> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(self.index_key.as_slice())
+            .expect("HMAC accepts keys of any length");
+        mac.update(value.as_bytes());
+
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    fn message(&self, row: &Row<'_>) -> Result<StoredMessage, HistoryError> {
+        let conversation: i64 = row.get(1)?;
+        let sender: Option<Vec<u8>> = row.get(3)?;
+        let body: Vec<u8> = row.get(5)?;
+        let state: Option<String> = row.get(7)?;
+
+        Ok(StoredMessage {
+            row: row.get(0)?,
+            id: row.get::<_, Option<i64>>(2)?.map(|id| id as MessageId),
+            sender: sender
+                .map(|sender| self.open_string("sender", conversation, &sender))
+                .transpose()?,
+            outgoing: row.get(4)?,
+            body: self.open_string("body", conversation, &body)?,
+            time: row.get(6)?,
+            state: state.and_then(|state| state.parse().ok()),
+        })
+    }
+}
+
+fn aad(column: &str, context: &[u8]) -> Vec<u8> {
+    let mut aad = column.as_bytes().to_vec();
+    aad.extend_from_slice(context);
+
+    aad
+}
+
+pub struct History {
+    pub(super) conn: Connection,
+    pub(super) cipher: Cipher,
+}
+
+impl History {
+    /// Opens the history database of `keypair` at `path`, creating it if needed.
+    pub fn open(path: &Path, keypair: &Keypair) -> Result<Self, HistoryError> {
+        if let Some(parent) = path.parent() {
+            create_private_dir(parent)?;
+        }
+
+        let history = History::init(Connection::open(path)?, Cipher::derive(keypair)?)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        }
+
+        Ok(history)
+    }
+
+    /// History that only lives as long as the process, for test identities.
+    pub fn in_memory() -> Result<Self, HistoryError> {
+        History::init(Connection::open_in_memory()?, Cipher::random())
+    }
+
+    fn init(mut conn: Connection, cipher: Cipher) -> Result<Self, HistoryError> {
+        conn.busy_timeout(std::time::Duration::from_secs(1))?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        // Overwrite deleted records instead of leaving them in free pages.
+        conn.pragma_update(None, "secure_delete", true)?;
+
+        let version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version < SCHEMA_VERSION {
+            let tx = conn.transaction()?;
+            tx.execute_batch(SCHEMA)?;
+            tx.execute(
+                "INSERT INTO meta (name, value) VALUES ('key_check', ?1)",
+                params![cipher.seal("meta", 0, KEY_CHECK)],
+            )?;
+            tx.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+            tx.commit()?;
+        }
+
+        let check: Vec<u8> = conn.query_row(
+            "SELECT value FROM meta WHERE name = 'key_check'",
+            [],
+            |row| row.get(0),
+        )?;
+        if cipher.open("meta", 0, &check).ok().as_deref() != Some(KEY_CHECK) {
+            return Err(HistoryError::WrongKey);
+        }
+
+        Ok(History { conn, cipher })
+    }
+
+    /// Makes sure `conversation` exists, e.g. for a topic we just joined.
+    pub fn conversation(&self, conversation: &Conversation) -> Result<i64, HistoryError> {
+        if let Some(id) = self.conversation_id(conversation)? {
+            return Ok(id);
+        }
+
+        let key = conversation.key();
+        let hash = self.cipher.blind(&key);
+        self.conn.execute(
+            "INSERT INTO conversations (kind, key_hash, key) VALUES (?1, ?2, ?3)",
+            params![
+                conversation.kind().as_str(),
+                hash,
+                self.cipher.seal_with("conversation", &hash, key.as_bytes()),
+            ],
+        )?;
+
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    fn conversation_id(&self, conversation: &Conversation) -> Result<Option<i64>, HistoryError> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT id FROM conversations WHERE kind = ?1 AND key_hash = ?2",
+                params![
+                    conversation.kind().as_str(),
+                    self.cipher.blind(&conversation.key())
+                ],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Appends `message` to `conversation` and returns its row.
+    pub fn record(
+        &self,
+        conversation: &Conversation,
+        message: NewMessage<'_>,
+    ) -> Result<i64, HistoryError> {
+        let conversation = self.conversation(conversation)?;
+        self.conn.execute(
+            "INSERT INTO messages (conversation, message_id, sender, outgoing, body, time, state)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                conversation,
+                message.id.map(|id| id as i64),
+                message.sender.map(|sender| self.cipher.seal(
+                    "sender",
+                    conversation,
+                    sender.as_bytes()
+                )),
+                message.outgoing,
+                self.cipher
+                    .seal("body", conversation, message.body.as_bytes()),
+                chrono::Utc::now().timestamp_millis(),
+                message.state.as_ref().map(DeliveryState::as_str),
+            ],
+        )?;
+
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// Updates the delivery state of a direct message we sent. An
+    /// acknowledgement can overtake the "sent" notification, so a delivered
+    /// message stays delivered.
+    pub fn set_state(&self, id: MessageId, state: DeliveryState) -> Result<(), HistoryError> {
+        self.conn.execute(
+            "UPDATE messages SET state = ?2
+             WHERE message_id = ?1 AND outgoing = 1 AND state IS NOT 'delivered'",
+            params![id as i64, state.as_str()],
+        )?;
+
+        Ok(())
+    }
+
+    /// The latest `limit` messages of `conversation`, oldest first.
+    pub fn latest(
+        &self,
+        conversation: &Conversation,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>, HistoryError> {
+        self.before(conversation, i64::MAX, limit)
+    }
+
+    /// Up to `limit` messages of `conversation` older than `row`, oldest first.
+    pub fn before(
+        &self,
+        conversation: &Conversation,
+        row: i64,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>, HistoryError> {
+        let Some(conversation) = self.conversation_id(conversation)? else {
+            return Ok(Vec::new());
+        };
+
+        let mut statement = self.conn.prepare_cached(&format!(
+            "SELECT {MESSAGE_COLUMNS} FROM messages
+             WHERE conversation = ?1 AND id < ?2
+             ORDER BY id DESC LIMIT ?3"
+        ))?;
+        let mut rows = statement.query(params![conversation, row, limit as i64])?;
+        let mut messages = Vec::new();
+        while let Some(row) = rows.next()? {
+            messages.push(self.cipher.message(row)?);
+        }
+        messages.reverse();
+
+        Ok(messages)
+    }
+
+    /// Marks every message of `conversation` as seen.
+    pub fn mark_read(&self, conversation: &Conversation) -> Result<(), HistoryError> {
+        let Some(conversation) = self.conversation_id(conversation)? else {
+            return Ok(());
+        };
+
+        self.conn.execute(
+            "UPDATE conversations SET read_up_to =
+                 (SELECT COALESCE(MAX(id), 0) FROM messages WHERE conversation = ?1)
+             WHERE id = ?1",
+            params![conversation],
+        )?;
+
+        Ok(())
+    }
+
+    /// Notes that `peer` is around right now.
+    pub fn saw_peer(&self, peer: &PeerId) -> Result<(), HistoryError> {
+        let peer = peer.to_base58();
+        let hash = self.cipher.blind(&peer);
+        let now = chrono::Utc::now().timestamp_millis();
+        self.conn.execute(
+            "INSERT INTO peers (peer_hash, peer_id, first_seen, last_seen) VALUES (?1, ?2, ?3, ?3)
+             ON CONFLICT (peer_hash) DO UPDATE SET last_seen = ?3",
+            params![
+                hash,
+                self.cipher.seal_with("peer", &hash, peer.as_bytes()),
+                now
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn conversations(&self) -> Result<Vec<ConversationSummary>, HistoryError> {
+        let mut statement = self.conn.prepare_cached(
+            "SELECT c.id, c.kind, c.key,
+                    (SELECT COUNT(*) FROM messages WHERE conversation = c.id),
+                    last.sender, last.body, last.time,
+                    (SELECT COUNT(*) FROM messages
+                     WHERE conversation = c.id AND outgoing = 0 AND id > c.read_up_to),
+                    c.key_hash
+             FROM conversations c
+             LEFT JOIN messages last
+               ON last.id = (SELECT MAX(id) FROM messages WHERE conversation = c.id)
+             ORDER BY c.kind DESC, c.id",
+        )?;
+
+        let mut rows = statement.query([])?;
+        let mut conversations = Vec::new();
+        while let Some(row) = rows.next()? {
+            let id: i64 = row.get(0)?;
+            let kind: String = row.get(1)?;
+            let key: Vec<u8> = row.get(2)?;
+            let last_sender: Option<Vec<u8>> = row.get(4)?;
+            let last_message: Option<Vec<u8>> = row.get(5)?;
+            let hash: Vec<u8> = row.get(8)?;
+
+            conversations.push(ConversationSummary {
+                kind: if kind == "direct" {
+                    ConversationKind::Direct
+                } else {
+                    ConversationKind::Topic
+                },
+                key: self.cipher.open_string_with("conversation", &hash, &key)?,
+                message_count: row.get::<_, i64>(3)? as usize,
+                last_sender: last_sender
+                    .map(|sender| self.cipher.open_string("sender", id, &sender))
+                    .transpose()?,
+                last_message: last_message
+                    .map(|body| self.cipher.open_string("body", id, &body))
+                    .transpose()?,
+                last_time: row.get(6)?,
+                unread: row.get::<_, i64>(7)? as usize,
+            });
+        }
+
+        Ok(conversations)
+    }
+}
+
+/// Location of the history database belonging to the identity keystore at `identity`.
+pub fn store_path(identity: &Path) -> PathBuf {
+    identity.with_extension("history")
+}
+
+/// Files SQLite may keep next to the database at `path`.
+pub fn companion_paths(path: &Path) -> [PathBuf; 3] {
+    ["-journal", "-wal", "-shm"].map(|suffix| {
+        let mut companion = path.as_os_str().to_owned();
+        companion.push(suffix);
+        PathBuf::from(companion)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("limiinal-history-{}.history", PeerId::random()))
+    }
+
+    fn remove(path: &Path) {
+        for file in std::iter::once(path.to_path_buf()).chain(companion_paths(path)) {
+            let _ = std::fs::remove_file(file);
+        }
+    }
+
+    fn text(body: &str) -> NewMessage<'_> {
+        NewMessage {
+            id: None,
+            sender: Some("alice".to_string()),
+            outgoing: false,
+            body,
+            state: None,
+        }
+    }
+
+    #[test]
+    fn seal_open_round_trip() {
+        let cipher = Cipher::derive(&Keypair::generate_ed25519()).unwrap();
+
+        let sealed = cipher.seal("body", 1, b"hello");
+        assert_eq!(cipher.open("body", 1, &sealed).unwrap(), b"hello");
+        assert!(matches!(
+            cipher.open("sender", 1, &sealed),
+            Err(HistoryError::Decrypt)
+        ));
+        assert!(matches!(
+            cipher.open("body", 2, &sealed),
+            Err(HistoryError::Decrypt)
+        ));
+        assert_ne!(cipher.seal("body", 1, b"hello"), sealed);
+    }
+
+    #[test]
+    fn reopening_under_another_identity_is_refused() {
+        let path = temp_path();
+        let keypair = Keypair::generate_ed25519();
+        let conversation = Conversation::Topic("chat".to_string());
+
+        History::open(&path, &keypair)
+            .unwrap()
+            .record(&conversation, text("kept"))
+            .unwrap();
+        assert!(matches!(
+            History::open(&path, &Keypair::generate_ed25519()),
+            Err(HistoryError::WrongKey)
+        ));
+
+        let history = History::open(&path, &keypair).unwrap();
+        assert_eq!(history.latest(&conversation, 10).unwrap()[0].body, "kept");
+        drop(history);
+        remove(&path);
+    }
+
+    #[test]
+    fn pages_backwards_oldest_first() {
+        let history = History::in_memory().unwrap();
+        let conversation = Conversation::Direct(PeerId::random());
+        let other = Conversation::Topic("chat".to_string());
+        for body in ["one", "two", "three", "four"] {
+            history.record(&conversation, text(body)).unwrap();
+            history.record(&other, text("elsewhere")).unwrap();
+        }
+
+        let bodies = |messages: Vec<StoredMessage>| -> Vec<String> {
+            messages.into_iter().map(|message| message.body).collect()
+        };
+        let latest = history.latest(&conversation, 2).unwrap();
+        let oldest_row = latest[0].row;
+        assert_eq!(bodies(latest), ["three", "four"]);
+        assert_eq!(
+            bodies(history.before(&conversation, oldest_row, 10).unwrap()),
+            ["one", "two"]
+        );
+        assert!(history
+            .latest(&Conversation::Topic("unknown".to_string()), 10)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn stores_no_plaintext() {
+        let path = temp_path();
+        let peer = PeerId::random();
+        let history = History::open(&path, &Keypair::generate_ed25519()).unwrap();
+        history
+            .record(&Conversation::Direct(peer), text("attack at dawn"))
+            .unwrap();
+        history
+            .record(&Conversation::Topic("secret-topic".to_string()), text("hi"))
+            .unwrap();
+        history.saw_peer(&peer).unwrap();
+        drop(history);
+
+        let file = std::fs::read(&path).unwrap();
+        for plaintext in [
+            "attack at dawn",
+            "secret-topic",
+            "alice",
+            peer.to_base58().as_str(),
+        ] {
+            assert!(
+                !file
+                    .windows(plaintext.len())
+                    .any(|window| window == plaintext.as_bytes()),
+                "{plaintext} stored in plaintext"
+            );
+        }
+        remove(&path);
+    }
+}
@@ -0,0 +1,269 @@
+//! Invite links for exchanging contacts out of band:
+//! `limiinal://add?peer=<peer id>&relay=<multiaddr>&name=<nickname>&sig=<signature>`.
+//!
+//! The link is signed with the identity key of the peer it introduces, so a
+//! pasted link cannot point at someone else's peer ID or a relay they did not
+//! choose. The relay address lets the recipient reach the peer through its
+//! relay circuit before they have ever been connected.
+
+use std::{error::Error, fmt};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use libp2p::{
+    identity::{Keypair, PublicKey},
+    multiaddr::Protocol,
+    Multiaddr, PeerId,
+};
+use qrcode::{render::svg, types::QrError, QrCode};
+use url::Url;
+
+use super::e2e;
+
+pub const SCHEME: &str = "limiinal";
+
+/// Domain separation for the invite signature.
+const SIGNATURE_CONTEXT: &[u8] = b"limiinal-invite-v1";
+
+/// Longest nickname an invite may carry, in characters.
+pub const MAX_NAME_LEN: usize = 64;
+
+#[derive(Debug)]
+pub enum InviteError {
+    /// Not a `limiinal://add` link.
+    NotAnInvite,
+    /// A parameter is missing or malformed.
+    Invalid(&'static str),
+    /// The relay address does not end in the relay's peer ID.
+    RelayWithoutPeerId,
+    /// The signature is not made by the peer the invite introduces.
+    BadSignature,
+    /// The identity cannot sign invites.
+    UnsupportedKey,
+}
+
+impl fmt::Display for InviteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InviteError::NotAnInvite => write!(f, "not a {SCHEME}://add link"),
+            InviteError::Invalid(field) => write!(f, "missing or malformed `{field}`"),
+            InviteError::RelayWithoutPeerId => {
+                write!(f, "relay address does not end in /p2p/<relay peer id>")
+            }
+            InviteError::BadSignature => write!(f, "invite is not signed by its peer"),
+            InviteError::UnsupportedKey => write!(f, "not an ed25519 identity"),
+        }
+    }
+}
+
+impl Error for InviteError {}
+
+/// A verified invite.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invite {
+    pub peer: PeerId,
+    /// Address of the relay the peer holds a reservation on, ending in `/p2p/<relay peer id>`.
+    pub relay: Multiaddr,
+    /// Nickname the peer suggests for itself.
+    pub name: Option<String>,
+}
+
+impl Invite {
+    /// Signed link introducing the owner of `keypair`, reachable through `relay`.
+    pub fn link(
+        keypair: &Keypair,
+        relay: &Multiaddr,
+        name: Option<&str>,
+    ) -> Result<String, InviteError> {
+        let peer = keypair.public().to_peer_id();
+        let name = name.map(str::trim).filter(|name| !name.is_empty());
+        check_relay(relay)?;
+        if name.is_some_and(|name| name.chars().count() > MAX_NAME_LEN) {
+            return Err(InviteError::Invalid("name"));
+        }
+
+        let signature = keypair
+            .sign(&signed_bytes(&peer, relay, name))
+            .map_err(|_| InviteError::UnsupportedKey)?;
+
+        let mut url = Url::parse(&format!("{SCHEME}://add")).expect("valid base URL");
+        {
+            let mut query = url.query_pairs_mut();
+            query.append_pair("peer", &peer.to_base58());
+            query.append_pair("relay", &relay.to_string());
+            if let Some(name) = name {
+                query.append_pair("name", name);
+            }
+            query.append_pair("sig", &URL_SAFE_NO_PAD.encode(signature));
+        }
+
+        Ok(url.into())
+    }
+
+    /// Parses a pasted link and checks its signature.
+    pub fn parse(link: &str) -> Result<Self, InviteError> {
+        let url = Url::parse(link.trim()).map_err(|_| InviteError::NotAnInvite)?;
+        if url.scheme() != SCHEME || url.host_str() != Some("add") {
+            return Err(InviteError::NotAnInvite);
+        }
+
+        let (mut peer, mut relay, mut name, mut signature) = (None, None, None, None);
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "peer" => peer = Some(value.parse().map_err(|_| InviteError::Invalid("peer"))?),
+                "relay" => relay = Some(value.parse().map_err(|_| InviteError::Invalid("relay"))?),
+                "name" => name = Some(value.into_owned()),
+                "sig" => {
+                    signature = Some(
+                        URL_SAFE_NO_PAD
+                            .decode(value.as_bytes())
+                            .map_err(|_| InviteError::Invalid("sig"))?,
+                    )
+                }
+                // Unknown parameters are left to newer versions.
+                _ => {}
+            }
+        }
+        let peer: PeerId = peer.ok_or(InviteError::Invalid("peer"))?;
+        let relay: Multiaddr = relay.ok_or(InviteError::Invalid("relay"))?;
+        let signature = signature.ok_or(InviteError::Invalid("sig"))?;
+        check_relay(&relay)?;
+        if name
+            .as_deref()
+            .is_some_and(|name| name.trim().is_empty() || name.chars().count() > MAX_NAME_LEN)
+        {
+            return Err(InviteError::Invalid("name"));
+        }
+
+        let key = e2e::identity_key(&peer).ok_or(InviteError::UnsupportedKey)?;
+        if !PublicKey::from(key).verify(&signed_bytes(&peer, &relay, name.as_deref()), &signature) {
+            return Err(InviteError::BadSignature);
+        }
+
+        Ok(Invite { peer, relay, name })
+    }
+
+    /// Address reaching the peer through its relay circuit.
+    pub fn circuit_address(&self) -> Multiaddr {
+        self.relay
+            .clone()
+            .with(Protocol::P2pCircuit)
+            .with(Protocol::P2p(self.peer))
+    }
+}
+
+/// The link as a QR code, in SVG. Fails if the link is too long for one,
+/// e.g. with a long relay address and nickname.
+pub fn qr_code(link: &str) -> Result<String, QrError> {
+    Ok(QrCode::new(link.as_bytes())?
+        .render::<svg::Color>()
+        .min_dimensions(240, 240)
+        .build())
+}
+
+fn check_relay(relay: &Multiaddr) -> Result<(), InviteError> {
+    match relay.iter().last() {
+        Some(Protocol::P2p(_)) => Ok(()),
+        _ => Err(InviteError::RelayWithoutPeerId),
+    }
+}
+
+fn signed_bytes(peer: &PeerId, relay: &Multiaddr, name: Option<&str>) -> Vec<u8> {
+    let mut bytes = SIGNATURE_CONTEXT.to_vec();
+    for field in [
+        peer.to_bytes().as_slice(),
+        relay.as_ref(),
+        name.unwrap_or_default().as_bytes(),
+    ] {
+        bytes.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(field);
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relay() -> Multiaddr {
+        format!("/ip4/192.0.2.1/tcp/4001/p2p/{}", PeerId::random())
+            .parse()
+            .unwrap()
+    }
+
+    /// `link` with the query parameter `key` replaced by `value`, or removed.
+    fn with_param(link: &str, key: &str, value: Option<&str>) -> String {
+        let mut url = Url::parse(link).unwrap();
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .filter(|(name, _)| name != key)
+            .map(|(name, value)| (name.into_owned(), value.into_owned()))
+            .collect();
+        {
+            let mut query = url.query_pairs_mut();
+            query.clear();
+            for (name, value) in &pairs {
+                query.append_pair(name, value);
+            }
+            if let Some(value) = value {
+                query.append_pair(key, value);
+            }
+        }
+
+        url.into()
+    }
+
+    #[test]
+    fn round_trip() {
+        let keypair = Keypair::generate_ed25519();
+        let relay = relay();
+
+        let link = Invite::link(&keypair, &relay, Some(" Alice ")).unwrap();
+        let invite = Invite::parse(&link).unwrap();
+        assert_eq!(invite.peer, keypair.public().to_peer_id());
+        assert_eq!(invite.relay, relay);
+        assert_eq!(invite.name.as_deref(), Some("Alice"));
+        assert_eq!(
+            invite.circuit_address().iter().last(),
+            Some(Protocol::P2p(invite.peer))
+        );
+
+        let unnamed = Invite::parse(&Invite::link(&keypair, &relay, None).unwrap()).unwrap();
+        assert_eq!(unnamed.name, None);
+    }
+
+    #[test]
+    fn rejects_tampered_links() {
+        let keypair = Keypair::generate_ed25519();
+        let link = Invite::link(&keypair, &relay(), Some("Alice")).unwrap();
+        let other = Keypair::generate_ed25519().public().to_peer_id();
+
+        for tampered in [
+            with_param(&link, "name", Some("Mallory")),
+            with_param(&link, "name", None),
+            with_param(&link, "relay", Some(&relay().to_string())),
+            with_param(&link, "peer", Some(&other.to_base58())),
+        ] {
+            assert!(matches!(
+                Invite::parse(&tampered),
+                Err(InviteError::BadSignature)
+            ));
+        }
+        assert!(matches!(
+            Invite::parse(&with_param(&link, "sig", None)),
+            Err(InviteError::Invalid("sig"))
+        ));
+        assert!(matches!(
+            Invite::parse("https://example.com/add"),
+            Err(InviteError::NotAnInvite)
+        ));
+    }
+
+    #[test]
+    fn qr_code_refuses_oversized_links() {
+        let keypair = Keypair::generate_ed25519();
+        let link = Invite::link(&keypair, &relay(), Some("Alice")).unwrap();
+        assert!(qr_code(&link).unwrap().starts_with("<?xml"));
+
+        assert!(qr_code(&"x".repeat(8000)).is_err());
+    }
+}
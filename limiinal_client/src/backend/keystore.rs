@@ -0,0 +1,386 @@
+use std::{
+    error::Error,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use libp2p::{identity, PeerId};
+use zeroize::Zeroizing;
+
+use super::{e2e, history, identity::write_private};
+
+// Envelope layout:
+//   magic (4) | version (1) | m_cost (4) | t_cost (4) | p_cost (4) | salt (16) | nonce (24) | ciphertext
+// Everything in front of the ciphertext is authenticated as associated data.
+const MAGIC: &[u8; 4] = b"LMKS";
+const VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 3 * 4 + SALT_LEN + NONCE_LEN;
+const KEY_LEN: usize = 32;
+
+/// Argon2 costs new envelopes are sealed with, and the most `open` accepts
+/// so that a corrupt or crafted header cannot make unlocking allocate
+/// gigabytes or run for hours.
+const M_COST: u32 = Params::DEFAULT_M_COST;
+const T_COST: u32 = Params::DEFAULT_T_COST;
+const P_COST: u32 = Params::DEFAULT_P_COST;
+
+#[derive(Debug)]
+pub enum KeystoreError {
+    Io(io::Error),
+    /// The file is neither a keystore envelope nor a legacy plaintext identity.
+    Format,
+    /// The passphrase is wrong or the envelope was tampered with.
+    WrongPassphrase,
+    /// The envelope asks for more memory, time or parallelism than this
+    /// version ever writes.
+    Costs {
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+    },
+    Kdf(argon2::Error),
+    Key(identity::DecodingError),
+}
+
+impl fmt::Display for KeystoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeystoreError::Io(e) => write!(f, "keystore I/O error: {e}"),
+            KeystoreError::Format => write!(f, "not a limiinal keystore"),
+            KeystoreError::WrongPassphrase => write!(f, "wrong passphrase"),
+            KeystoreError::Costs {
+                m_cost,
+                t_cost,
+                p_cost,
+            } => write!(
+                f,
+                "key derivation costs m={m_cost} KiB, t={t_cost}, p={p_cost} exceed the supported m={M_COST} KiB, t={T_COST}, p={P_COST}"
+            ),
+            KeystoreError::Kdf(e) => write!(f, "key derivation failed: {e}"),
+            KeystoreError::Key(e) => write!(f, "invalid keypair: {e}"),
+        }
+    }
+}
+
+impl Error for KeystoreError {}
+
+impl From<io::Error> for KeystoreError {
+    fn from(e: io::Error) -> Self {
+        KeystoreError::Io(e)
+    }
+}
+
+impl From<argon2::Error> for KeystoreError {
+    fn from(e: argon2::Error) -> Self {
+        KeystoreError::Kdf(e)
+    }
+}
+
+impl From<identity::DecodingError> for KeystoreError {
+    fn from(e: identity::DecodingError) -> Self {
+        KeystoreError::Key(e)
+    }
+}
+
+/// Identity keypair encrypted at rest with a key derived from a passphrase
+/// (Argon2id) and sealed with XChaCha20-Poly1305.
+pub struct Keystore {
+    path: PathBuf,
+}
+
+impl Keystore {
+    pub fn new(path: PathBuf) -> Self {
+        Keystore { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    /// Generates a new random identity and stores it encrypted under `passphrase`.
+    pub fn create(&self, passphrase: &str) -> Result<identity::Keypair, KeystoreError> {
+        let keypair = identity::Keypair::generate_ed25519();
+        write_private(&self.path, &seal(&keypair, passphrase)?)?;
+        tracing::info!(path=%self.path.display(), peer=%keypair.public().to_peer_id(), "Created keystore");
+
+        Ok(keypair)
+    }
+
+    /// Decrypts the stored identity.
+    ///
+    /// A plaintext protobuf identity written by earlier versions is accepted
+    /// once and re-written encrypted under `passphrase`.
+    pub fn unlock(&self, passphrase: &str) -> Result<identity::Keypair, KeystoreError> {
+        let bytes = fs::read(&self.path)?;
+        if bytes.starts_with(MAGIC) {
+            return open(&bytes, passphrase);
+        }
+
+        let keypair =
+            identity::Keypair::from_protobuf_encoding(&bytes).map_err(|_| KeystoreError::Format)?;
+        write_private(&self.path, &seal(&keypair, passphrase)?)?;
+        tracing::warn!(path=%self.path.display(), "Encrypted plaintext identity file");
+
+        Ok(keypair)
+    }
+
+    pub fn change_passphrase(&self, old: &str, new: &str) -> Result<(), KeystoreError> {
+        let keypair = open(&fs::read(&self.path)?, old)?;
+        write_private(&self.path, &seal(&keypair, new)?)?;
+
+        Ok(())
+    }
+
+    /// Copies the encrypted blob to `dest`. The identity stays encrypted under
+    /// its current passphrase.
+    pub fn export(&self, dest: &Path) -> Result<(), KeystoreError> {
+        let bytes = fs::read(&self.path)?;
+        if !bytes.starts_with(MAGIC) {
+            return Err(KeystoreError::Format);
+        }
+
+        write_private(dest, &bytes)?;
+        Ok(())
+    }
+
+    /// Replaces the stored identity with the encrypted blob at `src`, after
+    /// checking that `passphrase` opens it.
+    ///
+    /// The end-to-end sessions and the history next to the keystore belong
+    /// to `current` and cannot be opened under another identity, so they are
+    /// deleted when the imported identity is not `current`. Nothing may write
+    /// them while this runs.
+    pub fn import(
+        &self,
+        src: &Path,
+        passphrase: &str,
+        current: &PeerId,
+    ) -> Result<identity::Keypair, KeystoreError> {
+        let bytes = fs::read(src)?;
+        let keypair = open(&bytes, passphrase)?;
+        if keypair.public().to_peer_id() != *current {
+            remove(self.stores())?;
+        }
+        write_private(&self.path, &bytes)?;
+        tracing::info!(path=%self.path.display(), peer=%keypair.public().to_peer_id(), "Imported keystore");
+
+        Ok(keypair)
+    }
+
+    /// Deletes the identity together with the end-to-end sessions and the
+    /// message history kept next to it. Both are encrypted under keys derived
+    /// from the identity, so copies left behind on disk become unreadable too.
+    pub fn wipe(&self) -> Result<(), KeystoreError> {
+        remove(std::iter::once(self.path.clone()).chain(self.stores()))
+    }
+
+    /// Files keyed to the stored identity: the end-to-end sessions, the
+    /// history and the files SQLite keeps next to it.
+    fn stores(&self) -> impl Iterator<Item = PathBuf> {
+        let history = history::store_path(&self.path);
+        [e2e::store_path(&self.path), history.clone()]
+            .into_iter()
+            .chain(history::companion_paths(&history))
+    }
+}
+
+/// Deletes `files`, skipping those that do not exist.
+fn remove(files: impl IntoIterator<Item = PathBuf>) -> Result<(), KeystoreError> {
+    for file in files {
+        match fs::remove_file(&file) {
+            Ok(()) => tracing::info!(path=%file.display(), "Wiped"),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
+
+fn seal(keypair: &identity::Keypair, passphrase: &str) -> Result<Vec<u8>, KeystoreError> {
+    let params = Params::new(M_COST, T_COST, P_COST, Some(KEY_LEN))?;
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let mut blob = Vec::with_capacity(HEADER_LEN + 128);
+    blob.extend_from_slice(MAGIC);
+    blob.push(VERSION);
+    blob.extend_from_slice(&params.m_cost().to_le_bytes());
+    blob.extend_from_slice(&params.t_cost().to_le_bytes());
+    blob.extend_from_slice(&params.p_cost().to_le_bytes());
+    blob.extend_from_slice(&salt);
+    blob.extend_from_slice(&nonce);
+
+    let key = derive_key(passphrase, &salt, params)?;
+    let plaintext = Zeroizing::new(keypair.to_protobuf_encoding()?);
+    let ciphertext = XChaCha20Poly1305::new(key.as_slice().into())
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: &plaintext,
+                aad: &blob,
+            },
+        )
+        .expect("encryption with a valid key does not fail");
+    blob.extend_from_slice(&ciphertext);
+
+    Ok(blob)
+}
+
+fn open(blob: &[u8], passphrase: &str) -> Result<identity::Keypair, KeystoreError> {
+    if blob.len() <= HEADER_LEN || !blob.starts_with(MAGIC) || blob[MAGIC.len()] != VERSION {
+        return Err(KeystoreError::Format);
+    }
+
+    let (header, ciphertext) = blob.split_at(HEADER_LEN);
+    let read_u32 = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+    let (m_cost, t_cost, p_cost) = (read_u32(5), read_u32(9), read_u32(13));
+    if m_cost > M_COST || t_cost > T_COST || p_cost > P_COST {
+        return Err(KeystoreError::Costs {
+            m_cost,
+            t_cost,
+            p_cost,
+        });
+    }
+    let params = Params::new(m_cost, t_cost, p_cost, Some(KEY_LEN))?;
+    let salt = &header[17..17 + SALT_LEN];
+    let nonce = XNonce::from_slice(&header[17 + SALT_LEN..]);
+
+    let key = derive_key(passphrase, salt, params)?;
+    let plaintext = Zeroizing::new(
+        XChaCha20Poly1305::new(key.as_slice().into())
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| KeystoreError::WrongPassphrase)?,
+    );
+
+    Ok(identity::Keypair::from_protobuf_encoding(&plaintext)?)
+}
+
+fn derive_key(
+    passphrase: &str,
+    salt: &[u8],
+    params: Params,
+) -> Result<Zeroizing<[u8; KEY_LEN]>, KeystoreError> {
+    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params).hash_password_into(
+        passphrase.as_bytes(),
+        salt,
+        key.as_mut(),
+    )?;
+
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{
+        e2e::E2e,
+        history::{Conversation, History, NewMessage},
+    };
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("limiinal-keystore-{}", PeerId::random()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Opens the sessions and history of the keystore, leaving a message behind.
+    fn use_stores(keystore: &Keystore, keypair: &identity::Keypair) -> usize {
+        E2e::load(keypair, Some(e2e::store_path(keystore.path()))).unwrap();
+        let history = History::open(&history::store_path(keystore.path()), keypair).unwrap();
+        let conversation = Conversation::Topic("chat".to_string());
+        let before = history.latest(&conversation, 10).unwrap().len();
+        history
+            .record(
+                &conversation,
+                NewMessage {
+                    id: None,
+                    sender: None,
+                    outgoing: true,
+                    body: "hello",
+                    state: None,
+                },
+            )
+            .unwrap();
+
+        before
+    }
+
+    #[test]
+    fn round_trip() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let blob = seal(&keypair, "correct horse").unwrap();
+
+        let opened = open(&blob, "correct horse").unwrap();
+        assert_eq!(opened.public(), keypair.public());
+        assert!(matches!(
+            open(&blob, "battery staple"),
+            Err(KeystoreError::WrongPassphrase)
+        ));
+    }
+
+    #[test]
+    fn rejects_costs_above_the_written_ones() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let blob = seal(&keypair, "correct horse").unwrap();
+
+        for at in [5, 9, 13] {
+            let mut tampered = blob.clone();
+            tampered[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            assert!(matches!(
+                open(&tampered, "correct horse"),
+                Err(KeystoreError::Costs { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn importing_another_identity_drops_its_stores() {
+        let dir = temp_dir();
+        let keystore = Keystore::new(dir.join("identity.key"));
+        let first = keystore.create("correct horse").unwrap();
+        let first_peer = first.public().to_peer_id();
+        use_stores(&keystore, &first);
+
+        // Importing the same identity again keeps its history.
+        let backup = dir.join("backup.key");
+        keystore.export(&backup).unwrap();
+        keystore
+            .import(&backup, "correct horse", &first_peer)
+            .unwrap();
+        assert_eq!(use_stores(&keystore, &first), 1);
+
+        let other = Keystore::new(dir.join("other.key"));
+        let second = other.create("battery staple").unwrap();
+        let imported = keystore
+            .import(other.path(), "battery staple", &first_peer)
+            .unwrap();
+        assert_eq!(imported.public(), second.public());
+        assert_eq!(use_stores(&keystore, &second), 0);
+        assert_eq!(
+            keystore.unlock("battery staple").unwrap().public(),
+            second.public()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1 +1,21 @@
+pub mod admission;
+pub mod config;
+pub mod connections;
+pub mod contacts;
+#[cfg(unix)]
+pub mod daemon;
+pub mod discovery;
+pub mod dm;
+pub mod e2e;
+pub mod history;
+pub mod invite;
+pub mod keystore;
+pub mod nearby;
 pub mod network;
+pub mod presence;
+pub mod relays;
+
+pub use limiinal_common::identity;
+
+/// Name of the client's per-user directories, e.g. `~/.local/share/limiinal` on Linux.
+pub const APP_NAME: &str = "limiinal";
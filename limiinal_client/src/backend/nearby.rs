@@ -0,0 +1,57 @@
+//! Peers on the local network, found through mDNS. Contacts among them are
+//! dialled at their LAN addresses instead of through a relay, and peered
+//! with explicitly on gossipsub so topic messages reach them even outside
+//! the mesh.
+
+use std::collections::HashMap;
+
+use libp2p::{Multiaddr, PeerId};
+
+#[derive(Debug, Default)]
+pub struct Nearby {
+    peers: HashMap<PeerId, Vec<Multiaddr>>,
+}
+
+impl Nearby {
+    /// Records what mDNS found, returning the peers seen for the first time
+    /// with their addresses.
+    pub fn discovered(&mut self, found: Vec<(PeerId, Multiaddr)>) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        let mut new = Vec::new();
+        for (peer, address) in found {
+            let addresses = self.peers.entry(peer).or_default();
+            if addresses.is_empty() {
+                new.push(peer);
+            }
+            if !addresses.contains(&address) {
+                addresses.push(address);
+            }
+        }
+
+        new.into_iter()
+            .map(|peer| (peer, self.peers[&peer].clone()))
+            .collect()
+    }
+
+    /// Forgets the addresses mDNS stopped seeing, returning the peers that
+    /// have none left.
+    pub fn expired(&mut self, gone: Vec<(PeerId, Multiaddr)>) -> Vec<PeerId> {
+        let mut left = Vec::new();
+        for (peer, address) in gone {
+            let Some(addresses) = self.peers.get_mut(&peer) else {
+                continue;
+            };
+            addresses.retain(|known| *known != address);
+            if addresses.is_empty() {
+                self.peers.remove(&peer);
+                left.push(peer);
+            }
+        }
+
+        left
+    }
+
+    /// LAN addresses of `peer`, empty unless it is nearby.
+    pub fn addresses(&self, peer: &PeerId) -> &[Multiaddr] {
+        self.peers.get(peer).map(Vec::as_slice).unwrap_or_default()
+    }
+}
@@ -1,293 +1,1104 @@
 use std::{
-    collections::hash_map::DefaultHasher,
+    collections::{HashMap, HashSet},
+    convert::Infallible,
     error::Error,
-    hash::{Hash, Hasher},
-    str::FromStr,
-    time::Duration,
+    fmt, io,
+    path::PathBuf,
+    time::{Duration, Instant},
 };
 
-use futures_timer::Delay;
-
 use clap::Parser;
-use futures::{executor::block_on, future::FutureExt, stream::StreamExt};
+use futures::stream::StreamExt;
 use libp2p::{
-    core::multiaddr::{Multiaddr, Protocol},
-    dcutr, gossipsub, identify, identity, noise, ping, relay,
-    swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux, PeerId,
+    autonat,
+    core::{
+        multiaddr::{Multiaddr, Protocol},
+        transport::ListenerId,
+    },
+    dcutr, gossipsub, identify, identity, kad, mdns, noise, ping, relay,
+    rendezvous::{self, Namespace},
+    request_response,
+    swarm::{
+        dial_opts::{DialOpts, PeerCondition},
+        NetworkBehaviour, SwarmEvent,
+    },
+    tcp, yamux, PeerId, Swarm, TransportError,
 };
-use tokio::runtime::Runtime;
-use tokio::{io, io::AsyncBufReadExt, select, task};
-use tracing_subscriber::{filter, EnvFilter};
+use tokio::sync::mpsc;
 
-use log::*;
+use super::{
+    admission::{self, AdmissionRequest, AdmissionResponse},
+    config::{Config, ConfigError, GossipsubConfig},
+    connections::{ConnectionManager, Connectivity, HolePunchStatus, Reachability},
+    discovery::{Discovered, Discovery},
+    dm::{self, MessageId},
+    e2e::{self, E2e, E2eError},
+    nearby::Nearby,
+    presence::{self, Presence},
+    relays::{RelayChange, Relays},
+};
 
 #[derive(Debug, Parser)]
 #[clap(name = "libp2p DCUtR client")]
-struct Opts {
-    /// The mode (client-listen, client-dial).
+pub struct Opts {
+    /// Path of the config file, defaults to `config.toml` in the user config directory.
+    #[clap(long)]
+    pub(crate) config: Option<PathBuf>,
+
+    /// Path of the identity keystore, defaults to `identity.key` in the user data directory.
     #[clap(long)]
-    mode: Mode,
+    pub(crate) identity: Option<PathBuf>,
 
-    /// Fixed value to generate deterministic peer id.
+    /// INSECURE: derive the peer id from a single byte instead of the identity keystore.
+    /// Only 256 such identities exist, use this for local tests only.
     #[clap(long)]
-    secret_key_seed: u8,
+    pub insecure_test_seed: Option<u8>,
 
-    /// The listening address
+    /// Address of a relay, may be repeated. Replaces the relays of the config file.
     #[clap(long)]
-    relay_address: Multiaddr,
+    pub(crate) relay_address: Vec<Multiaddr>,
 
-    /// Peer ID of the remote peer to hole punch to.
+    /// Peer to connect to on startup, through the relays and then hole punched.
     #[clap(long)]
-    remote_peer_id: Option<PeerId>,
+    pub(crate) remote_peer_id: Option<PeerId>,
 
     #[clap(long, action = clap::ArgAction::SetTrue)]
-    backend_enable: bool,
+    pub(crate) backend_enable: bool,
+
+    #[clap(subcommand)]
+    pub command: Option<Subcommand>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Subcommand {
+    /// Run the backend without the GUI, serving a JSON-RPC API on a Unix socket.
+    Daemon {
+        /// Path of the API socket, defaults to `daemon.sock` in the user data directory.
+        #[clap(long)]
+        socket: Option<PathBuf>,
+
+        /// File holding the keystore passphrase, `LIMIINAL_PASSPHRASE` is used otherwise.
+        #[clap(long)]
+        passphrase_file: Option<PathBuf>,
+    },
+    /// Inspect the config file.
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommand,
+    },
 }
 
-#[derive(Clone, Debug, PartialEq, Parser)]
-enum Mode {
-    Dial,
-    Listen,
+#[derive(Debug, clap::Subcommand)]
+pub enum ConfigCommand {
+    /// Parse and validate the config file together with the other flags.
+    Check,
 }
 
-impl FromStr for Mode {
-    type Err = String;
-    fn from_str(mode: &str) -> Result<Self, Self::Err> {
-        match mode {
-            "dial" => Ok(Mode::Dial),
-            "listen" => Ok(Mode::Listen),
-            _ => Err("Expected either 'dial' or 'listen'".to_string()),
+/// Gossipsub topic every client joins on startup.
+pub const DEFAULT_TOPIC: &str = "example-topic";
+
+/// Longest wait for the listeners to report their addresses before the relays are dialled.
+const LISTEN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often relays and desired peers whose backoff ran out are redialled,
+/// and reservations topped up.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Requests from the GUI or the daemon API to the swarm task.
+#[derive(Debug, Clone)]
+pub enum Command {
+    Publish {
+        topic: String,
+        data: Vec<u8>,
+    },
+    Dial(Multiaddr),
+    /// Keep `peer` connected, through the relays and then hole punched.
+    Connect(PeerId),
+    /// Stop keeping `peer` connected, e.g. once its conversation is closed.
+    Release(PeerId),
+    /// Look up the addresses `peer` published in the DHT.
+    Locate(PeerId),
+    Subscribe(String),
+    SendDirect {
+        peer: PeerId,
+        id: MessageId,
+        body: String,
+    },
+    /// Register under the namespace at the rendezvous points and look up its peers.
+    Register(Namespace),
+    Unregister(Namespace),
+    /// Look up the peers registered under the namespace again.
+    Discover(Namespace),
+}
+
+/// Notifications from the swarm task to the GUI or the daemon API.
+#[derive(Debug, Clone)]
+pub enum Event {
+    MessageReceived {
+        topic: String,
+        source: Option<PeerId>,
+        data: Vec<u8>,
+    },
+    PeerConnected(PeerId),
+    PeerDisconnected(PeerId),
+    /// The relay holds a reservation for us, so peers can reach us through its circuit.
+    ReservationAccepted {
+        relay_peer_id: PeerId,
+        relay_address: Multiaddr,
+    },
+    /// The relay went away or dropped our reservation, its circuit address is gone.
+    ReservationLost {
+        relay_peer_id: PeerId,
+    },
+    DirectMessageReceived {
+        peer: PeerId,
+        id: MessageId,
+        body: String,
+        sent_at: i64,
+    },
+    ConnectivityChanged(Connectivity),
+    /// AutoNAT changed its mind about whether we are publicly reachable, at
+    /// `address` if we are.
+    ReachabilityChanged {
+        reachability: Reachability,
+        address: Option<Multiaddr>,
+    },
+    /// The connection to a peer other than a relay got relayed, direct, or a hole punch failed.
+    HolePunch {
+        peer: PeerId,
+        status: HolePunchStatus,
+    },
+    DirectMessageStateChanged {
+        peer: PeerId,
+        id: MessageId,
+        state: dm::DeliveryState,
+    },
+    /// A rendezvous point accepted our registration under the namespace.
+    Registered {
+        namespace: String,
+        rendezvous_peer_id: PeerId,
+    },
+    RegistrationFailed {
+        namespace: String,
+        rendezvous_peer_id: PeerId,
+        error: String,
+    },
+    /// A lookup found a peer under one of our namespaces.
+    PeerDiscovered(Discovered),
+    /// The registrations of a discovered peer expired without being renewed.
+    DiscoveryExpired(PeerId),
+    /// mDNS found a peer on the local network.
+    NearbyDiscovered {
+        peer: PeerId,
+        addresses: Vec<Multiaddr>,
+    },
+    /// A peer on the local network is no longer seen.
+    NearbyExpired(PeerId),
+    /// A DHT lookup found the addresses `peer` published, or none if it
+    /// found no valid record.
+    PeerLocated {
+        peer: PeerId,
+        addresses: Vec<Multiaddr>,
+    },
+}
+
+/// Why [`AppCore`] could not start.
+#[derive(Debug)]
+pub enum BackendError {
+    Config(ConfigError),
+    /// The end-to-end session store could not be opened.
+    Sessions(E2eError),
+    /// The transports could not be set up.
+    Transport(noise::Error),
+    Dns(io::Error),
+    Websocket(Box<dyn Error + Send + Sync>),
+    Mdns(io::Error),
+    Gossipsub(&'static str),
+    Listen(Multiaddr, TransportError<io::Error>),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::Config(e) => write!(f, "{e}"),
+            BackendError::Sessions(e) => write!(f, "cannot open the session store: {e}"),
+            BackendError::Transport(e) => write!(f, "cannot set up the transports: {e}"),
+            BackendError::Dns(e) => write!(f, "cannot set up DNS resolution: {e}"),
+            BackendError::Websocket(e) => write!(f, "cannot set up WebSocket: {e}"),
+            BackendError::Mdns(e) => write!(f, "cannot set up mDNS: {e}"),
+            BackendError::Gossipsub(e) => write!(f, "cannot set up gossipsub: {e}"),
+            BackendError::Listen(address, TransportError::Other(e)) => {
+                write!(f, "cannot listen on {address}: {e}")
+            }
+            BackendError::Listen(address, TransportError::MultiaddrNotSupported(_)) => {
+                write!(f, "cannot listen on {address}: unsupported address")
+            }
         }
     }
 }
 
-pub struct AppCore {
-    pub backend_thread: Option<task::JoinHandle<()>>,
+impl Error for BackendError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BackendError::Config(e) => Some(e),
+            BackendError::Sessions(e) => Some(e),
+            BackendError::Transport(e) => Some(e),
+            BackendError::Dns(e) => Some(e),
+            BackendError::Websocket(e) => Some(e.as_ref()),
+            BackendError::Mdns(e) => Some(e),
+            BackendError::Listen(_, e) => Some(e),
+            BackendError::Gossipsub(_) => None,
+        }
+    }
 }
 
-impl AppCore {
-    pub fn new() -> Self {
-        AppCore {
-            backend_thread: None,
+impl From<ConfigError> for BackendError {
+    fn from(e: ConfigError) -> Self {
+        BackendError::Config(e)
+    }
+}
+
+impl From<E2eError> for BackendError {
+    fn from(e: E2eError) -> Self {
+        BackendError::Sessions(e)
+    }
+}
+
+impl From<noise::Error> for BackendError {
+    fn from(e: noise::Error) -> Self {
+        BackendError::Transport(e)
+    }
+}
+
+impl From<Infallible> for BackendError {
+    fn from(e: Infallible) -> Self {
+        match e {}
+    }
+}
+
+/// Where [`AppCore`] is in its startup. Events arriving meanwhile are handled
+/// as usual, only the relays wait until we know our listen addresses.
+#[derive(Debug)]
+enum Startup {
+    /// Waiting for each listener to report an address, or for the deadline.
+    Listening {
+        pending: HashSet<ListenerId>,
+        deadline: Instant,
+    },
+    Running,
+}
+
+impl Startup {
+    fn listening(listeners: impl IntoIterator<Item = ListenerId>) -> Self {
+        Startup::Listening {
+            pending: listeners.into_iter().collect(),
+            deadline: Instant::now() + LISTEN_TIMEOUT,
+        }
+    }
+
+    /// The listener reported an address, or closed.
+    fn listened(&mut self, listener: ListenerId) {
+        if let Startup::Listening { pending, .. } = self {
+            pending.remove(&listener);
+        }
+    }
+
+    /// Moves on to running once every listener reported or the deadline
+    /// passed, returning whether it did just now.
+    fn advance(&mut self) -> bool {
+        match self {
+            Startup::Listening { pending, deadline }
+                if pending.is_empty() || *deadline <= Instant::now() =>
+            {
+                *self = Startup::Running;
+                true
+            }
+            _ => false,
         }
     }
 
-    pub async fn run(&mut self) {
-        if let Err(e) = AppCore::start().await {
-            tracing::error!("Failed to start AppCore: {:?}", e);
+    fn is_running(&self) -> bool {
+        matches!(self, Startup::Running)
+    }
+}
+
+/// Cloneable sender side of a running [`AppCore`].
+#[derive(Debug, Clone)]
+pub struct AppCoreHandle {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl AppCoreHandle {
+    pub fn publish(&self, topic: impl Into<String>, data: Vec<u8>) {
+        self.send(Command::Publish {
+            topic: topic.into(),
+            data,
+        });
+    }
+
+    pub fn dial(&self, address: Multiaddr) {
+        self.send(Command::Dial(address));
+    }
+
+    pub fn connect(&self, peer: PeerId) {
+        self.send(Command::Connect(peer));
+    }
+
+    pub fn release(&self, peer: PeerId) {
+        self.send(Command::Release(peer));
+    }
+
+    pub fn locate(&self, peer: PeerId) {
+        self.send(Command::Locate(peer));
+    }
+
+    pub fn subscribe(&self, topic: impl Into<String>) {
+        self.send(Command::Subscribe(topic.into()));
+    }
+
+    pub fn register(&self, namespace: Namespace) {
+        self.send(Command::Register(namespace));
+    }
+
+    pub fn unregister(&self, namespace: Namespace) {
+        self.send(Command::Unregister(namespace));
+    }
+
+    pub fn discover(&self, namespace: Namespace) {
+        self.send(Command::Discover(namespace));
+    }
+
+    /// Sends `body` straight to `peer`, returning the id its delivery state
+    /// is reported under.
+    pub fn send_direct(&self, peer: PeerId, body: impl Into<String>) -> MessageId {
+        let id = rand::random();
+        self.send(Command::SendDirect {
+            peer,
+            id,
+            body: body.into(),
+        });
+
+        id
+    }
+
+    fn send(&self, command: Command) {
+        if let Err(mpsc::error::SendError(command)) = self.commands.send(command) {
+            tracing::error!(?command, "Backend stopped, dropping command");
         }
     }
+}
 
-    async fn start() -> Result<(), Box<dyn Error>> {
-        let _ = tracing_subscriber::fmt()
-            .with_env_filter(EnvFilter::from_default_env())
-            .try_init();
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    relay_client: relay::client::Behaviour,
+    ping: ping::Behaviour,
+    identify: identify::Behaviour,
+    dcutr: dcutr::Behaviour,
+    gossipsub: gossipsub::Behaviour,
+    dm: dm::Behaviour,
+    prekey: e2e::PrekeyBehaviour,
+    rendezvous: rendezvous::client::Behaviour,
+    mdns: mdns::tokio::Behaviour,
+    kademlia: kad::Behaviour<kad::store::MemoryStore>,
+    autonat: autonat::Behaviour,
+    admission: admission::Behaviour,
+}
 
-        let opts = Opts::parse();
+/// What a direct request in flight carries.
+enum Carried {
+    Message(MessageId),
+    SenderKey(String),
+}
 
-        #[derive(NetworkBehaviour)]
-        struct Behaviour {
-            relay_client: relay::client::Behaviour,
-            ping: ping::Behaviour,
-            identify: identify::Behaviour,
-            dcutr: dcutr::Behaviour,
-            gossipsub: gossipsub::Behaviour,
+impl Carried {
+    fn of(id: MessageId, payload: &e2e::Payload) -> Self {
+        match payload {
+            e2e::Payload::Text { .. } => Carried::Message(id),
+            e2e::Payload::SenderKey { topic, .. } => Carried::SenderKey(topic.clone()),
         }
+    }
 
-        let mut swarm =
-            libp2p::SwarmBuilder::with_existing_identity(generate_ed25519(opts.secret_key_seed))
-                .with_tokio()
-                .with_tcp(
-                    tcp::Config::default().nodelay(true),
-                    noise::Config::new,
-                    yamux::Config::default,
-                )?
-                .with_quic()
-                .with_dns()?
-                .with_relay_client(noise::Config::new, yamux::Config::default)?
-                .with_behaviour(|keypair, relay_behaviour| Behaviour {
-                    relay_client: relay_behaviour,
-                    ping: ping::Behaviour::new(ping::Config::new()),
-                    identify: identify::Behaviour::new(identify::Config::new(
-                        "/TODO/0.0.1".to_string(),
-                        keypair.public(),
-                    )),
-                    dcutr: dcutr::Behaviour::new(keypair.public().to_peer_id()),
-                    gossipsub: {
-                        let gossipsub_config = gossipsub::ConfigBuilder::default()
-                            .heartbeat_interval(Duration::from_secs(1))
-                            .validation_mode(gossipsub::ValidationMode::Strict)
-                            .build()
-                            .expect("Valid Gossipsub configuration");
-                        gossipsub::Behaviour::new(
-                            gossipsub::MessageAuthenticity::Signed(keypair.clone()),
-                            gossipsub_config,
-                        )
-                        .expect("Failed to create Gossipsub behaviour")
-                    },
-                })?
-                .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
-                .build();
-
-        swarm
-            .listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse().unwrap())
-            .unwrap();
-        swarm
-            .listen_on("/ip4/0.0.0.0/tcp/0".parse().unwrap())
-            .unwrap();
-
-        let topic = gossipsub::IdentTopic::new("example-topic");
-        if swarm.behaviour_mut().gossipsub.subscribe(&topic).is_err() {
-            tracing::error!("Failed to subscribe to topic");
+    /// Handles a payload that did not reach `peer`, returning the event to report.
+    fn failed(self, e2e: &mut E2e, peer: PeerId) -> Option<Event> {
+        match self {
+            Carried::Message(id) => Some(Event::DirectMessageStateChanged {
+                peer,
+                id,
+                state: dm::DeliveryState::Failed,
+            }),
+            Carried::SenderKey(topic) => {
+                e2e.sender_key_lost(&topic, &peer);
+                None
+            }
         }
+    }
+}
 
-        println!("Now blocking");
+/// Direct requests in flight, and payloads waiting for the prekey bundle of
+/// their recipient.
+#[derive(Default)]
+struct Outbox {
+    requests: HashMap<request_response::OutboundRequestId, (PeerId, Carried)>,
+    awaiting_session: HashMap<PeerId, Vec<(MessageId, e2e::Payload)>>,
+}
 
-        // Wait to listen on all interfaces.
-        async {
-            let mut delay = futures_timer::Delay::new(std::time::Duration::from_secs(1)).fuse();
-            loop {
-                futures::select! {
-                    event = swarm.next() => {
-                        match event.unwrap() {
-                            SwarmEvent::NewListenAddr { address, .. } => {
-                                tracing::info!(%address, "Listening on address");
-                            }
-                            event => panic!("{event:?}"),
-                        }
-                    }
-                    _ = delay => {
-                        // Likely listening on all interfaces now, thus continuing by breaking the loop.
-                        break;
-                    }
-                }
+impl Outbox {
+    /// Seals `payload` for `peer` and sends it, fetching the peer's prekey
+    /// bundle first if there is no session yet. Returns the event to report.
+    fn send(
+        &mut self,
+        swarm: &mut Swarm<Behaviour>,
+        e2e: &mut E2e,
+        peer: PeerId,
+        id: MessageId,
+        payload: e2e::Payload,
+    ) -> Option<Event> {
+        if !e2e.has_session(&peer) {
+            let queue = self.awaiting_session.entry(peer).or_default();
+            if queue.is_empty() {
+                swarm
+                    .behaviour_mut()
+                    .prekey
+                    .send_request(&peer, e2e::PrekeyRequest);
+            }
+            queue.push((id, payload));
+            return None;
+        }
+
+        let carried = Carried::of(id, &payload);
+        let envelope = match e2e.encrypt(&peer, &payload) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                tracing::warn!(%peer, "Failed to seal direct message: {e}");
+                return carried.failed(e2e, peer);
             }
         };
 
-        println!("The block has ended");
+        let request_id = swarm
+            .behaviour_mut()
+            .dm
+            .send_request(&peer, dm::DirectMessage { id, envelope });
+        let event =
+            matches!(carried, Carried::Message(_)).then_some(Event::DirectMessageStateChanged {
+                peer,
+                id,
+                state: dm::DeliveryState::Sent,
+            });
+        self.requests.insert(request_id, (peer, carried));
 
-        println!("The swarm begins");
+        event
+    }
 
-        // Connect to the relay server. Not for the reservation or relayed connection, but to (a) learn
-        // our local public address and (b) enable a freshly started relay to learn its public address.
-        swarm.dial(opts.relay_address.clone()).unwrap();
-        async {
-            let mut learned_observed_addr = false;
-            let mut told_relay_observed_addr = false;
+    /// Sends everything that waited for the session with `peer`.
+    fn flush(&mut self, swarm: &mut Swarm<Behaviour>, e2e: &mut E2e, peer: PeerId) -> Vec<Event> {
+        self.awaiting_session
+            .remove(&peer)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(id, payload)| self.send(swarm, e2e, peer, id, payload))
+            .collect()
+    }
 
-            loop {
-                match swarm.next().await.unwrap() {
-                    SwarmEvent::NewListenAddr { .. } => {}
-                    SwarmEvent::Dialing { .. } => {}
-                    SwarmEvent::ConnectionEstablished { .. } => {}
-                    SwarmEvent::Behaviour(BehaviourEvent::Ping(_)) => {}
-                    SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Sent {
-                        ..
-                    })) => {
-                        tracing::info!("Told relay its public address");
-                        told_relay_observed_addr = true;
-                    }
-                    SwarmEvent::Behaviour(BehaviourEvent::Identify(
-                        identify::Event::Received {
-                            info: identify::Info { observed_addr, .. },
-                            ..
-                        },
-                    )) => {
-                        tracing::info!(address=%observed_addr, "Relay told us our observed address");
-                        learned_observed_addr = true;
-                    }
-                    event => {
-                        tracing::error!(?event, "Unhandled event occurred");
-                    }
-                }
+    /// Gives up on everything that waited for the session with `peer`.
+    fn abandon(&mut self, e2e: &mut E2e, peer: PeerId) -> Vec<Event> {
+        self.awaiting_session
+            .remove(&peer)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(id, payload)| Carried::of(id, &payload).failed(e2e, peer))
+            .collect()
+    }
+}
 
-                if learned_observed_addr && told_relay_observed_addr {
-                    break;
-                }
-            }
-        }.await;
+/// Dials `peer` at its LAN `addresses` unless it is directly connected
+/// already, and peers with it on gossipsub.
+fn dial_nearby(
+    swarm: &mut Swarm<Behaviour>,
+    connections: &ConnectionManager,
+    peer: PeerId,
+    addresses: &[Multiaddr],
+) {
+    if addresses.is_empty() {
+        return;
+    }
+    swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer);
+    if connections.is_directly_connected(&peer) {
+        return;
+    }
+
+    let opts = DialOpts::peer_id(peer)
+        .addresses(addresses.to_vec())
+        .condition(PeerCondition::NotDialing)
+        .build();
+    if let Err(e) = swarm.dial(opts) {
+        tracing::debug!(%peer, "Did not dial nearby peer: {e}");
+    }
+}
+
+fn relay_events(changes: Vec<RelayChange>) -> impl Iterator<Item = Event> {
+    changes.into_iter().map(|change| match change {
+        RelayChange::Reserved { peer, address } => Event::ReservationAccepted {
+            relay_peer_id: peer,
+            relay_address: address,
+        },
+        RelayChange::Lost { peer } => Event::ReservationLost {
+            relay_peer_id: peer,
+        },
+    })
+}
+
+/// Publishes `data` on `topic` sealed with our sender key, handing the key to
+/// the subscribed peers that do not have it yet.
+fn publish(
+    swarm: &mut Swarm<Behaviour>,
+    e2e: &mut E2e,
+    outbox: &mut Outbox,
+    topic: &str,
+    data: &[u8],
+) {
+    let topic = gossipsub::IdentTopic::new(topic);
+    let hash = topic.hash();
+    let members: Vec<PeerId> = swarm
+        .behaviour()
+        .gossipsub
+        .all_peers()
+        .filter(|(_, topics)| topics.contains(&&hash))
+        .map(|(peer, _)| *peer)
+        .collect();
+
+    let (envelope, sender_keys) = e2e.encrypt_group(hash.as_str(), data, members);
+    for (peer, payload) in sender_keys {
+        outbox.send(swarm, e2e, peer, rand::random(), payload);
+    }
+
+    let data = serde_json::to_vec(&envelope).expect("envelopes serialize");
+    if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic, data) {
+        tracing::error!("Failed to publish message: {:?}", e);
+    }
+}
+
+/// Everything [`AppCore`] needs besides the identity. Binaries build it from
+/// the [`Config`], tests and embedders may fill it in by hand.
+#[derive(Debug, Clone)]
+pub struct BackendConfig {
+    /// File keeping the end-to-end sessions, see [`e2e::store_path`], in
+    /// memory only when `None`.
+    pub session_store: Option<PathBuf>,
+    /// Peer to connect to on startup.
+    pub remote_peer_id: Option<PeerId>,
+    pub listen: Vec<Multiaddr>,
+    /// Relays, each ending in `/p2p/<relay peer id>`.
+    pub relays: Vec<Multiaddr>,
+    /// Number of relays to hold a reservation on at the same time.
+    pub reservations: usize,
+    /// Admission token presented to relays that ask for one.
+    pub relay_token: Option<String>,
+    pub identify_protocol: String,
+    pub idle_timeout: Duration,
+    pub gossipsub: GossipsubConfig,
+    /// Rendezvous namespaces to register under on startup.
+    pub namespaces: Vec<String>,
+}
+
+impl BackendConfig {
+    /// The backend settings of `config`, keeping end-to-end sessions in memory.
+    pub fn new(config: &Config) -> Self {
+        BackendConfig {
+            session_store: None,
+            remote_peer_id: config.network.remote_peer_id,
+            listen: config.network.listen.clone(),
+            relays: config.network.relays.clone(),
+            reservations: config.network.reservations,
+            relay_token: config.network.relay_token.clone(),
+            identify_protocol: config.network.identify_protocol.clone(),
+            idle_timeout: Duration::from_secs(config.network.idle_timeout_secs),
+            gossipsub: config.gossipsub.clone(),
+            namespaces: config.network.namespaces.clone(),
+        }
+    }
+}
+
+impl Default for BackendConfig {
+    fn default() -> Self {
+        BackendConfig::new(&Config::default())
+    }
+}
+
+pub struct AppCore {
+    keypair: identity::Keypair,
+    config: BackendConfig,
+    commands: mpsc::UnboundedReceiver<Command>,
+    events: mpsc::UnboundedSender<Event>,
+}
+
+impl AppCore {
+    /// Creates the core together with the handle used to command it and the
+    /// receiver of its events. Nothing happens until [`AppCore::run`] is polled.
+    ///
+    /// Both channels are unbounded: the swarm never waits for a slow GUI to
+    /// take its events, and commands are never turned away for lack of room.
+    pub fn new(
+        keypair: identity::Keypair,
+        config: BackendConfig,
+    ) -> (Self, AppCoreHandle, mpsc::UnboundedReceiver<Event>) {
+        let (command_tx, command_rx) = mpsc::unbounded_channel();
+        let (event_tx, event_rx) = mpsc::unbounded_channel();
+
+        (
+            AppCore {
+                keypair,
+                config,
+                commands: command_rx,
+                events: event_tx,
+            },
+            AppCoreHandle {
+                commands: command_tx,
+            },
+            event_rx,
+        )
+    }
+
+    /// Runs the swarm until every handle is dropped, or fails to start it.
+    pub async fn run(self) -> Result<(), BackendError> {
+        let result = AppCore::start(self.keypair, self.config, self.commands, self.events).await;
+        if let Err(e) = &result {
+            tracing::error!("Failed to start AppCore: {e}");
+        }
+        result
+    }
+
+    async fn start(
+        local_key: identity::Keypair,
+        config: BackendConfig,
+        mut commands: mpsc::UnboundedReceiver<Command>,
+        events: mpsc::UnboundedSender<Event>,
+    ) -> Result<(), BackendError> {
+        let gossipsub = gossipsub::Behaviour::new(
+            gossipsub::MessageAuthenticity::Signed(local_key.clone()),
+            config.gossipsub.build()?,
+        )
+        .map_err(BackendError::Gossipsub)?;
+        let namespaces = config
+            .namespaces
+            .iter()
+            .map(|namespace| {
+                Namespace::new(namespace.clone())
+                    .map_err(|e| ConfigError::Invalid(format!("namespace {namespace:?}: {e}")))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let local_peer_id = local_key.public().to_peer_id();
+        let mdns = mdns::tokio::Behaviour::new(mdns::Config::default(), local_peer_id)
+            .map_err(BackendError::Mdns)?;
+        let mut presence = Presence::new(local_key.clone());
+        let mut e2e = E2e::load(&local_key, config.session_store.clone())?;
+        let mut outbox = Outbox::default();
 
-        println!("The swarm is dialed");
+        let mut swarm = libp2p::SwarmBuilder::with_existing_identity(local_key)
+            .with_tokio()
+            .with_tcp(
+                tcp::Config::default().nodelay(true),
+                noise::Config::new,
+                yamux::Config::default,
+            )?
+            .with_quic()
+            .with_dns()
+            .map_err(BackendError::Dns)?
+            // Dials `/ws` and `/wss` relay addresses, for networks that only let HTTPS out.
+            .with_websocket(noise::Config::new, yamux::Config::default)
+            .await
+            .map_err(|e| BackendError::Websocket(e.into()))?
+            .with_relay_client(noise::Config::new, yamux::Config::default)?
+            .with_behaviour(|keypair, relay_behaviour| Behaviour {
+                relay_client: relay_behaviour,
+                ping: ping::Behaviour::new(ping::Config::new()),
+                identify: identify::Behaviour::new(identify::Config::new(
+                    config.identify_protocol.clone(),
+                    keypair.public(),
+                )),
+                dcutr: dcutr::Behaviour::new(keypair.public().to_peer_id()),
+                gossipsub,
+                dm: dm::new_behaviour(),
+                prekey: e2e::new_prekey_behaviour(),
+                rendezvous: rendezvous::client::Behaviour::new(keypair.clone()),
+                mdns,
+                kademlia: presence::new_behaviour(keypair.public().to_peer_id()),
+                autonat: autonat::Behaviour::new(
+                    keypair.public().to_peer_id(),
+                    autonat::Config::default(),
+                ),
+                admission: admission::new_behaviour(),
+            })?
+            .with_swarm_config(|c| c.with_idle_connection_timeout(config.idle_timeout))
+            .build();
 
-        match opts.mode {
-            Mode::Dial => {
+        let mut listeners = Vec::new();
+        for address in &config.listen {
+            let listener = swarm
+                .listen_on(address.clone())
+                .map_err(|e| BackendError::Listen(address.clone(), e))?;
+            listeners.push(listener);
+        }
+        let mut startup = Startup::listening(listeners);
+
+        let topic = gossipsub::IdentTopic::new(DEFAULT_TOPIC);
+        if swarm.behaviour_mut().gossipsub.subscribe(&topic).is_err() {
+            tracing::error!("Failed to subscribe to topic");
+        }
+
+        // Once listening, dial every relay, reserve on the first ones that tell us our observed
+        // address and keep the rest connected as spares.
+        let mut relays = Relays::new(&config.relays, config.reservations);
+        // The relays bootstrap the DHT and probe whether we are reachable without them.
+        for address in &config.relays {
+            if let Some(Protocol::P2p(relay)) = address.iter().last() {
                 swarm
-                    .dial(
-                        opts.relay_address
-                            .with(Protocol::P2pCircuit)
-                            .with(Protocol::P2p(opts.remote_peer_id.unwrap())),
-                    )
-                    .unwrap();
-            }
-            Mode::Listen => {
+                    .behaviour_mut()
+                    .kademlia
+                    .add_address(&relay, address.clone());
                 swarm
-                    .listen_on(opts.relay_address.with(Protocol::P2pCircuit))
-                    .unwrap();
-
-                let message = b"Hello, Gossipsub!".to_vec();
-                if swarm
                     .behaviour_mut()
-                    .gossipsub
-                    .publish(topic.clone(), message)
-                    .is_err()
-                {
-                    tracing::error!("Failed to publish message");
-                }
+                    .autonat
+                    .add_server(relay, Some(address.clone()));
             }
         }
+        let mut reachability = Reachability::Unknown;
+        let mut maintenance = tokio::time::interval(MAINTENANCE_INTERVAL);
 
-        async {
-            // Create an asynchronous stdin reader
-            let stdin = io::BufReader::new(io::stdin());
-            let mut lines = stdin.lines();
+        // Peers we stay connected to, dialled through the relays we get to know and redialled
+        // whenever the connection drops. Every client also holds reservations, so either side
+        // may dial and DCUtR upgrades the circuit to a direct connection.
+        let mut connections = ConnectionManager::default();
+        if let Some(remote) = config.remote_peer_id {
+            connections.want(remote, None);
+        }
+
+        // Peers on the local network, dialled directly once they are wanted.
+        let mut nearby = Nearby::default();
 
-            // Define the Gossipsub topic
-            let topic = gossipsub::IdentTopic::new("example-topic");
+        // Namespaces we register under at every identified relay, renewed and looked up again
+        // until we leave them.
+        let mut discovery = Discovery::default();
+        for namespace in namespaces {
+            discovery.join(&mut swarm.behaviour_mut().rendezvous, &[], namespace);
+        }
 
+        async {
             loop {
                 tokio::select! {
                     // Handle Gossipsub and swarm events
-                    event = swarm.next() => match event.unwrap() {
-                        SwarmEvent::NewListenAddr { address, .. } => {
+                    event = swarm.select_next_some() => match event {
+                        SwarmEvent::NewListenAddr { listener_id, address } => {
                             tracing::info!(%address, "Listening on address");
+                            startup.listened(listener_id);
+                            // Circuit addresses are what we register at the rendezvous points.
+                            if address.iter().any(|p| p == Protocol::P2pCircuit) {
+                                swarm.add_external_address(address);
+                                discovery.refresh(&mut swarm.behaviour_mut().rendezvous, &relays.rendezvous_points());
+                                let addresses: Vec<_> = swarm.external_addresses().cloned().collect();
+                                presence.publish(&mut swarm.behaviour_mut().kademlia, addresses);
+                            }
+                        }
+                        SwarmEvent::ListenerClosed { listener_id, addresses, reason } => {
+                            tracing::info!(?listener_id, ?reason, "Listener closed");
+                            startup.listened(listener_id);
+                            if !addresses.is_empty() {
+                                for address in addresses {
+                                    swarm.remove_external_address(&address);
+                                }
+                                let addresses: Vec<_> = swarm.external_addresses().cloned().collect();
+                                presence.publish(&mut swarm.behaviour_mut().kademlia, addresses);
+                            }
+                            for event in relay_events(relays.listener_closed(listener_id)) {
+                                let _ = events.send(event);
+                            }
+                            relays.maintain(&mut swarm);
                         }
                         SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
-                            relay::client::Event::ReservationReqAccepted { .. },
+                            relay::client::Event::ReservationReqAccepted { relay_peer_id, .. },
                         )) => {
-                            assert!(opts.mode == Mode::Listen);
-                            tracing::info!("Relay accepted our reservation request");
+                            tracing::info!(relay=%relay_peer_id, "Relay accepted our reservation request");
+                            for event in relay_events(relays.reservation_accepted(&relay_peer_id)) {
+                                let _ = events.send(event);
+                            }
                         }
                         SwarmEvent::Behaviour(BehaviourEvent::RelayClient(event)) => {
                             tracing::info!(?event)
                         }
-                        SwarmEvent::Behaviour(BehaviourEvent::Dcutr(event)) => {
-                            tracing::info!(?event)
+                        SwarmEvent::Behaviour(BehaviourEvent::Dcutr(dcutr::Event { remote_peer_id, result })) => {
+                            let result = match result {
+                                Ok(_) => {
+                                    tracing::info!(peer=%remote_peer_id, "Hole punched");
+                                    Ok(())
+                                }
+                                Err(e) => {
+                                    tracing::warn!(peer=%remote_peer_id, "Hole punch failed: {e}");
+                                    Err(e.to_string())
+                                }
+                            };
+                            let status = connections.hole_punched(remote_peer_id, result);
+                            let _ = events.send(Event::HolePunch { peer: remote_peer_id, status });
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Identify(
+                            identify::Event::Received { peer_id, info, .. },
+                        )) if relays.is_relay(&peer_id) => {
+                            tracing::info!(relay=%peer_id, address=%info.observed_addr, "Relay told us our observed address");
+                            // Relays that admit token holders only get to serve us once they accepted our token.
+                            let token = config.relay_token.as_ref().filter(|_| info.protocols.contains(&admission::PROTOCOL));
+                            match token {
+                                Some(token) if relays.admitting(&peer_id) => {
+                                    tracing::info!(relay=%peer_id, "Presenting admission token");
+                                    swarm.behaviour_mut().admission.send_request(&peer_id, AdmissionRequest { token: token.clone() });
+                                }
+                                Some(_) => {}
+                                None => {
+                                    if relays.identified(&peer_id) {
+                                        discovery.joined_at(&mut swarm.behaviour_mut().rendezvous, peer_id);
+                                    }
+                                }
+                            }
+                            relays.maintain(&mut swarm);
+                            connections.maintain(&mut swarm, |peer| relays.circuit_addresses(peer));
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Identify(
+                            identify::Event::Received { peer_id, info, .. },
+                        )) if info.protocols.contains(&presence::KAD_PROTOCOL) => {
+                            for address in info.listen_addrs {
+                                swarm.behaviour_mut().kademlia.add_address(&peer_id, address);
+                            }
                         }
                         SwarmEvent::Behaviour(BehaviourEvent::Identify(event)) => {
                             tracing::info!(?event)
                         }
-                        SwarmEvent::Behaviour(BehaviourEvent::Ping(_)) => {}
+                        SwarmEvent::Behaviour(BehaviourEvent::Admission(event)) => {
+                            let peer = match event {
+                                request_response::Event::Message {
+                                    peer,
+                                    message: request_response::Message::Response { response, .. },
+                                } => {
+                                    match response {
+                                        AdmissionResponse::Accepted { expires } => {
+                                            tracing::info!(relay=%peer, expires, "Relay admitted us");
+                                        }
+                                        AdmissionResponse::Rejected { reason } => {
+                                            tracing::warn!(relay=%peer, "Relay refused our admission token: {reason}");
+                                        }
+                                    }
+                                    peer
+                                }
+                                request_response::Event::OutboundFailure { peer, error, .. } => {
+                                    tracing::warn!(relay=%peer, "Failed to present admission token: {error}");
+                                    peer
+                                }
+                                _ => continue,
+                            };
+                            // A refused token still leaves the relay to try, it may serve us anyway.
+                            if relays.admitted(&peer) {
+                                discovery.joined_at(&mut swarm.behaviour_mut().rendezvous, peer);
+                            }
+                            relays.maintain(&mut swarm);
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Kademlia(kad::Event::InboundRequest {
+                            request: kad::InboundRequest::PutRecord { source, record: Some(record), .. },
+                        })) => {
+                            presence::store(&mut swarm.behaviour_mut().kademlia, &source, record);
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+                            id,
+                            result: kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(found))),
+                            step,
+                            ..
+                        })) => {
+                            let located = presence.found(&mut swarm.behaviour_mut().kademlia, id, &found.record);
+                            if let Some((peer, addresses)) = &located {
+                                tracing::info!(%peer, ?addresses, "Located peer");
+                                connections.located(peer, addresses.clone());
+                                connections.maintain(&mut swarm, |peer| relays.circuit_addresses(peer));
+                            }
+                            // An invalid record may be the last one the lookup gets.
+                            let located = located.or_else(|| {
+                                step.last
+                                    .then(|| presence.finished(id))
+                                    .flatten()
+                                    .map(|peer| (peer, Vec::new()))
+                            });
+                            if let Some((peer, addresses)) = located {
+                                let _ = events.send(Event::PeerLocated { peer, addresses });
+                            }
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+                            id,
+                            result: kad::QueryResult::GetRecord(result),
+                            step,
+                            ..
+                        })) if step.last => {
+                            if let Some(peer) = presence.finished(id) {
+                                match result {
+                                    Err(e) => tracing::info!(%peer, "Could not locate peer: {e}"),
+                                    Ok(_) => tracing::info!(%peer, "Could not locate peer"),
+                                }
+                                let _ = events.send(Event::PeerLocated { peer, addresses: Vec::new() });
+                            }
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+                            result: kad::QueryResult::PutRecord(Err(e)),
+                            ..
+                        })) => {
+                            tracing::warn!("Failed to publish presence record: {e}");
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Kademlia(event)) => {
+                            tracing::debug!(?event)
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Rendezvous(
+                            rendezvous::client::Event::Registered { rendezvous_node, ttl, namespace },
+                        )) => {
+                            tracing::info!(%namespace, rendezvous=%rendezvous_node, ttl, "Registered");
+                            let _ = events
+                                .send(Event::Registered {
+                                    namespace: namespace.to_string(),
+                                    rendezvous_peer_id: rendezvous_node,
+                                });
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Rendezvous(
+                            rendezvous::client::Event::RegisterFailed { rendezvous_node, namespace, error },
+                        )) => {
+                            tracing::warn!(%namespace, rendezvous=%rendezvous_node, "Registration failed: {error:?}");
+                            let _ = events
+                                .send(Event::RegistrationFailed {
+                                    namespace: namespace.to_string(),
+                                    rendezvous_peer_id: rendezvous_node,
+                                    error: format!("{error:?}"),
+                                });
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Rendezvous(
+                            rendezvous::client::Event::Discovered { registrations, .. },
+                        )) => {
+                            for discovered in discovery.discovered(&local_peer_id, registrations) {
+                                tracing::info!(namespace=%discovered.namespace, peer=%discovered.peer, "Discovered peer");
+                                let _ = events.send(Event::PeerDiscovered(discovered));
+                            }
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Rendezvous(
+                            rendezvous::client::Event::DiscoverFailed { rendezvous_node, namespace, error },
+                        )) => {
+                            tracing::warn!(?namespace, rendezvous=%rendezvous_node, "Lookup failed: {error:?}");
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Rendezvous(
+                            rendezvous::client::Event::Expired { peer },
+                        )) if discovery.expired(&peer) => {
+                            let _ = events.send(Event::DiscoveryExpired(peer));
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Discovered(found))) => {
+                            for (peer, addresses) in nearby.discovered(found) {
+                                tracing::info!(%peer, "Found peer on the local network");
+                                if connections.is_wanted(&peer) {
+                                    dial_nearby(&mut swarm, &connections, peer, &addresses);
+                                }
+                                let _ = events.send(Event::NearbyDiscovered { peer, addresses });
+                            }
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Expired(gone))) => {
+                            for peer in nearby.expired(gone) {
+                                tracing::info!(%peer, "Peer left the local network");
+                                swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer);
+                                let _ = events.send(Event::NearbyExpired(peer));
+                            }
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Autonat(autonat::Event::StatusChanged { old, new })) => {
+                            tracing::info!(?old, ?new, "NAT status changed");
+                            for event in relay_events(relays.set_public(&mut swarm, new.is_public())) {
+                                let _ = events.send(event);
+                            }
+                            relays.maintain(&mut swarm);
+                            reachability = Reachability::from(&new);
+                            let address = match new {
+                                autonat::NatStatus::Public(address) => Some(address),
+                                _ => None,
+                            };
+                            let _ = events.send(Event::ReachabilityChanged { reachability, address });
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Autonat(event)) => {
+                            tracing::debug!(?event)
+                        }
+                        // AutoNAT confirmed or gave up on a public address, peers should dial it.
+                        SwarmEvent::ExternalAddrConfirmed { address } | SwarmEvent::ExternalAddrExpired { address } => {
+                            tracing::info!(%address, "External addresses changed");
+                            discovery.refresh(&mut swarm.behaviour_mut().rendezvous, &relays.rendezvous_points());
+                            let addresses: Vec<_> = swarm.external_addresses().cloned().collect();
+                            presence.publish(&mut swarm.behaviour_mut().kademlia, addresses);
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Ping(event)) => {
+                            relays.ping(&mut swarm, &event);
+                        }
                         SwarmEvent::ConnectionEstablished {
-                            peer_id, endpoint, ..
+                            peer_id, connection_id, endpoint, ..
                         } => {
                             tracing::info!(peer=%peer_id, ?endpoint, "Established new connection");
+                            let is_relay = relays.is_relay(&peer_id);
+                            if let Some(status) = connections.connected(connection_id, peer_id, endpoint.is_relayed(), is_relay) {
+                                let _ = events.send(Event::HolePunch { peer: peer_id, status });
+                            }
+                            relays.connected(&peer_id);
+                            let _ = events.send(Event::PeerConnected(peer_id));
+                        }
+                        SwarmEvent::ConnectionClosed {
+                            peer_id, connection_id, num_established, ..
+                        } => {
+                            if let Some(status) = connections.closed(connection_id) {
+                                let _ = events.send(Event::HolePunch { peer: peer_id, status });
+                            }
+                            if num_established == 0 {
+                                for event in relay_events(relays.disconnected(&mut swarm, &peer_id)) {
+                                    let _ = events.send(event);
+                                }
+                                relays.maintain(&mut swarm);
+                                let _ = events.send(Event::PeerDisconnected(peer_id));
+                            }
                         }
                         SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
                             tracing::info!(peer=?peer_id, "Outgoing connection failed: {error}");
+                            if let Some(peer) = peer_id.filter(|peer| !swarm.is_connected(peer)) {
+                                relays.disconnected(&mut swarm, &peer);
+                                connections.failed(&peer);
+                                // The peer may be reachable through other relays by now.
+                                if connections.is_wanted(&peer) && !relays.is_relay(&peer) {
+                                    presence.lookup(&mut swarm.behaviour_mut().kademlia, peer);
+                                }
+                            }
                         }
                         SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(
                             gossipsub::Event::Message {
                                 propagation_source,
-                                message_id,
                                 message,
+                                ..
                             },
                         )) => {
-                            tracing::info!(
-                                "Received: '{:?}' from {:?}",
-                                String::from_utf8_lossy(&message.data),
-                                propagation_source
-                            );
+                            let topic = message.topic.into_string();
+                            let opened = match (
+                                message.source,
+                                serde_json::from_slice::<e2e::GroupEnvelope>(&message.data),
+                            ) {
+                                (Some(source), Ok(envelope)) => e2e
+                                    .decrypt_group(source, &topic, envelope)
+                                    .map(|data| (source, data)),
+                                _ => Err(E2eError::Decrypt),
+                            };
+                            match opened {
+                                Ok((source, data)) => {
+                                    tracing::debug!(%topic, %source, via=%propagation_source, len=data.len(), "Received topic message");
+                                    let _ = events
+                                        .send(Event::MessageReceived {
+                                            topic,
+                                            source: Some(source),
+                                            data,
+                                        });
+                                }
+                                Err(E2eError::MissingSenderKey) => {
+                                    tracing::debug!(%topic, "Holding message back until its sender key arrives");
+                                }
+                                Err(e) => {
+                                    tracing::warn!(%topic, peer=%propagation_source, "Dropped topic message: {e}");
+                                }
+                            }
                         }
                         SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(
                             gossipsub::Event::Subscribed { peer_id, topic },
@@ -298,37 +1109,215 @@ impl AppCore {
                             gossipsub::Event::Unsubscribed { peer_id, topic },
                         )) => {
                             tracing::info!("{:?} unsubscribed from {:?}", peer_id, topic);
+                            e2e.member_left(topic.as_str(), &peer_id);
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Dm(request_response::Event::Message {
+                            peer,
+                            message: request_response::Message::Request { request, channel, .. },
+                        })) => {
+                            tracing::info!(%peer, id = request.id, "Received direct message");
+                            // Without an acknowledgement the sender reports the message as failed.
+                            let payload = match e2e.decrypt(peer, &request.envelope) {
+                                Ok(payload) => payload,
+                                Err(e) => {
+                                    tracing::warn!(%peer, "Failed to open direct message: {e}");
+                                    continue;
+                                }
+                            };
+                            if swarm
+                                .behaviour_mut()
+                                .dm
+                                .send_response(channel, dm::Ack { id: request.id })
+                                .is_err()
+                            {
+                                tracing::warn!(%peer, "Failed to acknowledge direct message");
+                            }
+
+                            match payload {
+                                e2e::Payload::Text { body, sent_at } => {
+                                    let _ = events
+                                        .send(Event::DirectMessageReceived {
+                                            peer,
+                                            id: request.id,
+                                            body,
+                                            sent_at,
+                                        });
+                                }
+                                e2e::Payload::SenderKey { topic, key } => {
+                                    for (topic, data) in e2e.add_sender_key(peer, &topic, &key) {
+                                        let _ = events
+                                            .send(Event::MessageReceived {
+                                                topic,
+                                                source: Some(peer),
+                                                data,
+                                            });
+                                    }
+                                }
+                            }
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Dm(request_response::Event::Message {
+                            peer,
+                            message: request_response::Message::Response { request_id, .. },
+                        })) => {
+                            if let Some((_, Carried::Message(id))) = outbox.requests.remove(&request_id) {
+                                let _ = events
+                                    .send(Event::DirectMessageStateChanged {
+                                        peer,
+                                        id,
+                                        state: dm::DeliveryState::Delivered,
+                                    });
+                            }
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Dm(request_response::Event::OutboundFailure {
+                            peer,
+                            request_id,
+                            error,
+                        })) => {
+                            tracing::warn!(%peer, "Direct message failed: {error}");
+                            if let Some(event) = outbox
+                                .requests
+                                .remove(&request_id)
+                                .and_then(|(peer, carried)| carried.failed(&mut e2e, peer))
+                            {
+                                let _ = events.send(event);
+                            }
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Dm(event)) => {
+                            tracing::debug!(?event)
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Prekey(request_response::Event::Message {
+                            peer,
+                            message: request_response::Message::Request { channel, .. },
+                        })) => {
+                            tracing::info!(%peer, "Handing out prekey bundle");
+                            if swarm.behaviour_mut().prekey.send_response(channel, e2e.bundle()).is_err() {
+                                tracing::warn!(%peer, "Failed to send prekey bundle");
+                            }
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Prekey(request_response::Event::Message {
+                            peer,
+                            message: request_response::Message::Response { response, .. },
+                        })) => {
+                            let reports = match e2e.initiate(peer, &response) {
+                                Ok(()) => {
+                                    tracing::info!(%peer, "Started end-to-end session");
+                                    outbox.flush(&mut swarm, &mut e2e, peer)
+                                }
+                                Err(e) => {
+                                    tracing::warn!(%peer, "Rejected prekey bundle: {e}");
+                                    outbox.abandon(&mut e2e, peer)
+                                }
+                            };
+                            for event in reports {
+                                let _ = events.send(event);
+                            }
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Prekey(request_response::Event::OutboundFailure {
+                            peer,
+                            error,
+                            ..
+                        })) => {
+                            tracing::warn!(%peer, "Failed to fetch prekey bundle: {error}");
+                            for event in outbox.abandon(&mut e2e, peer) {
+                                let _ = events.send(event);
+                            }
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Prekey(event)) => {
+                            tracing::debug!(?event)
                         }
                         _ => {}
                     },
-                    // Read lines from stdin
-                    line = lines.next_line() => match line {
-                        Ok(Some(text)) => {
-                            // Publish the input text to the Gossipsub topic
-                            if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), text.clone().into_bytes()) {
-                                tracing::error!("Failed to publish message: {:?}", e);
-                            } else {
-                                tracing::info!("Published message: {:?}", text);
+                    _ = maintenance.tick() => {
+                        e2e.flush();
+                        if startup.is_running() {
+                            relays.maintain(&mut swarm);
+                            connections.maintain(&mut swarm, |peer| relays.circuit_addresses(peer));
+                            discovery.maintain(&mut swarm.behaviour_mut().rendezvous, &relays.rendezvous_points());
+                            presence.maintain(&mut swarm.behaviour_mut().kademlia);
+                        }
+                    }
+                    // Handle commands from the GUI or the daemon API
+                    command = commands.recv() => match command {
+                        Some(Command::Publish { topic, data }) => {
+                            publish(&mut swarm, &mut e2e, &mut outbox, &topic, &data);
+                        }
+                        Some(Command::Connect(peer)) => {
+                            dial_nearby(&mut swarm, &connections, peer, nearby.addresses(&peer));
+                            connections.want(peer, None);
+                            connections.maintain(&mut swarm, |peer| relays.circuit_addresses(peer));
+                            presence.lookup(&mut swarm.behaviour_mut().kademlia, peer);
+                        }
+                        Some(Command::Release(peer)) => connections.release(&peer),
+                        Some(Command::Locate(peer)) => {
+                            presence.lookup(&mut swarm.behaviour_mut().kademlia, peer);
+                        }
+                        Some(Command::Dial(address)) => match address.iter().last() {
+                            // A known peer is kept connected from now on.
+                            Some(Protocol::P2p(peer)) => {
+                                connections.want(peer, Some(address));
+                                connections.maintain(&mut swarm, |peer| relays.circuit_addresses(peer));
+                            }
+                            _ => {
+                                if let Err(e) = swarm.dial(address.clone()) {
+                                    tracing::error!(%address, "Failed to dial: {e}");
+                                }
+                            }
+                        },
+                        Some(Command::Subscribe(topic)) => {
+                            let topic = gossipsub::IdentTopic::new(topic);
+                            if let Err(e) = swarm.behaviour_mut().gossipsub.subscribe(&topic) {
+                                tracing::error!(%topic, "Failed to subscribe: {:?}", e);
                             }
                         }
-                        Ok(None) => {
-                            tracing::warn!("Stdin closed");
-                            break; // Exit the loop if stdin is closed
+                        Some(Command::SendDirect { peer, id, body }) => {
+                            // Without a connection, reach the peer through the circuits of our relays
+                            // and let DCUtR upgrade to a direct connection.
+                            if !swarm.is_connected(&peer) {
+                                for address in relays.circuit_addresses(peer) {
+                                    swarm.add_peer_address(peer, address);
+                                }
+                            }
+
+                            let payload = e2e::Payload::Text {
+                                body,
+                                sent_at: chrono::Utc::now().timestamp_millis(),
+                            };
+                            if let Some(event) = outbox.send(&mut swarm, &mut e2e, peer, id, payload) {
+                                let _ = events.send(event);
+                            }
                         }
-                        Err(e) => {
-                            tracing::error!("Failed to read from stdin: {:?}", e);
+                        Some(Command::Register(namespace)) => {
+                            discovery.join(&mut swarm.behaviour_mut().rendezvous, &relays.rendezvous_points(), namespace);
                         }
-                    }
+                        Some(Command::Unregister(namespace)) => {
+                            discovery.leave(&mut swarm.behaviour_mut().rendezvous, &relays.rendezvous_points(), &namespace);
+                        }
+                        Some(Command::Discover(namespace)) => {
+                            discovery.discover(&mut swarm.behaviour_mut().rendezvous, &relays.rendezvous_points(), &namespace);
+                        }
+                        None => {
+                            tracing::info!("All backend handles dropped");
+                            break;
+                        }
+                    },
+                }
+
+                if startup.advance() {
+                    tracing::info!("Listening, dialling the relays");
+                    relays.maintain(&mut swarm);
+                }
+                if let Some(connectivity) =
+                    connections.update_connectivity(
+                        relays.is_reserved() || reachability == Reachability::Public,
+                        |peer| relays.is_relay(peer),
+                    )
+                {
+                    tracing::info!(%connectivity, "Connectivity changed");
+                    let _ = events.send(Event::ConnectivityChanged(connectivity));
                 }
             }
-        }.await;
+        }
+        .await;
         Ok(())
     }
 }
-
-fn generate_ed25519(secret_key_seed: u8) -> identity::Keypair {
-    let mut bytes = [0u8; 32];
-    bytes[0] = secret_key_seed;
-
-    identity::Keypair::ed25519_from_bytes(bytes).expect("only errors on wrong length")
-}
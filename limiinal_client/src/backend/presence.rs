@@ -0,0 +1,153 @@
+//! Presence records in the Kademlia DHT. Every client publishes its current
+//! addresses, usually relay circuits, under `/limiinal/presence/<peer id>`,
+//! signed with its identity key, so peers can still find it when the relay
+//! they knew it by is gone. The relays bootstrap the DHT and only store
+//! records whose signature matches the peer ID in their key.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use libp2p::{
+    core::PeerRecord,
+    identity::{Keypair, SigningError},
+    kad::{self, store::MemoryStore},
+    Multiaddr, PeerId,
+};
+
+pub use limiinal_common::presence::{key, new_behaviour, open, KAD_PROTOCOL, PRESENCE_TTL};
+
+/// How often our record is published again, well before it expires.
+const REPUBLISH_INTERVAL: Duration = Duration::from_secs(20 * 60);
+
+/// Our presence record, announcing `addresses`, and its sequence number.
+fn record(
+    keypair: &Keypair,
+    addresses: Vec<Multiaddr>,
+) -> Result<(kad::Record, u64), SigningError> {
+    let peer = keypair.public().to_peer_id();
+    let peer_record = PeerRecord::new(keypair, addresses)?;
+    let seq = peer_record.seq();
+    let envelope = peer_record.into_signed_envelope();
+
+    let record = kad::Record {
+        key: key(&peer),
+        value: envelope.into_protobuf_encoding(),
+        publisher: Some(peer),
+        expires: Some(Instant::now() + PRESENCE_TTL),
+    };
+    Ok((record, seq))
+}
+
+/// Stores a record another peer asked us to keep, if it is a valid presence record.
+pub fn store(kademlia: &mut kad::Behaviour<MemoryStore>, source: &PeerId, record: kad::Record) {
+    if let Err(e) = limiinal_common::presence::store(kademlia.store_mut(), record) {
+        tracing::warn!(peer=%source, "Rejected presence record: {e}");
+    }
+}
+
+#[derive(Debug)]
+pub struct Presence {
+    keypair: Keypair,
+    /// Lookups in flight and the peer each one is for.
+    lookups: HashMap<kad::QueryId, PeerId>,
+    /// Addresses we published last, our record is only renewed while there are some.
+    published: Vec<Multiaddr>,
+    /// Sequence number of the record we published last.
+    seq: u64,
+    republish_at: Instant,
+}
+
+impl Presence {
+    pub fn new(keypair: Keypair) -> Self {
+        Presence {
+            keypair,
+            lookups: HashMap::new(),
+            published: Vec::new(),
+            seq: 0,
+            republish_at: Instant::now() + REPUBLISH_INTERVAL,
+        }
+    }
+
+    /// Publishes `addresses` as our current ones.
+    pub fn publish(
+        &mut self,
+        kademlia: &mut kad::Behaviour<MemoryStore>,
+        addresses: impl IntoIterator<Item = Multiaddr>,
+    ) {
+        self.published = addresses.into_iter().collect();
+        self.republish_at = Instant::now() + REPUBLISH_INTERVAL;
+        if self.published.is_empty() {
+            return;
+        }
+
+        let (record, seq) = match record(&self.keypair, self.published.clone()) {
+            Ok(record) => record,
+            Err(e) => {
+                tracing::error!("Failed to sign presence record: {e}");
+                return;
+            }
+        };
+        // Sequence numbers count seconds, and the DHT refuses a different
+        // record with the same one. Publish on the next maintenance instead.
+        if seq <= self.seq {
+            self.republish_at = Instant::now();
+            return;
+        }
+        self.seq = seq;
+        if let Err(e) = kademlia.put_record(record, kad::Quorum::One) {
+            tracing::warn!("Failed to publish presence record: {e}");
+        }
+    }
+
+    /// Publishes our record again when it is due.
+    pub fn maintain(&mut self, kademlia: &mut kad::Behaviour<MemoryStore>) {
+        if self.republish_at <= Instant::now() {
+            let addresses = self.published.clone();
+            self.publish(kademlia, addresses);
+        }
+    }
+
+    /// Looks up the current addresses of `peer`, unless a lookup is in flight already.
+    pub fn lookup(&mut self, kademlia: &mut kad::Behaviour<MemoryStore>, peer: PeerId) {
+        if self.lookups.values().any(|pending| *pending == peer) {
+            return;
+        }
+        let query = kademlia.get_record(key(&peer));
+        self.lookups.insert(query, peer);
+    }
+
+    /// A lookup found a record. Returns the peer and its addresses if the
+    /// record is valid, ending the lookup.
+    pub fn found(
+        &mut self,
+        kademlia: &mut kad::Behaviour<MemoryStore>,
+        query: kad::QueryId,
+        record: &kad::Record,
+    ) -> Option<(PeerId, Vec<Multiaddr>)> {
+        let wanted = *self.lookups.get(&query)?;
+        match open(record) {
+            Ok(peer_record) if peer_record.peer_id() == wanted => {
+                self.lookups.remove(&query);
+                if let Some(mut query) = kademlia.query_mut(&query) {
+                    query.finish();
+                }
+                Some((wanted, peer_record.addresses().to_vec()))
+            }
+            Ok(peer_record) => {
+                tracing::warn!(%wanted, peer=%peer_record.peer_id(), "Presence record of another peer");
+                None
+            }
+            Err(e) => {
+                tracing::warn!(peer=%wanted, "Invalid presence record: {e}");
+                None
+            }
+        }
+    }
+
+    /// A lookup ended, returning its peer if no valid record turned up.
+    pub fn finished(&mut self, query: kad::QueryId) -> Option<PeerId> {
+        self.lookups.remove(&query)
+    }
+}
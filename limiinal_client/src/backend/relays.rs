@@ -0,0 +1,526 @@
+//! Relays the client reaches other peers through. Every configured relay is
+//! kept connected and pinged, and reservations are held on a few of them at
+//! once, so losing one relay only drops its `/p2p-circuit` address while the
+//! others keep the client reachable. A lost relay is redialled with backoff
+//! and a spare one takes over its reservation in the meantime. Reservations
+//! are renewed by the relay client before they expire; when a renewal fails
+//! the listener closes and the reservation is requested again. Once AutoNAT
+//! finds us publicly reachable no reservation is held at all, the relays are
+//! then only dialled through and used for rendezvous and the DHT.
+
+use std::time::{Duration, Instant};
+
+use libp2p::{
+    core::transport::ListenerId,
+    multiaddr::Protocol,
+    ping,
+    swarm::{NetworkBehaviour, Swarm},
+    Multiaddr, PeerId,
+};
+
+use super::connections::Backoff;
+
+/// Consecutive failed pings after which a relay counts as gone.
+const MAX_FAILED_PINGS: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RelayState {
+    Disconnected,
+    Dialing,
+    /// Connected, but identify has not told us our observed address yet.
+    Connected,
+    /// Identified, waiting for the relay to accept our admission token.
+    Admitting,
+    /// Connected and identified, ready to hold a reservation.
+    Ready,
+    Reserving(ListenerId),
+    Reserved(ListenerId),
+}
+
+#[derive(Debug)]
+struct Relay {
+    address: Multiaddr,
+    peer: PeerId,
+    state: RelayState,
+    failed_pings: u32,
+    rtt: Option<Duration>,
+    backoff: Backoff,
+    /// Nothing is attempted on the relay before this time.
+    retry_at: Option<Instant>,
+}
+
+impl Relay {
+    fn listener(&self) -> Option<ListenerId> {
+        match self.state {
+            RelayState::Reserving(listener) | RelayState::Reserved(listener) => Some(listener),
+            _ => None,
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        !matches!(self.state, RelayState::Disconnected | RelayState::Dialing)
+    }
+
+    fn waiting(&self, now: Instant) -> bool {
+        self.retry_at.is_some_and(|at| at > now)
+    }
+
+    fn retry_later(&mut self, now: Instant) {
+        self.retry_at = Some(now + self.backoff.next_delay());
+    }
+}
+
+/// What a call changed about our reservations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelayChange {
+    Reserved { peer: PeerId, address: Multiaddr },
+    Lost { peer: PeerId },
+}
+
+#[derive(Debug)]
+pub struct Relays {
+    relays: Vec<Relay>,
+    /// Number of relays to hold a reservation on at the same time.
+    reservations: usize,
+    /// Whether AutoNAT found us publicly reachable.
+    public: bool,
+}
+
+impl Relays {
+    /// Relays at `addresses`, each ending in `/p2p/<relay peer id>`. With
+    /// `reservations` set to 0 the relays are only used to dial through.
+    pub fn new(addresses: &[Multiaddr], reservations: usize) -> Self {
+        let relays = addresses
+            .iter()
+            .filter_map(|address| match address.iter().last() {
+                Some(Protocol::P2p(peer)) => Some(Relay {
+                    address: address.clone(),
+                    peer,
+                    state: RelayState::Disconnected,
+                    failed_pings: 0,
+                    rtt: None,
+                    backoff: Backoff::default(),
+                    retry_at: None,
+                }),
+                _ => {
+                    tracing::warn!(%address, "Ignoring relay without /p2p/<relay peer id>");
+                    None
+                }
+            })
+            .collect();
+
+        Relays {
+            relays,
+            reservations,
+            public: false,
+        }
+    }
+
+    pub fn is_relay(&self, peer: &PeerId) -> bool {
+        self.relays.iter().any(|relay| relay.peer == *peer)
+    }
+
+    /// Whether any relay holds a reservation for us.
+    pub fn is_reserved(&self) -> bool {
+        self.relays
+            .iter()
+            .any(|r| matches!(r.state, RelayState::Reserved(_)))
+    }
+
+    /// Identified relays, which also serve as rendezvous points.
+    pub fn rendezvous_points(&self) -> Vec<PeerId> {
+        self.relays
+            .iter()
+            .filter(|r| {
+                matches!(
+                    r.state,
+                    RelayState::Ready | RelayState::Reserving(_) | RelayState::Reserved(_)
+                )
+            })
+            .map(|relay| relay.peer)
+            .collect()
+    }
+
+    /// Addresses reaching `peer` through each connected relay, best first.
+    pub fn circuit_addresses(&self, peer: PeerId) -> Vec<Multiaddr> {
+        let mut relays: Vec<_> = self.relays.iter().filter(|r| r.is_connected()).collect();
+        relays.sort_by_key(|relay| relay.rtt.unwrap_or(Duration::MAX));
+        relays
+            .into_iter()
+            .map(|relay| {
+                relay
+                    .address
+                    .clone()
+                    .with(Protocol::P2pCircuit)
+                    .with(Protocol::P2p(peer))
+            })
+            .collect()
+    }
+
+    /// Dials relays that are due and tops up the reservations from the
+    /// connected relays with the lowest round-trip time.
+    pub fn maintain<B: NetworkBehaviour>(&mut self, swarm: &mut Swarm<B>) {
+        let now = Instant::now();
+
+        for relay in &mut self.relays {
+            if relay.state != RelayState::Disconnected || relay.waiting(now) {
+                continue;
+            }
+            match swarm.dial(relay.address.clone()) {
+                Ok(()) => relay.state = RelayState::Dialing,
+                Err(e) => {
+                    tracing::warn!(address=%relay.address, "Failed to dial relay: {e}");
+                    relay.retry_later(now);
+                }
+            }
+        }
+
+        let held = self
+            .relays
+            .iter()
+            .filter(|r| r.listener().is_some())
+            .count();
+        let mut ready: Vec<_> = self
+            .relays
+            .iter_mut()
+            .filter(|r| r.state == RelayState::Ready && !r.waiting(now))
+            .collect();
+        ready.sort_by_key(|relay| relay.rtt.unwrap_or(Duration::MAX));
+
+        let wanted = if self.public { 0 } else { self.reservations };
+        for relay in ready.into_iter().take(wanted.saturating_sub(held)) {
+            match swarm.listen_on(relay.address.clone().with(Protocol::P2pCircuit)) {
+                Ok(listener) => {
+                    tracing::info!(address=%relay.address, "Requesting relay reservation");
+                    relay.state = RelayState::Reserving(listener);
+                }
+                Err(e) => {
+                    tracing::warn!(address=%relay.address, "Failed to listen via relay: {e}");
+                    relay.retry_later(now);
+                }
+            }
+        }
+    }
+
+    pub fn connected(&mut self, peer: &PeerId) {
+        for relay in self.relays.iter_mut().filter(|r| r.peer == *peer) {
+            if !relay.is_connected() {
+                relay.state = RelayState::Connected;
+                relay.failed_pings = 0;
+                relay.retry_at = None;
+            }
+        }
+    }
+
+    /// The relay told us our observed address, so reservations on it carry
+    /// an address other peers can hole punch to. Returns whether it just
+    /// became ready.
+    pub fn identified(&mut self, peer: &PeerId) -> bool {
+        self.ready(peer, RelayState::Connected)
+    }
+
+    /// The relay asks for an admission token before reserving. Returns
+    /// whether we should present ours, once per connection.
+    pub fn admitting(&mut self, peer: &PeerId) -> bool {
+        let mut admitting = false;
+        for relay in self.relays.iter_mut().filter(|r| r.peer == *peer) {
+            if relay.state == RelayState::Connected {
+                relay.state = RelayState::Admitting;
+                admitting = true;
+            }
+        }
+
+        admitting
+    }
+
+    /// The relay answered our admission token, or failed to. Returns whether
+    /// it just became ready.
+    pub fn admitted(&mut self, peer: &PeerId) -> bool {
+        self.ready(peer, RelayState::Admitting)
+    }
+
+    fn ready(&mut self, peer: &PeerId, from: RelayState) -> bool {
+        let mut ready = false;
+        for relay in self.relays.iter_mut().filter(|r| r.peer == *peer) {
+            if relay.state == from {
+                relay.state = RelayState::Ready;
+                relay.backoff.reset();
+                ready = true;
+            }
+        }
+
+        ready
+    }
+
+    /// The last connection to `peer` closed or could not be established.
+    pub fn disconnected<B: NetworkBehaviour>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        peer: &PeerId,
+    ) -> Vec<RelayChange> {
+        let mut changes = Vec::new();
+        for relay in self.relays.iter_mut().filter(|r| r.peer == *peer) {
+            if relay.state == RelayState::Disconnected {
+                continue;
+            }
+            tracing::warn!(address=%relay.address, "Lost relay");
+
+            if let Some(listener) = relay.listener() {
+                swarm.remove_listener(listener);
+                if matches!(relay.state, RelayState::Reserved(_)) {
+                    changes.push(RelayChange::Lost { peer: relay.peer });
+                }
+            }
+            relay.state = RelayState::Disconnected;
+            relay.rtt = None;
+            relay.retry_later(Instant::now());
+        }
+
+        changes
+    }
+
+    /// AutoNAT found us publicly reachable, or no longer. Reservations are
+    /// released while we are public and requested again by [`Relays::maintain`]
+    /// once we are not.
+    pub fn set_public<B: NetworkBehaviour>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        public: bool,
+    ) -> Vec<RelayChange> {
+        self.public = public;
+        let mut changes = Vec::new();
+        if !public {
+            return changes;
+        }
+
+        for relay in &mut self.relays {
+            if let Some(listener) = relay.listener() {
+                tracing::info!(address=%relay.address, "Publicly reachable, releasing relay reservation");
+                swarm.remove_listener(listener);
+                if matches!(relay.state, RelayState::Reserved(_)) {
+                    changes.push(RelayChange::Lost { peer: relay.peer });
+                }
+                relay.state = RelayState::Ready;
+            }
+        }
+
+        changes
+    }
+
+    pub fn reservation_accepted(&mut self, peer: &PeerId) -> Vec<RelayChange> {
+        let mut changes = Vec::new();
+        for relay in self.relays.iter_mut().filter(|r| r.peer == *peer) {
+            if let RelayState::Reserving(listener) = relay.state {
+                relay.state = RelayState::Reserved(listener);
+                relay.backoff.reset();
+                changes.push(RelayChange::Reserved {
+                    peer: relay.peer,
+                    address: relay.address.clone(),
+                });
+            }
+        }
+
+        changes
+    }
+
+    /// A listener closed, e.g. because the relay refused or dropped our reservation.
+    pub fn listener_closed(&mut self, listener: ListenerId) -> Vec<RelayChange> {
+        let mut changes = Vec::new();
+        for relay in self
+            .relays
+            .iter_mut()
+            .filter(|r| r.listener() == Some(listener))
+        {
+            tracing::warn!(address=%relay.address, "Relay reservation closed");
+            if matches!(relay.state, RelayState::Reserved(_)) {
+                changes.push(RelayChange::Lost { peer: relay.peer });
+            }
+            relay.state = RelayState::Ready;
+            relay.retry_later(Instant::now());
+        }
+
+        changes
+    }
+
+    /// Health check: a relay that keeps failing pings is disconnected so a
+    /// spare one takes over.
+    pub fn ping<B: NetworkBehaviour>(&mut self, swarm: &mut Swarm<B>, event: &ping::Event) {
+        let Some(relay) = self.relays.iter_mut().find(|r| r.peer == event.peer) else {
+            return;
+        };
+
+        match &event.result {
+            Ok(rtt) => {
+                relay.rtt = Some(*rtt);
+                relay.failed_pings = 0;
+            }
+            Err(e) => {
+                relay.failed_pings += 1;
+                tracing::warn!(address=%relay.address, failed=relay.failed_pings, "Relay ping failed: {e}");
+                if relay.failed_pings >= MAX_FAILED_PINGS {
+                    let _ = swarm.disconnect_peer_id(relay.peer);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{noise, relay, tcp, yamux};
+
+    use super::*;
+
+    fn swarm() -> Swarm<relay::client::Behaviour> {
+        libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_tcp(
+                tcp::Config::default(),
+                noise::Config::new,
+                yamux::Config::default,
+            )
+            .unwrap()
+            .with_relay_client(noise::Config::new, yamux::Config::default)
+            .unwrap()
+            .with_behaviour(|_, relay| relay)
+            .unwrap()
+            .build()
+    }
+
+    fn relay_address(port: u16) -> (PeerId, Multiaddr) {
+        let peer = PeerId::random();
+        let address = format!("/ip4/127.0.0.1/tcp/{port}/p2p/{peer}")
+            .parse()
+            .unwrap();
+
+        (peer, address)
+    }
+
+    fn state(relays: &Relays, peer: &PeerId) -> RelayState {
+        relays
+            .relays
+            .iter()
+            .find(|r| r.peer == *peer)
+            .unwrap()
+            .state
+    }
+
+    #[tokio::test]
+    async fn reserves_once_identified() {
+        let mut swarm = swarm();
+        let (peer, address) = relay_address(4001);
+        let mut relays = Relays::new(std::slice::from_ref(&address), 1);
+
+        relays.maintain(&mut swarm);
+        assert_eq!(state(&relays, &peer), RelayState::Dialing);
+        relays.connected(&peer);
+        relays.maintain(&mut swarm);
+        assert_eq!(state(&relays, &peer), RelayState::Connected);
+        assert!(relays.rendezvous_points().is_empty());
+
+        assert!(relays.identified(&peer));
+        assert_eq!(relays.rendezvous_points(), vec![peer]);
+        relays.maintain(&mut swarm);
+        assert!(matches!(state(&relays, &peer), RelayState::Reserving(_)));
+        assert!(!relays.is_reserved());
+
+        assert_eq!(
+            relays.reservation_accepted(&peer),
+            vec![RelayChange::Reserved { peer, address }]
+        );
+        assert!(relays.is_reserved());
+    }
+
+    #[tokio::test]
+    async fn admission_comes_before_the_reservation() {
+        let mut swarm = swarm();
+        let (peer, address) = relay_address(4001);
+        let mut relays = Relays::new(&[address], 1);
+        relays.connected(&peer);
+
+        assert!(relays.admitting(&peer));
+        assert!(!relays.admitting(&peer));
+        assert!(!relays.identified(&peer));
+        relays.maintain(&mut swarm);
+        assert_eq!(state(&relays, &peer), RelayState::Admitting);
+
+        assert!(relays.admitted(&peer));
+        assert_eq!(state(&relays, &peer), RelayState::Ready);
+        relays.maintain(&mut swarm);
+        assert!(matches!(state(&relays, &peer), RelayState::Reserving(_)));
+    }
+
+    #[tokio::test]
+    async fn spare_relay_takes_over_a_lost_reservation() {
+        let mut swarm = swarm();
+        let (first, first_address) = relay_address(4001);
+        let (spare, spare_address) = relay_address(4002);
+        let mut relays = Relays::new(&[first_address, spare_address], 1);
+        for peer in [first, spare] {
+            relays.connected(&peer);
+            relays.identified(&peer);
+        }
+        relays.relays[0].rtt = Some(Duration::from_millis(10));
+        relays.relays[1].rtt = Some(Duration::from_millis(50));
+
+        relays.maintain(&mut swarm);
+        assert!(matches!(state(&relays, &first), RelayState::Reserving(_)));
+        assert_eq!(state(&relays, &spare), RelayState::Ready);
+        relays.reservation_accepted(&first);
+
+        assert_eq!(
+            relays.disconnected(&mut swarm, &first),
+            vec![RelayChange::Lost { peer: first }]
+        );
+        relays.maintain(&mut swarm);
+        assert_eq!(state(&relays, &first), RelayState::Disconnected);
+        assert!(matches!(state(&relays, &spare), RelayState::Reserving(_)));
+    }
+
+    #[tokio::test]
+    async fn closed_reservation_is_retried_after_a_backoff() {
+        let mut swarm = swarm();
+        let (peer, address) = relay_address(4001);
+        let mut relays = Relays::new(&[address], 1);
+        relays.connected(&peer);
+        relays.identified(&peer);
+        relays.maintain(&mut swarm);
+        let RelayState::Reserving(listener) = state(&relays, &peer) else {
+            panic!("expected a reservation request");
+        };
+        relays.reservation_accepted(&peer);
+
+        assert_eq!(
+            relays.listener_closed(listener),
+            vec![RelayChange::Lost { peer }]
+        );
+        relays.maintain(&mut swarm);
+        assert_eq!(state(&relays, &peer), RelayState::Ready);
+
+        relays.relays[0].retry_at = Some(Instant::now());
+        relays.maintain(&mut swarm);
+        assert!(matches!(state(&relays, &peer), RelayState::Reserving(_)));
+    }
+
+    #[tokio::test]
+    async fn no_reservations_while_public() {
+        let mut swarm = swarm();
+        let (peer, address) = relay_address(4001);
+        let mut relays = Relays::new(&[address], 1);
+        relays.connected(&peer);
+        relays.identified(&peer);
+        relays.maintain(&mut swarm);
+        relays.reservation_accepted(&peer);
+
+        assert_eq!(
+            relays.set_public(&mut swarm, true),
+            vec![RelayChange::Lost { peer }]
+        );
+        relays.maintain(&mut swarm);
+        assert_eq!(state(&relays, &peer), RelayState::Ready);
+
+        relays.set_public(&mut swarm, false);
+        relays.maintain(&mut swarm);
+        assert!(matches!(state(&relays, &peer), RelayState::Reserving(_)));
+    }
+}
@@ -1,18 +1,58 @@
-mod backend;
-mod ui;
-
-use backend::network::AppCore;
 //use iced::widget::container::background;
+use clap::Parser;
 use iced::Theme;
-use ui::gui::AppUI;
+use limiinal_client::backend::config::{self, Config, ThemePreference};
+use limiinal_client::backend::network::{ConfigCommand, Opts, Subcommand};
+use limiinal_client::ui::gui::AppUI;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init();
+    let opts = Opts::parse();
+
+    match opts.command {
+        // `limiinal_client [OPTIONS] daemon` runs the backend headless, without a window.
+        #[cfg(unix)]
+        Some(Subcommand::Daemon { .. }) => {
+            return limiinal_client::backend::daemon::run(opts).await
+        }
+        #[cfg(not(unix))]
+        Some(Subcommand::Daemon { .. }) => return Err("the daemon needs Unix sockets".into()),
+        Some(Subcommand::Config {
+            command: ConfigCommand::Check,
+        }) => {
+            if let Err(e) = config::check(&opts) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        None => {}
+    }
+
+    let config = Config::load(&opts)?;
+    if config.ui.backend {
+        config.validate()?;
+    }
+    let mut logger = env_logger::Builder::from_default_env();
+    if let (Some(filter), None) = (&config.logging.filter, std::env::var_os("RUST_LOG")) {
+        logger.parse_filters(filter);
+    }
+    logger.init();
+    // The backend logs through `tracing`.
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(config.logging.env_filter())
+        .try_init();
+
+    let theme = match config.ui.theme {
+        ThemePreference::Dark => Theme::Dark,
+        ThemePreference::Light => Theme::Light,
+    };
+    let insecure_test_seed = opts.insecure_test_seed;
 
     iced::application("Limiinal", AppUI::update, AppUI::view)
-        .theme(|_| Theme::Dark)
-        .run_with(AppUI::new)?;
+        .subscription(AppUI::subscription)
+        .theme(move |_| theme.clone())
+        .run_with(move || AppUI::new(config, insecure_test_seed))?;
 
     Ok(())
 }
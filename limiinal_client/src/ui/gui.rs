@@ -1,26 +1,39 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")] // hide console window on Windows in release
 
-use std::fmt::format;
-
-use crate::backend::network::AppCore;
-
-use chrono::Local;
-use clap::{Arg, Command};
+use crate::backend::config::Config;
+use crate::backend::connections::{Connectivity, HolePunchState, HolePunchStatus, Reachability};
+use crate::backend::contacts::MAX_AVATAR_LEN;
+use crate::backend::discovery::Discovered;
+use crate::backend::dm::{DeliveryState, MessageId};
+use crate::backend::e2e;
+use crate::backend::history::{
+    self, Conversation, ConversationKind, History, HistoryError, NewMessage, StoredMessage,
+};
+use crate::backend::identity;
+use crate::backend::invite::{self, Invite};
+use crate::backend::keystore::{Keystore, KeystoreError};
+use crate::backend::network::{self, AppCore, AppCoreHandle, BackendConfig};
+
+use chrono::{DateTime, Local};
 use iced::border::Radius;
-use iced::keyboard;
+use iced::futures::{SinkExt, Stream};
 use iced::widget;
-use iced::widget::image::Handle;
 use iced::widget::scrollable;
 use iced::widget::Button;
-use iced::widget::Text;
 use iced::widget::TextInput;
 use iced::widget::{button, center, column, container, image, row, svg, text, text_input};
 use iced::widget::{button::Status, Column, Space};
-use iced::{Alignment, Background, Border, Color, Element, Length, Padding, Task, Theme};
-use log::info;
+use iced::{
+    clipboard, Alignment, Background, Border, Color, Element, Length, Padding, Subscription, Task,
+    Theme,
+};
+use libp2p::{identity::Keypair, rendezvous::Namespace, Multiaddr, PeerId};
+use log::{error, info, trace, warn};
 use once_cell::sync::Lazy;
+use std::collections::HashMap;
 use std::env;
-use tokio::runtime::Runtime;
+use std::fs;
+use std::path::PathBuf;
 
 macro_rules! asset_path {
     ($path:expr) => {
@@ -33,18 +46,82 @@ pub struct AppUI {
     window_width: f32,
     window_height: f32,
 
+    backend_enable: bool,
+    keystore_path: PathBuf,
+    // settings the backend is started with; end-to-end sessions are kept in
+    // memory only for test identities
+    backend_config: BackendConfig,
+    // set once the identity keystore is unlocked
+    keypair: Option<Keypair>,
+    // the identity comes from --insecure-test-seed, not from the keystore
+    test_identity: bool,
+    // opened together with the identity
+    history: Option<History>,
+    // set once the backend subscription has started the swarm
+    backend: Option<AppCoreHandle>,
+    // bumped by a retry, so the backend subscription starts over
+    backend_attempt: u32,
+    // relays holding a reservation for us, the first one is put in invites
+    relays: Vec<(PeerId, Multiaddr)>,
+
     // float views
+    unlock_float_view: UnlockFloatView,
+    banner_float_view: BannerFloatView,
     logo_float_view: LogoFloatView,
     nav_float_views: NavFloatView,
+    status_float_view: StatusFloatView,
     message_list_float_view: MessageListFloatView,
     message_float_view: MessageFloatView,
-    search_query: String,
-    active_containers: Vec<bool>,
+    settings_float_view: SettingsFloatView,
 }
 
 #[derive(Debug, Clone)]
 pub enum Message {
     RunningBackend,
+    BackendFailed(String),
+    RetryBackend,
+
+    // Backend events
+    BackendReady(AppCoreHandle),
+    MessageReceived {
+        topic: String,
+        source: Option<PeerId>,
+        body: String,
+    },
+    PeerConnected(PeerId),
+    PeerDisconnected(PeerId),
+    ReservationAccepted {
+        relay_peer_id: PeerId,
+        relay_address: Multiaddr,
+    },
+    ReservationLost(PeerId),
+    ConnectivityChanged(Connectivity),
+    ReachabilityChanged(Reachability),
+    HolePunch {
+        peer: PeerId,
+        status: HolePunchStatus,
+    },
+    DirectMessageReceived {
+        peer: PeerId,
+        body: String,
+    },
+    DirectMessageStateChanged {
+        id: MessageId,
+        state: DeliveryState,
+    },
+    Registered(String),
+    RegistrationFailed {
+        namespace: String,
+        error: String,
+    },
+    PeerDiscovered(Discovered),
+    DiscoveryExpired(PeerId),
+    NearbyDiscovered(PeerId),
+    NearbyExpired(PeerId),
+    PeerLocated {
+        peer: PeerId,
+        addresses: Vec<Multiaddr>,
+    },
 
     Resize(f32, f32),
     ContentChanged(String),
@@ -55,155 +132,1814 @@ pub enum Message {
     NavToSettings,
 
     ChatInputChanged(String),
+    RecipientChanged(String),
+    ChatScrolled(scrollable::Viewport),
     SendMessage,
-    ContainerPressed(usize),
+
+    // Contact events
+    ConversationSelected(Conversation),
+    NewContactPeerChanged(String),
+    NewContactNameChanged(String),
+    AddContact,
+    ContactNicknameChanged(String),
+    SaveContact,
+    ContactAvatarPathChanged(String),
+    SetContactAvatar,
+    RemoveContact,
+    InviteLinkChanged(String),
+    AcceptInvite,
+
+    // Unlock events
+    UnlockPassphraseChanged(String),
+    UnlockConfirmChanged(String),
+    Unlock,
+    Unlocked(Result<Keypair, String>),
+
+    // Settings events
+    SettingsCurrentPassphraseChanged(String),
+    SettingsNewPassphraseChanged(String),
+    SettingsConfirmPassphraseChanged(String),
+    ChangePassphrase,
+    PassphraseChanged(Result<(), String>),
+    SettingsExportPathChanged(String),
+    ExportKeystore,
+    KeystoreExported(Result<(), String>),
+    SettingsImportPathChanged(String),
+    SettingsImportPassphraseChanged(String),
+    ImportKeystore,
+    KeystoreImported(Result<Keypair, String>),
+    SettingsInviteNameChanged(String),
+    CreateInvite,
+    CopyInvite,
+    SettingsNamespaceChanged(String),
+    JoinNamespace,
+    LeaveNamespace(String),
+    AddDiscovered(Discovered),
+    WipeLocalData,
+    CancelWipe,
+    ConfirmWipe,
+    LocalDataWiped(Result<(), String>),
 }
 
 impl AppUI {
-    pub fn new() -> (Self, Task<Message>) {
+    pub fn new(config: Config, insecure_test_seed: Option<u8>) -> (Self, Task<Message>) {
         let mut tasks = vec![];
 
-        let args: Vec<String> = env::args().skip(1).collect();
-        let mut backend_enable = false;
+        let keystore_path = config.identity_path();
+
+        // Test identities skip the keystore and therefore the unlock screen.
+        let keypair = insecure_test_seed.map(|seed| {
+            warn!("Using an insecure identity derived from --insecure-test-seed");
+            identity::insecure_from_seed(seed)
+        });
+        let session_store = keypair.is_none().then(|| e2e::store_path(&keystore_path));
+
+        let test_identity = keypair.is_some();
+        let mut app = Self {
+            backend_enable: config.ui.backend,
+            backend_config: BackendConfig {
+                session_store,
+                ..BackendConfig::new(&config)
+            },
+            unlock_float_view: UnlockFloatView::new(Keystore::new(keystore_path.clone()).exists()),
+            keystore_path,
+            keypair,
+            test_identity,
+            ..Default::default()
+        };
+        if test_identity {
+            app.open_history();
+        }
+
+        tasks.push(widget::focus_next());
+
+        (app, Task::batch(tasks))
+    }
+
+    pub fn subscription(&self) -> Subscription<Message> {
+        match &self.keypair {
+            Some(keypair) if self.backend_enable => Subscription::run_with_id(
+                (keypair.public().to_peer_id(), self.backend_attempt),
+                backend_worker(keypair.clone(), self.backend_config.clone()),
+            ),
+            _ => Subscription::none(),
+        }
+    }
+
+    fn keystore(&self) -> Keystore {
+        Keystore::new(self.keystore_path.clone())
+    }
+
+    /// Opens the message history of the unlocked identity and shows the open
+    /// conversation. Test identities are shared by design, so their history
+    /// is kept in memory only.
+    ///
+    /// A history written under another identity is left alone and nothing is
+    /// recorded, with a banner saying so. Other failures fall back to a
+    /// history in memory.
+    fn open_history(&mut self) {
+        let Some(keypair) = &self.keypair else {
+            return;
+        };
+        let history = match self.backend_config.session_store {
+            Some(_) => History::open(&history::store_path(&self.keystore_path), keypair),
+            None => History::in_memory(),
+        };
+        self.banner_float_view.history_error = None;
+        self.history = match history {
+            Ok(history) => Some(history),
+            Err(e @ HistoryError::WrongKey) => {
+                error!("Not recording messages: {e}");
+                self.banner_float_view.history_error = Some(e.to_string());
+                None
+            }
+            Err(e) => {
+                error!("Failed to open message history, keeping it in memory: {e}");
+                History::in_memory()
+                    .inspect_err(|e| error!("Failed to open message history: {e}"))
+                    .ok()
+            }
+        };
+
+        self.load_conversation();
+    }
+
+    /// Conversation selected by the recipient field: the default topic if it
+    /// is empty, the direct messages with the peer if it holds a peer ID.
+    fn selected_conversation(&self) -> Option<Conversation> {
+        let recipient = self.message_float_view.recipient.trim();
+        if recipient.is_empty() {
+            Some(Conversation::Topic(network::DEFAULT_TOPIC.to_string()))
+        } else {
+            recipient.parse().ok().map(Conversation::Direct)
+        }
+    }
+
+    fn set_recipient(&mut self, recipient: String) {
+        self.message_float_view.safety_number =
+            match (&self.keypair, recipient.trim().parse::<PeerId>()) {
+                (Some(keypair), Ok(peer)) => {
+                    e2e::safety_number(&keypair.public().to_peer_id(), &peer)
+                }
+                _ => None,
+            };
+        self.message_float_view.recipient = recipient;
+        if self.selected_conversation() != self.message_float_view.conversation {
+            self.load_conversation();
+        }
+    }
+
+    /// Replaces the chat view with the latest messages of the selected conversation.
+    fn load_conversation(&mut self) {
+        let conversation = self.selected_conversation();
+        // Peers opened a conversation with are kept connected while it stays open.
+        if let (Some(backend), Some(Conversation::Direct(peer))) =
+            (&self.backend, &self.message_float_view.conversation)
+        {
+            if conversation.as_ref() != self.message_float_view.conversation.as_ref() {
+                backend.release(*peer);
+            }
+        }
+        let messages = match (&self.history, &conversation) {
+            (Some(history), Some(conversation)) => {
+                if let Err(e) = history.mark_read(conversation) {
+                    error!("Failed to mark conversation as read: {e}");
+                }
+                history
+                    .latest(conversation, HISTORY_PAGE)
+                    .unwrap_or_else(|e| {
+                        error!("Failed to load message history: {e}");
+                        Vec::new()
+                    })
+            }
+            _ => Vec::new(),
+        };
+        self.refresh_contacts();
+
+        let list = &mut self.message_list_float_view;
+        list.nickname = match &conversation {
+            Some(Conversation::Direct(peer)) => list
+                .nickname_of(&peer.to_base58())
+                .map(str::to_string)
+                .unwrap_or_default(),
+            _ => String::new(),
+        };
+        list.avatar_path.clear();
+        list.status = None;
+
+        let view = &mut self.message_float_view;
+        view.has_older = messages.len() == HISTORY_PAGE;
+        view.chat_message = messages
+            .into_iter()
+            .map(|msg| ChatMessage::from_stored(msg, list))
+            .collect();
+        view.conversation = conversation;
+    }
+
+    /// Rebuilds the conversation list from the contacts and the history.
+    fn refresh_contacts(&mut self) {
+        let Some(history) = &self.history else {
+            return;
+        };
+        let (conversations, contacts) = match (history.conversations(), history.contacts()) {
+            (Ok(conversations), Ok(contacts)) => (conversations, contacts),
+            (Err(e), _) | (_, Err(e)) => {
+                error!("Failed to load contacts: {e}");
+                return;
+            }
+        };
+
+        let view = &mut self.message_list_float_view;
+        let mut entries: Vec<_> = contacts
+            .into_iter()
+            .map(|contact| {
+                let mut entry =
+                    ConversationEntry::new(Conversation::Direct(contact.peer), contact.nickname);
+                entry.contact = true;
+                entry.last_seen = contact.last_seen;
+                entry.avatar = contact.avatar.map(|avatar| {
+                    view.avatars
+                        .entry(contact.peer)
+                        .or_insert_with(|| image::Handle::from_bytes(avatar))
+                        .clone()
+                });
+                entry
+            })
+            .collect();
+
+        // The default topic always comes first, other topics are only used by the daemon.
+        let mut topic = ConversationEntry::new(
+            Conversation::Topic(network::DEFAULT_TOPIC.to_string()),
+            format!("# {}", network::DEFAULT_TOPIC),
+        );
+        for summary in conversations {
+            let conversation = match summary.kind {
+                ConversationKind::Topic => Conversation::Topic(summary.key),
+                ConversationKind::Direct => match summary.key.parse() {
+                    Ok(peer) => Conversation::Direct(peer),
+                    Err(_) => continue,
+                },
+            };
+
+            let entry = if conversation == topic.conversation {
+                &mut topic
+            } else if let Some(i) = entries.iter().position(|e| e.conversation == conversation) {
+                &mut entries[i]
+            } else if let Conversation::Direct(peer) = &conversation {
+                let name = short_peer_id(peer);
+                entries.push(ConversationEntry::new(conversation, name));
+                entries.last_mut().expect("just pushed")
+            } else {
+                continue;
+            };
+            entry.preview = summary.last_message;
+            entry.last_time = summary.last_time;
+            entry.unread = summary.unread;
+        }
+
+        // Most recent conversations first, the rest by name.
+        entries.sort_by(|a, b| {
+            b.last_time
+                .cmp(&a.last_time)
+                .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase()))
+        });
+        entries.insert(0, topic);
+        view.entries = entries;
+    }
+
+    /// Adds or renames a contact, reporting failures in the conversation list.
+    fn save_contact(&mut self, peer: &PeerId, name: &str) -> bool {
+        let Some(history) = &self.history else {
+            return false;
+        };
+
+        match history.add_contact(peer, name) {
+            Ok(()) => {
+                info!("Saved contact {peer} as {name}");
+                self.message_list_float_view.status = None;
+                self.refresh_contacts();
+                true
+            }
+            Err(e) => {
+                self.message_list_float_view.status = Some(format!("Could not save contact: {e}"));
+                false
+            }
+        }
+    }
+
+    /// Prepends the page of messages before the oldest one shown.
+    fn load_older(&mut self) {
+        let view = &mut self.message_float_view;
+        let (Some(history), Some(conversation), Some(oldest)) = (
+            &self.history,
+            &view.conversation,
+            view.chat_message.first().and_then(|msg| msg.row),
+        ) else {
+            return;
+        };
 
-        for arg in &args {
-            if arg == "--backend-enable" {
-                backend_enable = true;
+        match history.before(conversation, oldest, HISTORY_PAGE) {
+            Ok(messages) => {
+                let list = &self.message_list_float_view;
+                view.has_older = messages.len() == HISTORY_PAGE;
+                view.chat_message.splice(
+                    0..0,
+                    messages
+                        .into_iter()
+                        .map(|msg| ChatMessage::from_stored(msg, list)),
+                );
             }
+            Err(e) => {
+                error!("Failed to load message history: {e}");
+                view.has_older = false;
+            }
+        }
+    }
+
+    /// Stores a message and returns its row. The chat keeps working without history.
+    fn record(&self, conversation: &Conversation, message: NewMessage<'_>) -> Option<i64> {
+        let history = self.history.as_ref()?;
+        history
+            .record(conversation, message)
+            .inspect_err(|e| error!("Failed to record message: {e}"))
+            .ok()
+    }
+
+    /// Shows a message if it belongs to the open conversation, and counts it
+    /// as unread in the conversation list otherwise.
+    fn show_message(&mut self, conversation: &Conversation, message: ChatMessage) -> Task<Message> {
+        if self.message_float_view.conversation.as_ref() != Some(conversation) {
+            self.refresh_contacts();
+            return Task::none();
+        }
+
+        if let Some(Err(e)) = self.history.as_ref().map(|h| h.mark_read(conversation)) {
+            error!("Failed to mark conversation as read: {e}");
         }
+        self.refresh_contacts();
+
+        self.message_float_view.chat_message.push(message);
+        scrollable::snap_to(
+            self.message_float_view.message_scroll_id.clone(),
+            scrollable::RelativeOffset::START,
+        )
+    }
+
+    /// Nickname of `peer` if it is a contact, its peer ID otherwise.
+    fn display_name(&self, peer: &str) -> String {
+        self.message_list_float_view
+            .nickname_of(peer)
+            .unwrap_or(peer)
+            .to_string()
+    }
+
+    /// Notes that `peer` is around and refreshes its last-seen time.
+    fn saw_peer(&mut self, peer: &PeerId) {
+        if let Some(Err(e)) = self.history.as_ref().map(|h| h.saw_peer(peer)) {
+            error!("Failed to record peer: {e}");
+        }
+        self.refresh_contacts();
+    }
+
+    pub fn update(&mut self, message: Message) -> Task<Message> {
+        match message {
+            Message::RunningBackend => {
+                info!("Backend done running");
+                self.backend = None;
+                self.status_float_view.connectivity = Connectivity::Offline;
+                self.status_float_view.reachability = Reachability::Unknown;
+
+                // The swarm no longer writes its sessions, so they can go.
+                if self.settings_float_view.wiping {
+                    return self.wipe_local_data();
+                }
+                if self.settings_float_view.importing.is_some() {
+                    return self.import_keystore();
+                }
+
+                Task::none()
+            }
+            Message::BackendFailed(e) => {
+                error!("Backend failed: {e}");
+                self.banner_float_view.error = Some(e);
+
+                self.update(Message::RunningBackend)
+            }
+            Message::RetryBackend => {
+                info!("Restarting the backend");
+                self.banner_float_view.error = None;
+                self.backend_attempt += 1;
+
+                Task::none()
+            }
+            Message::BackendReady(handle) => {
+                info!("Backend started");
+                self.backend = Some(handle);
+
+                Task::none()
+            }
+            Message::MessageReceived {
+                topic,
+                source,
+                body,
+            } => {
+                let conversation = Conversation::Topic(topic);
+                let sender = source.map(|peer| peer.to_base58());
+                let row = self.record(
+                    &conversation,
+                    NewMessage {
+                        id: None,
+                        sender: sender.clone(),
+                        outgoing: false,
+                        body: &body,
+                        state: None,
+                    },
+                );
+
+                self.show_message(
+                    &conversation,
+                    ChatMessage {
+                        row,
+                        sender: match sender {
+                            Some(peer) => self.display_name(&peer),
+                            None => "Unknown".to_string(),
+                        },
+                        time: Local::now().format("%H:%M:%S").to_string(),
+                        body,
+                        id: None,
+                        state: None,
+                    },
+                )
+            }
+            Message::PeerConnected(peer_id) => {
+                info!("Connected to {peer_id}");
+                self.saw_peer(&peer_id);
+
+                Task::none()
+            }
+            Message::PeerDisconnected(peer_id) => {
+                info!("Disconnected from {peer_id}");
+                self.saw_peer(&peer_id);
+                self.message_list_float_view.hole_punches.remove(&peer_id);
+
+                Task::none()
+            }
+            Message::ReservationAccepted {
+                relay_peer_id,
+                relay_address,
+            } => {
+                info!("Relay {relay_peer_id} accepted our reservation");
+                self.relays.push((relay_peer_id, relay_address));
+
+                Task::none()
+            }
+            Message::ReservationLost(relay_peer_id) => {
+                warn!("Lost the reservation on relay {relay_peer_id}");
+                self.relays.retain(|(peer, _)| *peer != relay_peer_id);
+
+                Task::none()
+            }
+            Message::ConnectivityChanged(connectivity) => {
+                info!("Connectivity: {connectivity}");
+                self.status_float_view.connectivity = connectivity;
+
+                Task::none()
+            }
+            Message::ReachabilityChanged(reachability) => {
+                info!("Reachability: {reachability}");
+                self.status_float_view.reachability = reachability;
+
+                Task::none()
+            }
+            Message::Registered(namespace) => {
+                info!("Registered under {namespace}");
+
+                Task::none()
+            }
+            Message::RegistrationFailed { namespace, error } => {
+                warn!("Could not register under {namespace}: {error}");
+                self.settings_float_view.status =
+                    Some(format!("Could not register under {namespace}: {error}"));
+
+                Task::none()
+            }
+            Message::PeerDiscovered(discovered) => {
+                info!(
+                    "Discovered {} under {}",
+                    discovered.peer, discovered.namespace
+                );
+                let view = &mut self.settings_float_view;
+                view.discovered.retain(|known| {
+                    (&known.namespace, known.peer) != (&discovered.namespace, discovered.peer)
+                });
+                view.discovered.push(discovered);
+
+                Task::none()
+            }
+            Message::DiscoveryExpired(peer) => {
+                self.settings_float_view
+                    .discovered
+                    .retain(|known| known.peer != peer);
+
+                Task::none()
+            }
+            Message::NearbyDiscovered(peer) => {
+                info!("{peer} is on the local network");
+                let view = &mut self.message_list_float_view;
+                // Contacts are dialled at their LAN address right away.
+                if view.nickname_of(&peer.to_base58()).is_some() {
+                    if let Some(backend) = &self.backend {
+                        backend.connect(peer);
+                    }
+                }
+                if !view.nearby.contains(&peer) {
+                    view.nearby.push(peer);
+                }
+
+                Task::none()
+            }
+            Message::NearbyExpired(peer) => {
+                self.message_list_float_view
+                    .nearby
+                    .retain(|nearby| *nearby != peer);
+
+                Task::none()
+            }
+            Message::PeerLocated { peer, addresses } => {
+                // Contacts are redialled at the located addresses by the backend itself.
+                if addresses.is_empty() {
+                    info!("{peer} has no presence record in the DHT");
+                } else {
+                    info!("Located {peer} at {} addresses", addresses.len());
+                }
+
+                Task::none()
+            }
+            Message::HolePunch { peer, status } => {
+                info!("Connection to {peer}: {}", status.state);
+                self.message_list_float_view
+                    .hole_punches
+                    .insert(peer, status);
+
+                Task::none()
+            }
+            Message::DirectMessageReceived { peer, body } => {
+                self.saw_peer(&peer);
+                let conversation = Conversation::Direct(peer);
+                let row = self.record(
+                    &conversation,
+                    NewMessage {
+                        id: None,
+                        sender: Some(peer.to_string()),
+                        outgoing: false,
+                        body: &body,
+                        state: None,
+                    },
+                );
+
+                self.show_message(
+                    &conversation,
+                    ChatMessage {
+                        row,
+                        sender: self.display_name(&peer.to_base58()),
+                        time: Local::now().format("%H:%M:%S").to_string(),
+                        body,
+                        id: None,
+                        state: None,
+                    },
+                )
+            }
+            Message::DirectMessageStateChanged { id, state } => {
+                if let Some(Err(e)) = self.history.as_ref().map(|h| h.set_state(id, state)) {
+                    error!("Failed to record delivery state: {e}");
+                }
+                if let Some(msg) = self
+                    .message_float_view
+                    .chat_message
+                    .iter_mut()
+                    .rev()
+                    .find(|msg| msg.id == Some(id))
+                {
+                    // An acknowledgement can overtake the "sent" notification.
+                    if msg.state != Some(DeliveryState::Delivered) {
+                        msg.state = Some(state);
+                    }
+                }
+
+                Task::none()
+            }
+            Message::RecipientChanged(recipient) => {
+                self.set_recipient(recipient);
+
+                Task::none()
+            }
+            Message::ChatScrolled(viewport) => {
+                // Older messages are prepended once the top of the chat comes into view.
+                if self.message_float_view.has_older
+                    && viewport.absolute_offset_reversed().y < LOAD_OLDER_THRESHOLD
+                {
+                    self.load_older();
+                }
+
+                Task::none()
+            }
+            Message::Resize(width, height) => {
+                self.window_width = width;
+                self.window_height = height;
+                println!("Resized! New width: {}", self.window_width);
+
+                Task::none()
+            }
+            Message::NavToHome => {
+                info!("Navigating to Home");
+                self.nav_float_views.current_active = NavFloatViewButton::Home;
+
+                Task::none()
+            }
+            Message::NavToChat => {
+                info!("Navigating to Chat");
+                self.nav_float_views.current_active = NavFloatViewButton::Chat;
+
+                Task::none()
+            }
+            Message::NavToSettings => {
+                info!("Navigating to Settings");
+                self.nav_float_views.current_active = NavFloatViewButton::Settings;
+
+                Task::none()
+            }
+            Message::ContentChanged(new_content) => {
+                self.message_list_float_view.search_query = new_content;
+
+                Task::none()
+            }
+            Message::ChatInputChanged(new_content) => {
+                self.message_float_view.input_message = new_content.to_string();
+
+                Task::none()
+            }
+            Message::ConversationSelected(conversation) => {
+                let recipient = match conversation {
+                    Conversation::Direct(peer) => {
+                        // Either side may dial, the circuit is upgraded by hole punching.
+                        if let Some(backend) = &self.backend {
+                            backend.connect(peer);
+                        }
+                        peer.to_base58()
+                    }
+                    Conversation::Topic(_) => String::new(),
+                };
+                self.set_recipient(recipient);
+
+                Task::none()
+            }
+            Message::NewContactPeerChanged(peer) => {
+                self.message_list_float_view.new_contact_peer = peer;
 
-        if backend_enable {
-            let runtime = Runtime::new().unwrap();
+                Task::none()
+            }
+            Message::NewContactNameChanged(name) => {
+                self.message_list_float_view.new_contact_name = name;
+
+                Task::none()
+            }
+            Message::AddContact => {
+                let view = &mut self.message_list_float_view;
+                let peer = match view.new_contact_peer.trim().parse::<PeerId>() {
+                    Ok(peer) => peer,
+                    Err(e) => {
+                        view.status = Some(format!("Invalid peer ID: {e}"));
+                        return Task::none();
+                    }
+                };
+                let name = match view.new_contact_name.trim() {
+                    "" => short_peer_id(&peer),
+                    name => name.to_string(),
+                };
+
+                if self.save_contact(&peer, &name) {
+                    let view = &mut self.message_list_float_view;
+                    view.new_contact_peer.clear();
+                    view.new_contact_name.clear();
+                    self.set_recipient(peer.to_base58());
+                }
+
+                Task::none()
+            }
+            Message::ContactNicknameChanged(nickname) => {
+                self.message_list_float_view.nickname = nickname;
+
+                Task::none()
+            }
+            Message::SaveContact => {
+                let Some(Conversation::Direct(peer)) = self.message_float_view.conversation else {
+                    return Task::none();
+                };
+                let name = self.message_list_float_view.nickname.trim().to_string();
+                if name.is_empty() {
+                    self.message_list_float_view.status = Some("Enter a nickname".to_string());
+                    return Task::none();
+                }
+
+                if self.save_contact(&peer, &name) {
+                    // Show the nickname on the messages already in view.
+                    self.load_conversation();
+                }
+
+                Task::none()
+            }
+            Message::ContactAvatarPathChanged(path) => {
+                self.message_list_float_view.avatar_path = path;
+
+                Task::none()
+            }
+            Message::SetContactAvatar => {
+                let (Some(history), Some(Conversation::Direct(peer))) =
+                    (&self.history, &self.message_float_view.conversation)
+                else {
+                    return Task::none();
+                };
+                let view = &mut self.message_list_float_view;
+
+                // An empty path clears the avatar.
+                let avatar = match view.avatar_path.trim() {
+                    "" => None,
+                    path => match fs::metadata(path).and_then(|metadata| {
+                        if metadata.len() > MAX_AVATAR_LEN as u64 {
+                            return Ok(None);
+                        }
+                        fs::read(path).map(Some)
+                    }) {
+                        Ok(Some(avatar)) => Some(avatar),
+                        Ok(None) => {
+                            view.status = Some(format!(
+                                "Avatar images are limited to {} KiB",
+                                MAX_AVATAR_LEN / 1024
+                            ));
+                            return Task::none();
+                        }
+                        Err(e) => {
+                            view.status = Some(format!("Could not read {path}: {e}"));
+                            return Task::none();
+                        }
+                    },
+                };
+
+                match history.set_avatar(peer, avatar.as_deref()) {
+                    Ok(_) => {
+                        view.avatars.remove(peer);
+                        view.avatar_path.clear();
+                        view.status = None;
+                    }
+                    Err(e) => view.status = Some(format!("Could not set avatar: {e}")),
+                }
+                self.refresh_contacts();
+
+                Task::none()
+            }
+            Message::InviteLinkChanged(link) => {
+                self.message_list_float_view.invite_link = link;
+
+                Task::none()
+            }
+            Message::AcceptInvite => {
+                let view = &mut self.message_list_float_view;
+                let invite = match Invite::parse(&view.invite_link) {
+                    Ok(invite) => invite,
+                    Err(e) => {
+                        view.status = Some(format!("Invalid invite: {e}"));
+                        return Task::none();
+                    }
+                };
+                if Some(invite.peer) == self.keypair.as_ref().map(|k| k.public().to_peer_id()) {
+                    view.status = Some("This is your own invite".to_string());
+                    return Task::none();
+                }
+
+                // Keep the nickname of a known contact, the invite only suggests one.
+                let name = match view.nickname_of(&invite.peer.to_base58()) {
+                    Some(name) => name.to_string(),
+                    None => invite
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| short_peer_id(&invite.peer)),
+                };
+                if self.save_contact(&invite.peer, &name) {
+                    self.message_list_float_view.invite_link.clear();
+                    if let Some(backend) = &self.backend {
+                        backend.dial(invite.circuit_address());
+                    }
+                    self.set_recipient(invite.peer.to_base58());
+                }
+
+                Task::none()
+            }
+            Message::RemoveContact => {
+                let (Some(history), Some(Conversation::Direct(peer))) =
+                    (&self.history, &self.message_float_view.conversation)
+                else {
+                    return Task::none();
+                };
+
+                match history.remove_contact(peer) {
+                    Ok(_) => {
+                        info!("Removed contact {peer}");
+                        self.message_list_float_view.avatars.remove(peer);
+                        self.message_list_float_view.status = None;
+                    }
+                    Err(e) => {
+                        self.message_list_float_view.status =
+                            Some(format!("Could not remove contact: {e}"))
+                    }
+                }
+                self.load_conversation();
+
+                Task::none()
+            }
+            Message::SendMessage => {
+                if self.message_float_view.input_message.is_empty() {
+                    return Task::none();
+                }
+
+                // An empty recipient broadcasts on the default topic.
+                let recipient = self.message_float_view.recipient.trim();
+                let recipient = if recipient.is_empty() {
+                    None
+                } else {
+                    match recipient.parse::<PeerId>() {
+                        Ok(peer) => Some(peer),
+                        Err(e) => {
+                            warn!("Invalid recipient {recipient}: {e}");
+                            return Task::none();
+                        }
+                    }
+                };
+
+                let body = self.message_float_view.input_message.to_string();
+                let (id, state) = match (&self.backend, recipient) {
+                    (Some(backend), Some(peer)) => (
+                        Some(backend.send_direct(peer, body.clone())),
+                        Some(DeliveryState::Sending),
+                    ),
+                    (Some(backend), None) => {
+                        backend.publish(network::DEFAULT_TOPIC, body.clone().into_bytes());
+                        (None, None)
+                    }
+                    (None, _) => {
+                        warn!("Backend not running, message stays local");
+                        (None, None)
+                    }
+                };
+
+                let conversation = match recipient {
+                    Some(peer) => Conversation::Direct(peer),
+                    None => Conversation::Topic(network::DEFAULT_TOPIC.to_string()),
+                };
+                let row = self.record(
+                    &conversation,
+                    NewMessage {
+                        id,
+                        sender: self
+                            .keypair
+                            .as_ref()
+                            .map(|keypair| keypair.public().to_peer_id().to_string()),
+                        outgoing: true,
+                        body: &body,
+                        state,
+                    },
+                );
+                trace!("Message sent, {} bytes", body.len());
+
+                self.message_float_view.input_message = String::new();
+                self.show_message(
+                    &conversation,
+                    ChatMessage {
+                        row,
+                        sender: "Me".to_string(),
+                        time: Local::now().format("%H:%M:%S").to_string(),
+                        body,
+                        id,
+                        state,
+                    },
+                )
+            }
+            Message::UnlockPassphraseChanged(passphrase) => {
+                self.unlock_float_view.passphrase = passphrase;
+
+                Task::none()
+            }
+            Message::UnlockConfirmChanged(confirm) => {
+                self.unlock_float_view.confirm = confirm;
+
+                Task::none()
+            }
+            Message::Unlock => {
+                let view = &mut self.unlock_float_view;
+                if view.busy || view.passphrase.is_empty() {
+                    return Task::none();
+                }
+                if view.creating && view.passphrase != view.confirm {
+                    view.error = Some("Passphrases do not match".to_string());
+                    return Task::none();
+                }
+
+                view.busy = true;
+                view.error = None;
+                let passphrase = std::mem::take(&mut view.passphrase);
+                view.confirm.clear();
+
+                let keystore = Keystore::new(self.keystore_path.clone());
+                if view.creating {
+                    keystore_task(move || keystore.create(&passphrase), Message::Unlocked)
+                } else {
+                    keystore_task(move || keystore.unlock(&passphrase), Message::Unlocked)
+                }
+            }
+            Message::Unlocked(result) => {
+                self.unlock_float_view.busy = false;
+                match result {
+                    Ok(keypair) => {
+                        info!("Unlocked identity {}", keypair.public().to_peer_id());
+                        self.keypair = Some(keypair);
+                        self.open_history();
+
+                        Task::none()
+                    }
+                    Err(e) => {
+                        self.unlock_float_view.error = Some(e);
+
+                        Task::none()
+                    }
+                }
+            }
+            Message::SettingsCurrentPassphraseChanged(passphrase) => {
+                self.settings_float_view.current_passphrase = passphrase;
+
+                Task::none()
+            }
+            Message::SettingsNewPassphraseChanged(passphrase) => {
+                self.settings_float_view.new_passphrase = passphrase;
+
+                Task::none()
+            }
+            Message::SettingsConfirmPassphraseChanged(passphrase) => {
+                self.settings_float_view.confirm_passphrase = passphrase;
+
+                Task::none()
+            }
+            Message::ChangePassphrase => {
+                let view = &mut self.settings_float_view;
+                if view.new_passphrase.is_empty() {
+                    return Task::none();
+                }
+                if view.new_passphrase != view.confirm_passphrase {
+                    view.status = Some("New passphrases do not match".to_string());
+                    return Task::none();
+                }
+
+                let old = std::mem::take(&mut view.current_passphrase);
+                let new = std::mem::take(&mut view.new_passphrase);
+                view.confirm_passphrase.clear();
+                view.status = Some("Changing passphrase...".to_string());
+
+                let keystore = self.keystore();
+                keystore_task(
+                    move || keystore.change_passphrase(&old, &new),
+                    Message::PassphraseChanged,
+                )
+            }
+            Message::PassphraseChanged(result) => {
+                self.settings_float_view.status = Some(match result {
+                    Ok(()) => "Passphrase changed".to_string(),
+                    Err(e) => format!("Could not change passphrase: {e}"),
+                });
+
+                Task::none()
+            }
+            Message::SettingsExportPathChanged(path) => {
+                self.settings_float_view.export_path = path;
+
+                Task::none()
+            }
+            Message::ExportKeystore => {
+                if self.settings_float_view.export_path.is_empty() {
+                    return Task::none();
+                }
+
+                let keystore = self.keystore();
+                let dest = PathBuf::from(&self.settings_float_view.export_path);
+                keystore_task(move || keystore.export(&dest), Message::KeystoreExported)
+            }
+            Message::KeystoreExported(result) => {
+                self.settings_float_view.status = Some(match result {
+                    Ok(()) => format!(
+                        "Exported encrypted identity to {}",
+                        self.settings_float_view.export_path
+                    ),
+                    Err(e) => format!("Could not export identity: {e}"),
+                });
+
+                Task::none()
+            }
+            Message::SettingsImportPathChanged(path) => {
+                self.settings_float_view.import_path = path;
+
+                Task::none()
+            }
+            Message::SettingsImportPassphraseChanged(passphrase) => {
+                self.settings_float_view.import_passphrase = passphrase;
+
+                Task::none()
+            }
+            Message::ImportKeystore => {
+                // The keystore on disk is not the identity in use, leave it alone.
+                if self.test_identity {
+                    self.settings_float_view.status =
+                        Some("Test identities do not use the keystore".to_string());
+                    return Task::none();
+                }
+                let view = &mut self.settings_float_view;
+                if view.import_path.is_empty() || view.importing.is_some() {
+                    return Task::none();
+                }
+
+                view.importing = Some((
+                    PathBuf::from(&view.import_path),
+                    std::mem::take(&mut view.import_passphrase),
+                ));
+                view.status = Some("Importing identity...".to_string());
+                self.history = None;
+
+                // The swarm writes the sessions of the current identity, which
+                // the import may delete. Wait for it to stop.
+                if self.backend.take().is_some() {
+                    Task::none()
+                } else {
+                    self.import_keystore()
+                }
+            }
+            Message::KeystoreImported(result) => {
+                self.settings_float_view.status = Some(match result {
+                    Ok(keypair) => {
+                        let peer = keypair.public().to_peer_id();
+                        info!("Switched to imported identity {peer}");
+                        if self.keypair.as_ref().map(|k| k.public().to_peer_id()) != Some(peer) {
+                            self.relays.clear();
+                            self.message_float_view = MessageFloatView::default();
+                        }
+                        self.keypair = Some(keypair);
+                        format!("Imported identity {peer}")
+                    }
+                    Err(e) => format!("Could not import identity: {e}"),
+                });
+                self.backend_attempt += 1;
+                self.open_history();
+
+                Task::none()
+            }
+            Message::SettingsInviteNameChanged(name) => {
+                self.settings_float_view.invite_name = name;
+
+                Task::none()
+            }
+            Message::CreateInvite => {
+                let (Some(keypair), Some(relay)) = (&self.keypair, self.invite_relay().cloned())
+                else {
+                    return Task::none();
+                };
+                let view = &mut self.settings_float_view;
+                let name = Some(view.invite_name.as_str());
+
+                match Invite::link(keypair, &relay, name) {
+                    Ok(link) => {
+                        let qr = match invite::qr_code(&link) {
+                            Ok(qr) => Some(svg::Handle::from_memory(qr.into_bytes())),
+                            Err(e) => {
+                                warn!("Invite link does not fit in a QR code: {e}");
+                                None
+                            }
+                        };
+                        view.status = qr.is_none().then(|| {
+                            "The link is too long for a QR code, share it as text".to_string()
+                        });
+                        view.invite = Some((link, qr));
+                    }
+                    Err(e) => view.status = Some(format!("Could not create invite: {e}")),
+                }
+
+                Task::none()
+            }
+            Message::CopyInvite => match &self.settings_float_view.invite {
+                Some((link, _)) => clipboard::write(link.clone()),
+                None => Task::none(),
+            },
+            Message::SettingsNamespaceChanged(namespace) => {
+                self.settings_float_view.namespace = namespace;
+
+                Task::none()
+            }
+            Message::JoinNamespace => {
+                let name = self.settings_float_view.namespace.trim().to_string();
+                if name.is_empty() || self.backend_config.namespaces.contains(&name) {
+                    return Task::none();
+                }
+                let namespace = match Namespace::new(name.clone()) {
+                    Ok(namespace) => namespace,
+                    Err(e) => {
+                        self.settings_float_view.status = Some(format!("Invalid namespace: {e}"));
+                        return Task::none();
+                    }
+                };
+
+                // Kept in the backend settings, so a restarted backend joins it again.
+                self.backend_config.namespaces.push(name);
+                if let Some(backend) = &self.backend {
+                    backend.register(namespace);
+                }
+                self.settings_float_view.namespace.clear();
+                self.settings_float_view.status = None;
+
+                Task::none()
+            }
+            Message::LeaveNamespace(name) => {
+                self.backend_config
+                    .namespaces
+                    .retain(|joined| *joined != name);
+                self.settings_float_view
+                    .discovered
+                    .retain(|known| known.namespace != name);
+                if let (Some(backend), Ok(namespace)) = (&self.backend, Namespace::new(name)) {
+                    backend.unregister(namespace);
+                }
+
+                Task::none()
+            }
+            Message::AddDiscovered(discovered) => {
+                // Keep the nickname of a known contact.
+                let name = match self
+                    .message_list_float_view
+                    .nickname_of(&discovered.peer.to_base58())
+                {
+                    Some(name) => name.to_string(),
+                    None => short_peer_id(&discovered.peer),
+                };
+                if self.save_contact(&discovered.peer, &name) {
+                    if let Some(backend) = &self.backend {
+                        match discovered.addresses.first() {
+                            Some(address) => backend.dial(address.clone()),
+                            None => backend.connect(discovered.peer),
+                        }
+                    }
+                    self.settings_float_view.status = Some(format!("Added {name} as a contact"));
+                }
+
+                Task::none()
+            }
+            Message::WipeLocalData => {
+                // The keystore on disk is not the identity in use, leave it alone.
+                if self.test_identity {
+                    self.settings_float_view.status =
+                        Some("Test identities keep no local data to wipe".to_string());
+                    return Task::none();
+                }
+                self.settings_float_view.confirm_wipe = true;
+
+                Task::none()
+            }
+            Message::CancelWipe => {
+                self.settings_float_view.confirm_wipe = false;
+
+                Task::none()
+            }
+            Message::ConfirmWipe => {
+                let view = &mut self.settings_float_view;
+                if view.wiping {
+                    return Task::none();
+                }
+
+                view.wiping = true;
+                view.status = Some("Wiping local data...".to_string());
+                self.history = None;
+
+                // Dropping the handle stops the swarm, wait for it before deleting its files.
+                if self.backend.take().is_some() {
+                    Task::none()
+                } else {
+                    self.wipe_local_data()
+                }
+            }
+            Message::LocalDataWiped(result) => {
+                info!("Local data wiped");
+                self.keypair = None;
+                self.relays.clear();
+                self.banner_float_view = BannerFloatView::default();
+                self.message_float_view = MessageFloatView::default();
+                self.settings_float_view = SettingsFloatView::default();
+                self.nav_float_views.current_active = NavFloatViewButton::Home;
+                self.unlock_float_view = UnlockFloatView::new(self.keystore().exists());
+                if let Err(e) = result {
+                    self.unlock_float_view.error = Some(format!("Could not wipe local data: {e}"));
+                }
+
+                Task::none()
+            }
+        }
+    }
+
+    /// Relay put in invites: the first one holding a reservation, or while we
+    /// are public and hold none, the first configured one. Dialling its circuit
+    /// then fails and the peer finds our public address in the DHT instead.
+    fn invite_relay(&self) -> Option<&Multiaddr> {
+        match self.relays.first() {
+            Some((_, relay)) => Some(relay),
+            None if self.status_float_view.reachability == Reachability::Public => {
+                self.backend_config.relays.first()
+            }
+            None => None,
+        }
+    }
+
+    fn import_keystore(&mut self) -> Task<Message> {
+        let (Some((src, passphrase)), Some(keypair)) =
+            (self.settings_float_view.importing.take(), &self.keypair)
+        else {
+            return Task::none();
+        };
+
+        let current = keypair.public().to_peer_id();
+        let keystore = self.keystore();
+        keystore_task(
+            move || keystore.import(&src, &passphrase, &current),
+            Message::KeystoreImported,
+        )
+    }
+
+    fn wipe_local_data(&self) -> Task<Message> {
+        let keystore = self.keystore();
+        keystore_task(move || keystore.wipe(), Message::LocalDataWiped)
+    }
+
+    pub fn view(&self) -> Column<'_, Message> {
+        if self.keypair.is_none() {
+            return column![self.unlock_float_view.container_view()].padding(10);
+        }
+
+        column![self.containers(),].padding(10)
+    }
+
+    pub fn containers(&self) -> Element<'_, Message> {
+        let sidebar = column![
+            self.logo_float_view.container_view(),
+            self.nav_float_views.container_view(),
+            self.status_float_view.container_view(),
+        ]
+        .spacing(10);
+
+        let content: Element<'_, Message> =
+            if self.nav_float_views.current_active == NavFloatViewButton::Settings {
+                self.settings_float_view.container_view(
+                    self.keypair.as_ref(),
+                    self.invite_relay().is_some(),
+                    &self.backend_config.namespaces,
+                )
+            } else {
+                row![
+                    self.message_list_float_view
+                        .container_view(self.message_float_view.conversation.as_ref()),
+                    self.message_float_view.container_view(),
+                ]
+                .spacing(10)
+                .into()
+            };
+
+        let content = match self.banner_float_view.container_view() {
+            Some(banner) => column![banner, content].spacing(10).into(),
+            None => content,
+        };
+
+        row![sidebar, content]
+            .width(Length::Fill)
+            .spacing(10)
+            .into()
+    }
+}
+
+/// How the connection to a contact stands, e.g. "Relayed, hole punch failed after 2 attempts: ...".
+fn hole_punch_label(status: &HolePunchStatus) -> String {
+    match &status.state {
+        HolePunchState::Direct => "Direct connection".to_string(),
+        HolePunchState::Relayed => {
+            format!("Relayed, hole punching (attempt {})", status.attempts + 1)
+        }
+        HolePunchState::Failed(reason) => format!(
+            "Relayed, hole punch failed after {} attempt{}: {reason}",
+            status.attempts,
+            if status.attempts == 1 { "" } else { "s" }
+        ),
+    }
+}
+
+/// Runs the swarm for `keypair` with `config` and forwards its events as [`Message`]s.
+fn backend_worker(keypair: Keypair, config: BackendConfig) -> impl Stream<Item = Message> {
+    iced::stream::channel(100, move |mut output| async move {
+        let (app_core, handle, mut events) = AppCore::new(keypair, config);
+        let backend = tokio::spawn(app_core.run());
+        let _ = output.send(Message::BackendReady(handle)).await;
+
+        while let Some(event) = events.recv().await {
+            let message = match event {
+                network::Event::MessageReceived {
+                    topic,
+                    source,
+                    data,
+                } => Message::MessageReceived {
+                    topic,
+                    source,
+                    body: String::from_utf8_lossy(&data).into_owned(),
+                },
+                network::Event::PeerConnected(peer_id) => Message::PeerConnected(peer_id),
+                network::Event::PeerDisconnected(peer_id) => Message::PeerDisconnected(peer_id),
+                network::Event::ReservationAccepted {
+                    relay_peer_id,
+                    relay_address,
+                } => Message::ReservationAccepted {
+                    relay_peer_id,
+                    relay_address,
+                },
+                network::Event::ReservationLost { relay_peer_id } => {
+                    Message::ReservationLost(relay_peer_id)
+                }
+                network::Event::ConnectivityChanged(connectivity) => {
+                    Message::ConnectivityChanged(connectivity)
+                }
+                network::Event::ReachabilityChanged { reachability, .. } => {
+                    Message::ReachabilityChanged(reachability)
+                }
+                network::Event::HolePunch { peer, status } => Message::HolePunch { peer, status },
+                network::Event::DirectMessageReceived { peer, body, .. } => {
+                    Message::DirectMessageReceived { peer, body }
+                }
+                network::Event::DirectMessageStateChanged { id, state, .. } => {
+                    Message::DirectMessageStateChanged { id, state }
+                }
+                network::Event::Registered { namespace, .. } => Message::Registered(namespace),
+                network::Event::RegistrationFailed {
+                    namespace, error, ..
+                } => Message::RegistrationFailed { namespace, error },
+                network::Event::PeerDiscovered(discovered) => Message::PeerDiscovered(discovered),
+                network::Event::DiscoveryExpired(peer) => Message::DiscoveryExpired(peer),
+                network::Event::NearbyDiscovered { peer, .. } => Message::NearbyDiscovered(peer),
+                network::Event::NearbyExpired(peer) => Message::NearbyExpired(peer),
+                network::Event::PeerLocated { peer, addresses } => {
+                    Message::PeerLocated { peer, addresses }
+                }
+            };
+
+            if output.send(message).await.is_err() {
+                break;
+            }
+        }
+
+        let message = match backend.await {
+            Ok(Ok(())) => Message::RunningBackend,
+            Ok(Err(e)) => Message::BackendFailed(e.to_string()),
+            Err(e) => Message::BackendFailed(format!("backend task failed: {e}")),
+        };
+        let _ = output.send(message).await;
+    })
+}
+
+/// Runs a keystore operation off the GUI thread, Argon2 takes a noticeable moment.
+fn keystore_task<T: Send + 'static>(
+    op: impl FnOnce() -> Result<T, KeystoreError> + Send + 'static,
+    to_message: impl Fn(Result<T, String>) -> Message + Send + 'static,
+) -> Task<Message> {
+    Task::perform(
+        async move {
+            tokio::task::spawn_blocking(op)
+                .await
+                .expect("keystore task panicked")
+                .map_err(|e| e.to_string())
+        },
+        to_message,
+    )
+}
+
+//====== Unlock Float View ======//
+#[derive(Default)]
+struct UnlockFloatView {
+    // no keystore on disk yet, so a new identity is created
+    pub creating: bool,
+    pub passphrase: String,
+    pub confirm: String,
+    pub busy: bool,
+    pub error: Option<String>,
+}
+
+impl UnlockFloatView {
+    fn new(keystore_exists: bool) -> Self {
+        Self {
+            creating: !keystore_exists,
+            ..Default::default()
+        }
+    }
+
+    fn container_view(&self) -> Element<'_, Message> {
+        let (title, hint) = if self.creating {
+            (
+                "Create your identity",
+                "Choose a passphrase to encrypt your identity on this device.",
+            )
+        } else {
+            ("Unlock Limiinal", "Enter the passphrase of your identity.")
+        };
+
+        let mut passphrase = text_input("Passphrase", &self.passphrase).secure(true);
+        let mut confirm = text_input("Confirm passphrase", &self.confirm).secure(true);
+        let mut unlock_button = button(if self.creating { "Create" } else { "Unlock" })
+            .padding(10)
+            .style(settings_button_style);
+
+        if !self.busy {
+            passphrase = passphrase
+                .on_input(Message::UnlockPassphraseChanged)
+                .on_submit(Message::Unlock);
+            confirm = confirm
+                .on_input(Message::UnlockConfirmChanged)
+                .on_submit(Message::Unlock);
+            unlock_button = unlock_button.on_press(Message::Unlock);
+        }
+
+        let mut form = column![
+            svg::Svg::from_path(asset_path!("./assets/icons/logo.svg"))
+                .width(Length::Fixed(100.0))
+                .height(Length::Fixed(100.0)),
+            text(title).size(24),
+            text(hint).size(14),
+            passphrase,
+        ]
+        .spacing(15)
+        .align_x(Alignment::Center)
+        .width(Length::Fixed(350.0));
+
+        if self.creating {
+            form = form.push(confirm);
+        }
+        form = form.push(unlock_button);
+
+        if self.busy {
+            form = form.push(text("Deriving key...").size(12));
+        }
+        if let Some(error) = &self.error {
+            form = form.push(text(error).size(12).color(Color::from_rgb(1.0, 0.4, 0.4)));
+        }
+
+        center(form).style(MessageFloatView::style()).into()
+    }
+}
+
+//====== Banner Float View ======//
+/// Error bar above the content, shown while the backend is down.
+#[derive(Default)]
+struct BannerFloatView {
+    pub error: Option<String>,
+    // why the message history is not being saved
+    pub history_error: Option<String>,
+}
 
-            tasks.push(Task::perform(
-                async move {
-                    let mut app_core = AppCore::new();
-                    runtime
-                        .spawn(async move {
-                            app_core.run().await;
-                        })
-                        .await
-                        .unwrap();
-                },
-                |_| Message::RunningBackend,
-            ));
+impl BannerFloatView {
+    fn container_view(&self) -> Option<Element<'_, Message>> {
+        let mut banner = column![].spacing(10);
+        if let Some(error) = &self.error {
+            banner = banner.push(
+                row![
+                    text(format!("The network backend stopped: {error}"))
+                        .size(14)
+                        .width(Length::Fill),
+                    button("Retry")
+                        .on_press(Message::RetryBackend)
+                        .style(settings_button_style),
+                ]
+                .spacing(10)
+                .align_y(Alignment::Center),
+            );
+        }
+        if let Some(error) = &self.history_error {
+            banner = banner.push(
+                text(format!(
+                    "Messages are not being saved: {error}. Import the identity the history \
+                     belongs to, or wipe local data in Settings to start a new one."
+                ))
+                .size(14),
+            );
+        }
+        if self.error.is_none() && self.history_error.is_none() {
+            return None;
         }
 
-        tasks.push(widget::focus_next());
-
-        (
-            Self {
-                ..Default::default()
-            },
-            Task::batch(tasks),
+        Some(
+            container(banner)
+                .width(Length::Fill)
+                .padding(10)
+                .style(BannerFloatView::style())
+                .into(),
         )
     }
 
-    pub fn update(&mut self, message: Message) -> Task<Message> {
-        match message {
-            Message::RunningBackend => {
-                info!("Backend done running");
+    fn style() -> impl Fn(&Theme) -> container::Style {
+        move |_| container::Style {
+            background: Some(Color::from_rgb(0.6, 0.2, 0.2).into()),
+            text_color: Some(Color::WHITE),
+            border: Border {
+                radius: Radius {
+                    top_left: 20.0,
+                    top_right: 20.0,
+                    bottom_left: 20.0,
+                    bottom_right: 20.0,
+                },
+                ..Border::default()
+            },
+            ..container::Style::default()
+        }
+    }
+}
 
-                Task::none()
-            }
-            Message::Resize(width, height) => {
-                self.window_width = width;
-                self.window_height = height;
-                println!("Resized! New width: {}", self.window_width);
+//====== Settings Float View ======//
+#[derive(Default)]
+struct SettingsFloatView {
+    pub current_passphrase: String,
+    pub new_passphrase: String,
+    pub confirm_passphrase: String,
+    pub export_path: String,
+    pub import_path: String,
+    pub import_passphrase: String,
+    pub invite_name: String,
+    // link and its QR code, made on request, without one if the link is too long
+    pub invite: Option<(String, Option<svg::Handle>)>,
+    // rendezvous namespace to join
+    pub namespace: String,
+    // peers found under the joined namespaces
+    pub discovered: Vec<Discovered>,
+    pub status: Option<String>,
+    // the wipe button was pressed once and asks for confirmation
+    pub confirm_wipe: bool,
+    pub wiping: bool,
+    // source and passphrase of an import waiting for the backend to stop
+    pub importing: Option<(PathBuf, String)>,
+}
 
-                Task::none()
-            }
-            Message::NavToHome => {
-                info!("Navigating to Home");
-                self.nav_float_views.current_active = NavFloatViewButton::Home;
+impl SettingsFloatView {
+    fn container_view<'a>(
+        &'a self,
+        keypair: Option<&Keypair>,
+        has_relay: bool,
+        namespaces: &'a [String],
+    ) -> Element<'a, Message> {
+        let peer_id = keypair
+            .map(|keypair| keypair.public().to_peer_id().to_string())
+            .unwrap_or_default();
+
+        let change_passphrase = column![
+            text("Change passphrase").size(18),
+            text_input("Current passphrase", &self.current_passphrase)
+                .secure(true)
+                .on_input(Message::SettingsCurrentPassphraseChanged),
+            text_input("New passphrase", &self.new_passphrase)
+                .secure(true)
+                .on_input(Message::SettingsNewPassphraseChanged),
+            text_input("Confirm new passphrase", &self.confirm_passphrase)
+                .secure(true)
+                .on_input(Message::SettingsConfirmPassphraseChanged)
+                .on_submit(Message::ChangePassphrase),
+            button("Change passphrase")
+                .on_press(Message::ChangePassphrase)
+                .style(settings_button_style),
+        ]
+        .spacing(10);
 
-                Task::none()
+        let invite = {
+            let mut create = button("Create invite").style(settings_button_style);
+            if has_relay {
+                create = create.on_press(Message::CreateInvite);
             }
-            Message::NavToChat => {
-                info!("Navigating to Chat");
-                self.nav_float_views.current_active = NavFloatViewButton::Chat;
-
-                Task::none()
+            let mut invite = column![
+                text("Invite a contact").size(18),
+                text(if has_relay {
+                    "Share the link or let the other client scan the QR code. It tells them your \
+                     peer ID and how to reach you through your relay."
+                } else {
+                    "Invites can be created once a relay accepted our reservation."
+                })
+                .size(12),
+                row![
+                    text_input("Nickname to suggest (optional)", &self.invite_name)
+                        .on_input(Message::SettingsInviteNameChanged)
+                        .on_submit(Message::CreateInvite),
+                    create,
+                ]
+                .spacing(10),
+            ]
+            .spacing(10);
+
+            if let Some((link, qr)) = &self.invite {
+                if let Some(qr) = qr {
+                    invite = invite.push(
+                        svg::Svg::new(qr.clone())
+                            .width(Length::Fixed(240.0))
+                            .height(Length::Fixed(240.0)),
+                    );
+                }
+                invite = invite.push(
+                    row![
+                        text_input("", link).size(12),
+                        button("Copy")
+                            .on_press(Message::CopyInvite)
+                            .style(settings_button_style),
+                    ]
+                    .spacing(10),
+                );
             }
-            Message::NavToSettings => {
-                info!("Navigating to Settings");
-                self.nav_float_views.current_active = NavFloatViewButton::Settings;
 
-                Task::none()
-            }
-            // This needs to be updated with functionality when a search is entered.
-            Message::ContentChanged(new_content) => {
-                self.message_list_float_view.search_query = new_content;
-                info!("Content changed to...");
+            invite
+        };
 
-                Task::none()
-            }
-            Message::ChatInputChanged(new_content) => {
-                self.message_float_view.input_message = new_content.to_string();
-                info!(
-                    "{}",
-                    format!(
-                        "Chat input changed to {}",
-                        self.message_float_view.input_message
-                    )
+        let discovery = {
+            let mut discovery = column![
+                text("Discovery").size(18),
+                text(
+                    "Join a namespace, e.g. your team name, to find everyone else who joined it \
+                     through the relays, without exchanging peer IDs first."
+                )
+                .size(12),
+                row![
+                    text_input("Namespace", &self.namespace)
+                        .on_input(Message::SettingsNamespaceChanged)
+                        .on_submit(Message::JoinNamespace),
+                    button("Join")
+                        .on_press(Message::JoinNamespace)
+                        .style(settings_button_style),
+                ]
+                .spacing(10),
+            ]
+            .spacing(10);
+
+            for namespace in namespaces {
+                discovery = discovery.push(
+                    row![
+                        text(namespace).width(Length::Fill),
+                        button("Leave")
+                            .on_press(Message::LeaveNamespace(namespace.clone()))
+                            .style(settings_button_style),
+                    ]
+                    .spacing(10)
+                    .align_y(Alignment::Center),
                 );
-
-                Task::none()
-            }
-            Message::ContainerPressed(index) => {
-                if let Some(active) = self.active_containers.get_mut(index) {
-                    *active = !*active; // Toggle the active state
+                for discovered in self
+                    .discovered
+                    .iter()
+                    .filter(|discovered| discovered.namespace == *namespace)
+                {
+                    discovery = discovery.push(
+                        row![
+                            text(discovered.peer.to_base58())
+                                .size(12)
+                                .width(Length::Fill),
+                            button("Add contact")
+                                .on_press(Message::AddDiscovered(discovered.clone()))
+                                .style(settings_button_style),
+                        ]
+                        .spacing(10)
+                        .padding(Padding::ZERO.left(20))
+                        .align_y(Alignment::Center),
+                    );
                 }
-                Task::none()
             }
-            Message::SendMessage => {
-                if self.message_float_view.input_message.is_empty() {
-                    return Task::none();
-                }
-                self.message_float_view.chat_message.push(ChatMessage {
-                    sender: "Me".to_string(),
-                    time: Local::now().format("%H:%M:%S").to_string(),
-                    body: self.message_float_view.input_message.to_string(),
-                    is_read: false,
-                });
-                info!(
-                    "{}",
-                    format!("Message sent: {}", self.message_float_view.input_message)
-                );
 
-                self.message_float_view.input_message = String::new();
-                scrollable::snap_to(
-                    self.message_float_view.message_scroll_id.clone(),
-                    scrollable::RelativeOffset::START,
+            discovery
+        };
+
+        let export = column![
+            text("Export encrypted identity").size(18),
+            row![
+                text_input("Destination file", &self.export_path)
+                    .on_input(Message::SettingsExportPathChanged)
+                    .on_submit(Message::ExportKeystore),
+                button("Export")
+                    .on_press(Message::ExportKeystore)
+                    .style(settings_button_style),
+            ]
+            .spacing(10),
+        ]
+        .spacing(10);
+
+        let import = column![
+            text("Import encrypted identity").size(18),
+            text_input("Source file", &self.import_path)
+                .on_input(Message::SettingsImportPathChanged),
+            row![
+                text_input(
+                    "Passphrase of the imported identity",
+                    &self.import_passphrase
+                )
+                .secure(true)
+                .on_input(Message::SettingsImportPassphraseChanged)
+                .on_submit(Message::ImportKeystore),
+                button("Import")
+                    .on_press(Message::ImportKeystore)
+                    .style(settings_button_style),
+            ]
+            .spacing(10),
+        ]
+        .spacing(10);
+
+        let wipe = {
+            let mut wipe = column![
+                text("Wipe all local data").size(18),
+                text(
+                    "Deletes the identity, end-to-end sessions and message history from this \
+                     device. Export the identity first to keep the peer ID."
                 )
+                .size(12),
+            ]
+            .spacing(10);
+
+            if self.confirm_wipe {
+                let mut confirm = button("Wipe everything").style(wipe_button_style);
+                if !self.wiping {
+                    confirm = confirm.on_press(Message::ConfirmWipe);
+                }
+                wipe = wipe.push(
+                    row![
+                        confirm,
+                        button("Cancel")
+                            .on_press(Message::CancelWipe)
+                            .style(settings_button_style),
+                    ]
+                    .spacing(10),
+                );
+            } else {
+                wipe = wipe.push(
+                    button("Wipe all local data")
+                        .on_press(Message::WipeLocalData)
+                        .style(settings_button_style),
+                );
             }
+
+            wipe
+        };
+
+        let mut content = column![
+            text("Settings").size(24),
+            text(format!("Peer ID: {peer_id}")).size(12),
+            invite,
+            discovery,
+            change_passphrase,
+            export,
+            import,
+            wipe,
+        ]
+        .spacing(25)
+        .max_width(600);
+
+        if let Some(status) = &self.status {
+            content = content.push(text(status).size(12));
         }
+
+        container(scrollable(content))
+            .padding(20)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .style(MessageFloatView::style())
+            .into()
     }
+}
 
-    pub fn view(&self) -> Column<Message> {
-        column![self.containers(),].padding(10)
+fn settings_button_style(_: &Theme, _: Status) -> button::Style {
+    button::Style {
+        background: Some(Color::from_rgb(0.4, 0.4, 0.4).into()),
+        text_color: Color::WHITE,
+        border: Border {
+            radius: Radius {
+                top_left: 20.0,
+                top_right: 20.0,
+                bottom_left: 20.0,
+                bottom_right: 20.0,
+            },
+            ..Border::default()
+        },
+        ..button::Style::default()
     }
+}
 
-    pub fn containers(&self) -> Element<Message> {
-        row![
-            column![
-                self.logo_float_view.container_view(),
-                self.nav_float_views.container_view(),
-            ]
-            .spacing(10),
-            self.message_list_float_view.container_view(),
-            self.message_float_view.container_view(),
-        ]
-        .width(Length::Fill)
-        .spacing(10)
-        .into()
+fn wipe_button_style(theme: &Theme, status: Status) -> button::Style {
+    button::Style {
+        background: Some(Color::from_rgb(0.7, 0.2, 0.2).into()),
+        ..settings_button_style(theme, status)
     }
 }
 
 //====== Logo Float View ======//
 struct LogoFloatView {
-    pub id: i32,
-    pub name: String,
     pub width: Length,
     pub height: Length,
 }
@@ -238,8 +1974,6 @@ impl LogoFloatView {
 impl Default for LogoFloatView {
     fn default() -> Self {
         Self {
-            id: 0,
-            name: String::from("Logo"),
             width: Length::Fixed(100.0),
             height: Length::Fixed(100.0),
         }
@@ -254,9 +1988,6 @@ enum NavFloatViewButton {
 }
 
 struct NavFloatView {
-    pub id: i32,
-    pub name: String,
-    pub home: String,
     pub width: Length,
     pub height: Length,
     pub current_active: NavFloatViewButton,
@@ -365,9 +2096,6 @@ impl NavFloatView {
 impl Default for NavFloatView {
     fn default() -> Self {
         Self {
-            id: 1,
-            name: String::from("Nav"),
-            home: String::from(""),
             width: Length::Fixed(100.0),
             height: Length::Fixed(300.0),
             current_active: NavFloatViewButton::Chat,
@@ -375,22 +2103,129 @@ impl Default for NavFloatView {
     }
 }
 
+//====== Status Float View ======//
+#[derive(Default)]
+struct StatusFloatView {
+    connectivity: Connectivity,
+    reachability: Reachability,
+}
+
+impl StatusFloatView {
+    fn container_view(&self) -> Element<'_, Message> {
+        container(
+            column![
+                row![
+                    text("●").color(Self::color(self.connectivity)),
+                    text(self.connectivity.to_string()).size(14),
+                ]
+                .spacing(5)
+                .align_y(Alignment::Center),
+                text(self.reachability.to_string()).size(11),
+            ]
+            .spacing(2)
+            .align_x(Alignment::Center),
+        )
+        .center_x(Length::Fixed(100.0))
+        .padding(10)
+        .style(StatusFloatView::style())
+        .into()
+    }
+
+    fn color(connectivity: Connectivity) -> Color {
+        match connectivity {
+            Connectivity::Offline => Color::from_rgb(0.8, 0.3, 0.3),
+            Connectivity::Online => Color::from_rgb(0.9, 0.7, 0.3),
+            Connectivity::Relayed => Color::from_rgb(0.4, 0.6, 0.9),
+            Connectivity::Direct => Color::from_rgb(0.3, 0.8, 0.4),
+        }
+    }
+
+    fn style() -> impl Fn(&Theme) -> container::Style {
+        move |_| container::Style {
+            background: Some(Color::from_rgb(0.4, 0.4, 0.4).into()),
+            text_color: Some(Color::WHITE),
+            border: Border {
+                radius: Radius {
+                    top_left: 20.0,
+                    top_right: 20.0,
+                    bottom_left: 20.0,
+                    bottom_right: 20.0,
+                },
+                ..Border::default()
+            },
+            ..container::Style::default()
+        }
+    }
+}
+
 //====== Message List Float View ======//
+/// A row of the conversation list: the default topic, a contact, or a peer
+/// that sent us direct messages without being a contact.
+struct ConversationEntry {
+    conversation: Conversation,
+    name: String,
+    contact: bool,
+    avatar: Option<image::Handle>,
+    preview: Option<String>,
+    last_time: Option<i64>,
+    last_seen: Option<i64>,
+    unread: usize,
+}
+
+impl ConversationEntry {
+    fn new(conversation: Conversation, name: String) -> Self {
+        Self {
+            conversation,
+            name,
+            contact: false,
+            avatar: None,
+            preview: None,
+            last_time: None,
+            last_seen: None,
+            unread: 0,
+        }
+    }
+
+    fn matches(&self, query: &str) -> bool {
+        let query = query.trim().to_lowercase();
+        self.name.to_lowercase().contains(&query)
+            || self.conversation.key().to_lowercase().contains(&query)
+    }
+}
+
 struct MessageListFloatView {
-    pub id: i32,
-    pub name: String,
     pub width: Length,
     pub height: Length,
-    pub content: String,
     pub search_query: String,
-    pub active_containers: Vec<bool>,
+    pub entries: Vec<ConversationEntry>,
+    // decoded avatars, so refreshing the list does not decode them again
+    pub avatars: HashMap<PeerId, image::Handle>,
+    // how the connection to each connected peer stands
+    pub hole_punches: HashMap<PeerId, HolePunchStatus>,
+    // peers on the local network, found through mDNS
+    pub nearby: Vec<PeerId>,
+    pub new_contact_peer: String,
+    pub new_contact_name: String,
+    pub invite_link: String,
+    // nickname and avatar file of the open direct conversation
+    pub nickname: String,
+    pub avatar_path: String,
+    pub status: Option<String>,
 }
 
 impl MessageListFloatView {
-    fn container_view(&self) -> Element<'_, Message> {
+    /// Nickname of the contact with the peer ID `peer`.
+    fn nickname_of(&self, peer: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|entry| entry.contact && entry.conversation.key() == peer)
+            .map(|entry| entry.name.as_str())
+    }
+
+    fn container_view(&self, open: Option<&Conversation>) -> Element<'_, Message> {
         let input: TextInput<'_, Message> =
             text_input::<Message, iced::theme::Theme, iced::Renderer>(
-                "Search messages",
+                "Search contacts",
                 &self.search_query,
             )
             .on_input(Message::ContentChanged)
@@ -400,38 +2235,50 @@ impl MessageListFloatView {
 
         let input_element: Element<'_, Message> = input.into();
 
-        let names = vec!["John Doe", "John Smith", "Jane Doe", "Alice Johnson"];
-
-        // Map each name to an index and create a button for each
-        let containers: Vec<Element<'_, Message>> = names
-            .into_iter()
-            .enumerate()
-            .map(|(index, name)| {
-                let is_active = self.active_containers.get(index).copied().unwrap_or(false);
+        // Create a button for each conversation matching the search
+        let containers: Vec<Element<'_, Message>> = self
+            .entries
+            .iter()
+            .filter(|entry| entry.matches(&self.search_query))
+            .map(|entry| {
+                let is_active = open == Some(&entry.conversation);
 
                 // Wrap the container in a button
                 Button::new(
-                    container(Text::new(name).size(16))
+                    container(self.entry_view(entry))
                         .padding(5)
                         .width(self.width)
                         .height(Length::Fixed(65.0))
                         .style(MessageListFloatView::message_ui_style(is_active)),
                 )
-                .on_press(Message::ContainerPressed(index)) // Handle press event
+                .on_press(Message::ConversationSelected(entry.conversation.clone()))
                 .style(self.button_style(is_active))
                 .into()
             })
             .collect();
 
         // Combine the buttons into a column
-        let mut content_column = Column::new().align_x(iced::Alignment::End).padding(10);
+        let mut entries_column = Column::new();
+        for button in containers {
+            entries_column = entries_column.push(button); // Push each button into the column
+        }
 
-        content_column = content_column
-            .push(input_element)
-            .push(Space::with_height(10)); // Push the search input box first
+        let mut content_column = Column::new()
+            .align_x(iced::Alignment::End)
+            .padding(10)
+            .spacing(10)
+            .push(input_element) // Push the search input box first
+            .push(scrollable(entries_column).height(Length::Fill));
 
-        for button in containers {
-            content_column = content_column.push(button); // Push each button into the column
+        if !self.nearby.is_empty() {
+            content_column = content_column.push(self.nearby_view(open));
+        }
+        if let Some(Conversation::Direct(_)) = open {
+            content_column = content_column.push(self.contact_view(open));
+        }
+        content_column = content_column.push(self.add_contact_view());
+        if let Some(status) = &self.status {
+            content_column = content_column.push(text(status).size(12).width(Length::Fill));
         }
 
         container(content_column)
@@ -442,13 +2289,172 @@ impl MessageListFloatView {
             .into()
     }
 
-    fn message_ui_style(is_active: bool) -> impl Fn(&Theme) -> container::Style {
+    fn entry_view<'a>(&self, entry: &'a ConversationEntry) -> Element<'a, Message> {
+        let avatar: Element<_> = match &entry.avatar {
+            Some(handle) => image(handle.clone()).width(40).height(40).into(),
+            None => container(text(initial(&entry.name)).size(16))
+                .center_x(40)
+                .center_y(40)
+                .style(MessageListFloatView::avatar_style())
+                .into(),
+        };
+
+        let unread: Element<_> = if entry.unread > 0 {
+            container(text(entry.unread).size(10))
+                .padding([2, 6])
+                .style(MessageListFloatView::badge_style())
+                .into()
+        } else {
+            Space::with_width(0).into()
+        };
+
+        let grey = Color::from_rgb(0.7, 0.7, 0.7);
+        let preview = entry.preview.as_deref().map(preview).unwrap_or_default();
+        let mut details = column![
+            row![text(&entry.name).size(16).width(Length::Fill), unread].align_y(Alignment::Center),
+            text(preview).size(12).color(grey),
+        ];
+        if let Some(last_seen) = entry.last_seen {
+            details = details.push(
+                text(format!("Last seen {}", format_time(last_seen)))
+                    .size(10)
+                    .color(grey),
+            );
+        }
+        if let Conversation::Direct(peer) = &entry.conversation {
+            if let Some(status) = self.hole_punches.get(peer) {
+                details = details.push(text(hole_punch_label(status)).size(10).color(grey));
+            }
+        }
+
+        row![avatar, details]
+            .spacing(10)
+            .align_y(Alignment::Center)
+            .into()
+    }
+
+    /// Rename, avatar and removal of the open direct conversation's contact,
+    /// or adding its peer as one.
+    fn contact_view(&self, open: Option<&Conversation>) -> Element<'_, Message> {
+        let contact = self
+            .entries
+            .iter()
+            .any(|entry| entry.contact && Some(&entry.conversation) == open);
+
+        let mut view = column![row![
+            text_input("Nickname", &self.nickname)
+                .on_input(Message::ContactNicknameChanged)
+                .on_submit(Message::SaveContact)
+                .size(12),
+            button(text(if contact { "Rename" } else { "Add to contacts" }).size(12))
+                .on_press(Message::SaveContact)
+                .style(settings_button_style),
+        ]
+        .spacing(10)]
+        .spacing(10);
+
+        if contact {
+            view = view.push(
+                row![
+                    text_input("Avatar image file (empty to clear)", &self.avatar_path)
+                        .on_input(Message::ContactAvatarPathChanged)
+                        .on_submit(Message::SetContactAvatar)
+                        .size(12),
+                    button(text("Set avatar").size(12))
+                        .on_press(Message::SetContactAvatar)
+                        .style(settings_button_style),
+                ]
+                .spacing(10),
+            );
+            view = view.push(
+                button(text("Remove contact").size(12))
+                    .on_press(Message::RemoveContact)
+                    .style(wipe_button_style),
+            );
+        }
+
+        view.into()
+    }
+
+    /// Peers on the local network, each opening its direct conversation.
+    fn nearby_view(&self, open: Option<&Conversation>) -> Element<'_, Message> {
+        let mut view = column![text("Nearby").size(14).width(Length::Fill)].spacing(5);
+        for peer in &self.nearby {
+            let conversation = Conversation::Direct(*peer);
+            let is_active = open == Some(&conversation);
+            let name = match self.nickname_of(&peer.to_base58()) {
+                Some(name) => name.to_string(),
+                None => short_peer_id(peer),
+            };
+            view = view.push(
+                button(text(name).size(12).width(Length::Fill))
+                    .on_press(Message::ConversationSelected(conversation))
+                    .style(self.button_style(is_active)),
+            );
+        }
+
+        view.into()
+    }
+
+    fn add_contact_view(&self) -> Element<'_, Message> {
+        column![
+            row![
+                text_input("Paste an invite link", &self.invite_link)
+                    .on_input(Message::InviteLinkChanged)
+                    .on_submit(Message::AcceptInvite)
+                    .size(12),
+                button(text("Import").size(12))
+                    .on_press(Message::AcceptInvite)
+                    .style(settings_button_style),
+            ]
+            .spacing(10),
+            text_input("Peer ID of a new contact", &self.new_contact_peer)
+                .on_input(Message::NewContactPeerChanged)
+                .size(12),
+            row![
+                text_input("Nickname", &self.new_contact_name)
+                    .on_input(Message::NewContactNameChanged)
+                    .on_submit(Message::AddContact)
+                    .size(12),
+                button(text("Add").size(12))
+                    .on_press(Message::AddContact)
+                    .style(settings_button_style),
+            ]
+            .spacing(10),
+        ]
+        .spacing(10)
+        .into()
+    }
+
+    fn message_ui_style(_is_active: bool) -> impl Fn(&Theme) -> container::Style {
         move |_| container::Style {
             text_color: Some(Color::WHITE),
             ..container::Style::default()
         }
     }
 
+    fn avatar_style() -> impl Fn(&Theme) -> container::Style {
+        move |_| container::Style {
+            background: Some(Color::from_rgb(0.45, 0.45, 0.45).into()),
+            border: Border {
+                radius: 20.0.into(),
+                ..Border::default()
+            },
+            ..container::Style::default()
+        }
+    }
+
+    fn badge_style() -> impl Fn(&Theme) -> container::Style {
+        move |_| container::Style {
+            background: Some(Color::from_rgb(0.8, 0.3, 0.3).into()),
+            text_color: Some(Color::WHITE),
+            border: Border {
+                radius: 8.0.into(),
+                ..Border::default()
+            },
+            ..container::Style::default()
+        }
+    }
     fn style() -> impl Fn(&Theme) -> container::Style {
         move |_| container::Style {
             background: Some(Color::from_rgb(0.3, 0.3, 0.3).into()),
@@ -509,39 +2515,122 @@ impl MessageListFloatView {
 
 impl Default for MessageListFloatView {
     fn default() -> Self {
-        let container_count = 4;
         Self {
-            id: 2,
-            name: String::from("MessageList"),
             width: Length::FillPortion(3),
             height: Length::Fill,
-            content: String::from("Search Messages"),
             search_query: String::new(),
-            active_containers: vec![false; container_count],
+            entries: Vec::new(),
+            avatars: HashMap::new(),
+            hole_punches: HashMap::new(),
+            nearby: Vec::new(),
+            new_contact_peer: String::new(),
+            new_contact_name: String::new(),
+            invite_link: String::new(),
+            nickname: String::new(),
+            avatar_path: String::new(),
+            status: None,
         }
     }
 }
 
+/// First letter of a name, shown in place of a missing avatar.
+fn initial(name: &str) -> String {
+    name.chars()
+        .find(|c| c.is_alphanumeric())
+        .map(|c| c.to_uppercase().to_string())
+        .unwrap_or_default()
+}
+
+/// First line of a message, cut to fit the conversation list.
+fn preview(body: &str) -> String {
+    const PREVIEW_LEN: usize = 32;
+
+    let line = body.lines().next().unwrap_or_default();
+    if line.chars().count() > PREVIEW_LEN || line.len() < body.len() {
+        let cut: String = line.chars().take(PREVIEW_LEN).collect();
+        format!("{cut}…")
+    } else {
+        line.to_string()
+    }
+}
+
+/// Abbreviated peer ID for peers that are not contacts.
+fn short_peer_id(peer: &PeerId) -> String {
+    let peer = peer.to_base58();
+    format!("{}…{}", &peer[..8], &peer[peer.len() - 6..])
+}
+
+/// Local time of a Unix timestamp in milliseconds, with the date unless it is today.
+fn format_time(millis: i64) -> String {
+    let time = DateTime::from_timestamp_millis(millis)
+        .map(|time| time.with_timezone(&Local))
+        .unwrap_or_default();
+    if time.date_naive() == Local::now().date_naive() {
+        time.format("%H:%M:%S").to_string()
+    } else {
+        time.format("%Y-%m-%d %H:%M").to_string()
+    }
+}
+
 //====== Message Float View ======//
+/// Messages loaded from the history at once.
+const HISTORY_PAGE: usize = 50;
+/// Distance from the top of the chat, in pixels, at which older messages are loaded.
+const LOAD_OLDER_THRESHOLD: f32 = 20.0;
+
 struct ChatMessage {
+    // position in the history, none if it could not be stored
+    row: Option<i64>,
     time: String,
     sender: String,
     body: String,
-    is_read: bool,
+    // direct messages we sent, tracked until the recipient acknowledges them
+    id: Option<MessageId>,
+    state: Option<DeliveryState>,
+}
+
+impl ChatMessage {
+    /// Message loaded from the history, with contacts shown by their nickname.
+    fn from_stored(msg: StoredMessage, contacts: &MessageListFloatView) -> Self {
+        let sender = match (msg.outgoing, &msg.sender) {
+            (true, _) => "Me".to_string(),
+            (false, Some(peer)) => contacts.nickname_of(peer).unwrap_or(peer).to_string(),
+            (false, None) => "Unknown".to_string(),
+        };
+
+        ChatMessage {
+            row: Some(msg.row),
+            time: format_time(msg.time),
+            sender,
+            body: msg.body,
+            id: msg.id,
+            state: msg.state,
+        }
+    }
+
+    fn state_label(&self) -> &'static str {
+        self.state.as_ref().map_or("", DeliveryState::as_str)
+    }
 }
 
 struct MessageFloatView {
-    pub id: i32,
-    pub name: String,
     pub width: Length,
     pub height: Length,
     pub input_message: String,
+    // peer id for direct messages, empty to broadcast
+    pub recipient: String,
+    // safety number of the conversation with the recipient
+    pub safety_number: Option<String>,
+    // conversation selected by the recipient, none if it is not a valid peer id
+    pub conversation: Option<Conversation>,
+    // whether the history holds messages before the first one shown
+    pub has_older: bool,
     pub message_scroll_id: Lazy<scrollable::Id>,
-    pub chat_message: Box<Vec<ChatMessage>>,
+    pub chat_message: Vec<ChatMessage>,
 }
 
 impl MessageFloatView {
-    fn container_view(&self) -> Element<Message> {
+    fn container_view(&self) -> Element<'_, Message> {
         // chat view
         let chat_view: Element<_> = if self.chat_message.is_empty() {
             center(text("Start a Conversation")).into()
@@ -564,11 +2653,23 @@ impl MessageFloatView {
                         .height(Length::Fixed(20.0))
                         .align_x(Alignment::End)
                         .align_y(Alignment::Center),
+                    text(msg.state_label())
+                        .size(8)
+                        .color(if msg.state == Some(DeliveryState::Failed) {
+                            Color::from_rgb(1.0, 0.4, 0.4)
+                        } else {
+                            Color::from_rgb(0.8, 0.8, 0.8)
+                        })
+                        .width(Length::Fixed(45.0))
+                        .height(Length::Fixed(20.0))
+                        .align_x(Alignment::End)
+                        .align_y(Alignment::Center),
                 ]
                 .into()
             })))
             .id(self.message_scroll_id.clone())
             .anchor_bottom()
+            .on_scroll(Message::ChatScrolled)
             .width(Length::Fill)
             .height(Length::Fill)
             .into()
@@ -578,7 +2679,7 @@ impl MessageFloatView {
         let message_input = {
             // input field
             let input = text_input("Message", &self.input_message)
-                .on_input(|content| Message::ChatInputChanged(content))
+                .on_input(Message::ChatInputChanged)
                 .on_submit(Message::SendMessage);
 
             // send button
@@ -603,8 +2704,26 @@ impl MessageFloatView {
             row![input, send_button].spacing(10)
         };
 
+        // recipient of direct messages
+        let recipient_input = text_input(
+            "Send directly to Peer ID (leave empty to send to everyone)",
+            &self.recipient,
+        )
+        .on_input(Message::RecipientChanged)
+        .size(12);
+
+        // compare out of band to rule out a man in the middle
+        let safety_number: Element<_> = match &self.safety_number {
+            Some(number) => text(format!("Safety number: {number}"))
+                .size(12)
+                .color(Color::from_rgb(0.6, 0.6, 0.6))
+                .into(),
+            None => Space::with_height(0).into(),
+        };
+
         // message view
-        let message_view = column![chat_view, message_input];
+        let message_view =
+            column![recipient_input, safety_number, chat_view, message_input].spacing(10);
         container(message_view)
             .padding(20)
             .width(self.width)
@@ -634,22 +2753,15 @@ impl MessageFloatView {
 impl Default for MessageFloatView {
     fn default() -> Self {
         Self {
-            id: 3,
-            name: String::from("Message"),
             width: Length::FillPortion(8),
             height: Length::Fill,
-            chat_message: Box::new(Vec::new()),
+            chat_message: Vec::new(),
             message_scroll_id: Lazy::new(scrollable::Id::unique),
             input_message: String::new(),
+            recipient: String::new(),
+            safety_number: None,
+            conversation: None,
+            has_older: false,
         }
     }
 }
-
-fn load_svg(name: &str) -> Result<svg::Handle, std::io::Error> {
-    let path = format!("{}/resources/{}.svg", env!("CARGO_MANIFEST_DIR"), name);
-
-    // Attempt to create the SVG handle
-    let handle = svg::Handle::from_path(path);
-
-    Ok(handle)
-}
@@ -0,0 +1,69 @@
+//! Two backends in one process, talking over loopback without a relay.
+
+use std::{net::TcpListener, time::Duration};
+
+use libp2p::{identity::Keypair, multiaddr::Protocol, Multiaddr};
+use limiinal_client::backend::network::{AppCore, BackendConfig, Event};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A loopback TCP address on a port nobody listens on.
+fn free_address() -> Multiaddr {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    format!("/ip4/127.0.0.1/tcp/{port}").parse().unwrap()
+}
+
+#[tokio::test]
+async fn direct_message_between_two_cores() {
+    let (alice_key, bob_key) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+    let bob_peer = bob_key.public().to_peer_id();
+    let bob_address = free_address();
+
+    let (alice, alice_handle, mut alice_events) = AppCore::new(
+        alice_key,
+        BackendConfig {
+            listen: vec![free_address()],
+            ..BackendConfig::default()
+        },
+    );
+    let (bob, _bob_handle, mut bob_events) = AppCore::new(
+        bob_key,
+        BackendConfig {
+            listen: vec![bob_address.clone()],
+            ..BackendConfig::default()
+        },
+    );
+    tokio::spawn(alice.run());
+    tokio::spawn(bob.run());
+
+    alice_handle.dial(bob_address.with(Protocol::P2p(bob_peer)));
+    tokio::time::timeout(TIMEOUT, async {
+        while let Some(event) = alice_events.recv().await {
+            if matches!(event, Event::PeerConnected(peer) if peer == bob_peer) {
+                return;
+            }
+        }
+        panic!("alice's core stopped");
+    })
+    .await
+    .expect("alice connected to bob in time");
+
+    alice_handle.send_direct(bob_peer, "hello bob");
+    let received = tokio::time::timeout(TIMEOUT, async {
+        while let Some(event) = bob_events.recv().await {
+            if let Event::DirectMessageReceived { body, .. } = event {
+                return body;
+            }
+        }
+        panic!("bob's core stopped");
+    })
+    .await
+    .expect("bob received the message in time");
+
+    assert_eq!(received, "hello bob");
+}
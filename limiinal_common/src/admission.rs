@@ -0,0 +1,39 @@
+//! The admission protocol, `/limiinal/admission/1.0.0`. A client that holds
+//! a token an operator minted for it sends the token to the relay before
+//! reserving, and the relay answers whether it accepted it.
+
+use libp2p::{
+    request_response::{self, ProtocolSupport},
+    StreamProtocol,
+};
+use serde::{Deserialize, Serialize};
+
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/limiinal/admission/1.0.0");
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdmissionRequest {
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum AdmissionResponse {
+    /// The peer may reserve until `expires`, in Unix seconds.
+    Accepted {
+        expires: u64,
+    },
+    Rejected {
+        reason: String,
+    },
+}
+
+pub type Behaviour = request_response::json::Behaviour<AdmissionRequest, AdmissionResponse>;
+
+/// The behaviour for one end of the protocol, `Outbound` for clients and
+/// `Inbound` for relays.
+pub fn new_behaviour(support: ProtocolSupport) -> Behaviour {
+    request_response::json::Behaviour::new(
+        [(PROTOCOL, support)],
+        request_response::Config::default(),
+    )
+}
@@ -0,0 +1,109 @@
+//! Plumbing shared by the TOML config files of the client and the relay:
+//! where they live, how they are read and the `[logging]` table both have.
+
+use std::{
+    error::Error,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Deserialize};
+use tracing_subscriber::EnvFilter;
+
+/// Name of the config file inside the config directory.
+const CONFIG_FILE: &str = "config.toml";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// The settings parse but cannot work together.
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "cannot read {}: {e}", path.display()),
+            ConfigError::Parse(path, e) => write!(f, "invalid config {}: {e}", path.display()),
+            ConfigError::Invalid(reason) => write!(f, "invalid config: {reason}"),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+/// Per-user config directory of `app`, e.g. `~/.config/<app>` on Linux.
+pub fn config_dir(app: &str) -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join(app)
+}
+
+/// Default location of the config file of `app`.
+pub fn default_path(app: &str) -> PathBuf {
+    config_dir(app).join(CONFIG_FILE)
+}
+
+pub fn read<T: DeserializeOwned>(path: &Path) -> Result<T, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+    toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+}
+
+/// Reads the file at `path`, or the default one of `app` if it exists.
+pub fn load<T: DeserializeOwned + Default>(
+    path: Option<&Path>,
+    app: &str,
+) -> Result<T, ConfigError> {
+    match path {
+        Some(path) => read(path),
+        None => match read(&default_path(app)) {
+            Err(ConfigError::Io(_, e)) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+            config => config,
+        },
+    }
+}
+
+/// Tells on stdout which file `config check` looks at, then whether
+/// `validate` accepts it. The error is left to the caller to report.
+pub fn check(
+    path: Option<&Path>,
+    app: &str,
+    validate: impl FnOnce() -> Result<(), ConfigError>,
+) -> Result<(), ConfigError> {
+    let shown = path.map_or_else(|| default_path(app), Path::to_path_buf);
+    if path.is_none() && !shown.exists() {
+        println!("No config file at {}, using the defaults", shown.display());
+    }
+
+    validate()?;
+    println!("{}: OK", shown.display());
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Log filter in `RUST_LOG` syntax, e.g. `info,libp2p_gossipsub=warn`.
+    /// `RUST_LOG` takes precedence when set.
+    pub filter: Option<String>,
+}
+
+impl LoggingConfig {
+    /// Filter for the `tracing` subscriber.
+    pub fn env_filter(&self) -> EnvFilter {
+        match (&self.filter, std::env::var_os(EnvFilter::DEFAULT_ENV)) {
+            (Some(filter), None) => EnvFilter::new(filter),
+            _ => EnvFilter::from_default_env(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(filter) = &self.filter {
+            EnvFilter::try_new(filter)
+                .map_err(|e| ConfigError::Invalid(format!("logging.filter: {e}")))?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    error::Error,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use libp2p::identity;
+
+/// Name of the file holding the keypair inside the data directory.
+const IDENTITY_FILE: &str = "identity.key";
+
+/// Per-user data directory of `app`, e.g. `~/.local/share/<app>` on Linux.
+pub fn data_dir(app: &str) -> PathBuf {
+    dirs::data_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join(app)
+}
+
+/// Default location of the identity file of `app`.
+pub fn default_path(app: &str) -> PathBuf {
+    data_dir(app).join(IDENTITY_FILE)
+}
+
+/// Loads the protobuf-encoded keypair stored at `path`, generating and
+/// persisting a new random ed25519 keypair if the file does not exist yet.
+pub fn load_or_generate(path: &Path) -> Result<identity::Keypair, Box<dyn Error>> {
+    if path.exists() {
+        let bytes = fs::read(path)?;
+        let keypair = identity::Keypair::from_protobuf_encoding(&bytes)?;
+        tracing::info!(path=%path.display(), peer=%keypair.public().to_peer_id(), "Loaded identity");
+
+        return Ok(keypair);
+    }
+
+    let keypair = identity::Keypair::generate_ed25519();
+    write_private(path, &keypair.to_protobuf_encoding()?)?;
+    tracing::info!(path=%path.display(), peer=%keypair.public().to_peer_id(), "Generated new identity");
+
+    Ok(keypair)
+}
+
+/// Builds a deterministic keypair from a single byte.
+///
+/// Only 256 distinct identities exist this way, so this must never be used
+/// outside of tests and local experiments.
+pub fn insecure_from_seed(seed: u8) -> identity::Keypair {
+    let mut bytes = [0u8; 32];
+    bytes[0] = seed;
+
+    identity::Keypair::ed25519_from_bytes(bytes).expect("only errors on wrong length")
+}
+
+/// Replaces `path` with `bytes`, readable and writable by the current user
+/// only. The bytes go to a temporary file next to it that is synced and then
+/// renamed over `path`, so a crash leaves either the old or the new contents.
+pub fn write_private(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let parent = path.parent().unwrap_or(Path::new(""));
+    create_private_dir(parent)?;
+
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    let temp = path.with_file_name(name);
+    // `mode` only applies when the file is created, so never reuse a leftover.
+    match fs::remove_file(&temp) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let written = options.open(&temp).and_then(|mut file| {
+        file.write_all(bytes)?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|()| fs::rename(&temp, path)) {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+
+    // Persist the rename itself.
+    #[cfg(unix)]
+    if !parent.as_os_str().is_empty() {
+        fs::File::open(parent)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Creates `dir` and its missing parents, accessible to the current user only.
+/// Directories that exist already are left as they are.
+pub fn create_private_dir(dir: &Path) -> io::Result<()> {
+    if dir.as_os_str().is_empty() || dir.exists() {
+        return Ok(());
+    }
+
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+
+    builder.create(dir)
+}
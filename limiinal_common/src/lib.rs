@@ -0,0 +1,9 @@
+//! Code the client and the relay share: identity files, the config file
+//! plumbing and the wire formats both ends of a protocol must agree on.
+
+pub mod admission;
+pub mod config;
+pub mod identity;
+pub mod presence;
+#[cfg(unix)]
+pub mod socket;
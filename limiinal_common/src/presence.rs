@@ -0,0 +1,170 @@
+//! Presence records in the Kademlia DHT. Every client publishes its current
+//! addresses under `/limiinal/presence/<peer id>`, signed with its identity
+//! key, and every node, client or relay, only stores records whose signature
+//! matches the peer ID in their key.
+
+use std::{
+    error::Error,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use libp2p::{
+    core::{peer_record, signed_envelope, PeerRecord, SignedEnvelope},
+    kad::{
+        self,
+        store::{self, MemoryStore, RecordStore},
+    },
+    PeerId, StreamProtocol,
+};
+
+/// Kademlia protocol of the Limiinal DHT.
+pub const KAD_PROTOCOL: StreamProtocol = StreamProtocol::new("/limiinal/kad/1.0.0");
+
+/// Prefix of the record keys, followed by the peer ID.
+const KEY_PREFIX: &str = "/limiinal/presence/";
+
+/// How long a record is kept, however long its publisher asked for.
+pub const PRESENCE_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug)]
+pub enum PresenceError {
+    Envelope(signed_envelope::DecodingError),
+    Record(peer_record::FromEnvelopeError),
+    /// The record is signed by another peer than its key names.
+    WrongKey,
+    /// A record with the same or a higher sequence number is stored already.
+    Stale {
+        seq: u64,
+        stored: u64,
+    },
+    Store(store::Error),
+}
+
+impl fmt::Display for PresenceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PresenceError::Envelope(e) => write!(f, "invalid envelope: {e}"),
+            PresenceError::Record(e) => write!(f, "invalid peer record: {e}"),
+            PresenceError::WrongKey => write!(f, "record is not stored under its peer ID"),
+            PresenceError::Stale { seq, stored } => {
+                write!(f, "record {seq} is not newer than the stored {stored}")
+            }
+            PresenceError::Store(e) => write!(f, "cannot store record: {e}"),
+        }
+    }
+}
+
+impl Error for PresenceError {}
+
+/// Key the presence record of `peer` is stored under.
+pub fn key(peer: &PeerId) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("{KEY_PREFIX}{peer}"))
+}
+
+/// Checks the signature of a presence record against the peer ID in its key.
+pub fn open(record: &kad::Record) -> Result<PeerRecord, PresenceError> {
+    let envelope =
+        SignedEnvelope::from_protobuf_encoding(&record.value).map_err(PresenceError::Envelope)?;
+    let peer_record = PeerRecord::from_signed_envelope(envelope).map_err(PresenceError::Record)?;
+    if record.key != key(&peer_record.peer_id()) {
+        return Err(PresenceError::WrongKey);
+    }
+
+    Ok(peer_record)
+}
+
+/// The Kademlia behaviour, leaving the records other peers send to
+/// [`store`].
+pub fn new_behaviour(peer: PeerId) -> kad::Behaviour<MemoryStore> {
+    let mut config = kad::Config::new(KAD_PROTOCOL);
+    config.set_record_filtering(kad::StoreInserts::FilterBoth);
+    kad::Behaviour::with_config(peer, MemoryStore::new(peer), config)
+}
+
+/// Stores a record another peer asked us to keep if it is a valid presence
+/// record newer than the one we have, so that a replayed old record cannot
+/// displace it. The same record may be stored again. It expires after
+/// [`PRESENCE_TTL`] at the latest.
+pub fn store(store: &mut MemoryStore, mut record: kad::Record) -> Result<(), PresenceError> {
+    let seq = open(&record)?.seq();
+    if let Some(stored) = store.get(&record.key) {
+        let stored_seq = open(&stored)?.seq();
+        if seq < stored_seq || (seq == stored_seq && stored.value != record.value) {
+            return Err(PresenceError::Stale {
+                seq,
+                stored: stored_seq,
+            });
+        }
+    }
+
+    let latest = Instant::now() + PRESENCE_TTL;
+    record.expires = Some(record.expires.map_or(latest, |expires| expires.min(latest)));
+    store.put(record).map_err(PresenceError::Store)
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{identity::Keypair, Multiaddr};
+
+    use super::*;
+
+    fn record(keypair: &Keypair, address: &str, expires: Option<Instant>) -> kad::Record {
+        let addresses = vec![address.parse::<Multiaddr>().unwrap()];
+        let envelope = PeerRecord::new(keypair, addresses)
+            .unwrap()
+            .into_signed_envelope();
+
+        kad::Record {
+            key: key(&keypair.public().to_peer_id()),
+            value: envelope.into_protobuf_encoding(),
+            publisher: None,
+            expires,
+        }
+    }
+
+    #[test]
+    fn rejects_records_signed_by_someone_else() {
+        let (owner, forger) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let mut forged = record(&forger, "/ip4/10.0.0.1/tcp/1", None);
+        forged.key = key(&owner.public().to_peer_id());
+
+        let mut store = MemoryStore::new(PeerId::random());
+        assert!(matches!(
+            super::store(&mut store, forged),
+            Err(PresenceError::WrongKey)
+        ));
+    }
+
+    #[test]
+    fn keeps_the_newest_record() {
+        let keypair = Keypair::generate_ed25519();
+        let old = record(&keypair, "/ip4/10.0.0.1/tcp/1", None);
+        std::thread::sleep(Duration::from_millis(1100));
+        let new = record(&keypair, "/ip4/10.0.0.2/tcp/1", None);
+
+        let mut store = MemoryStore::new(PeerId::random());
+        super::store(&mut store, new.clone()).unwrap();
+        super::store(&mut store, new.clone()).unwrap();
+        assert!(matches!(
+            super::store(&mut store, old),
+            Err(PresenceError::Stale { .. })
+        ));
+        assert_eq!(store.get(&new.key).unwrap().value, new.value);
+    }
+
+    #[test]
+    fn clamps_the_expiry() {
+        let keypair = Keypair::generate_ed25519();
+        let record = record(
+            &keypair,
+            "/ip4/10.0.0.1/tcp/1",
+            Some(Instant::now() + 100 * PRESENCE_TTL),
+        );
+
+        let mut store = MemoryStore::new(PeerId::random());
+        super::store(&mut store, record.clone()).unwrap();
+        let expires = store.get(&record.key).unwrap().expires.unwrap();
+        assert!(expires <= Instant::now() + PRESENCE_TTL);
+    }
+}
@@ -0,0 +1,46 @@
+//! Unix sockets of the local APIs, reachable by the current user only.
+
+use std::{
+    fs, io,
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+};
+
+use crate::identity::create_private_dir;
+
+/// Binds `path`, replacing a stale socket a crashed process left behind.
+///
+/// The socket is only ever created inside a directory no other user may
+/// enter: a missing one is created with mode 0700, and an existing one that
+/// others can access is refused rather than trusted.
+pub fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    create_private_dir(dir)?;
+    if fs::metadata(dir)?.permissions().mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} is accessible to other users, `chmod 700` it or put the socket elsewhere",
+                dir.display()
+            ),
+        ));
+    }
+
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("another process is listening on {}", path.display()),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+
+    UnixListener::bind(path)
+}
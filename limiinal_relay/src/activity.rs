@@ -0,0 +1,219 @@
+//! What the relay is doing for whom: open connections, reservations and
+//! circuits, followed from the swarm and relay events. Feeds the metrics
+//! gauges and the admin API.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use libp2p::{relay, swarm::ConnectionId, swarm::SwarmEvent, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+
+use crate::traffic::{Bytes, Traffic};
+
+#[derive(Debug)]
+struct Connection {
+    id: ConnectionId,
+    address: Multiaddr,
+    since: Instant,
+}
+
+#[derive(Debug)]
+struct Reservation {
+    since: Instant,
+    renewed: Instant,
+}
+
+#[derive(Debug)]
+struct Circuit {
+    src: PeerId,
+    dst: PeerId,
+    since: Instant,
+    /// Relayed bytes of both ends when the circuit opened.
+    src_relayed: u64,
+    dst_relayed: u64,
+}
+
+/// A connected peer, as reported by the admin API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerStatus {
+    pub peer: PeerId,
+    pub addresses: Vec<Multiaddr>,
+    /// Seconds since the oldest open connection was established.
+    pub connected_secs: u64,
+    pub bytes: Bytes,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReservationStatus {
+    pub peer: PeerId,
+    pub age_secs: u64,
+    /// Seconds until the reservation lapses unless the peer renews it.
+    pub expires_in_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CircuitStatus {
+    pub src: PeerId,
+    pub dst: PeerId,
+    pub age_secs: u64,
+    /// Bytes relayed from each end since the circuit opened. Exact while the
+    /// peer has no other circuit open, otherwise those share the count.
+    pub src_bytes: u64,
+    pub dst_bytes: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Activity {
+    connections: HashMap<PeerId, Vec<Connection>>,
+    reservations: HashMap<PeerId, Reservation>,
+    circuits: Vec<Circuit>,
+}
+
+impl Activity {
+    pub fn swarm<E>(&mut self, event: &SwarmEvent<E>) {
+        match event {
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                connection_id,
+                endpoint,
+                ..
+            } => {
+                self.connections
+                    .entry(*peer_id)
+                    .or_default()
+                    .push(Connection {
+                        id: *connection_id,
+                        address: endpoint.get_remote_address().clone(),
+                        since: Instant::now(),
+                    });
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                connection_id,
+                num_established,
+                ..
+            } => {
+                if let Some(connections) = self.connections.get_mut(peer_id) {
+                    connections.retain(|connection| connection.id != *connection_id);
+                }
+                // The relay drops the reservations of a peer with its last
+                // connection, without an event.
+                if *num_established == 0 {
+                    self.connections.remove(peer_id);
+                    self.reservations.remove(peer_id);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn relay(&mut self, event: &relay::Event, traffic: &Traffic) {
+        let now = Instant::now();
+        match event {
+            relay::Event::ReservationReqAccepted { src_peer_id, .. } => {
+                self.reservations
+                    .entry(*src_peer_id)
+                    .and_modify(|reservation| reservation.renewed = now)
+                    .or_insert(Reservation {
+                        since: now,
+                        renewed: now,
+                    });
+            }
+            relay::Event::ReservationTimedOut { src_peer_id } => {
+                self.reservations.remove(src_peer_id);
+            }
+            relay::Event::CircuitReqAccepted {
+                src_peer_id,
+                dst_peer_id,
+            } => {
+                self.circuits.push(Circuit {
+                    src: *src_peer_id,
+                    dst: *dst_peer_id,
+                    since: now,
+                    src_relayed: traffic.bytes(src_peer_id).relayed,
+                    dst_relayed: traffic.bytes(dst_peer_id).relayed,
+                });
+            }
+            relay::Event::CircuitClosed {
+                src_peer_id,
+                dst_peer_id,
+                ..
+            } => {
+                if let Some(index) = self
+                    .circuits
+                    .iter()
+                    .position(|c| c.src == *src_peer_id && c.dst == *dst_peer_id)
+                {
+                    self.circuits.remove(index);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn reservation_count(&self) -> usize {
+        self.reservations.len()
+    }
+
+    pub fn circuit_count(&self) -> usize {
+        self.circuits.len()
+    }
+
+    pub fn peers(&self, traffic: &Traffic) -> Vec<PeerStatus> {
+        let mut peers: Vec<_> = self
+            .connections
+            .iter()
+            .filter(|(_, connections)| !connections.is_empty())
+            .map(|(peer, connections)| PeerStatus {
+                peer: *peer,
+                addresses: connections.iter().map(|c| c.address.clone()).collect(),
+                connected_secs: connections
+                    .iter()
+                    .map(|c| c.since.elapsed().as_secs())
+                    .max()
+                    .unwrap_or_default(),
+                bytes: traffic.bytes(peer),
+            })
+            .collect();
+        peers.sort_by_key(|peer| std::cmp::Reverse(peer.connected_secs));
+        peers
+    }
+
+    /// Reservations held, lasting `duration` from their last renewal.
+    pub fn reservations(&self, duration: Duration) -> Vec<ReservationStatus> {
+        let mut reservations: Vec<_> = self
+            .reservations
+            .iter()
+            .map(|(peer, reservation)| ReservationStatus {
+                peer: *peer,
+                age_secs: reservation.since.elapsed().as_secs(),
+                expires_in_secs: duration
+                    .saturating_sub(reservation.renewed.elapsed())
+                    .as_secs(),
+            })
+            .collect();
+        reservations.sort_by_key(|reservation| std::cmp::Reverse(reservation.age_secs));
+        reservations
+    }
+
+    pub fn circuits(&self, traffic: &Traffic) -> Vec<CircuitStatus> {
+        self.circuits
+            .iter()
+            .map(|circuit| CircuitStatus {
+                src: circuit.src,
+                dst: circuit.dst,
+                age_secs: circuit.since.elapsed().as_secs(),
+                src_bytes: traffic
+                    .bytes(&circuit.src)
+                    .relayed
+                    .saturating_sub(circuit.src_relayed),
+                dst_bytes: traffic
+                    .bytes(&circuit.dst)
+                    .relayed
+                    .saturating_sub(circuit.dst_relayed),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,463 @@
+//! Local admin API: line-delimited JSON-RPC 2.0 on a Unix domain socket, by
+//! default `admin.sock` in the user data directory and only accessible to the
+//! user running the relay. It reports who is connected, holds a reservation
+//! or has a circuit open, and kicks or bans peers. `limiinal_relay status`
+//! and `limiinal_relay peer` are its command line clients.
+//!
+//! Every request is one JSON object per line, e.g.
+//! `{"jsonrpc":"2.0","id":1,"method":"ban","params":{"peer":"12D3KooW..."}}`,
+//! and gets exactly one response line with the same `id`.
+
+use std::{
+    collections::HashSet,
+    error::Error,
+    io,
+    path::{Path, PathBuf},
+};
+
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    activity::{CircuitStatus, PeerStatus, ReservationStatus},
+    allowlist,
+    config::{Config, ConfigError},
+    identity, Opt, PeerCommand, APP_NAME,
+};
+
+/// Name of the admin socket inside the data directory.
+const SOCKET_FILE: &str = "admin.sock";
+
+/// Name of the list of banned peers inside the data directory.
+const BANNED_FILE: &str = "banned_peers.txt";
+
+/// JSON-RPC error codes, see <https://www.jsonrpc.org/specification#error_object>.
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+
+/// What the admin API asks of the swarm.
+#[derive(Debug)]
+pub enum AdminRequest {
+    Status,
+    Moderate(Moderation),
+}
+
+/// Changes to who may use the relay.
+#[derive(Debug)]
+pub enum Moderation {
+    /// Close every connection to the peer, it may come back.
+    Kick(PeerId),
+    /// Disconnect the peer and refuse it from now on, across restarts.
+    Ban(PeerId),
+    Unban(PeerId),
+}
+
+/// A request handed to the swarm loop, answered through `reply`.
+#[derive(Debug)]
+pub struct Call {
+    pub request: AdminRequest,
+    pub reply: oneshot::Sender<Result<Value, String>>,
+}
+
+/// Answer to `status`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Status {
+    pub peer_id: PeerId,
+    pub uptime_secs: u64,
+    pub listen_addresses: Vec<Multiaddr>,
+    pub external_addresses: Vec<Multiaddr>,
+    pub peers: Vec<PeerStatus>,
+    pub reservations: Vec<ReservationStatus>,
+    pub circuits: Vec<CircuitStatus>,
+    pub banned: Vec<PeerId>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct PeerParams {
+    peer: PeerId,
+}
+
+/// Peers refused by the relay, kept in a file with one peer ID per line.
+#[derive(Debug)]
+pub struct Bans {
+    path: PathBuf,
+    peers: HashSet<PeerId>,
+}
+
+impl Bans {
+    /// Reads the banned peers from `path`, which need not exist yet.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let peers = match allowlist::read_list(path, "peer id") {
+            Ok((peers, _)) => peers,
+            Err(ConfigError::Io(_, e)) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Bans {
+            path: path.to_path_buf(),
+            peers,
+        })
+    }
+
+    pub fn peers(&self) -> Vec<PeerId> {
+        let mut peers: Vec<_> = self.peers.iter().copied().collect();
+        peers.sort_by_key(|peer| peer.to_base58());
+        peers
+    }
+
+    /// Bans `peer`, returning whether it was not banned before. Nothing
+    /// changes unless the file is written.
+    pub fn ban(&mut self, peer: PeerId) -> io::Result<bool> {
+        if self.peers.contains(&peer) {
+            return Ok(false);
+        }
+        let mut peers = self.peers.clone();
+        peers.insert(peer);
+        self.save(peers).map(|()| true)
+    }
+
+    /// Lifts the ban on `peer`, returning whether it was banned. Nothing
+    /// changes unless the file is written.
+    pub fn unban(&mut self, peer: &PeerId) -> io::Result<bool> {
+        if !self.peers.contains(peer) {
+            return Ok(false);
+        }
+        let mut peers = self.peers.clone();
+        peers.remove(peer);
+        self.save(peers).map(|()| true)
+    }
+
+    /// Replaces the file with `peers`, then takes them as the banned ones.
+    fn save(&mut self, peers: HashSet<PeerId>) -> io::Result<()> {
+        let mut sorted: Vec<_> = peers.iter().map(|peer| peer.to_base58()).collect();
+        sorted.sort();
+        let mut text = String::from("# Peers banned through the admin API\n");
+        for peer in sorted {
+            text.push_str(&format!("{peer}\n"));
+        }
+        identity::write_private(&self.path, text.as_bytes())?;
+
+        self.peers = peers;
+        Ok(())
+    }
+}
+
+/// Default location of the admin socket.
+pub fn default_socket_path() -> PathBuf {
+    identity::data_dir(APP_NAME).join(SOCKET_FILE)
+}
+
+/// Default location of the banned peer list.
+pub fn default_banned_path() -> PathBuf {
+    identity::data_dir(APP_NAME).join(BANNED_FILE)
+}
+
+/// Binds the admin socket and hands every request to `calls`.
+#[cfg(unix)]
+pub fn listen(socket: &Path, calls: mpsc::Sender<Call>) -> Result<(), Box<dyn Error>> {
+    let listener = bind(socket)?;
+    tracing::info!(socket=%socket.display(), "Admin API listening");
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(serve(stream, calls.clone()));
+                }
+                Err(e) => tracing::warn!("Failed to accept admin connection: {e}"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn listen(_socket: &Path, _calls: mpsc::Sender<Call>) -> Result<(), Box<dyn Error>> {
+    tracing::warn!("The admin API needs Unix sockets, it is off on this platform");
+    Ok(())
+}
+
+/// Binds the admin socket, replacing a stale one, in a directory only the
+/// current user may enter.
+#[cfg(unix)]
+fn bind(socket: &Path) -> Result<tokio::net::UnixListener, Box<dyn Error>> {
+    let listener = limiinal_common::socket::bind_private(socket).map_err(|e| e.to_string())?;
+    listener.set_nonblocking(true)?;
+
+    Ok(tokio::net::UnixListener::from_std(listener)?)
+}
+
+#[cfg(unix)]
+async fn serve(stream: tokio::net::UnixStream, calls: mpsc::Sender<Call>) {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) if line.trim().is_empty() => continue,
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                tracing::warn!("Failed to read admin request: {e}");
+                break;
+            }
+        };
+
+        let mut reply = handle_line(&line, &calls).await.to_string();
+        reply.push('\n');
+        if writer.write_all(reply.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn handle_line(line: &str, calls: &mpsc::Sender<Call>) -> Value {
+    let request: Request = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) => return error(Value::Null, PARSE_ERROR, e.to_string()),
+    };
+
+    let admin_request = match request.method.as_str() {
+        "status" => Ok(AdminRequest::Status),
+        "kick" => peer_params(request.params)
+            .map(Moderation::Kick)
+            .map(AdminRequest::Moderate),
+        "ban" => peer_params(request.params)
+            .map(Moderation::Ban)
+            .map(AdminRequest::Moderate),
+        "unban" => peer_params(request.params)
+            .map(Moderation::Unban)
+            .map(AdminRequest::Moderate),
+        method => Err((METHOD_NOT_FOUND, format!("unknown method `{method}`"))),
+    };
+    let admin_request = match admin_request {
+        Ok(admin_request) => admin_request,
+        Err((code, message)) => return error(request.id, code, message),
+    };
+
+    let (reply, result) = oneshot::channel();
+    let call = Call {
+        request: admin_request,
+        reply,
+    };
+    if calls.send(call).await.is_err() {
+        return error(
+            request.id,
+            INTERNAL_ERROR,
+            "relay is shutting down".to_string(),
+        );
+    }
+    match result.await {
+        Ok(Ok(result)) => json!({ "jsonrpc": "2.0", "id": request.id, "result": result }),
+        Ok(Err(message)) => error(request.id, INTERNAL_ERROR, message),
+        Err(_) => error(request.id, INTERNAL_ERROR, "request dropped".to_string()),
+    }
+}
+
+fn peer_params(params: Value) -> Result<PeerId, (i64, String)> {
+    serde_json::from_value::<PeerParams>(params)
+        .map(|params| params.peer)
+        .map_err(|e| (INVALID_PARAMS, e.to_string()))
+}
+
+fn error(id: Value, code: i64, message: String) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+/// Sends one request to the admin API of the relay `opt` configures.
+#[cfg(unix)]
+async fn call(opt: &Opt, method: &str, params: Value) -> Result<Value, Box<dyn Error>> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let socket = Config::load(opt)?.admin.socket_path();
+    let mut stream = tokio::net::UnixStream::connect(&socket)
+        .await
+        .map_err(|e| format!("cannot reach the relay at {}: {e}", socket.display()))?;
+    let mut request =
+        json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }).to_string();
+    request.push('\n');
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).await?;
+    let mut response: Value = serde_json::from_str(&line)?;
+    if let Some(message) = response["error"]["message"].as_str() {
+        return Err(message.into());
+    }
+
+    Ok(response["result"].take())
+}
+
+#[cfg(not(unix))]
+async fn call(_opt: &Opt, _method: &str, _params: Value) -> Result<Value, Box<dyn Error>> {
+    Err("the admin API needs Unix sockets".into())
+}
+
+/// Entry point of `limiinal_relay status`.
+pub async fn status(opt: &Opt, as_json: bool) -> Result<(), Box<dyn Error>> {
+    let result = call(opt, "status", Value::Null).await?;
+    if as_json {
+        println!("{}", serde_json::to_string_pretty(&result)?);
+        return Ok(());
+    }
+
+    let status: Status = serde_json::from_value(result)?;
+    println!(
+        "Relay {} up {}",
+        status.peer_id,
+        format_duration(status.uptime_secs)
+    );
+    for address in &status.listen_addresses {
+        println!("Listening on {address}");
+    }
+    for address in &status.external_addresses {
+        println!("External address {address}");
+    }
+
+    println!("\nPeers ({})", status.peers.len());
+    for peer in &status.peers {
+        let addresses: Vec<_> = peer.addresses.iter().map(Multiaddr::to_string).collect();
+        println!(
+            "  {}  {:>8}  in {}  out {}  relayed {}  {}",
+            peer.peer,
+            format_duration(peer.connected_secs),
+            format_bytes(peer.bytes.received),
+            format_bytes(peer.bytes.sent),
+            format_bytes(peer.bytes.relayed),
+            addresses.join(" "),
+        );
+    }
+
+    println!("\nReservations ({})", status.reservations.len());
+    for reservation in &status.reservations {
+        println!(
+            "  {}  {:>8}  expires in {}",
+            reservation.peer,
+            format_duration(reservation.age_secs),
+            format_duration(reservation.expires_in_secs),
+        );
+    }
+
+    println!("\nCircuits ({})", status.circuits.len());
+    for circuit in &status.circuits {
+        println!(
+            "  {} -> {}  {:>8}  {} / {}",
+            circuit.src,
+            circuit.dst,
+            format_duration(circuit.age_secs),
+            format_bytes(circuit.src_bytes),
+            format_bytes(circuit.dst_bytes),
+        );
+    }
+
+    if !status.banned.is_empty() {
+        println!("\nBanned ({})", status.banned.len());
+        for peer in &status.banned {
+            println!("  {peer}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Entry point of `limiinal_relay peer`.
+pub async fn peer(opt: &Opt, command: &PeerCommand) -> Result<(), Box<dyn Error>> {
+    let (method, peer) = match command {
+        PeerCommand::Kick { peer } => ("kick", peer),
+        PeerCommand::Ban { peer } => ("ban", peer),
+        PeerCommand::Unban { peer } => ("unban", peer),
+    };
+    let changed = call(opt, method, json!({ "peer": peer })).await?;
+
+    match (method, changed.as_bool().unwrap_or_default()) {
+        ("kick", true) => println!("Disconnected {peer}"),
+        ("kick", false) => println!("{peer} is not connected"),
+        ("ban", true) => println!("Banned {peer}"),
+        ("ban", false) => println!("{peer} was banned already"),
+        (_, true) => println!("Lifted the ban on {peer}"),
+        (_, false) => println!("{peer} is not banned"),
+    }
+    Ok(())
+}
+
+/// `1h 02m`, `3m 05s` or `42s`.
+fn format_duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("limiinal-bans-{}", PeerId::random()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn bans_survive_a_reload() {
+        let path = temp_dir().join("banned_peers.txt");
+        let (kept, lifted) = (PeerId::random(), PeerId::random());
+
+        let mut bans = Bans::load(&path).unwrap();
+        assert!(bans.ban(kept).unwrap());
+        assert!(bans.ban(lifted).unwrap());
+        assert!(!bans.ban(kept).unwrap());
+        assert!(bans.unban(&lifted).unwrap());
+        assert!(!bans.unban(&lifted).unwrap());
+
+        assert_eq!(Bans::load(&path).unwrap().peers(), vec![kept]);
+    }
+
+    #[test]
+    fn failed_saves_change_nothing() {
+        let dir = temp_dir();
+        let peer = PeerId::random();
+
+        let mut bans = Bans::load(&dir.join("banned_peers.txt")).unwrap();
+        // Nothing can be written below a file.
+        std::fs::remove_dir(&dir).unwrap();
+        std::fs::write(&dir, "").unwrap();
+        assert!(bans.ban(peer).is_err());
+        assert!(bans.peers().is_empty());
+    }
+}
@@ -0,0 +1,299 @@
+//! Token-based admission over `/limiinal/admission/1.0.0`. Before reserving,
+//! a client sends the token an operator minted for it; once accepted, the
+//! token's expiry and hourly quotas decide whether its reservations and the
+//! circuits it opens are let through. Tokens whose ID is listed in the
+//! revocation file stop working, and their holders are disconnected.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use libp2p::{request_response::ProtocolSupport, PeerId};
+
+pub use limiinal_common::admission::{AdmissionResponse, Behaviour};
+
+use crate::{
+    allowlist,
+    config::ConfigError,
+    token::{self, Claims, TokenError},
+};
+
+/// Period the token quotas are counted over.
+const QUOTA_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+pub fn new_behaviour() -> Behaviour {
+    limiinal_common::admission::new_behaviour(ProtocolSupport::Inbound)
+}
+
+/// Requests counted against an hourly limit.
+#[derive(Debug)]
+struct Quota {
+    per_hour: Option<u32>,
+    window_start: Instant,
+    used: u32,
+}
+
+impl Quota {
+    fn new(per_hour: Option<u32>) -> Self {
+        Quota {
+            per_hour,
+            window_start: Instant::now(),
+            used: 0,
+        }
+    }
+
+    fn take(&mut self, now: Instant) -> bool {
+        let Some(per_hour) = self.per_hour else {
+            return true;
+        };
+        if now.saturating_duration_since(self.window_start) >= QUOTA_WINDOW {
+            self.window_start = now;
+            self.used = 0;
+        }
+        if self.used >= per_hour {
+            return false;
+        }
+        self.used += 1;
+        true
+    }
+}
+
+/// What an accepted token grants its holder.
+#[derive(Debug)]
+struct Grant {
+    id: String,
+    expires: SystemTime,
+    reservations: Quota,
+    circuits: Quota,
+}
+
+/// Peers admitted with a token, shared with the rate limiters the relay
+/// behaviour asks.
+#[derive(Debug, Clone, Default)]
+pub struct Admitted(Arc<Mutex<HashMap<PeerId, Grant>>>);
+
+impl Admitted {
+    /// Whether `peer` holds a valid token with reservations left, using one.
+    pub fn reserve(&self, peer: &PeerId, now: Instant) -> bool {
+        match self.0.lock().unwrap().get_mut(peer) {
+            Some(grant) => grant.expires > SystemTime::now() && grant.reservations.take(now),
+            None => false,
+        }
+    }
+
+    /// Whether `peer` may open another circuit. Only admitted peers have a
+    /// circuit quota, the relay limits apply to everyone else. An expired
+    /// token allows no more circuits until it is forgotten.
+    pub fn open_circuit(&self, peer: &PeerId, now: Instant) -> bool {
+        match self.0.lock().unwrap().get_mut(peer) {
+            Some(grant) => grant.expires > SystemTime::now() && grant.circuits.take(now),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Admissions {
+    operators: HashSet<PeerId>,
+    revoked_path: PathBuf,
+    revoked: HashSet<String>,
+    modified: Option<SystemTime>,
+    admitted: Admitted,
+}
+
+impl Admissions {
+    /// Trusts tokens signed by `operators` unless their ID is listed in
+    /// `revoked_path`, which need not exist yet.
+    pub fn load(operators: &[PeerId], revoked_path: &Path) -> Result<Self, ConfigError> {
+        let (revoked, modified) = read_revoked(revoked_path)?;
+        tracing::info!(
+            operators = operators.len(),
+            revoked = revoked.len(),
+            "Admitting peers with tokens"
+        );
+
+        Ok(Admissions {
+            operators: operators.iter().copied().collect(),
+            revoked_path: revoked_path.to_path_buf(),
+            revoked,
+            modified,
+            admitted: Admitted::default(),
+        })
+    }
+
+    pub fn admitted(&self) -> Admitted {
+        self.admitted.clone()
+    }
+
+    /// Checks the token `peer` presented and admits it. Presenting the same
+    /// token again keeps the quotas already used.
+    pub fn admit(&self, peer: PeerId, token: &str) -> Result<Claims, TokenError> {
+        let claims = token::open(token)?;
+        if !self.operators.contains(&claims.operator) {
+            return Err(TokenError::UnknownOperator(claims.operator));
+        }
+        if claims.peer != peer {
+            return Err(TokenError::WrongPeer);
+        }
+        if claims.is_expired() {
+            return Err(TokenError::Expired);
+        }
+        if self.revoked.contains(&claims.id) {
+            return Err(TokenError::Revoked);
+        }
+
+        let mut admitted = self.admitted.0.lock().unwrap();
+        if admitted.get(&peer).map(|grant| &grant.id) != Some(&claims.id) {
+            admitted.insert(
+                peer,
+                Grant {
+                    id: claims.id.clone(),
+                    expires: claims.expires_at(),
+                    reservations: Quota::new(claims.reservations_per_hour),
+                    circuits: Quota::new(claims.circuits_per_hour),
+                },
+            );
+        }
+
+        Ok(claims)
+    }
+
+    /// Reads the revocation file again if it changed and forgets expired
+    /// tokens, returning the peers whose token was revoked.
+    pub fn reload(&mut self) -> Vec<PeerId> {
+        let now = SystemTime::now();
+        self.admitted
+            .0
+            .lock()
+            .unwrap()
+            .retain(|_, grant| grant.expires > now);
+
+        let modified = allowlist::modified(&self.revoked_path);
+        if modified == self.modified {
+            return Vec::new();
+        }
+        self.modified = modified;
+
+        match read_revoked(&self.revoked_path) {
+            Ok((revoked, _)) => self.revoked = revoked,
+            Err(e) => {
+                tracing::warn!("Keeping the previous revoked tokens: {e}");
+                return Vec::new();
+            }
+        }
+        tracing::info!(path=%self.revoked_path.display(), revoked=self.revoked.len(), "Reloaded revoked tokens");
+
+        let mut removed = Vec::new();
+        self.admitted.0.lock().unwrap().retain(|peer, grant| {
+            let keep = !self.revoked.contains(&grant.id);
+            if !keep {
+                removed.push(*peer);
+            }
+            keep
+        });
+        removed
+    }
+}
+
+/// The revoked token IDs, none while the file does not exist.
+fn read_revoked(path: &Path) -> Result<(HashSet<String>, Option<SystemTime>), ConfigError> {
+    match allowlist::read_list(path, "token id") {
+        Err(ConfigError::Io(_, e)) if e.kind() == io::ErrorKind::NotFound => {
+            Ok((HashSet::new(), None))
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::identity::Keypair;
+
+    use super::*;
+
+    fn token(operator: &Keypair, peer: PeerId, expires: SystemTime, circuits: u32) -> String {
+        token::mint(
+            operator,
+            &Claims {
+                id: "0123456789abcdef".to_string(),
+                operator: operator.public().to_peer_id(),
+                peer,
+                expires: expires
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+                reservations_per_hour: Some(1),
+                circuits_per_hour: Some(circuits),
+            },
+        )
+        .unwrap()
+    }
+
+    fn admissions(operator: &Keypair) -> Admissions {
+        let revoked =
+            std::env::temp_dir().join(format!("limiinal-no-revoked-{}", PeerId::random()));
+        Admissions::load(&[operator.public().to_peer_id()], &revoked).unwrap()
+    }
+
+    #[test]
+    fn admits_within_quotas() {
+        let operator = Keypair::generate_ed25519();
+        let peer = PeerId::random();
+        let admissions = admissions(&operator);
+        let in_a_day = SystemTime::now() + Duration::from_secs(24 * 60 * 60);
+        admissions
+            .admit(peer, &token(&operator, peer, in_a_day, 2))
+            .unwrap();
+
+        let admitted = admissions.admitted();
+        let now = Instant::now();
+        assert!(admitted.reserve(&peer, now));
+        assert!(!admitted.reserve(&peer, now));
+        assert!(admitted.open_circuit(&peer, now));
+        assert!(admitted.open_circuit(&peer, now));
+        assert!(!admitted.open_circuit(&peer, now));
+        assert!(admitted.reserve(&peer, now + QUOTA_WINDOW));
+    }
+
+    #[test]
+    fn refuses_tokens_it_cannot_trust() {
+        let (operator, stranger) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let peer = PeerId::random();
+        let admissions = admissions(&operator);
+        let in_a_day = SystemTime::now() + Duration::from_secs(24 * 60 * 60);
+
+        assert!(matches!(
+            admissions.admit(peer, &token(&stranger, peer, in_a_day, 1)),
+            Err(TokenError::UnknownOperator(_))
+        ));
+        assert!(matches!(
+            admissions.admit(PeerId::random(), &token(&operator, peer, in_a_day, 1)),
+            Err(TokenError::WrongPeer)
+        ));
+        assert!(matches!(
+            admissions.admit(peer, &token(&operator, peer, SystemTime::now(), 1)),
+            Err(TokenError::Expired)
+        ));
+    }
+
+    #[test]
+    fn expired_grants_allow_nothing() {
+        let operator = Keypair::generate_ed25519();
+        let peer = PeerId::random();
+        let admissions = admissions(&operator);
+        let in_a_day = SystemTime::now() + Duration::from_secs(24 * 60 * 60);
+        admissions
+            .admit(peer, &token(&operator, peer, in_a_day, 10))
+            .unwrap();
+
+        let admitted = admissions.admitted();
+        admitted.0.lock().unwrap().get_mut(&peer).unwrap().expires = SystemTime::now();
+        assert!(!admitted.reserve(&peer, Instant::now()));
+        assert!(!admitted.open_circuit(&peer, Instant::now()));
+        assert!(admitted.open_circuit(&PeerId::random(), Instant::now()));
+    }
+}
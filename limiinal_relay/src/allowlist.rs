@@ -0,0 +1,110 @@
+//! Allowlist mode: only the peers listed in a file may hold a reservation.
+//! The file holds one peer ID per line, blank lines and lines starting with
+//! `#` are skipped. It is read again whenever it changes, and peers dropped
+//! from it are disconnected so their reservations go away too.
+
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    hash::Hash,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use libp2p::PeerId;
+
+use crate::config::ConfigError;
+
+/// How often the file is checked for changes.
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub struct Allowlist {
+    path: PathBuf,
+    /// Shared with the rate limiter the relay behaviour asks.
+    peers: Arc<RwLock<HashSet<PeerId>>>,
+    modified: Option<SystemTime>,
+}
+
+impl Allowlist {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let (peers, modified) = read_list(path, "peer id")?;
+        tracing::info!(path=%path.display(), peers=peers.len(), "Loaded allowlist");
+
+        Ok(Allowlist {
+            path: path.to_path_buf(),
+            peers: Arc::new(RwLock::new(peers)),
+            modified,
+        })
+    }
+
+    /// The listed peers, kept up to date by [`Allowlist::reload`].
+    pub fn peers(&self) -> Arc<RwLock<HashSet<PeerId>>> {
+        self.peers.clone()
+    }
+
+    /// Reads the file again if it changed, returning the peers dropped from
+    /// it. A file that became unreadable or invalid leaves the list as it was.
+    pub fn reload(&mut self) -> Vec<PeerId> {
+        let modified = modified(&self.path);
+        if modified == self.modified {
+            return Vec::new();
+        }
+        self.modified = modified;
+
+        let peers = match read_list(&self.path, "peer id") {
+            Ok((peers, _)) => peers,
+            Err(e) => {
+                tracing::warn!("Keeping the previous allowlist: {e}");
+                return Vec::new();
+            }
+        };
+        tracing::info!(path=%self.path.display(), peers=peers.len(), "Reloaded allowlist");
+
+        let mut current = self.peers.write().unwrap();
+        let removed = current.difference(&peers).copied().collect();
+        *current = peers;
+        removed
+    }
+}
+
+/// Last modification time of `path`, `None` if it cannot be read.
+pub fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Reads a list with one `what` per line, skipping blank lines and `#`
+/// comments, together with the time the file was last modified.
+pub fn read_list<T>(
+    path: &Path,
+    what: &str,
+) -> Result<(HashSet<T>, Option<SystemTime>), ConfigError>
+where
+    T: FromStr + Eq + Hash,
+    T::Err: fmt::Display,
+{
+    let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+    let modified = modified(path);
+
+    let items = text
+        .lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            line.parse().map_err(|e| {
+                ConfigError::Invalid(format!(
+                    "{} line {}: invalid {what}: {e}",
+                    path.display(),
+                    number + 1
+                ))
+            })
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok((items, modified))
+}
@@ -0,0 +1,466 @@
+//! Settings read from a TOML file, by default `config.toml` in the per-user
+//! config directory (e.g. `~/.config/limiinal_relay` on Linux), with `--config`
+//! pointing elsewhere. Flags given on the command line take precedence.
+//!
+//! ```toml
+//! identity = "/var/lib/limiinal_relay/identity.key"
+//! port = 4001
+//! use_ipv6 = false
+//! external_addresses = ["/ip4/195.114.14.137/tcp/4001"]
+//!
+//! # Only the peers listed in this file, one per line, may reserve
+//! allowlist = "/etc/limiinal_relay/allowlist.txt"
+//!
+//! # Peers presenting a token signed by one of these operators may reserve too
+//! operators = ["12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN"]
+//! revoked_tokens = "/etc/limiinal_relay/revoked_tokens.txt"
+//!
+//! # Peers banned through the admin API, one per line
+//! banned_peers = "/var/lib/limiinal_relay/banned_peers.txt"
+//!
+//! [limits]
+//! max_reservations = 128
+//! max_circuit_bytes = 131072
+//! reservation_rate_per_ip = { limit = 60, interval_secs = 60 }
+//!
+//! [websocket]
+//! port = 443
+//! certificate = "/etc/letsencrypt/live/relay.example.com/fullchain.pem"
+//! private_key = "/etc/letsencrypt/live/relay.example.com/privkey.pem"
+//!
+//! [metrics]
+//! listen = "127.0.0.1:9090"
+//!
+//! [admin]
+//! socket = "/run/limiinal_relay/admin.sock"
+//!
+//! [logging]
+//! filter = "info"
+//! ```
+
+use std::{
+    fs,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use libp2p::{multiaddr::Protocol, relay, websocket::tls, Multiaddr, PeerId};
+use serde::Deserialize;
+
+use crate::{
+    admin::{self, Bans},
+    admission::Admissions,
+    allowlist::Allowlist,
+    identity, token, Opt, APP_NAME,
+};
+
+pub use limiinal_common::config::{ConfigError, LoggingConfig};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Path of the identity file, defaults to `identity.key` in the user data directory.
+    pub identity: Option<PathBuf>,
+    /// Port to listen on over TCP and QUIC on all interfaces.
+    pub port: u16,
+    pub use_ipv6: bool,
+    /// Addresses to listen on instead of the ones derived from `port` and `use_ipv6`.
+    pub listen: Vec<Multiaddr>,
+    /// Public addresses announced right away. Without them the addresses
+    /// clients observe us at are announced once AutoNAT confirmed them.
+    pub external_addresses: Vec<Multiaddr>,
+    /// Protocol version announced over identify.
+    pub identify_protocol: String,
+    /// File listing the only peers allowed to reserve, anyone may when unset.
+    pub allowlist: Option<PathBuf>,
+    /// Operators whose admission tokens are accepted, tokens are ignored when empty.
+    pub operators: Vec<PeerId>,
+    /// File listing revoked token IDs, defaults to `revoked_tokens.txt` in the user data directory.
+    pub revoked_tokens: Option<PathBuf>,
+    /// File listing peers refused on connection, defaults to `banned_peers.txt` in the user data directory.
+    pub banned_peers: Option<PathBuf>,
+    pub limits: LimitsConfig,
+    pub websocket: WebsocketConfig,
+    pub metrics: MetricsConfig,
+    pub admin: AdminConfig,
+    pub logging: LoggingConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            identity: None,
+            port: 4001,
+            use_ipv6: false,
+            listen: Vec::new(),
+            external_addresses: Vec::new(),
+            identify_protocol: "/limiinal/0.1.0".to_string(),
+            allowlist: None,
+            operators: Vec::new(),
+            revoked_tokens: None,
+            banned_peers: None,
+            limits: LimitsConfig::default(),
+            websocket: WebsocketConfig::default(),
+            metrics: MetricsConfig::default(),
+            admin: AdminConfig::default(),
+            logging: LoggingConfig::default(),
+        }
+    }
+}
+
+/// How much of the relay its clients may use. The defaults are those of libp2p.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_reservations: usize,
+    pub max_reservations_per_peer: usize,
+    pub reservation_duration_secs: u64,
+    pub reservation_rate_per_peer: RateLimit,
+    pub reservation_rate_per_ip: RateLimit,
+    pub max_circuits: usize,
+    pub max_circuits_per_peer: usize,
+    /// How long a circuit may stay open, in seconds.
+    pub max_circuit_duration_secs: u64,
+    /// How many bytes a circuit may carry in each direction.
+    pub max_circuit_bytes: u64,
+    /// Circuits a peer or address may open through the relay.
+    pub circuit_rate_per_peer: RateLimit,
+    pub circuit_rate_per_ip: RateLimit,
+}
+
+/// Token bucket holding up to `limit` requests, one more every `interval_secs`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    pub limit: NonZeroU32,
+    pub interval_secs: u64,
+}
+
+impl RateLimit {
+    fn new(limit: u32, interval_secs: u64) -> Self {
+        RateLimit {
+            limit: NonZeroU32::new(limit).expect("limit > 0"),
+            interval_secs,
+        }
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_reservations: 128,
+            max_reservations_per_peer: 4,
+            reservation_duration_secs: 60 * 60,
+            reservation_rate_per_peer: RateLimit::new(30, 2 * 60),
+            reservation_rate_per_ip: RateLimit::new(60, 60),
+            max_circuits: 16,
+            max_circuits_per_peer: 4,
+            max_circuit_duration_secs: 2 * 60,
+            max_circuit_bytes: 1 << 17,
+            circuit_rate_per_peer: RateLimit::new(30, 2 * 60),
+            circuit_rate_per_ip: RateLimit::new(60, 60),
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn build(&self) -> relay::Config {
+        relay::Config {
+            max_reservations: self.max_reservations,
+            max_reservations_per_peer: self.max_reservations_per_peer,
+            reservation_duration: Duration::from_secs(self.reservation_duration_secs),
+            reservation_rate_limiters: Vec::new(),
+            max_circuits: self.max_circuits,
+            max_circuits_per_peer: self.max_circuits_per_peer,
+            max_circuit_duration: Duration::from_secs(self.max_circuit_duration_secs),
+            max_circuit_bytes: self.max_circuit_bytes,
+            circuit_src_rate_limiters: Vec::new(),
+        }
+        .reservation_rate_per_peer(
+            self.reservation_rate_per_peer.limit,
+            self.reservation_rate_per_peer.interval(),
+        )
+        .reservation_rate_per_ip(
+            self.reservation_rate_per_ip.limit,
+            self.reservation_rate_per_ip.interval(),
+        )
+        .circuit_src_per_peer(
+            self.circuit_rate_per_peer.limit,
+            self.circuit_rate_per_peer.interval(),
+        )
+        .circuit_src_per_ip(
+            self.circuit_rate_per_ip.limit,
+            self.circuit_rate_per_ip.interval(),
+        )
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let rates = [
+            ("reservation_rate_per_peer", self.reservation_rate_per_peer),
+            ("reservation_rate_per_ip", self.reservation_rate_per_ip),
+            ("circuit_rate_per_peer", self.circuit_rate_per_peer),
+            ("circuit_rate_per_ip", self.circuit_rate_per_ip),
+        ];
+        for (name, rate) in rates {
+            if rate.interval_secs == 0 {
+                return Err(ConfigError::Invalid(format!(
+                    "limits.{name}.interval_secs must be at least 1"
+                )));
+            }
+        }
+        if self.reservation_duration_secs == 0 || self.max_circuit_duration_secs == 0 {
+            return Err(ConfigError::Invalid(
+                "limits durations must be at least 1 second".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebsocketConfig {
+    /// Port to also listen on over WebSocket, e.g. 443 for networks that only
+    /// let HTTPS out. Off when unset.
+    pub port: Option<u16>,
+    /// PEM certificate chain and private key. With both set the WebSocket
+    /// listener speaks WSS, otherwise plain WS.
+    pub certificate: Option<PathBuf>,
+    pub private_key: Option<PathBuf>,
+}
+
+impl WebsocketConfig {
+    /// TLS settings of the WSS listener, if a certificate is configured.
+    pub fn tls(&self) -> Result<Option<tls::Config>, ConfigError> {
+        let (Some(certificate), Some(private_key)) = (&self.certificate, &self.private_key) else {
+            return Ok(None);
+        };
+
+        let certificates = read_pem(certificate)?
+            .into_iter()
+            .filter(|pem| pem.tag() == "CERTIFICATE")
+            .map(|pem| tls::Certificate::new(pem.into_contents()))
+            .collect::<Vec<_>>();
+        if certificates.is_empty() {
+            return Err(ConfigError::Invalid(format!(
+                "no certificate in {}",
+                certificate.display()
+            )));
+        }
+        let key = read_pem(private_key)?
+            .into_iter()
+            .find(|pem| {
+                matches!(
+                    pem.tag(),
+                    "PRIVATE KEY" | "RSA PRIVATE KEY" | "EC PRIVATE KEY"
+                )
+            })
+            .ok_or_else(|| {
+                ConfigError::Invalid(format!("no private key in {}", private_key.display()))
+            })?;
+
+        tls::Config::new(tls::PrivateKey::new(key.into_contents()), certificates)
+            .map(Some)
+            .map_err(|e| ConfigError::Invalid(format!("websocket certificate: {e}")))
+    }
+}
+
+fn read_pem(path: &Path) -> Result<Vec<pem::Pem>, ConfigError> {
+    let text = fs::read(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+    pem::parse_many(text).map_err(|e| ConfigError::Invalid(format!("{}: {e}", path.display())))
+}
+
+/// Whether `address` is a WSS one, which needs a certificate to listen on.
+fn is_secure_websocket(address: &Multiaddr) -> bool {
+    address
+        .iter()
+        .any(|protocol| matches!(protocol, Protocol::Tls | Protocol::Wss(_)))
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address to serve Prometheus metrics at `/metrics` on, off when unset.
+    /// Keep it on loopback or a private network, the endpoint has no authentication.
+    pub listen: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Serve the admin API `limiinal_relay status` and `limiinal_relay peer` talk to.
+    pub enabled: bool,
+    /// Unix socket of the admin API, defaults to `admin.sock` in the user data directory.
+    pub socket: Option<PathBuf>,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        AdminConfig {
+            enabled: true,
+            socket: None,
+        }
+    }
+}
+
+impl AdminConfig {
+    /// Path of the admin socket.
+    pub fn socket_path(&self) -> PathBuf {
+        self.socket
+            .clone()
+            .unwrap_or_else(admin::default_socket_path)
+    }
+}
+
+impl Config {
+    /// Reads the config file named by `--config`, or the default one if it
+    /// exists, and applies the command line flags on top.
+    pub fn load(opt: &Opt) -> Result<Self, ConfigError> {
+        let mut config: Config = limiinal_common::config::load(opt.config.as_deref(), APP_NAME)?;
+
+        if let Some(identity) = &opt.identity {
+            config.identity = Some(identity.clone());
+        }
+        if let Some(port) = opt.port {
+            config.port = port;
+        }
+        if let Some(use_ipv6) = opt.use_ipv6 {
+            config.use_ipv6 = use_ipv6;
+        }
+        // External addresses given on the command line replace the configured ones.
+        if !opt.external_address.is_empty() {
+            config.external_addresses = opt.external_address.clone();
+        }
+
+        Ok(config)
+    }
+
+    /// Path of the identity file.
+    pub fn identity_path(&self) -> PathBuf {
+        self.identity
+            .clone()
+            .unwrap_or_else(|| identity::default_path(APP_NAME))
+    }
+
+    /// Path of the revoked token list.
+    pub fn revoked_tokens_path(&self) -> PathBuf {
+        self.revoked_tokens
+            .clone()
+            .unwrap_or_else(token::default_revoked_path)
+    }
+
+    /// Path of the banned peer list.
+    pub fn banned_peers_path(&self) -> PathBuf {
+        self.banned_peers
+            .clone()
+            .unwrap_or_else(admin::default_banned_path)
+    }
+
+    /// Addresses to listen on: the configured ones, or TCP and QUIC on `port`
+    /// on all interfaces, plus WebSocket on `websocket.port` if set.
+    pub fn listen_addresses(&self) -> Vec<Multiaddr> {
+        if !self.listen.is_empty() {
+            return self.listen.clone();
+        }
+
+        let ip = if self.use_ipv6 {
+            Protocol::from(Ipv6Addr::UNSPECIFIED)
+        } else {
+            Protocol::from(Ipv4Addr::UNSPECIFIED)
+        };
+        let mut addresses = vec![
+            Multiaddr::empty()
+                .with(ip.clone())
+                .with(Protocol::Tcp(self.port)),
+            Multiaddr::empty()
+                .with(ip.clone())
+                .with(Protocol::Udp(self.port))
+                .with(Protocol::QuicV1),
+        ];
+        if let Some(port) = self.websocket.port {
+            let websocket = if self.websocket.certificate.is_some() {
+                Protocol::Wss("/".into())
+            } else {
+                Protocol::Ws("/".into())
+            };
+            addresses.push(
+                Multiaddr::empty()
+                    .with(ip)
+                    .with(Protocol::Tcp(port))
+                    .with(websocket),
+            );
+        }
+
+        addresses
+    }
+
+    /// Checks what parsing alone cannot: that the settings are usable together.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.identify_protocol.is_empty() {
+            return Err(ConfigError::Invalid(
+                "identify_protocol is empty".to_string(),
+            ));
+        }
+        if self.websocket.certificate.is_some() != self.websocket.private_key.is_some() {
+            return Err(ConfigError::Invalid(
+                "websocket.certificate and websocket.private_key go together".to_string(),
+            ));
+        }
+        if self.listen.is_empty() && self.websocket.port == Some(self.port) {
+            return Err(ConfigError::Invalid(
+                "websocket.port must differ from port".to_string(),
+            ));
+        }
+        if let Some(metrics) = self.metrics.listen {
+            if self.listen.is_empty()
+                && [Some(self.port), self.websocket.port].contains(&Some(metrics.port()))
+            {
+                return Err(ConfigError::Invalid(
+                    "metrics.listen must use another port than the relay".to_string(),
+                ));
+            }
+        }
+        let tls = self.websocket.tls()?;
+        if tls.is_none() {
+            if let Some(address) = self.listen.iter().find(|a| is_secure_websocket(a)) {
+                return Err(ConfigError::Invalid(format!(
+                    "listening on {address} needs websocket.certificate and websocket.private_key"
+                )));
+            }
+        }
+        self.limits.validate()?;
+        if let Some(path) = &self.allowlist {
+            Allowlist::load(path)?;
+        }
+        if let Some(operator) = self
+            .operators
+            .iter()
+            .find(|operator| token::operator_key(operator).is_none())
+        {
+            return Err(ConfigError::Invalid(format!(
+                "operator {operator} has no ed25519 key to check tokens with"
+            )));
+        }
+        if !self.operators.is_empty() {
+            Admissions::load(&self.operators, &self.revoked_tokens_path())?;
+        }
+        Bans::load(&self.banned_peers_path())?;
+        self.logging.validate()?;
+
+        Ok(())
+    }
+}
+
+/// Entry point of `limiinal_relay config check`.
+pub fn check(opt: &Opt) -> Result<(), ConfigError> {
+    limiinal_common::config::check(opt.config.as_deref(), APP_NAME, || {
+        Config::load(opt)?.validate()
+    })
+}
@@ -21,115 +21,548 @@
 
 #![doc = include_str!("../README.md")]
 
+mod activity;
+mod admin;
+mod admission;
+mod allowlist;
+mod config;
+mod metrics;
+mod presence;
+mod token;
+mod traffic;
+
 use std::{
     error::Error,
-    net::{Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use clap::Parser;
-use futures::StreamExt;
+use futures::{future::Either, StreamExt};
 use libp2p::{
-    core::{multiaddr::Protocol, Multiaddr},
-    identify, identity, noise, ping, relay,
-    swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux,
+    allow_block_list::{self, BlockedPeers},
+    autonat,
+    core::{muxing::StreamMuxerBox, transport::Boxed, upgrade::Version, Transport},
+    identify,
+    identity::Keypair,
+    kad,
+    metrics::Registry,
+    noise, ping, quic, relay, rendezvous, request_response,
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour, SwarmEvent},
+    tcp, websocket, yamux, Multiaddr, PeerId, Swarm,
 };
-use tracing_subscriber::EnvFilter;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use activity::Activity;
+use admin::{AdminRequest, Bans, Moderation};
+use admission::{AdmissionResponse, Admissions};
+use allowlist::Allowlist;
+use config::Config;
+use limiinal_common::identity;
+use metrics::Metrics;
+use traffic::Traffic;
+
+/// Name of the relay's per-user directories, e.g. `~/.local/share/limiinal_relay` on Linux.
+const APP_NAME: &str = "limiinal_relay";
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    let opt = Opt::parse();
+    match &opt.command {
+        Some(Command::Config {
+            command: ConfigCommand::Check,
+        }) => {
+            if let Err(e) = config::check(&opt) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Token { command }) => return token::run(&opt, command),
+        Some(Command::Status { json }) => return admin::status(&opt, *json).await,
+        Some(Command::Peer { command }) => return admin::peer(&opt, command).await,
+        None => {}
+    }
+
+    let config = Config::load(&opt)?;
+    config.validate()?;
     let _ = tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
+        .with_env_filter(config.logging.env_filter())
         .try_init();
 
-    let opt = Opt::parse();
+    let local_key = match opt.insecure_test_seed {
+        Some(seed) => {
+            tracing::warn!("Using an insecure identity derived from --insecure-test-seed");
+            identity::insecure_from_seed(seed)
+        }
+        None => identity::load_or_generate(&config.identity_path())?,
+    };
 
-    // Create a static known PeerId based on given secret
-    let local_key: identity::Keypair = generate_ed25519(opt.secret_key_seed);
+    let tls = config.websocket.tls()?;
+    let mut allowlist = config
+        .allowlist
+        .as_deref()
+        .map(Allowlist::load)
+        .transpose()?;
+    let mut admissions = (!config.operators.is_empty())
+        .then(|| Admissions::load(&config.operators, &config.revoked_tokens_path()))
+        .transpose()?;
+    let mut bans = Bans::load(&config.banned_peers_path())?;
+
+    let mut relay_config = config.limits.build();
+
+    // Reservations hand out our external addresses. Without one, clients would
+    // fail with `NoAddressesInReservation`, so refuse them until AutoNAT
+    // confirms an address. This goes first so refusals cost no quota.
+    let addressed = Arc::new(AtomicBool::new(!config.external_addresses.is_empty()));
+    if config.external_addresses.is_empty() {
+        tracing::warn!("No external address configured, refusing reservations until AutoNAT confirms one; pass --external-address if the public address is known");
+    }
+    {
+        let addressed = addressed.clone();
+        relay_config.reservation_rate_limiters.insert(
+            0,
+            Box::new(move |peer, _: &Multiaddr, _| {
+                let addressed = addressed.load(Ordering::Relaxed);
+                if !addressed {
+                    tracing::warn!(%peer, "Refusing reservation, no external address confirmed yet");
+                }
+                addressed
+            }),
+        );
+    }
+
+    // With an allowlist or operators configured, only listed peers and peers
+    // holding a token with reservations left may reserve.
+    let allowed = allowlist.as_ref().map(Allowlist::peers);
+    let admitted = admissions.as_ref().map(Admissions::admitted);
+    if allowed.is_some() || admitted.is_some() {
+        let admitted = admitted.clone();
+        relay_config
+            .reservation_rate_limiters
+            .push(Box::new(move |peer, _: &Multiaddr, now| {
+                allowed
+                    .as_ref()
+                    .is_some_and(|peers| peers.read().unwrap().contains(&peer))
+                    || admitted
+                        .as_ref()
+                        .is_some_and(|admitted| admitted.reserve(&peer, now))
+            }));
+    }
+    if let Some(admitted) = admitted {
+        relay_config
+            .circuit_src_rate_limiters
+            .push(Box::new(move |peer, _: &Multiaddr, now| {
+                admitted.open_circuit(&peer, now)
+            }));
+    }
+
+    let mut registry = Registry::default();
+    let mut metrics = Metrics::new(&mut registry);
+    let traffic = Traffic::new(metrics.relayed_bytes());
+    let counted = traffic.clone();
 
     let mut swarm = libp2p::SwarmBuilder::with_existing_identity(local_key)
         .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),
-            noise::Config::new,
-            yamux::Config::default,
-        )?
-        .with_quic()
+        .with_other_transport(|key| transport(key, tls, counted))?
+        .with_bandwidth_metrics(&mut registry)
         .with_behaviour(|key| Behaviour {
-            relay: relay::Behaviour::new(key.public().to_peer_id(), Default::default()),
+            relay: relay::Behaviour::new(key.public().to_peer_id(), relay_config),
             ping: ping::Behaviour::new(ping::Config::new()),
             identify: identify::Behaviour::new(identify::Config::new(
-                "/TODO/0.0.1".to_string(),
+                config.identify_protocol.clone(),
                 key.public(),
             )),
+            rendezvous: rendezvous::server::Behaviour::new(rendezvous::server::Config::default()),
+            kademlia: presence::new_behaviour(key.public().to_peer_id()),
+            autonat: autonat::Behaviour::new(key.public().to_peer_id(), autonat::Config::default()),
+            admission: Toggle::from(admissions.is_some().then(admission::new_behaviour)),
+            blocked: allow_block_list::Behaviour::default(),
         })?
         .build();
 
-    // Listen on all interfaces
-    let listen_addr_tcp = Multiaddr::empty()
-        .with(match opt.use_ipv6 {
-            Some(true) => Protocol::from(Ipv6Addr::UNSPECIFIED),
-            _ => Protocol::from(Ipv4Addr::UNSPECIFIED),
-        })
-        .with(Protocol::Tcp(opt.port));
-    swarm.listen_on(listen_addr_tcp)?;
+    for peer in bans.peers() {
+        swarm.behaviour_mut().blocked.block_peer(peer);
+    }
 
-    let listen_addr_quic = Multiaddr::empty()
-        .with(match opt.use_ipv6 {
-            Some(true) => Protocol::from(Ipv6Addr::UNSPECIFIED),
-            _ => Protocol::from(Ipv4Addr::UNSPECIFIED),
-        })
-        .with(Protocol::Udp(opt.port))
-        .with(Protocol::QuicV1);
-    swarm.listen_on(listen_addr_quic)?;
+    // Addresses the operator vouches for are announced right away, the ones
+    // peers observe only once AutoNAT confirmed them.
+    for address in &config.external_addresses {
+        swarm.add_external_address(address.clone());
+    }
+
+    for address in config.listen_addresses() {
+        swarm.listen_on(address)?;
+    }
 
+    if let Some(listen) = config.metrics.listen {
+        metrics::serve(listen, registry).await?;
+    }
+
+    // The sender stays alive as long as the relay runs, so the channel never
+    // closes, with the admin API disabled too.
+    let (admin_sender, mut admin_calls) = mpsc::channel(16);
+    if config.admin.enabled {
+        admin::listen(&config.admin.socket_path(), admin_sender.clone())?;
+    }
+
+    let started = Instant::now();
+    let reservation_duration = Duration::from_secs(config.limits.reservation_duration_secs);
+    let mut activity = Activity::default();
+    let mut reload = tokio::time::interval(allowlist::RELOAD_INTERVAL);
     loop {
-        match swarm.next().await.expect("Infinite Stream.") {
+        let event = tokio::select! {
+            event = swarm.next() => event.expect("Infinite Stream."),
+            Some(call) = admin_calls.recv() => {
+                let result = match call.request {
+                    AdminRequest::Status => {
+                        let status = admin::Status {
+                            peer_id: *swarm.local_peer_id(),
+                            uptime_secs: started.elapsed().as_secs(),
+                            listen_addresses: swarm.listeners().cloned().collect(),
+                            external_addresses: swarm.external_addresses().cloned().collect(),
+                            peers: activity.peers(&traffic),
+                            reservations: activity.reservations(reservation_duration),
+                            circuits: activity.circuits(&traffic),
+                            banned: bans.peers(),
+                        };
+                        serde_json::to_value(status).map_err(|e| e.to_string())
+                    }
+                    AdminRequest::Moderate(moderation) => {
+                        moderate(&mut swarm, &mut bans, moderation)
+                    }
+                };
+                let _ = call.reply.send(result);
+                continue;
+            }
+            _ = reload.tick() => {
+                for peer in allowlist.as_mut().map(Allowlist::reload).unwrap_or_default() {
+                    if swarm.is_connected(&peer) {
+                        tracing::info!(%peer, "Disconnecting peer dropped from the allowlist");
+                        let _ = swarm.disconnect_peer_id(peer);
+                    }
+                }
+                for peer in admissions.as_mut().map(Admissions::reload).unwrap_or_default() {
+                    if swarm.is_connected(&peer) {
+                        tracing::info!(%peer, "Disconnecting peer whose token was revoked");
+                        let _ = swarm.disconnect_peer_id(peer);
+                    }
+                }
+                traffic.sweep();
+                continue;
+            }
+        };
+
+        metrics.swarm(&event);
+        activity.swarm(&event);
+        match event {
+            SwarmEvent::Behaviour(BehaviourEvent::Kademlia(kad::Event::InboundRequest {
+                request:
+                    kad::InboundRequest::PutRecord {
+                        source,
+                        record: Some(record),
+                        ..
+                    },
+            })) => {
+                presence::store(&mut swarm.behaviour_mut().kademlia, &source, record);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Admission(
+                request_response::Event::Message {
+                    peer,
+                    message:
+                        request_response::Message::Request {
+                            request, channel, ..
+                        },
+                },
+            )) => {
+                let Some(admissions) = &admissions else {
+                    continue;
+                };
+                let response = match admissions.admit(peer, &request.token) {
+                    Ok(claims) => {
+                        tracing::info!(%peer, token = claims.id, "Admitted peer");
+                        AdmissionResponse::Accepted {
+                            expires: claims.expires,
+                        }
+                    }
+                    Err(e) => {
+                        tracing::info!(%peer, "Refused admission token: {e}");
+                        AdmissionResponse::Rejected {
+                            reason: e.to_string(),
+                        }
+                    }
+                };
+                if let Some(admission) = swarm.behaviour_mut().admission.as_mut() {
+                    let _ = admission.send_response(channel, response);
+                }
+            }
             SwarmEvent::Behaviour(event) => {
+                match &event {
+                    BehaviourEvent::Relay(event) => {
+                        metrics.record(event);
+                        activity.relay(event, &traffic);
+                        metrics.update(&activity);
+                    }
+                    BehaviourEvent::Identify(event) => metrics.identify(event),
+                    BehaviourEvent::Ping(event) => metrics.record(event),
+                    BehaviourEvent::Kademlia(event) => metrics.record(event),
+                    _ => {}
+                }
                 if let BehaviourEvent::Identify(identify::Event::Received {
-                    info: identify::Info { observed_addr, .. },
-                    ..
+                    peer_id, info, ..
                 }) = &event
                 {
-                    swarm.add_external_address(observed_addr.clone());
+                    // Clients speaking the DHT protocol are routed to at their listen addresses.
+                    if info.protocols.contains(&presence::KAD_PROTOCOL) {
+                        for address in &info.listen_addrs {
+                            swarm
+                                .behaviour_mut()
+                                .kademlia
+                                .add_address(peer_id, address.clone());
+                        }
+                    }
                 }
 
-                println!("{event:?}")
+                tracing::debug!(?event, "Behaviour event");
             }
             SwarmEvent::NewListenAddr { address, .. } => {
                 println!("Listening on {address:?}");
             }
+            SwarmEvent::ExternalAddrConfirmed { address } => {
+                println!("Confirmed external address {address}");
+                addressed.store(true, Ordering::Relaxed);
+            }
+            SwarmEvent::ExternalAddrExpired { address } => {
+                println!("External address {address} expired");
+                if swarm.external_addresses().next().is_none() {
+                    tracing::warn!("No external address left, refusing reservations");
+                    addressed.store(false, Ordering::Relaxed);
+                }
+            }
+            SwarmEvent::ConnectionClosed {
+                num_established: 0, ..
+            } => {
+                // Reservations go with the last connection of their peer.
+                metrics.update(&activity);
+            }
             _ => {}
         }
     }
 }
 
+/// Kicks, bans or unbans a peer for the admin API, answering whether that
+/// changed anything.
+fn moderate(
+    swarm: &mut Swarm<Behaviour>,
+    bans: &mut Bans,
+    moderation: Moderation,
+) -> Result<Value, String> {
+    match moderation {
+        Moderation::Kick(peer) => {
+            tracing::info!(%peer, "Kicking peer");
+            Ok(json!(swarm.disconnect_peer_id(peer).is_ok()))
+        }
+        Moderation::Ban(peer) => {
+            let banned = bans.ban(peer).map_err(|e| e.to_string())?;
+            tracing::info!(%peer, "Banned peer");
+            // Closes the open connections as well.
+            swarm.behaviour_mut().blocked.block_peer(peer);
+            Ok(json!(banned))
+        }
+        Moderation::Unban(peer) => {
+            let unbanned = bans.unban(&peer).map_err(|e| e.to_string())?;
+            tracing::info!(%peer, "Lifted ban on peer");
+            swarm.behaviour_mut().blocked.unblock_peer(peer);
+            Ok(json!(unbanned))
+        }
+    }
+}
+
+/// TCP, QUIC and WebSocket, with what each connection carries counted in
+/// `traffic`.
+fn transport(
+    key: &Keypair,
+    tls: Option<websocket::tls::Config>,
+    traffic: Traffic,
+) -> Result<Boxed<(PeerId, StreamMuxerBox)>, Box<dyn Error + Send + Sync>> {
+    let tcp = tcp::tokio::Transport::new(tcp::Config::default())
+        .upgrade(Version::V1Lazy)
+        .authenticate(noise::Config::new(key)?)
+        .multiplex(yamux::Config::default())
+        .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)));
+    let quic = quic::tokio::Transport::new(quic::Config::new(key))
+        .map(|(peer, connection), _| (peer, StreamMuxerBox::new(connection)));
+
+    Ok(tcp
+        .or_transport(quic)
+        .or_transport(websocket_transport(key, tls)?)
+        .map(move |output, _| {
+            let (peer, muxer) = match output {
+                Either::Left(Either::Left(output) | Either::Right(output)) => output,
+                Either::Right(output) => output,
+            };
+            let muxer = traffic.count(peer, muxer);
+            (peer, muxer)
+        })
+        .boxed())
+}
+
+/// WebSocket listener for clients behind firewalls that only let HTTP(S)
+/// out, speaking WSS when `tls` is set.
+fn websocket_transport(
+    key: &Keypair,
+    tls: Option<websocket::tls::Config>,
+) -> Result<Boxed<(PeerId, StreamMuxerBox)>, Box<dyn Error + Send + Sync>> {
+    let mut transport = websocket::WsConfig::new(tcp::tokio::Transport::new(
+        tcp::Config::default().nodelay(true),
+    ));
+    if let Some(tls) = tls {
+        transport.set_tls_config(tls);
+    }
+
+    Ok(transport
+        .upgrade(Version::V1Lazy)
+        .authenticate(noise::Config::new(key)?)
+        .multiplex(yamux::Config::default())
+        .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)))
+        .boxed())
+}
+
 #[derive(NetworkBehaviour)]
 struct Behaviour {
     relay: relay::Behaviour,
     ping: ping::Behaviour,
     identify: identify::Behaviour,
-}
-
-fn generate_ed25519(secret_key_seed: u8) -> identity::Keypair {
-    let mut bytes = [0u8; 32];
-    bytes[0] = secret_key_seed;
-
-    identity::Keypair::ed25519_from_bytes(bytes).expect("only errors on wrong length")
+    /// Lets clients register under a namespace and look each other up.
+    rendezvous: rendezvous::server::Behaviour,
+    /// Bootstrap node of the DHT clients publish their presence in.
+    kademlia: kad::Behaviour<kad::store::MemoryStore>,
+    /// Confirms the addresses clients observe us at before we advertise them,
+    /// and tells clients whether they are publicly reachable.
+    autonat: autonat::Behaviour,
+    /// Checks the tokens clients present before reserving, only when
+    /// operators are configured.
+    admission: Toggle<admission::Behaviour>,
+    /// Refuses the peers banned through the admin API.
+    blocked: allow_block_list::Behaviour<BlockedPeers>,
 }
 
 #[derive(Debug, Parser)]
 #[clap(name = "libp2p relay")]
 struct Opt {
+    /// Path of the config file, defaults to `config.toml` in the user config directory
+    #[clap(long)]
+    config: Option<PathBuf>,
+
     /// Determine if the relay listen on ipv6 or ipv4 loopback address. the default is ipv4
     #[clap(long)]
     use_ipv6: Option<bool>,
 
-    /// Fixed value to generate deterministic peer id
+    /// Path of the identity file, defaults to `identity.key` in the user data directory
+    #[clap(long)]
+    identity: Option<PathBuf>,
+
+    /// INSECURE: derive the peer id from a single byte instead of the identity file.
+    /// Only 256 such identities exist, use this for local tests only
+    #[clap(long)]
+    insecure_test_seed: Option<u8>,
+
+    /// The port used to listen on all interfaces, defaults to 4001
     #[clap(long)]
-    secret_key_seed: u8,
+    port: Option<u16>,
 
-    /// The port used to listen on all interfaces
+    /// Public address to announce without waiting for AutoNAT to confirm it, may be repeated
     #[clap(long)]
-    port: u16,
+    external_address: Vec<Multiaddr>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Inspect the config file
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Mint and revoke admission tokens
+    Token {
+        #[clap(subcommand)]
+        command: TokenCommand,
+    },
+    /// Show the connected peers, reservations and circuits of the running relay
+    Status {
+        /// Print the raw JSON the admin API answered
+        #[clap(long)]
+        json: bool,
+    },
+    /// Kick or ban peers from the running relay
+    Peer {
+        #[clap(subcommand)]
+        command: PeerCommand,
+    },
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum ConfigCommand {
+    /// Parse and validate the config file together with the other flags
+    Check,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum TokenCommand {
+    /// Generate the operator keypair tokens are signed with and print its peer ID
+    Keygen {
+        /// Where to write the keypair, defaults to `operator.key` in the user data directory.
+        /// An existing keypair is never overwritten
+        #[clap(long)]
+        operator_key: Option<PathBuf>,
+    },
+    /// Sign a token admitting a peer, printed on stdout
+    Mint {
+        /// Peer ID of the client the token is for
+        #[clap(long)]
+        peer: PeerId,
+
+        /// Path of the operator keypair, defaults to `operator.key` in the user data directory.
+        /// Create it with `token keygen` first
+        #[clap(long)]
+        operator_key: Option<PathBuf>,
+
+        /// Days until the token expires
+        #[clap(long, default_value_t = 30)]
+        valid_days: u32,
+
+        /// Reservations the peer may request per hour, unlimited when unset
+        #[clap(long)]
+        reservations_per_hour: Option<u32>,
+
+        /// Circuits the peer may open through the relay per hour, unlimited when unset
+        #[clap(long)]
+        circuits_per_hour: Option<u32>,
+    },
+    /// Add a token ID to the revoked tokens file of this machine's relay config.
+    ///
+    /// Only relays reading that file see the revocation: relays on other hosts keep accepting the
+    /// token until the ID is added to their own file or the token expires.
+    Revoke {
+        /// ID printed when the token was minted
+        id: String,
+    },
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum PeerCommand {
+    /// Close the connections of a peer, it may reconnect
+    Kick { peer: PeerId },
+    /// Disconnect a peer and refuse it from now on, across restarts
+    Ban { peer: PeerId },
+    /// Let a banned peer connect again
+    Unban { peer: PeerId },
 }
@@ -0,0 +1,262 @@
+//! Prometheus metrics, served over HTTP at `/metrics` when `metrics.listen`
+//! is set. Next to what `libp2p-metrics` records for the swarm and the
+//! protocols, the relay keeps a few gauges and counters of its own to alert
+//! on saturation: reservations and circuits held, bytes relayed, identify
+//! pushes and connection errors by kind.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use libp2p::{
+    identify,
+    metrics::{Metrics as Libp2pMetrics, Recorder, Registry},
+    swarm::{DialError, ListenError, SwarmEvent},
+};
+use prometheus_client::{
+    encoding::{text, EncodeLabelSet, EncodeLabelValue},
+    metrics::{counter::Counter, family::Family, gauge::Gauge},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::activity::Activity;
+
+/// Largest request head read before answering, the endpoint takes no body.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ConnectionErrorLabels {
+    direction: Direction,
+    kind: ErrorKind,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelValue)]
+enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelValue)]
+enum ErrorKind {
+    Aborted,
+    WrongPeerId,
+    LocalPeerId,
+    Denied,
+    Transport,
+    NoAddresses,
+    DialPeerConditionFalse,
+    /// An established connection closed with an I/O error.
+    Io,
+}
+
+impl From<&ListenError> for ErrorKind {
+    fn from(error: &ListenError) -> Self {
+        match error {
+            ListenError::Aborted => ErrorKind::Aborted,
+            ListenError::WrongPeerId { .. } => ErrorKind::WrongPeerId,
+            ListenError::LocalPeerId { .. } => ErrorKind::LocalPeerId,
+            ListenError::Denied { .. } => ErrorKind::Denied,
+            ListenError::Transport(_) => ErrorKind::Transport,
+        }
+    }
+}
+
+impl From<&DialError> for ErrorKind {
+    fn from(error: &DialError) -> Self {
+        match error {
+            DialError::LocalPeerId { .. } => ErrorKind::LocalPeerId,
+            DialError::NoAddresses => ErrorKind::NoAddresses,
+            DialError::DialPeerConditionFalse(_) => ErrorKind::DialPeerConditionFalse,
+            DialError::Aborted => ErrorKind::Aborted,
+            DialError::WrongPeerId { .. } => ErrorKind::WrongPeerId,
+            DialError::Denied { .. } => ErrorKind::Denied,
+            DialError::Transport(_) => ErrorKind::Transport,
+        }
+    }
+}
+
+pub struct Metrics {
+    libp2p: Libp2pMetrics,
+    reservations: Gauge,
+    circuits: Gauge,
+    relayed_bytes: Counter,
+    identify_pushes: Counter,
+    connection_errors: Family<ConnectionErrorLabels, Counter>,
+}
+
+impl Metrics {
+    pub fn new(registry: &mut Registry) -> Self {
+        let libp2p = Libp2pMetrics::new(registry);
+        let registry = registry.sub_registry_with_prefix("limiinal_relay");
+
+        let reservations = Gauge::default();
+        registry.register(
+            "reservations",
+            "Peers holding a reservation",
+            reservations.clone(),
+        );
+        let circuits = Gauge::default();
+        registry.register("circuits", "Open circuits", circuits.clone());
+        let relayed_bytes = Counter::default();
+        registry.register(
+            "relayed_bytes",
+            "Bytes read from relay hop and stop streams: circuit traffic plus reservation (RESERVE) and circuit signalling",
+            relayed_bytes.clone(),
+        );
+        let identify_pushes = Counter::default();
+        registry.register(
+            "identify_pushes",
+            "Identify pushes sent to peers after our addresses or protocols changed",
+            identify_pushes.clone(),
+        );
+        let connection_errors = Family::default();
+        registry.register(
+            "connection_errors",
+            "Connections that failed to establish or closed with an error",
+            connection_errors.clone(),
+        );
+
+        Metrics {
+            libp2p,
+            reservations,
+            circuits,
+            relayed_bytes,
+            identify_pushes,
+            connection_errors,
+        }
+    }
+
+    /// Counter the transport adds relayed bytes to, see [`crate::traffic::Traffic`].
+    pub fn relayed_bytes(&self) -> Counter {
+        self.relayed_bytes.clone()
+    }
+
+    pub fn identify(&mut self, event: &identify::Event) {
+        self.libp2p.record(event);
+
+        if let identify::Event::Pushed { .. } = event {
+            self.identify_pushes.inc();
+        }
+    }
+
+    pub fn swarm<E>(&mut self, event: &SwarmEvent<E>) {
+        self.libp2p.record(event);
+
+        match event {
+            SwarmEvent::IncomingConnectionError { error, .. } => {
+                self.connection_error(Direction::Incoming, error.into());
+            }
+            SwarmEvent::OutgoingConnectionError { error, .. } => {
+                self.connection_error(Direction::Outgoing, error.into());
+            }
+            SwarmEvent::ConnectionClosed {
+                endpoint,
+                cause: Some(_),
+                ..
+            } => {
+                let direction = if endpoint.is_dialer() {
+                    Direction::Outgoing
+                } else {
+                    Direction::Incoming
+                };
+                self.connection_error(direction, ErrorKind::Io);
+            }
+            _ => {}
+        }
+    }
+
+    fn connection_error(&self, direction: Direction, kind: ErrorKind) {
+        self.connection_errors
+            .get_or_create(&ConnectionErrorLabels { direction, kind })
+            .inc();
+    }
+
+    /// Sets the gauges to what `activity` holds.
+    pub fn update(&self, activity: &Activity) {
+        self.reservations.set(activity.reservation_count() as i64);
+        self.circuits.set(activity.circuit_count() as i64);
+    }
+
+    /// Records the events of the protocols `libp2p-metrics` knows about.
+    pub fn record<E>(&self, event: &E)
+    where
+        Libp2pMetrics: Recorder<E>,
+    {
+        self.libp2p.record(event);
+    }
+}
+
+/// Serves `registry` at `http://<listen>/metrics` until the relay exits.
+pub async fn serve(listen: SocketAddr, registry: Registry) -> std::io::Result<()> {
+    let listener = TcpListener::bind(listen).await?;
+    tracing::info!(%listen, "Serving metrics at /metrics");
+
+    let registry = Arc::new(registry);
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    tracing::warn!("Failed to accept metrics connection: {e}");
+                    continue;
+                }
+            };
+            let registry = registry.clone();
+            tokio::spawn(async move {
+                if let Err(e) = respond(stream, &registry).await {
+                    tracing::debug!("Metrics request failed: {e}");
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+/// Answers a single HTTP/1.1 request, then closes the connection.
+async fn respond(mut stream: TcpStream, registry: &Registry) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|end| end == b"\r\n\r\n") {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 || request.len() + read > MAX_REQUEST_SIZE {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+
+    let request_line = request.split(|&b| b == b'\r').next().unwrap_or_default();
+    let mut parts = request_line.split(|&b| b == b' ');
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => {
+            let mut body = String::new();
+            match text::encode(&mut body, registry) {
+                Ok(()) => (
+                    "200 OK",
+                    "application/openmetrics-text; version=1.0.0; charset=utf-8",
+                    body,
+                ),
+                Err(_) => (
+                    "500 Internal Server Error",
+                    "text/plain",
+                    "failed to encode metrics\n".to_string(),
+                ),
+            }
+        }
+        (Some(b"GET"), _) => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n".to_string(),
+        ),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
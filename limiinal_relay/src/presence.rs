@@ -0,0 +1,24 @@
+//! The relay bootstraps the Kademlia DHT clients publish their presence
+//! records in, see [`limiinal_common::presence`] for what it stores.
+
+use libp2p::{
+    kad::{self, store::MemoryStore},
+    PeerId,
+};
+
+pub use limiinal_common::presence::KAD_PROTOCOL;
+
+/// The Kademlia behaviour in server mode, storing only valid presence records.
+pub fn new_behaviour(peer: PeerId) -> kad::Behaviour<MemoryStore> {
+    let mut kademlia = limiinal_common::presence::new_behaviour(peer);
+    // Clients reach the relay at its public address, no need to wait for it to be confirmed.
+    kademlia.set_mode(Some(kad::Mode::Server));
+    kademlia
+}
+
+/// Stores a record `source` asked us to keep, if it is a valid presence record.
+pub fn store(kademlia: &mut kad::Behaviour<MemoryStore>, source: &PeerId, record: kad::Record) {
+    if let Err(e) = limiinal_common::presence::store(kademlia.store_mut(), record) {
+        tracing::warn!(%source, "Rejected presence record: {e}");
+    }
+}
@@ -0,0 +1,301 @@
+//! Admission tokens: `<claims>.<signature>`, both base64url encoded. The
+//! claims are JSON naming the token, the operator that signed it, the peer it
+//! admits, when it expires and its quotas. Relays accept tokens signed by the
+//! operators in their config, so access is handed out without touching the
+//! servers; revoking a token adds its ID to a list the relays reload.
+
+use std::{
+    error::Error,
+    fmt, fs,
+    io::{self, Write},
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use libp2p::{
+    identity::{Keypair, PublicKey},
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Config, identity, Opt, TokenCommand, APP_NAME};
+
+/// Domain separation for the token signature.
+const SIGNATURE_CONTEXT: &[u8] = b"limiinal-admission-token-v1";
+
+/// Name of the operator keypair file inside the data directory.
+const OPERATOR_KEY_FILE: &str = "operator.key";
+
+/// Name of the list of revoked token IDs inside the data directory.
+const REVOKED_FILE: &str = "revoked_tokens.txt";
+
+#[derive(Debug)]
+pub enum TokenError {
+    /// Not `<claims>.<signature>` or the claims do not parse.
+    Malformed,
+    /// The signature is not made by the operator the claims name.
+    BadSignature,
+    /// The operator key is not an ed25519 one.
+    UnsupportedKey,
+    /// The operator is not trusted by this relay.
+    UnknownOperator(PeerId),
+    /// The token admits another peer than the one presenting it.
+    WrongPeer,
+    Expired,
+    Revoked,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Malformed => write!(f, "malformed token"),
+            TokenError::BadSignature => write!(f, "token is not signed by its operator"),
+            TokenError::UnsupportedKey => write!(f, "operator key is not an ed25519 key"),
+            TokenError::UnknownOperator(operator) => write!(f, "unknown operator {operator}"),
+            TokenError::WrongPeer => write!(f, "token was issued to another peer"),
+            TokenError::Expired => write!(f, "token expired"),
+            TokenError::Revoked => write!(f, "token was revoked"),
+        }
+    }
+}
+
+impl Error for TokenError {}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Claims {
+    /// Random ID the token is revoked by.
+    pub id: String,
+    pub operator: PeerId,
+    /// Peer the token admits.
+    pub peer: PeerId,
+    /// Unix time in seconds after which the token is refused.
+    pub expires: u64,
+    /// Reservations the peer may request per hour, unlimited beyond the
+    /// relay's own limits when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reservations_per_hour: Option<u32>,
+    /// Circuits the peer may open through the relay per hour.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub circuits_per_hour: Option<u32>,
+}
+
+impl Claims {
+    pub fn expires_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.expires)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at() <= SystemTime::now()
+    }
+}
+
+/// Signs `claims` with the operator `keypair`.
+pub fn mint(keypair: &Keypair, claims: &Claims) -> Result<String, TokenError> {
+    let claims = serde_json::to_vec(claims).expect("claims serialize");
+    let signature = keypair
+        .sign(&signed_bytes(&claims))
+        .map_err(|_| TokenError::UnsupportedKey)?;
+
+    Ok(format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(claims),
+        URL_SAFE_NO_PAD.encode(signature)
+    ))
+}
+
+/// Parses a token and checks it is signed by the operator it names. Whether
+/// that operator is trusted, the peer and the expiry are up to the caller.
+pub fn open(token: &str) -> Result<Claims, TokenError> {
+    let (claims, signature) = token.trim().split_once('.').ok_or(TokenError::Malformed)?;
+    let claims = URL_SAFE_NO_PAD
+        .decode(claims)
+        .map_err(|_| TokenError::Malformed)?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| TokenError::Malformed)?;
+    let parsed: Claims = serde_json::from_slice(&claims).map_err(|_| TokenError::Malformed)?;
+
+    let key = operator_key(&parsed.operator).ok_or(TokenError::UnsupportedKey)?;
+    if !key.verify(&signed_bytes(&claims), &signature) {
+        return Err(TokenError::BadSignature);
+    }
+
+    Ok(parsed)
+}
+
+/// The public key an ed25519 peer ID embeds.
+pub fn operator_key(operator: &PeerId) -> Option<PublicKey> {
+    let multihash = operator.as_ref();
+    if multihash.code() != 0 {
+        return None;
+    }
+
+    PublicKey::try_decode_protobuf(multihash.digest()).ok()
+}
+
+fn signed_bytes(claims: &[u8]) -> Vec<u8> {
+    let mut bytes = SIGNATURE_CONTEXT.to_vec();
+    bytes.extend_from_slice(claims);
+    bytes
+}
+
+/// Default location of the operator keypair.
+pub fn default_operator_key_path() -> PathBuf {
+    identity::data_dir(APP_NAME).join(OPERATOR_KEY_FILE)
+}
+
+/// Default location of the revoked token list.
+pub fn default_revoked_path() -> PathBuf {
+    identity::data_dir(APP_NAME).join(REVOKED_FILE)
+}
+
+/// Entry point of `limiinal_relay token`.
+pub fn run(opt: &Opt, command: &TokenCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        TokenCommand::Keygen { operator_key } => {
+            let path = operator_key
+                .clone()
+                .unwrap_or_else(default_operator_key_path);
+            if path.exists() {
+                return Err(
+                    format!("{} exists already, not overwriting it", path.display()).into(),
+                );
+            }
+            let keypair = identity::load_or_generate(&path)?;
+
+            eprintln!("Operator key written to {}", path.display());
+            println!("{}", keypair.public().to_peer_id());
+            Ok(())
+        }
+        TokenCommand::Mint {
+            peer,
+            operator_key,
+            valid_days,
+            reservations_per_hour,
+            circuits_per_hour,
+        } => {
+            let path = operator_key
+                .clone()
+                .unwrap_or_else(default_operator_key_path);
+            if !path.exists() {
+                return Err(format!(
+                    "no operator key at {}, create one with `token keygen`",
+                    path.display()
+                )
+                .into());
+            }
+            let keypair = identity::load_or_generate(&path)?;
+            let expires =
+                SystemTime::now() + Duration::from_secs(u64::from(*valid_days) * 24 * 60 * 60);
+            let claims = Claims {
+                id: format!("{:016x}", rand::random::<u64>()),
+                operator: keypair.public().to_peer_id(),
+                peer: *peer,
+                expires: expires.duration_since(UNIX_EPOCH)?.as_secs(),
+                reservations_per_hour: *reservations_per_hour,
+                circuits_per_hour: *circuits_per_hour,
+            };
+            let token = mint(&keypair, &claims)?;
+
+            eprintln!(
+                "Token {} for {peer}, valid for {valid_days} days",
+                claims.id
+            );
+            eprintln!(
+                "Relays accept it with `operators = [\"{}\"]`",
+                claims.operator
+            );
+            println!("{token}");
+            Ok(())
+        }
+        TokenCommand::Revoke { id } => {
+            let config = Config::load(opt)?;
+            let path = config.revoked_tokens_path();
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+            writeln!(file, "{}", id.trim())?;
+
+            println!("Revoked token {id} in {}", path.display());
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(operator: &Keypair) -> Claims {
+        Claims {
+            id: "0123456789abcdef".to_string(),
+            operator: operator.public().to_peer_id(),
+            peer: PeerId::random(),
+            expires: 4_102_444_800,
+            reservations_per_hour: Some(10),
+            circuits_per_hour: None,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let operator = Keypair::generate_ed25519();
+        let claims = claims(&operator);
+
+        let token = mint(&operator, &claims).unwrap();
+        assert_eq!(open(&token).unwrap(), claims);
+        assert!(!claims.is_expired());
+    }
+
+    #[test]
+    fn rejects_tampered_tokens() {
+        let operator = Keypair::generate_ed25519();
+        let mut claims = claims(&operator);
+        let token = mint(&operator, &claims).unwrap();
+        let (_, signature) = token.split_once('.').unwrap();
+
+        claims.reservations_per_hour = None;
+        let widened = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap());
+        assert!(matches!(
+            open(&format!("{widened}.{signature}")),
+            Err(TokenError::BadSignature)
+        ));
+
+        let mut flipped = token.into_bytes();
+        let last = flipped.len() - 2;
+        flipped[last] = if flipped[last] == b'A' { b'B' } else { b'A' };
+        assert!(matches!(
+            open(std::str::from_utf8(&flipped).unwrap()),
+            Err(TokenError::BadSignature)
+        ));
+
+        assert!(matches!(open("not a token"), Err(TokenError::Malformed)));
+    }
+
+    #[test]
+    fn rejects_tokens_signed_by_another_operator() {
+        let (operator, impostor) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+
+        let token = mint(&impostor, &claims(&operator)).unwrap();
+        assert!(matches!(open(&token), Err(TokenError::BadSignature)));
+    }
+
+    #[test]
+    fn expiry() {
+        let operator = Keypair::generate_ed25519();
+        let mut claims = claims(&operator);
+        claims.expires = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let token = mint(&operator, &claims).unwrap();
+        assert!(open(&token).unwrap().is_expired());
+    }
+}
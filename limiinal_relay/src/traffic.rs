@@ -0,0 +1,251 @@
+//! Counts the bytes each peer's connections carry and the bytes the relay
+//! forwards over circuits. The relay behaviour copies circuit data between
+//! its streams internally, so the count is taken one layer down: every
+//! stream of every connection is watched until multistream-select names its
+//! protocol, and the bytes read from circuit relay streams (`hop` from the
+//! source, `stop` from the destination) are counted as relayed from then on.
+//! Relay signalling goes in with them, including the RESERVE requests and
+//! answers of reservations, which open hop streams too.
+
+use std::{
+    collections::HashMap,
+    io::{self, IoSlice, IoSliceMut},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+
+use futures::{ready, AsyncRead, AsyncWrite};
+use libp2p::{
+    core::{
+        muxing::{StreamMuxerBox, StreamMuxerEvent, StreamMuxerExt, SubstreamBox},
+        StreamMuxer,
+    },
+    PeerId,
+};
+use prometheus_client::metrics::counter::Counter;
+use serde::{Deserialize, Serialize};
+
+/// Shared by the hop and stop protocols of circuit relay v2.
+const CIRCUIT_PROTOCOL_PREFIX: &[u8] = b"/libp2p/circuit/relay/0.2.0/";
+
+/// Bytes read from a stream before giving up on it being a circuit. The
+/// protocol name is in the first multistream-select message either way.
+const SNIFF_LIMIT: usize = 128;
+
+/// What the connections of a peer carried so far.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct Bytes {
+    pub received: u64,
+    pub sent: u64,
+    /// Received over circuits, to be forwarded to the other end.
+    pub relayed: u64,
+}
+
+#[derive(Debug, Default)]
+struct PeerCounters {
+    received: AtomicU64,
+    sent: AtomicU64,
+    relayed: AtomicU64,
+}
+
+/// Byte counters of every connected peer, shared with their connections.
+#[derive(Debug, Clone)]
+pub struct Traffic {
+    relayed: Counter,
+    peers: Arc<Mutex<HashMap<PeerId, Arc<PeerCounters>>>>,
+}
+
+impl Traffic {
+    /// Counts the relayed bytes of all peers in `relayed` as well.
+    pub fn new(relayed: Counter) -> Self {
+        Traffic {
+            relayed,
+            peers: Arc::default(),
+        }
+    }
+
+    /// Wraps a connection to `peer` to count what it carries.
+    pub fn count(&self, peer: PeerId, muxer: StreamMuxerBox) -> StreamMuxerBox {
+        let counters = self.peers.lock().unwrap().entry(peer).or_default().clone();
+        StreamMuxerBox::new(Muxer {
+            inner: muxer,
+            relayed: self.relayed.clone(),
+            counters,
+        })
+    }
+
+    /// What the connections to `peer` carried since the first one opened.
+    pub fn bytes(&self, peer: &PeerId) -> Bytes {
+        let peers = self.peers.lock().unwrap();
+        let Some(counters) = peers.get(peer) else {
+            return Bytes::default();
+        };
+
+        Bytes {
+            received: counters.received.load(Ordering::Relaxed),
+            sent: counters.sent.load(Ordering::Relaxed),
+            relayed: counters.relayed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Forgets peers none of whose connections or streams are left.
+    pub fn sweep(&self) {
+        self.peers
+            .lock()
+            .unwrap()
+            .retain(|_, counters| Arc::strong_count(counters) > 1);
+    }
+}
+
+struct Muxer {
+    inner: StreamMuxerBox,
+    relayed: Counter,
+    counters: Arc<PeerCounters>,
+}
+
+impl Muxer {
+    fn stream(&self, inner: SubstreamBox) -> Stream {
+        Stream {
+            inner,
+            relayed: self.relayed.clone(),
+            counters: self.counters.clone(),
+            kind: Kind::Sniffing(Vec::new()),
+        }
+    }
+}
+
+impl StreamMuxer for Muxer {
+    type Substream = Stream;
+    type Error = io::Error;
+
+    fn poll_inbound(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let inner = ready!(self.inner.poll_inbound_unpin(cx))?;
+        Poll::Ready(Ok(self.stream(inner)))
+    }
+
+    fn poll_outbound(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let inner = ready!(self.inner.poll_outbound_unpin(cx))?;
+        Poll::Ready(Ok(self.stream(inner)))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_close_unpin(cx)
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+        self.inner.poll_unpin(cx)
+    }
+}
+
+enum Kind {
+    /// Protocol not known yet, holding what was read so far.
+    Sniffing(Vec<u8>),
+    Circuit,
+    Other,
+}
+
+struct Stream {
+    inner: SubstreamBox,
+    relayed: Counter,
+    counters: Arc<PeerCounters>,
+    kind: Kind,
+}
+
+impl Stream {
+    fn read(&mut self, bytes: &[u8]) {
+        let read = bytes.len() as u64;
+        self.counters.received.fetch_add(read, Ordering::Relaxed);
+        match &mut self.kind {
+            Kind::Circuit => {
+                self.relayed.inc_by(read);
+                self.counters.relayed.fetch_add(read, Ordering::Relaxed);
+            }
+            Kind::Sniffing(seen) => {
+                seen.extend_from_slice(bytes);
+                if seen
+                    .windows(CIRCUIT_PROTOCOL_PREFIX.len())
+                    .any(|window| window == CIRCUIT_PROTOCOL_PREFIX)
+                {
+                    self.kind = Kind::Circuit;
+                } else if seen.len() >= SNIFF_LIMIT {
+                    self.kind = Kind::Other;
+                }
+            }
+            Kind::Other => {}
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let read = ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.read(&buf[..read]);
+        Poll::Ready(Ok(read))
+    }
+
+    fn poll_read_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let read = ready!(Pin::new(&mut self.inner).poll_read_vectored(cx, bufs))?;
+        let mut left = read;
+        for buf in bufs.iter() {
+            let taken = left.min(buf.len());
+            self.read(&buf[..taken]);
+            left -= taken;
+        }
+        Poll::Ready(Ok(read))
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.counters
+            .sent
+            .fetch_add(written as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write_vectored(cx, bufs))?;
+        self.counters
+            .sent
+            .fetch_add(written as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}